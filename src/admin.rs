@@ -0,0 +1,110 @@
+//! HTTP API for inspecting and operating a running controller
+
+use crate::{reconcile::read_backup, types::PreservedState, Context, Surface};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use k8s_openapi::{api::core::v1::Node, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use std::{sync::Arc, time::Instant};
+
+/// Routes served on the admin address:
+/// - `GET /backoff`: The retry state of every node whose last reconcile failed
+/// - `POST /backoff/{node}/reset`: Clear a node's retry state and reconcile it immediately
+/// - `GET /backups/{node}`: The state preserved for a node, as a [`PreservedState`] with
+///   values redacted for [`Surface::Admin`]
+/// - `GET /shadow`: How the candidate policy would have changed restores
+/// - `GET /metrics`: Counters in the Prometheus text format
+/// - `GET /warmup`: Progress through the startup warmup, during which restores are paced
+/// - `GET /readyz`: 200 once the node watch has completed its initial list, 503 before
+/// - `GET /healthz`: 200 while nodes are being reconciled, 503 after none was for the liveness
+///   window
+pub fn router(ctx: Arc<Context>) -> Router {
+    Router::new()
+        .route("/readyz", get(readyz))
+        .route("/healthz", get(healthz))
+        .route("/backoff", get(backoff))
+        .route("/backoff/{node}/reset", post(reset_backoff))
+        .route("/backups/{node}", get(backup))
+        .route("/shadow", get(shadow))
+        .route("/warmup", get(warmup))
+        .route("/metrics", get(metrics))
+        .with_state(ctx)
+}
+
+async fn backoff(State(ctx): State<Arc<Context>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!(ctx.backoff_states()))
+}
+
+async fn reset_backoff(
+    State(ctx): State<Arc<Context>>,
+    Path(node): Path<String>,
+) -> Json<serde_json::Value> {
+    let previous = ctx.reset_backoff(&node);
+    Json(serde_json::json!({
+        "node": node,
+        "previous": previous,
+    }))
+}
+
+async fn backup(
+    State(ctx): State<Arc<Context>>,
+    Path(node): Path<String>,
+) -> Result<Json<PreservedState>, (StatusCode, String)> {
+    // Without the node's labels, every backup namespace is searched
+    let lookup = Node {
+        metadata: ObjectMeta {
+            name: Some(node.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match read_backup(&lookup, &ctx).await {
+        Ok(Some(backup)) => {
+            let mut state = PreservedState::from_backup(Some(node), &backup);
+            state.labels = ctx.config.redactor.labels(Surface::Admin, &state.labels);
+            Ok(Json(state))
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("no backup of node '{}'", node),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+async fn shadow(State(ctx): State<Arc<Context>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!(ctx.shadow_stats()))
+}
+
+async fn warmup(State(ctx): State<Arc<Context>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!(ctx.warmup_progress()))
+}
+
+async fn metrics(State(ctx): State<Arc<Context>>) -> String {
+    ctx.render_metrics()
+}
+
+async fn readyz(State(ctx): State<Arc<Context>>) -> (StatusCode, &'static str) {
+    if ctx.is_ready() {
+        (StatusCode::OK, "ok")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "waiting for the node watch",
+        )
+    }
+}
+
+async fn healthz(State(ctx): State<Arc<Context>>) -> (StatusCode, &'static str) {
+    if ctx.is_live(Instant::now()) {
+        (StatusCode::OK, "ok")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no node reconciled recently",
+        )
+    }
+}
@@ -0,0 +1,149 @@
+//! The format of the restored annotation's value. Controllers of different versions run side by
+//! side during rollouts and after rollbacks, so every controller reads every format in
+//! [`RESTORED_ANNOTATION_FORMATS`] and writes only the newest.
+//!
+//! Whether a node was restored depends on the annotation being present, and on the backup not
+//! having changed since: an annotation recording the digest of the restored labels is restored
+//! again once the backup holds different labels. A value no format here can read, e.g. one
+//! written by a newer controller, still means the node was restored; it just carries no
+//! correlation ID or digest, so it never matches a backup.
+
+use crate::{Backup, RESTORED_ANNOTATION_KEY};
+use k8s_openapi::api::core::v1::Node;
+use kube::ResourceExt;
+use serde::{Deserialize, Serialize};
+
+/// The format version of the restored annotation written by this controller
+pub const RESTORED_ANNOTATION_VERSION: u32 = 2;
+
+/// A restored annotation format, and how long it is supported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnnotationFormat {
+    pub version: u32,
+    /// A value in this format
+    pub example: &'static str,
+    /// The last release that writes this format, or None if it is still written
+    pub last_written: Option<&'static str>,
+    /// The last release that reads this format, or None if no removal is planned. A format is
+    /// read for at least two minor releases after the last one writing it, so a fleet mid-rollout
+    /// or rolled back by a release never sees a value it can't place.
+    pub read_until: Option<&'static str>,
+}
+
+/// Every format this controller reads, oldest first. Drop an entry only after its `read_until`
+/// release.
+pub const RESTORED_ANNOTATION_FORMATS: &[AnnotationFormat] = &[
+    // The bare correlation ID of the restored backup, or 1 if the backup had none
+    AnnotationFormat {
+        version: 1,
+        example: "5f0c7c4e-8a0b-4d8e-9f3a-2f6f3f1c9b7e",
+        last_written: Some("0.1.0"),
+        read_until: Some("0.3.0"),
+    },
+    // A JSON object with the version, the correlation ID, if any, and the digest of the restored
+    // labels, which older releases of this version don't write. New fields can be added within
+    // a version as long as older readers can ignore them.
+    AnnotationFormat {
+        version: 2,
+        example: r#"{"v":2,"correlationId":"5f0c7c4e-8a0b-4d8e-9f3a-2f6f3f1c9b7e","labelsDigest":"f634f8924adc209d8a4077e688810042adc32b2834cf2ae1bd0ea9d453529d7a"}"#,
+        last_written: None,
+        read_until: None,
+    },
+];
+
+/// The value of the restored annotation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestoredAnnotation {
+    /// The format the value was read from, or None if no supported format could read it
+    pub version: Option<u32>,
+    /// The correlation ID of the restored backup, if it had one
+    pub correlation_id: Option<String>,
+    /// [`Backup::labels_digest`] of the restored backup, unless an older release restored it
+    pub labels_digest: Option<String>,
+}
+
+/// The JSON formats, from version 2 on
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Versioned {
+    v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    labels_digest: Option<String>,
+}
+
+impl RestoredAnnotation {
+    /// The annotation to write for a restore of the backup with `correlation_id`. It holds
+    /// nothing that changes between restores of the same backup, so re-applying a restore
+    /// leaves the node unchanged.
+    pub fn new(correlation_id: Option<&str>) -> Self {
+        Self {
+            version: Some(RESTORED_ANNOTATION_VERSION),
+            correlation_id: correlation_id.map(str::to_string),
+            labels_digest: None,
+        }
+    }
+
+    /// The annotation to write for a restore of `backup`, recording its labels' digest
+    pub fn of_backup(backup: &Backup) -> Self {
+        Self {
+            labels_digest: Some(backup.labels_digest()),
+            ..Self::new(backup.correlation_id.as_deref())
+        }
+    }
+
+    /// The restored annotation on `node`, or None if it wasn't restored
+    pub fn of(node: &Node) -> Option<Self> {
+        node.annotations()
+            .get(RESTORED_ANNOTATION_KEY)
+            .map(|value| Self::parse(value))
+    }
+
+    /// Read a value in any supported format. Values in no supported format are kept as an
+    /// unknown version without a correlation ID, rather than failing.
+    pub fn parse(value: &str) -> Self {
+        if !value.starts_with('{') {
+            return Self {
+                version: Some(1),
+                correlation_id: (value != "1" && !value.is_empty()).then(|| value.to_string()),
+                labels_digest: None,
+            };
+        }
+        match serde_json::from_str::<Versioned>(value) {
+            Ok(versioned) if versioned.v == 2 => Self {
+                version: Some(2),
+                correlation_id: versioned.correlation_id,
+                labels_digest: versioned.labels_digest,
+            },
+            _ => Self {
+                version: None,
+                correlation_id: None,
+                labels_digest: None,
+            },
+        }
+    }
+
+    /// The value to write, always in the newest format
+    pub fn to_value(&self) -> String {
+        let versioned = Versioned {
+            v: RESTORED_ANNOTATION_VERSION,
+            correlation_id: self.correlation_id.clone(),
+            labels_digest: self.labels_digest.clone(),
+        };
+        serde_json::to_string(&versioned).expect("the annotation always serializes")
+    }
+
+    /// Whether the annotation records a restore of labels other than those with `digest`.
+    /// Annotations without a digest never do.
+    pub fn is_outdated(&self, digest: &str) -> bool {
+        self.labels_digest
+            .as_deref()
+            .is_some_and(|restored| restored != digest)
+    }
+
+    /// Whether the annotation records a restore of the backup with `correlation_id`
+    pub fn is_from(&self, correlation_id: Option<&str>) -> bool {
+        self.correlation_id.is_some() && self.correlation_id.as_deref() == correlation_id
+    }
+}
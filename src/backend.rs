@@ -0,0 +1,408 @@
+//! What reconciles read and write through: backups behind a [`LabelStore`], node patches
+//! behind a [`NodePatcher`]. The controller uses the API server, tests can use the in-memory
+//! fakes instead.
+
+use crate::{shard_key, Backup, BackupLayout, BackupStore, Deletion, Error, Operation, Result};
+use futures::future::{BoxFuture, FutureExt};
+use k8s_openapi::api::core::v1::{ConfigMap, Node};
+use kube::{
+    api::{Api, Patch, PatchParams, ResourceExt},
+    error::ErrorResponse,
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+/// A backup and its version, see [`BackupStore::version`]
+type Versioned = (Backup, Option<String>);
+
+/// Reads and writes node backups. `store` says where and how a backup is kept: its namespace,
+/// layout, and encryption keys.
+pub trait LabelStore: Send + Sync {
+    /// The backup of `node_name` and its version, or None if it has none, see
+    /// [`BackupStore::read`]
+    fn read<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Versioned>>>;
+
+    /// Write `configmap` as the backup of `node_name`, see [`BackupStore::write`]
+    fn write<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        configmap: &'a ConfigMap,
+    ) -> BoxFuture<'a, Result<ConfigMap>>;
+
+    /// Delete the backup of `node_name` unless it changed since `version`, see
+    /// [`BackupStore::delete`]
+    fn delete<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        version: Option<String>,
+    ) -> BoxFuture<'a, Result<Deletion>>;
+}
+
+/// Patches nodes. Errors are the API server's, so callers can tell conflicts apart.
+pub trait NodePatcher: Send + Sync {
+    /// The node named `name`
+    fn get<'a>(&'a self, name: &'a str) -> BoxFuture<'a, kube::Result<Node>>;
+
+    /// Server-side apply `node` with `params`. Returns the node as admitted.
+    fn apply<'a>(
+        &'a self,
+        node: &'a Node,
+        params: &'a PatchParams,
+    ) -> BoxFuture<'a, kube::Result<Node>>;
+
+    /// Merge `patch` into the node named `name`. Returns the node as admitted.
+    fn merge<'a>(&'a self, name: &'a str, patch: &'a Value) -> BoxFuture<'a, kube::Result<Node>>;
+}
+
+/// Backups in ConfigMaps on the API server
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KubeLabelStore;
+
+impl LabelStore for KubeLabelStore {
+    fn read<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Versioned>>> {
+        store.read(node_name).boxed()
+    }
+
+    fn write<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        configmap: &'a ConfigMap,
+    ) -> BoxFuture<'a, Result<ConfigMap>> {
+        store.write(node_name, configmap).boxed()
+    }
+
+    fn delete<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        version: Option<String>,
+    ) -> BoxFuture<'a, Result<Deletion>> {
+        store.delete(node_name, version).boxed()
+    }
+}
+
+/// Nodes on the API server
+impl NodePatcher for Api<Node> {
+    fn get<'a>(&'a self, name: &'a str) -> BoxFuture<'a, kube::Result<Node>> {
+        Api::get(self, name).boxed()
+    }
+
+    fn apply<'a>(
+        &'a self,
+        node: &'a Node,
+        params: &'a PatchParams,
+    ) -> BoxFuture<'a, kube::Result<Node>> {
+        async move {
+            let name = node.name_any();
+            self.patch(&name, params, &Patch::Apply(node)).await
+        }
+        .boxed()
+    }
+
+    fn merge<'a>(&'a self, name: &'a str, patch: &'a Value) -> BoxFuture<'a, kube::Result<Node>> {
+        async move {
+            self.patch(name, &PatchParams::default(), &Patch::Merge(patch))
+                .await
+        }
+        .boxed()
+    }
+}
+
+/// An API error with status `code`, as injected by [`MemoryLabelStore::fail_next`] and
+/// [`MemoryNodePatcher::fail_next`]
+fn injected(code: u16) -> kube::Error {
+    kube::Error::Api(ErrorResponse {
+        status: "Failure".to_string(),
+        message: format!("injected failure with status {}", code),
+        reason: match code {
+            404 => "NotFound",
+            409 => "Conflict",
+            _ => "InternalError",
+        }
+        .to_string(),
+        code,
+    })
+}
+
+/// Merge `patch` into `target` as a JSON merge patch: objects are merged, nulls remove keys, and
+/// anything else replaces what was there
+fn merge_json(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("made an object above");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_json(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Backup ConfigMaps held in memory, laid out and encrypted like on the API server
+#[derive(Default)]
+pub struct MemoryLabelStore {
+    /// By namespace and name
+    configmaps: Mutex<BTreeMap<(String, String), ConfigMap>>,
+    /// Status codes the next calls fail with, in order
+    failures: Mutex<VecDeque<u16>>,
+    /// The last resourceVersion given out
+    version: Mutex<u64>,
+}
+
+impl MemoryLabelStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `configmap` as is, e.g. to read a hand-edited backup
+    pub fn insert(&self, mut configmap: ConfigMap) {
+        self.stamp(&mut configmap);
+        let key = (
+            configmap.namespace().unwrap_or_default(),
+            configmap.name_any(),
+        );
+        self.configmaps.lock().unwrap().insert(key, configmap);
+    }
+
+    /// The ConfigMap `name` in `namespace`
+    pub fn get(&self, namespace: &str, name: &str) -> Option<ConfigMap> {
+        let key = (namespace.to_string(), name.to_string());
+        self.configmaps.lock().unwrap().get(&key).cloned()
+    }
+
+    /// Fail the next call with an API error with status `code`. Calls fail in the order queued.
+    pub fn fail_next(&self, code: u16) {
+        self.failures.lock().unwrap().push_back(code);
+    }
+
+    /// The failure queued for this call, if any, from `operation`
+    fn failure(&self, operation: Operation) -> Result<()> {
+        match self.failures.lock().unwrap().pop_front() {
+            Some(code) => Err(Error::from_api(operation, injected(code))),
+            None => Ok(()),
+        }
+    }
+
+    /// Give `configmap` a new resourceVersion
+    fn stamp(&self, configmap: &mut ConfigMap) {
+        let mut version = self.version.lock().unwrap();
+        *version += 1;
+        configmap.metadata.resource_version = Some(version.to_string());
+    }
+}
+
+impl LabelStore for MemoryLabelStore {
+    fn read<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Versioned>>> {
+        async move {
+            let namespace = store.namespace().to_string();
+            self.failure(Operation::ReadBackup { namespace })?;
+            match self.get(store.namespace(), &store.configmap_name(node_name)) {
+                Some(configmap) => store.parse(node_name, &configmap),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn write<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        configmap: &'a ConfigMap,
+    ) -> BoxFuture<'a, Result<ConfigMap>> {
+        async move {
+            let namespace = store.namespace().to_string();
+            self.failure(Operation::WriteBackup { namespace })?;
+            let name = store.configmap_name(node_name);
+            let mut configmaps = self.configmaps.lock().unwrap();
+            let key = (store.namespace().to_string(), name.clone());
+            let mut written = match store.layout() {
+                BackupLayout::PerNode => {
+                    let mut configmap = configmap.clone();
+                    store.keys().seal(&mut configmap)?;
+                    configmap
+                }
+                BackupLayout::Sharded => {
+                    let mut shard = configmaps.remove(&key).unwrap_or_default();
+                    shard.metadata.name = Some(name);
+                    shard
+                        .data
+                        .get_or_insert_with(BTreeMap::new)
+                        .insert(shard_key(node_name), BackupStore::shard_entry(configmap)?);
+                    shard
+                }
+            };
+            written.metadata.namespace = Some(store.namespace().to_string());
+            self.stamp(&mut written);
+            configmaps.insert(key, written.clone());
+            Ok(written)
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(
+        &'a self,
+        store: &'a BackupStore,
+        node_name: &'a str,
+        version: Option<String>,
+    ) -> BoxFuture<'a, Result<Deletion>> {
+        async move {
+            let namespace = store.namespace().to_string();
+            self.failure(Operation::DeleteBackup { namespace })?;
+            let key = (
+                store.namespace().to_string(),
+                store.configmap_name(node_name),
+            );
+            let mut configmaps = self.configmaps.lock().unwrap();
+            let Some(configmap) = configmaps.get(&key) else {
+                return Ok(Deletion::Missing);
+            };
+            let current = store.version(node_name, configmap);
+            if current.is_none() || version.is_some_and(|version| Some(version) != current) {
+                return Ok(Deletion::Changed);
+            }
+            match store.layout() {
+                BackupLayout::PerNode => {
+                    configmaps.remove(&key);
+                }
+                BackupLayout::Sharded => {
+                    let mut shard = configmaps.remove(&key).expect("looked up above");
+                    if let Some(data) = shard.data.as_mut() {
+                        data.remove(&shard_key(node_name));
+                    }
+                    self.stamp(&mut shard);
+                    configmaps.insert(key, shard);
+                }
+            }
+            Ok(Deletion::Deleted)
+        }
+        .boxed()
+    }
+}
+
+/// Nodes held in memory. Applies are merged into the node like merge patches, so unlike
+/// server-side apply they never remove fields, and a `metadata.resourceVersion` in a patch
+/// must match the node's.
+#[derive(Default)]
+pub struct MemoryNodePatcher {
+    nodes: Mutex<BTreeMap<String, Node>>,
+    /// Every apply and merge, as the node name and the patch
+    patches: Mutex<Vec<(String, Value)>>,
+    /// Status codes the next calls fail with, in order
+    failures: Mutex<VecDeque<u16>>,
+    /// The last resourceVersion given out
+    version: Mutex<u64>,
+}
+
+impl MemoryNodePatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `node`, replacing any node of the same name
+    pub fn insert(&self, mut node: Node) {
+        self.stamp(&mut node);
+        self.nodes.lock().unwrap().insert(node.name_any(), node);
+    }
+
+    /// The node named `name`, as patched so far
+    pub fn node(&self, name: &str) -> Option<Node> {
+        self.nodes.lock().unwrap().get(name).cloned()
+    }
+
+    /// Every patch made so far, as the node name and the patch
+    pub fn patches(&self) -> Vec<(String, Value)> {
+        self.patches.lock().unwrap().clone()
+    }
+
+    /// Fail the next call with an API error with status `code`. Calls fail in the order queued.
+    pub fn fail_next(&self, code: u16) {
+        self.failures.lock().unwrap().push_back(code);
+    }
+
+    /// The failure queued for this call, if any
+    fn failure(&self) -> kube::Result<()> {
+        match self.failures.lock().unwrap().pop_front() {
+            Some(code) => Err(injected(code)),
+            None => Ok(()),
+        }
+    }
+
+    /// Give `node` a new resourceVersion
+    fn stamp(&self, node: &mut Node) {
+        let mut version = self.version.lock().unwrap();
+        *version += 1;
+        node.metadata.resource_version = Some(version.to_string());
+    }
+
+    /// Merge `patch` into the node named `name`
+    fn patch(&self, name: &str, patch: Value) -> kube::Result<Node> {
+        self.failure()?;
+        self.patches
+            .lock()
+            .unwrap()
+            .push((name.to_string(), patch.clone()));
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(name).ok_or_else(|| injected(404))?;
+        if let Some(version) = patch["metadata"]["resourceVersion"].as_str() {
+            if node.metadata.resource_version.as_deref() != Some(version) {
+                return Err(injected(409));
+            }
+        }
+        let mut merged = serde_json::to_value(&*node).map_err(kube::Error::SerdeError)?;
+        merge_json(&mut merged, &patch);
+        *node = serde_json::from_value(merged).map_err(kube::Error::SerdeError)?;
+        self.stamp(node);
+        Ok(node.clone())
+    }
+}
+
+impl NodePatcher for MemoryNodePatcher {
+    fn get<'a>(&'a self, name: &'a str) -> BoxFuture<'a, kube::Result<Node>> {
+        async move {
+            self.failure()?;
+            self.node(name).ok_or_else(|| injected(404))
+        }
+        .boxed()
+    }
+
+    fn apply<'a>(
+        &'a self,
+        node: &'a Node,
+        _params: &'a PatchParams,
+    ) -> BoxFuture<'a, kube::Result<Node>> {
+        async move {
+            let patch = serde_json::to_value(node).map_err(kube::Error::SerdeError)?;
+            self.patch(&node.name_any(), patch)
+        }
+        .boxed()
+    }
+
+    fn merge<'a>(&'a self, name: &'a str, patch: &'a Value) -> BoxFuture<'a, kube::Result<Node>> {
+        async move { self.patch(name, patch.clone()) }.boxed()
+    }
+}
@@ -0,0 +1,234 @@
+//! Backup bundles: every backup ConfigMap's data in one file, for keeping exports off-cluster.
+//! A bundle can be verified without a cluster using the same rules restores apply.
+
+use crate::{
+    configmap_name, types::PreservedState, KnownPrefixes, Redactor, Result, Surface, SCHEMA_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The bundle file format written by this version
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub backups: Vec<BundleEntry>,
+}
+
+/// One backup ConfigMap
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub configmap: String,
+    /// The node the backup was taken from, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// The ConfigMap data, as read by [`crate::Backup::from_configmap_data`]
+    pub data: BTreeMap<String, String>,
+    /// [`checksum`] of `data` when the bundle was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+impl BundleEntry {
+    /// The preserved state the entry holds
+    pub fn state(&self) -> Result<PreservedState> {
+        PreservedState::from_configmap_data(self.node.clone(), &self.data)
+    }
+}
+
+/// The hex SHA-256 of the JSON serialization of `data`. Keys serialize in order, so equal
+/// data always has the same checksum.
+pub fn checksum(data: &BTreeMap<String, String>) -> String {
+    let json = serde_json::to_vec(data).expect("string maps always serialize");
+    hex::encode(Sha256::digest(json))
+}
+
+/// Something wrong with a bundle
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    /// The ConfigMap of the entry at fault, or None for the bundle as a whole
+    pub configmap: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.configmap {
+            Some(configmap) => write!(f, "{}: {}", configmap, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// The result of verifying a bundle
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Verification {
+    pub backups: usize,
+    pub labels: usize,
+    pub problems: Vec<Problem>,
+    /// Findings worth a look that don't make the bundle invalid, e.g. label keys whose prefix
+    /// has no known owner
+    pub warnings: Vec<Problem>,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// A human-readable summary, one problem per line
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "{} backups with {} labels, {} problems",
+            self.backups,
+            self.labels,
+            self.problems.len()
+        );
+        if !self.warnings.is_empty() {
+            out.push_str(&format!(", {} warnings", self.warnings.len()));
+        }
+        out.push('\n');
+        for problem in &self.problems {
+            out.push_str(&format!("  {}\n", problem));
+        }
+        for warning in &self.warnings {
+            out.push_str(&format!("  warning: {}\n", warning));
+        }
+        out
+    }
+}
+
+/// Verify a bundle file's contents, with the default [`Redactor`]
+pub fn verify_json(json: &str) -> Verification {
+    verify_json_with(json, &Redactor::default())
+}
+
+/// Verify a bundle file's contents, hiding label values in problems as `redactor` does for
+/// [`Surface::Reports`]
+pub fn verify_json_with(json: &str, redactor: &Redactor) -> Verification {
+    verify_json_against(json, redactor, &KnownPrefixes::default())
+}
+
+/// [`verify_json_with`], also warning about label keys whose prefix isn't in `known_prefixes`
+pub fn verify_json_against(
+    json: &str,
+    redactor: &Redactor,
+    known_prefixes: &KnownPrefixes,
+) -> Verification {
+    match serde_json::from_str::<Bundle>(json) {
+        Ok(bundle) => verify_against(&bundle, redactor, known_prefixes),
+        Err(e) => Verification {
+            problems: vec![Problem {
+                configmap: None,
+                message: format!("not a backup bundle: {}", e),
+            }],
+            ..Default::default()
+        },
+    }
+}
+
+/// Check the bundle version, then each entry's checksum, schema version, and labels, and that
+/// no ConfigMap or node appears twice
+pub fn verify(bundle: &Bundle) -> Verification {
+    verify_with(bundle, &Redactor::default())
+}
+
+/// [`verify`], hiding label values in problems as `redactor` does for [`Surface::Reports`]
+pub fn verify_with(bundle: &Bundle, redactor: &Redactor) -> Verification {
+    verify_against(bundle, redactor, &KnownPrefixes::default())
+}
+
+/// [`verify_with`], also warning about label keys whose prefix isn't in `known_prefixes`
+pub fn verify_against(
+    bundle: &Bundle,
+    redactor: &Redactor,
+    known_prefixes: &KnownPrefixes,
+) -> Verification {
+    let mut warnings = Vec::new();
+    let mut verification = Verification {
+        backups: bundle.backups.len(),
+        ..Default::default()
+    };
+    let mut problem = |configmap: Option<&str>, message: String| {
+        verification.problems.push(Problem {
+            configmap: configmap.map(str::to_string),
+            message,
+        })
+    };
+    if bundle.version != BUNDLE_VERSION {
+        problem(
+            None,
+            format!(
+                "unsupported bundle version {}, expected {}",
+                bundle.version, BUNDLE_VERSION
+            ),
+        );
+    }
+
+    let mut labels = 0;
+    let mut configmaps = BTreeSet::new();
+    let mut nodes = BTreeSet::new();
+    for entry in &bundle.backups {
+        let configmap = Some(entry.configmap.as_str());
+        if !configmaps.insert(&entry.configmap) {
+            problem(configmap, "duplicate ConfigMap".to_string());
+        }
+        if let Some(node) = &entry.node {
+            if !nodes.insert(node) {
+                problem(configmap, format!("duplicate backup of node '{}'", node));
+            }
+            if configmap_name(node) != entry.configmap {
+                problem(
+                    configmap,
+                    format!("is not the backup ConfigMap of node '{}'", node),
+                );
+            }
+        }
+        if let Some(expected) = &entry.checksum {
+            let actual = checksum(&entry.data);
+            if *expected != actual {
+                problem(
+                    configmap,
+                    format!(
+                        "checksum mismatch, expected {} but got {}",
+                        expected, actual
+                    ),
+                );
+            }
+        }
+        let state = match entry.state() {
+            Ok(state) => state,
+            Err(e) => {
+                problem(configmap, e.to_string());
+                continue;
+            }
+        };
+        if state.schema_version > SCHEMA_VERSION {
+            problem(
+                configmap,
+                format!(
+                    "schema version {} is newer than the supported {}",
+                    state.schema_version, SCHEMA_VERSION
+                ),
+            );
+        }
+        for (key, reason) in state.to_backup().invalid_labels() {
+            let reason = redactor.text(Surface::Reports, &key, &state.labels[&key], &reason);
+            problem(configmap, format!("invalid label '{}': {}", key, reason));
+        }
+        for key in state.labels.keys() {
+            if known_prefixes.is_unknown(key) {
+                warnings.push(Problem {
+                    configmap: configmap.map(str::to_string),
+                    message: format!("label '{}' has a prefix with no known owner", key),
+                });
+            }
+        }
+        labels += state.labels.len();
+    }
+    verification.labels = labels;
+    verification.warnings = warnings;
+    verification
+}
@@ -0,0 +1,127 @@
+//! Building API clients the way kubectl picks its cluster, shared by every command
+
+use crate::{client_with_user_agent, Error, Result};
+use kube::{
+    config::{KubeConfigOptions, Kubeconfig},
+    Client, Config,
+};
+use std::path::PathBuf;
+
+/// Which cluster to talk to and as whom, like kubectl's flags of the same names
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Kubeconfig file to read instead of `KUBECONFIG` or `~/.kube/config`
+    pub kubeconfig: Option<PathBuf>,
+    /// Kubeconfig context to use instead of the current context
+    pub context: Option<String>,
+    /// Kubeconfig cluster to use instead of the context's cluster
+    pub cluster: Option<String>,
+    /// User to impersonate, e.g. `system:serviceaccount:kube-system:label-preserver`
+    pub impersonate: Option<String>,
+    /// Groups to impersonate
+    pub impersonate_groups: Vec<String>,
+}
+
+impl ClientOptions {
+    /// These options with every option set in `overrides` replaced, e.g. a subcommand's own
+    /// flags over the global ones
+    pub fn overridden_by(&self, overrides: &ClientOptions) -> ClientOptions {
+        ClientOptions {
+            kubeconfig: overrides.kubeconfig.clone().or(self.kubeconfig.clone()),
+            context: overrides.context.clone().or(self.context.clone()),
+            cluster: overrides.cluster.clone().or(self.cluster.clone()),
+            impersonate: overrides.impersonate.clone().or(self.impersonate.clone()),
+            impersonate_groups: if overrides.impersonate_groups.is_empty() {
+                self.impersonate_groups.clone()
+            } else {
+                overrides.impersonate_groups.clone()
+            },
+        }
+    }
+
+    /// Whether a kubeconfig has to be read rather than inferring the config, which prefers the
+    /// in-cluster service account
+    fn selects_kubeconfig(&self) -> bool {
+        self.kubeconfig.is_some() || self.context.is_some() || self.cluster.is_some()
+    }
+}
+
+/// Builds the clients of every command from the same [`ClientOptions`], so each command
+/// targets the cluster the same way. Commands take a factory rather than a client, so tests can
+/// point one at a mock API server with [`ClientFactory::with_base_config`].
+#[derive(Clone)]
+pub struct ClientFactory {
+    options: ClientOptions,
+    instance_id: String,
+    base_config: Option<Config>,
+}
+
+impl ClientFactory {
+    /// A factory loading the config like kubectl. `instance_id` goes into the User-Agent.
+    pub fn new(options: ClientOptions, instance_id: &str) -> Self {
+        Self {
+            options,
+            instance_id: instance_id.to_string(),
+            base_config: None,
+        }
+    }
+
+    /// A factory deriving every config from `config` instead of loading one. Kubeconfig
+    /// selection is ignored, impersonation still applies.
+    pub fn with_base_config(config: Config, options: ClientOptions, instance_id: &str) -> Self {
+        Self {
+            base_config: Some(config),
+            ..Self::new(options, instance_id)
+        }
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// This factory with `overrides` taking precedence over its options
+    pub fn overridden_by(&self, overrides: &ClientOptions) -> Self {
+        Self {
+            options: self.options.overridden_by(overrides),
+            ..self.clone()
+        }
+    }
+
+    /// The config clients are built from. An explicit kubeconfig, context, or cluster reads the
+    /// kubeconfig; otherwise the config is inferred, preferring the in-cluster service account.
+    pub async fn config(&self) -> Result<Config> {
+        let mut config = match &self.base_config {
+            Some(config) => config.clone(),
+            None if self.options.selects_kubeconfig() => {
+                let kubeconfig = match &self.options.kubeconfig {
+                    Some(path) => Kubeconfig::read_from(path),
+                    None => Kubeconfig::read(),
+                }
+                .map_err(|e| Error::ClientConfig(e.to_string()))?;
+                let kubeconfig_options = KubeConfigOptions {
+                    context: self.options.context.clone(),
+                    cluster: self.options.cluster.clone(),
+                    user: None,
+                };
+                Config::from_custom_kubeconfig(kubeconfig, &kubeconfig_options)
+                    .await
+                    .map_err(|e| Error::ClientConfig(e.to_string()))?
+            }
+            None => Config::infer()
+                .await
+                .map_err(|e| Error::ClientConfig(e.to_string()))?,
+        };
+        if let Some(user) = &self.options.impersonate {
+            config.auth_info.impersonate = Some(user.clone());
+        }
+        if !self.options.impersonate_groups.is_empty() {
+            config.auth_info.impersonate_groups = Some(self.options.impersonate_groups.clone());
+        }
+        Ok(config)
+    }
+
+    /// A client sending our User-Agent, see [`client_with_user_agent`]
+    pub async fn client(&self) -> Result<Client> {
+        client_with_user_agent(self.config().await?, &self.instance_id, None)
+    }
+}
@@ -0,0 +1,1014 @@
+//! Controller settings and the restore policy they configure
+
+use crate::{
+    apply_params,
+    metrics::PoolLimit,
+    naming::{RESERVED_LABEL_DOMAINS, UNPRESERVED_ANNOTATION_PREFIXES, UNPRESERVED_TAINT_PREFIXES},
+    node_name_error, status_configmap,
+    types::PreservedTaint,
+    Backup, BackupLayout, EncryptionKeys, Error, LeaderElection, MachineIdentity, NamingScheme,
+    Operation, Redactor, Result, SkipReason, CONFIGMAP_NAMESPACE, FINALIZER_NAME,
+    MANAGED_LABEL_KEY, STATUS_CONFIGMAP_NAME,
+};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, ListParams, Patch, ResourceExt},
+    runtime::{controller, watcher},
+    Client,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Settings that change how the controller behaves
+#[derive(Clone, Debug)]
+pub struct ControllerConfig {
+    /// Identifies this replica, e.g. the pod name
+    pub instance_id: String,
+    /// Namespace of the backup and status ConfigMaps. Backups of nodes `namespace_map` has no
+    /// namespace for are kept here.
+    pub namespace: String,
+    /// Node label whose value picks the namespace of the node's backup from `namespace_map`,
+    /// e.g. `node-pool`
+    pub namespace_label: Option<String>,
+    /// Backup namespaces by value of `namespace_label`
+    pub namespace_map: BTreeMap<String, String>,
+    /// Finalizer that holds deleted nodes until their labels are backed up. Replicas sharing a
+    /// cluster with different finalizers never release each other's nodes.
+    pub finalizer: String,
+    /// When set, only these nodes are managed. Every other node is left alone, and our finalizer
+    /// is released from any node that falls out of the list.
+    pub node_names: Option<BTreeSet<String>>,
+    /// Label selector applied server-side to the node watch
+    pub label_selector: Option<String>,
+    /// Field selector applied server-side to the node watch, e.g. `spec.unschedulable=false`
+    pub field_selector: Option<String>,
+    /// Decides which backed up labels are restored
+    pub policy: RestorePolicy,
+    /// Evaluated alongside `policy` on every restore to report what it would do differently,
+    /// without changing what is restored
+    pub candidate_policy: Option<RestorePolicy>,
+    /// More than this many restores within `burst_window` is reported as a single summary
+    pub burst_threshold: usize,
+    pub burst_window: Duration,
+    /// Backups are degraded to fit within this many bytes of ConfigMap data. This leaves room
+    /// under the API server's 1MiB object limit for metadata.
+    pub max_backup_bytes: usize,
+    /// The most labels kept when a backup has to be degraded to fit
+    pub max_labels: usize,
+    /// JSON values in a backup longer than this many bytes are split into chunks, see
+    /// [`crate::Backup::to_configmap_data_chunked`]
+    pub backup_chunk_bytes: usize,
+    /// Whether to set MANAGED_LABEL_KEY on the nodes we manage
+    pub managed_label: bool,
+    /// Restores per second allowed during `startup_warmup`. Zero disables startup pacing.
+    pub startup_rate: f64,
+    /// How long after startup restores are paced
+    pub startup_warmup: Duration,
+    /// Node label whose value is attached to metrics as the `pool` label
+    pub pool_label: Option<String>,
+    /// Which pool label values are kept distinct in metrics
+    pub pool_limit: PoolLimit,
+    /// Marks that a node is about to be deleted, which trigger an early backup
+    pub deletion_markers: DeletionMarkers,
+    /// More than this many nodes blocked in Terminating by our finalizer for longer than
+    /// `breaker_blocked_after` trips the circuit breaker, see [`crate::Context::check_breaker`]
+    pub breaker_threshold: usize,
+    pub breaker_blocked_after: Duration,
+    /// `/healthz` fails once no node has been reconciled for this long. Kubelets update their
+    /// node's status at least every 5 minutes, so a controller watching nodes reconciles more
+    /// often than that.
+    pub liveness_window: Duration,
+    /// Whether to delete a node's backup once it has been restored. The node's next deletion
+    /// writes a new one.
+    pub cleanup_after_restore: bool,
+    /// Which value wins when a backed up label is already on the node
+    pub merge_strategy: MergeStrategy,
+    /// Whether to write label values rewritten on admission, e.g. by a mutating webhook, back
+    /// into the backup once they are stable
+    pub record_rewrites: bool,
+    /// Whether to restore missing backed up labels onto a restored node again when its kubelet
+    /// re-registers after a reboot, see [`crate::Context::observe_boot`]
+    pub repair_on_reregistration: bool,
+    /// Whether to preserve node annotations alongside labels. Annotations are restored the same
+    /// way, without overwriting ones the new node already has.
+    pub preserve_annotations: bool,
+    /// Whether to preserve node taints alongside labels. Taints are restored when the node has
+    /// none with the same key and effect.
+    pub preserve_taints: bool,
+    /// Which label values are hidden in logs and other output
+    pub redactor: Redactor,
+    /// Whether to only log what would be restored and backed up, without adding our finalizer
+    /// or writing nodes and ConfigMaps
+    pub dry_run: bool,
+    /// Where replicas elect the one that runs the controller, or None to run it right away
+    pub leader_election: Option<LeaderElection>,
+    /// How often to check that the backup of a restored node holds its current labels, and
+    /// rewrite it if not. None only backs nodes up when they are deleted.
+    pub resync_interval: Option<Duration>,
+    /// Where backups written by older versions and forks may be, tried in order when a node has
+    /// no backup under the current name. A backup found is moved to the current name before
+    /// the node is restored from it.
+    pub legacy_naming_schemes: Vec<NamingScheme>,
+    /// Whether each node's backup gets its own ConfigMap or a key in a shared shard ConfigMap
+    pub backup_layout: BackupLayout,
+    /// Whether to back restored nodes up whenever their labels change instead of adding our
+    /// finalizer, so node deletion never waits on the controller. Labels changed while the
+    /// controller is down are lost if the node is deleted before it catches up.
+    pub snapshot_mode: bool,
+    /// Whether restores read backups from a cache kept by the backup watch, falling back to the
+    /// API server on a miss, so node storms don't get throttled reading them one by one
+    pub backup_cache: bool,
+    /// Whether to delete backups refused as stale, see [`RestorePolicy::max_backup_age`]
+    pub delete_stale_backups: bool,
+    /// How many nodes are reconciled at once. 0 is unbounded.
+    pub concurrency: u16,
+    /// How many objects each list of the node and backup watches fetches at a time, or None
+    /// for kube's default
+    pub watch_page_size: Option<u32>,
+    /// How long each list or watch call of the node and backup watches may take, or None for
+    /// kube's default. Must be under 295 seconds.
+    pub watch_timeout: Option<Duration>,
+    /// How many backup ConfigMap writes and deletes may be in flight at once, to protect the
+    /// API server during mass deletions. None is unbounded.
+    pub max_concurrent_writes: Option<usize>,
+    /// The keys preserved labels are encrypted with. None stores them in plain text.
+    pub encryption_keys: EncryptionKeys,
+    /// How often to list every node and report the ones in scope that lack our finalizer, e.g.
+    /// because a cleanup tool stripped it, see [`crate::sweep_finalizers`]. None skips the sweep.
+    pub finalizer_sweep_interval: Option<Duration>,
+    /// Nodes a sweep reports per second. Zero doesn't limit the rate.
+    pub finalizer_sweep_rate: f64,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            instance_id: "unknown".to_string(),
+            namespace: CONFIGMAP_NAMESPACE.to_string(),
+            namespace_label: None,
+            namespace_map: BTreeMap::new(),
+            finalizer: FINALIZER_NAME.to_string(),
+            node_names: None,
+            label_selector: None,
+            field_selector: None,
+            policy: RestorePolicy::default(),
+            candidate_policy: None,
+            burst_threshold: 20,
+            burst_window: Duration::from_secs(5 * 60),
+            max_backup_bytes: 900 * 1024,
+            max_labels: 1000,
+            backup_chunk_bytes: 64 * 1024,
+            managed_label: true,
+            startup_rate: 10.0,
+            startup_warmup: Duration::from_secs(5 * 60),
+            pool_label: None,
+            pool_limit: PoolLimit::FirstSeen(20),
+            deletion_markers: DeletionMarkers::default(),
+            breaker_threshold: 50,
+            breaker_blocked_after: Duration::from_secs(10 * 60),
+            liveness_window: Duration::from_secs(15 * 60),
+            cleanup_after_restore: false,
+            merge_strategy: MergeStrategy::default(),
+            record_rewrites: false,
+            repair_on_reregistration: false,
+            preserve_annotations: false,
+            preserve_taints: false,
+            redactor: Redactor::default(),
+            dry_run: false,
+            leader_election: None,
+            resync_interval: None,
+            legacy_naming_schemes: vec![NamingScheme::plain()],
+            backup_layout: BackupLayout::default(),
+            snapshot_mode: false,
+            backup_cache: true,
+            delete_stale_backups: false,
+            concurrency: 0,
+            watch_page_size: None,
+            watch_timeout: None,
+            max_concurrent_writes: None,
+            encryption_keys: EncryptionKeys::default(),
+            finalizer_sweep_interval: Some(Duration::from_secs(10 * 60)),
+            finalizer_sweep_rate: 5.0,
+        }
+    }
+}
+
+/// Taint and annotation keys an autoscaler puts on a node it is about to remove. Seeing one
+/// refreshes the node's backup right away, so a controller crash during the actual deletion
+/// doesn't lose labels changed since the last restore.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeletionMarkers {
+    pub taints: Vec<String>,
+    pub annotations: Vec<String>,
+}
+
+impl Default for DeletionMarkers {
+    /// The markers cluster-autoscaler and Karpenter set
+    fn default() -> Self {
+        Self {
+            taints: vec![
+                "ToBeDeletedByClusterAutoscaler".to_string(),
+                "DeletionCandidateOfClusterAutoscaler".to_string(),
+                "karpenter.sh/disrupted".to_string(),
+            ],
+            annotations: Vec::new(),
+        }
+    }
+}
+
+impl DeletionMarkers {
+    /// The first marker found on `node`
+    pub fn find(&self, node: &Node) -> Option<&str> {
+        let taints = node
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.taints.as_ref())
+            .into_iter()
+            .flatten();
+        for taint in taints {
+            if let Some(marker) = self.taints.iter().find(|key| **key == taint.key) {
+                return Some(marker);
+            }
+        }
+        self.annotations
+            .iter()
+            .find(|key| node.annotations().contains_key(*key))
+            .map(String::as_str)
+    }
+}
+
+/// The settings that decide which backed up labels are restored onto a node
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestorePolicy {
+    /// Labels matching these rules are not restored once they are older than the rule's TTL
+    pub label_expiry: Vec<LabelExpiry>,
+    /// What to do when a recreated node is a different machine than its backup
+    pub identity_mismatch: IdentityMismatchPolicy,
+    /// Label key prefixes that are still restored by [`IdentityMismatchPolicy::MachineIndependent`]
+    pub machine_independent_prefixes: Vec<String>,
+    /// Label key prefixes with a known owner. Backed up keys under any other prefix are flagged.
+    pub known_prefixes: KnownPrefixes,
+    /// Leave keys flagged by `known_prefixes` out of restores. They stay in the backup for
+    /// manual review.
+    pub exclude_unknown_prefixes: bool,
+    /// Which label keys are preserved at all. Applied when backing up, and again when restoring
+    /// in case the backup predates the filter.
+    pub label_filter: LabelFilter,
+    /// Restore keys under domains reserved for Kubernetes too, see [`filter_restorable_labels`]
+    pub allow_reserved_label_restore: bool,
+    /// Restore nothing from backups written longer ago than this, e.g. by an earlier node that
+    /// happened to have the same name. None restores backups of any age.
+    pub max_backup_age: Option<Duration>,
+    /// Whether backups written before their backup time was recorded are restored when
+    /// `max_backup_age` is set
+    pub unknown_backup_age: UnknownBackupAgePolicy,
+}
+
+impl RestorePolicy {
+    /// Whether `backup` is too old to restore anything from at `now`, see `max_backup_age`
+    pub fn is_stale(&self, backup: &Backup, now: DateTime<Utc>) -> bool {
+        let Some(max_age) = self.max_backup_age else {
+            return false;
+        };
+        match backup.backed_up_at {
+            Some(backed_up_at) => (now - backed_up_at).to_std().unwrap_or_default() > max_age,
+            None => self.unknown_backup_age == UnknownBackupAgePolicy::Skip,
+        }
+    }
+
+    /// The labels this policy would restore from `backup` onto `node` at `now`
+    pub fn plan(&self, backup: &Backup, node: &Node, now: DateTime<Utc>) -> RestorePlan {
+        if self.is_stale(backup, now) {
+            return RestorePlan {
+                stale: backup.labels.keys().cloned().collect(),
+                ..Default::default()
+            };
+        }
+        let mut labels = backup.labels.clone();
+        let filtered: Vec<String> = labels
+            .keys()
+            .filter(|key| !self.label_filter.allows(key))
+            .cloned()
+            .collect();
+        for key in &filtered {
+            labels.remove(key);
+        }
+        let invalid: Vec<(String, String)> = backup
+            .invalid_labels()
+            .into_iter()
+            .filter(|(key, _)| labels.contains_key(key))
+            .collect();
+        for (key, _) in &invalid {
+            labels.remove(key);
+        }
+        let expired: Vec<String> = backup
+            .expired_labels(&self.label_expiry, now)
+            .into_iter()
+            .filter(|key| labels.contains_key(key))
+            .collect();
+        for key in &expired {
+            labels.remove(key);
+        }
+        // Backups written before the managed label was excluded may still contain it
+        labels.remove(MANAGED_LABEL_KEY);
+        let reserved = filter_restorable_labels(&mut labels, self.allow_reserved_label_restore);
+        let unknown_prefix: Vec<String> = labels
+            .keys()
+            .filter(|key| self.known_prefixes.is_unknown(key))
+            .cloned()
+            .collect();
+        if self.exclude_unknown_prefixes {
+            for key in &unknown_prefix {
+                labels.remove(key);
+            }
+        }
+
+        let machine_changed = self.identity_mismatch != IdentityMismatchPolicy::Ignore
+            && backup.identity.differs_from(&MachineIdentity::of(node));
+        let mut annotations = backup.annotations.clone();
+        let mut taints = backup.taints.clone();
+        let mut identity_mismatch = Vec::new();
+        if machine_changed {
+            let keep = |key: &String| match self.identity_mismatch {
+                IdentityMismatchPolicy::Skip => false,
+                IdentityMismatchPolicy::MachineIndependent => self
+                    .machine_independent_prefixes
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix)),
+                IdentityMismatchPolicy::Ignore | IdentityMismatchPolicy::Warn => true,
+            };
+            labels.retain(|key, _| {
+                let kept = keep(key);
+                if !kept {
+                    identity_mismatch.push(key.clone());
+                }
+                kept
+            });
+            annotations.retain(|key, _| keep(key));
+            taints.retain(|taint| keep(&taint.key));
+        }
+        RestorePlan {
+            labels,
+            annotations,
+            taints,
+            filtered,
+            invalid,
+            expired,
+            reserved,
+            unknown_prefix,
+            identity_mismatch,
+            stale: Vec::new(),
+            machine_changed,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "label_expiry": self
+                .label_expiry
+                .iter()
+                .map(|rule| format!("{}={}", rule.prefix, rule.ttl.as_secs() / (24 * 60 * 60)))
+                .collect::<Vec<_>>(),
+            "identity_mismatch": self.identity_mismatch.to_string(),
+            "machine_independent_prefixes": self.machine_independent_prefixes,
+            "known_prefixes": self.known_prefixes.0,
+            "exclude_unknown_prefixes": self.exclude_unknown_prefixes,
+            "preserve_label_prefixes": self.label_filter.preserve_prefixes,
+            "ignore_label_prefixes": self.label_filter.ignore_prefixes,
+            "allow_reserved_label_restore": self.allow_reserved_label_restore,
+            "max_backup_age_days": self
+                .max_backup_age
+                .map(|age| age.as_secs() / (24 * 60 * 60)),
+            "unknown_backup_age": self.unknown_backup_age.to_string(),
+        })
+    }
+}
+
+/// The labels a policy would restore from a backup, before they are merged into the node's
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestorePlan {
+    pub labels: BTreeMap<String, String>,
+    /// Backed up annotations, which only the identity mismatch policy filters
+    pub annotations: BTreeMap<String, String>,
+    /// Backed up taints, which only the identity mismatch policy filters
+    pub taints: Vec<PreservedTaint>,
+    /// Keys left out by the label filter
+    pub filtered: Vec<String>,
+    /// Keys left out because they are not valid labels, with the reason
+    pub invalid: Vec<(String, String)>,
+    /// Keys left out because they expired
+    pub expired: Vec<String>,
+    /// Keys left out because their domain is reserved for Kubernetes
+    pub reserved: Vec<String>,
+    /// Keys whose prefix has no known owner. They are only left out if the policy excludes
+    /// them.
+    pub unknown_prefix: Vec<String>,
+    /// Keys left out because the node is a different machine than the backup
+    pub identity_mismatch: Vec<String>,
+    /// Keys left out because the whole backup is too old, see [`RestorePolicy::is_stale`]
+    pub stale: Vec<String>,
+    /// Whether the policy found the node to be a different machine than the backup
+    pub machine_changed: bool,
+}
+
+impl RestorePlan {
+    /// Why each backed up key the plan leaves out is left out
+    pub fn skipped(&self) -> BTreeMap<String, SkipReason> {
+        let invalid = self
+            .invalid
+            .iter()
+            .map(|(key, _)| (key, SkipReason::InvalidSyntax));
+        let expired = self.expired.iter().map(|key| (key, SkipReason::Expired));
+        let reserved = self
+            .reserved
+            .iter()
+            .map(|key| (key, SkipReason::ReservedDomain));
+        let filtered = self
+            .filtered
+            .iter()
+            .chain(&self.identity_mismatch)
+            .map(|key| (key, SkipReason::FilteredByPolicy));
+        let unknown_prefix = self
+            .unknown_prefix
+            .iter()
+            .filter(|key| !self.labels.contains_key(*key))
+            .map(|key| (key, SkipReason::UnknownPrefix));
+        let stale = self.stale.iter().map(|key| (key, SkipReason::StaleBackup));
+        invalid
+            .chain(expired)
+            .chain(stale)
+            .chain(reserved)
+            .chain(unknown_prefix)
+            .chain(filtered)
+            .map(|(key, reason)| (key.clone(), reason))
+            .collect()
+    }
+
+    /// How `candidate` differs from this plan
+    pub fn diff(&self, candidate: &RestorePlan) -> PlanDiff {
+        PlanDiff {
+            newly_restored: candidate
+                .labels
+                .keys()
+                .filter(|key| !self.labels.contains_key(*key))
+                .cloned()
+                .collect(),
+            no_longer_restored: self
+                .labels
+                .keys()
+                .filter(|key| !candidate.labels.contains_key(*key))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// The label keys a candidate policy would restore differently from the active one
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PlanDiff {
+    pub newly_restored: Vec<String>,
+    pub no_longer_restored: Vec<String>,
+}
+
+impl PlanDiff {
+    pub fn is_empty(&self) -> bool {
+        self.newly_restored.is_empty() && self.no_longer_restored.is_empty()
+    }
+}
+
+/// Label key prefixes, the part of a key before the `/`, that have a known owner. Backups
+/// accumulate keys under prefixes of decommissioned teams and tools, and restoring those keeps
+/// the labels alive forever. Keys without a prefix are never unknown, and neither are prefixes
+/// reserved for Kubernetes. An empty registry disables the check.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KnownPrefixes(pub Vec<String>);
+
+impl KnownPrefixes {
+    /// Whether `key` has a prefix that is neither registered nor a subdomain of a registered one
+    pub fn is_unknown(&self, key: &str) -> bool {
+        if self.0.is_empty() {
+            return false;
+        }
+        let Some((prefix, _)) = key.split_once('/') else {
+            return false;
+        };
+        let known = |owner: &str| {
+            let owner = owner.trim_end_matches('/');
+            prefix == owner
+                || prefix
+                    .strip_suffix(owner)
+                    .is_some_and(|sub| sub.ends_with('.'))
+        };
+        !self.0.iter().map(String::as_str).any(known) && !is_reserved_label(key)
+    }
+}
+
+/// Whether `key`'s prefix is a domain reserved for Kubernetes or a subdomain of one, e.g.
+/// `kubernetes.io/hostname` or `node.kubernetes.io/instance-type`
+pub fn is_reserved_label(key: &str) -> bool {
+    let Some((prefix, _)) = key.split_once('/') else {
+        return false;
+    };
+    RESERVED_LABEL_DOMAINS.iter().any(|domain| {
+        prefix == *domain
+            || prefix
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+/// Remove the labels that are never restored from `labels`, returning their keys. Kubernetes and
+/// cloud providers set labels under reserved domains from the machine the node runs on, like
+/// `kubernetes.io/hostname` or `topology.kubernetes.io/zone`, so a backed up value is stale on a
+/// node recreated elsewhere. They are still backed up. `allow_reserved` restores them anyway.
+pub fn filter_restorable_labels(
+    labels: &mut BTreeMap<String, String>,
+    allow_reserved: bool,
+) -> Vec<String> {
+    if allow_reserved {
+        return Vec::new();
+    }
+    let reserved: Vec<String> = labels
+        .keys()
+        .filter(|key| is_reserved_label(key))
+        .cloned()
+        .collect();
+    for key in &reserved {
+        labels.remove(key);
+    }
+    reserved
+}
+
+/// Which label keys are preserved, by key prefix. Cloud providers label nodes with facts about
+/// the machine, like `topology.kubernetes.io/zone` or `node.kubernetes.io/instance-type`, which
+/// are wrong on a replacement in another zone or of another type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LabelFilter {
+    /// When not empty, only keys starting with one of these are preserved
+    pub preserve_prefixes: Vec<String>,
+    /// Keys starting with any of these are never preserved, even when they also match
+    /// `preserve_prefixes`
+    pub ignore_prefixes: Vec<String>,
+}
+
+impl LabelFilter {
+    /// Whether the label `key` is preserved
+    pub fn allows(&self, key: &str) -> bool {
+        let matches = |prefixes: &[String]| prefixes.iter().any(|prefix| key.starts_with(prefix));
+        (self.preserve_prefixes.is_empty() || matches(&self.preserve_prefixes))
+            && !matches(&self.ignore_prefixes)
+    }
+}
+
+/// Labels with keys starting with `prefix` expire `ttl` after they were preserved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelExpiry {
+    pub prefix: String,
+    pub ttl: Duration,
+}
+
+impl FromStr for LabelExpiry {
+    type Err = Error;
+
+    /// Parse `<prefix>=<days>`, e.g. `maintenance.example.com/=7`
+    fn from_str(rule: &str) -> Result<Self> {
+        let (prefix, days) = rule
+            .rsplit_once('=')
+            .ok_or_else(|| Error::InvalidExpiryRule(rule.to_string()))?;
+        let days: u64 = days
+            .parse()
+            .map_err(|_| Error::InvalidExpiryRule(rule.to_string()))?;
+        Ok(Self {
+            prefix: prefix.to_string(),
+            ttl: Duration::from_secs(days * 24 * 60 * 60),
+        })
+    }
+}
+
+/// Backups of nodes whose namespace label has `value` are kept in `namespace`, see
+/// [`ControllerConfig::namespace_map`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamespaceMapping {
+    pub value: String,
+    pub namespace: String,
+}
+
+impl FromStr for NamespaceMapping {
+    type Err = Error;
+
+    /// Parse `<label value>=<namespace>`, e.g. `gpu=team-ml`
+    fn from_str(mapping: &str) -> Result<Self> {
+        match mapping.split_once('=') {
+            Some((value, namespace)) if !namespace.is_empty() => Ok(Self {
+                value: value.to_string(),
+                namespace: namespace.to_string(),
+            }),
+            _ => Err(Error::InvalidNamespaceMapping(mapping.to_string())),
+        }
+    }
+}
+
+/// What to do when a node is restored from a backup of a different machine. Labels such as
+/// rack or failure domain describe the hardware, not the node name, so restoring them onto a
+/// replacement machine is wrong.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdentityMismatchPolicy {
+    /// Don't compare identities
+    #[default]
+    Ignore,
+    /// Restore everything, but warn about the mismatch
+    Warn,
+    /// Restore nothing
+    Skip,
+    /// Only restore labels matching `machine_independent_prefixes`
+    MachineIndependent,
+}
+
+impl FromStr for IdentityMismatchPolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "ignore" => Ok(Self::Ignore),
+            "warn" => Ok(Self::Warn),
+            "skip" => Ok(Self::Skip),
+            "machine-independent" => Ok(Self::MachineIndependent),
+            _ => Err(Error::InvalidIdentityPolicy(policy.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for IdentityMismatchPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = match self {
+            Self::Ignore => "ignore",
+            Self::Warn => "warn",
+            Self::Skip => "skip",
+            Self::MachineIndependent => "machine-independent",
+        };
+        write!(f, "{}", policy)
+    }
+}
+
+/// Whether a backup without a backup time is restored when a maximum backup age is set. Backups
+/// written before version 7 have none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownBackupAgePolicy {
+    /// Restore it like a fresh backup
+    #[default]
+    Restore,
+    /// Restore nothing from it, like from a stale backup
+    Skip,
+}
+
+impl FromStr for UnknownBackupAgePolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "restore" => Ok(Self::Restore),
+            "skip" => Ok(Self::Skip),
+            _ => Err(Error::InvalidUnknownAgePolicy(policy.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for UnknownBackupAgePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = match self {
+            Self::Restore => "restore",
+            Self::Skip => "skip",
+        };
+        write!(f, "{}", policy)
+    }
+}
+
+/// Which value wins when a backed up label is already on the node with a different value
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep the node's value, so backed up labels are only restored onto vacant keys
+    #[default]
+    PreferCurrent,
+    /// Overwrite the node's value, e.g. one a provisioner stamped on the fresh node, with the
+    /// backed up one
+    PreferPreserved,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = Error;
+
+    fn from_str(strategy: &str) -> Result<Self> {
+        match strategy {
+            "prefer-current" => Ok(Self::PreferCurrent),
+            "prefer-preserved" => Ok(Self::PreferPreserved),
+            _ => Err(Error::InvalidMergeStrategy(strategy.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for MergeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let strategy = match self {
+            Self::PreferCurrent => "prefer-current",
+            Self::PreferPreserved => "prefer-preserved",
+        };
+        write!(f, "{}", strategy)
+    }
+}
+
+impl ControllerConfig {
+    /// Whether the controller should manage the named node
+    pub fn in_scope(&self, node_name: &str) -> bool {
+        self.node_names
+            .as_ref()
+            .is_none_or(|names| names.contains(node_name))
+    }
+
+    /// The labels to back up for `node`: those the label filter allows, without our own
+    pub fn preserved_labels(&self, node: &Node) -> BTreeMap<String, String> {
+        let mut labels = node.labels().clone();
+        labels.remove(MANAGED_LABEL_KEY);
+        labels.retain(|key, _| self.policy.label_filter.allows(key));
+        labels
+    }
+
+    /// The annotations to back up for `node`, which are none unless `preserve_annotations` is
+    /// set. Our own annotations, kubectl's, and deletion markers are never preserved, since a
+    /// restored marker would tell the autoscaler to remove the new node too.
+    pub fn preserved_annotations(&self, node: &Node) -> BTreeMap<String, String> {
+        if !self.preserve_annotations {
+            return BTreeMap::new();
+        }
+        node.annotations()
+            .iter()
+            .filter(|(key, _)| {
+                !UNPRESERVED_ANNOTATION_PREFIXES
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+                    && !self.deletion_markers.annotations.contains(key)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// The taints to back up for `node`, which are none unless `preserve_taints` is set. Taints
+    /// reflecting the node's condition and deletion markers are never preserved.
+    pub fn preserved_taints(&self, node: &Node) -> Vec<PreservedTaint> {
+        if !self.preserve_taints {
+            return Vec::new();
+        }
+        node.spec
+            .as_ref()
+            .and_then(|spec| spec.taints.as_ref())
+            .into_iter()
+            .flatten()
+            .filter(|taint| {
+                !UNPRESERVED_TAINT_PREFIXES
+                    .iter()
+                    .any(|prefix| taint.key.starts_with(prefix))
+                    && !self.deletion_markers.taints.contains(&taint.key)
+            })
+            .map(PreservedTaint::of)
+            .collect()
+    }
+
+    /// Check that every listed node name is one a node can have. A name that isn't would never
+    /// match, and a single listed name goes into the `metadata.name` field selector.
+    pub fn validate_node_names(&self) -> Result<()> {
+        for name in self.node_names.iter().flatten() {
+            if let Some(e) = node_name_error(name) {
+                return Err(Error::InvalidNodeName(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that encryption is only enabled for per-node backups, since shard entries are
+    /// stored as plain text
+    pub fn validate_encryption(&self) -> Result<()> {
+        if self.encryption_keys.is_enabled() && self.backup_layout == BackupLayout::Sharded {
+            return Err(Error::InvalidEncryption(
+                "the sharded backup layout doesn't support encryption".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The combined field selector for the node watch. All selectors must match.
+    /// `metadata.name` field selectors only support a single value, so a single listed node
+    /// is filtered server-side and multiple listed nodes are filtered in [`reconcile`].
+    fn combined_field_selector(&self) -> Option<String> {
+        let mut selectors = Vec::new();
+        if let Some(names) = &self.node_names {
+            if names.len() == 1 {
+                let name = names.iter().next().expect("one name");
+                selectors.push(format!("metadata.name={}", name));
+            }
+        }
+        if let Some(field_selector) = &self.field_selector {
+            selectors.push(field_selector.clone());
+        }
+        (!selectors.is_empty()).then(|| selectors.join(","))
+    }
+
+    /// The watcher configuration for the node watch
+    pub fn watcher_config(&self) -> watcher::Config {
+        let mut config = self.backup_watcher_config();
+        if let Some(field_selector) = self.combined_field_selector() {
+            config = config.fields(&field_selector);
+        }
+        if let Some(label_selector) = &self.label_selector {
+            config = config.labels(label_selector);
+        }
+        config
+    }
+
+    /// The watcher settings of the backup watch, which the node watch shares
+    pub fn backup_watcher_config(&self) -> watcher::Config {
+        let mut config = watcher::Config::default();
+        if let Some(page_size) = self.watch_page_size {
+            config = config.page_size(page_size);
+        }
+        if let Some(timeout) = self.watch_timeout {
+            config = config.timeout(timeout.as_secs() as u32);
+        }
+        config
+    }
+
+    /// The settings of the node Controller
+    pub fn reconciler_config(&self) -> controller::Config {
+        controller::Config::default().concurrency(self.concurrency)
+    }
+
+    /// The resolved configuration as JSON, for display in the status ConfigMap
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "instance_id": self.instance_id,
+            "namespace": self.namespace,
+            "finalizer": self.finalizer,
+            "node_names": self.node_names,
+            "label_selector": self.label_selector,
+            "field_selector": self.field_selector,
+            "burst_threshold": self.burst_threshold,
+            "burst_window_secs": self.burst_window.as_secs(),
+            "max_backup_bytes": self.max_backup_bytes,
+            "max_labels": self.max_labels,
+            "backup_chunk_bytes": self.backup_chunk_bytes,
+            "managed_label": self.managed_label,
+            "startup_rate": self.startup_rate,
+            "startup_warmup_secs": self.startup_warmup.as_secs(),
+            "pool_label": self.pool_label,
+            "namespace_label": self.namespace_label,
+            "namespace_map": self.namespace_map,
+            "pool_limit": match &self.pool_limit {
+                PoolLimit::Allowlist(values) => serde_json::json!(values),
+                PoolLimit::FirstSeen(max) => serde_json::json!(max),
+            },
+            "candidate_policy": self.candidate_policy.as_ref().map(RestorePolicy::to_json),
+            "deletion_marker_taints": self.deletion_markers.taints,
+            "deletion_marker_annotations": self.deletion_markers.annotations,
+            "breaker_threshold": self.breaker_threshold,
+            "breaker_blocked_after_secs": self.breaker_blocked_after.as_secs(),
+            "liveness_window_secs": self.liveness_window.as_secs(),
+            "cleanup_after_restore": self.cleanup_after_restore,
+            "merge_strategy": self.merge_strategy.to_string(),
+            "record_rewrites": self.record_rewrites,
+            "repair_on_reregistration": self.repair_on_reregistration,
+            "preserve_annotations": self.preserve_annotations,
+            "preserve_taints": self.preserve_taints,
+            "redaction": self.redactor,
+            "dry_run": self.dry_run,
+            "resync_interval_secs": self.resync_interval.map(|interval| interval.as_secs()),
+            "leader_election": self.leader_election.as_ref().map(|election| serde_json::json!({
+                "lease_name": election.lease_name,
+                "lease_namespace": election.lease_namespace,
+                "lease_duration_secs": election.lease_duration.as_secs(),
+            })),
+            "legacy_naming_schemes": self
+                .legacy_naming_schemes
+                .iter()
+                .map(NamingScheme::to_string)
+                .collect::<Vec<_>>(),
+            "backup_layout": self.backup_layout.to_string(),
+            "snapshot_mode": self.snapshot_mode,
+            "backup_cache": self.backup_cache,
+            "delete_stale_backups": self.delete_stale_backups,
+            "concurrency": self.concurrency,
+            "watch_page_size": self.watch_page_size,
+            "watch_timeout_secs": self.watch_timeout.map(|timeout| timeout.as_secs()),
+            "max_concurrent_writes": self.max_concurrent_writes,
+            "finalizer_sweep_interval_secs": self
+                .finalizer_sweep_interval
+                .map(|interval| interval.as_secs()),
+            "finalizer_sweep_rate": self.finalizer_sweep_rate,
+        });
+        // Only how many keys there are, never the keys
+        json["encryption_keys"] = self.encryption_keys.0.len().into();
+        // The active policy's settings are shown at the top level
+        if let (Some(config), serde_json::Value::Object(policy)) =
+            (json.as_object_mut(), self.policy.to_json())
+        {
+            config.extend(policy);
+        }
+        json
+    }
+
+    /// List parameters selecting the nodes the node watch sees
+    pub fn node_list_params(&self) -> ListParams {
+        let mut list_params = ListParams::default();
+        if let Some(field_selector) = self.combined_field_selector() {
+            list_params = list_params.fields(&field_selector);
+        }
+        if let Some(label_selector) = &self.label_selector {
+            list_params = list_params.labels(label_selector);
+        }
+        list_params
+    }
+
+    /// Check the watch selectors against the API server so that an invalid selector fails at
+    /// startup instead of on every watch attempt
+    pub async fn validate_selectors(&self, client: Client) -> Result<()> {
+        let node_api: Api<Node> = Api::all(client);
+        match node_api
+            .list_metadata(&self.node_list_params().limit(1))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 400 => {
+                Err(Error::InvalidSelector(response.message))
+            }
+            Err(e) => Err(Error::from_api(Operation::ListNodes, e)),
+        }
+    }
+
+    /// The namespace `node`'s backup is written to: the one its namespace label's value maps
+    /// to, or `namespace`
+    pub fn backup_namespace(&self, node: &Node) -> &str {
+        self.namespace_label
+            .as_ref()
+            .and_then(|label| node.labels().get(label))
+            .and_then(|value| self.namespace_map.get(value))
+            .unwrap_or(&self.namespace)
+    }
+
+    /// Where `node`'s backup is looked for, in order: the namespace it maps to now, `namespace`,
+    /// then every other mapped namespace. A node recreated before its namespace label is set
+    /// again, or with another value, still finds the backup of the node it replaces.
+    pub fn backup_namespaces(&self, node: &Node) -> Vec<&str> {
+        let mut namespaces = vec![self.backup_namespace(node)];
+        for namespace in self.all_backup_namespaces() {
+            if !namespaces.contains(&namespace) {
+                namespaces.push(namespace);
+            }
+        }
+        namespaces
+    }
+
+    /// Every namespace backups may be kept in, `namespace` first
+    pub fn all_backup_namespaces(&self) -> Vec<&str> {
+        let mut namespaces = vec![self.namespace.as_str()];
+        for namespace in self.namespace_map.values() {
+            if !namespaces.contains(&namespace.as_str()) {
+                namespaces.push(namespace);
+            }
+        }
+        namespaces
+    }
+
+    /// Check that backups can be written to every backup namespace with a dry-run apply of the
+    /// status ConfigMap, so a missing namespace or RBAC rule fails at startup instead of on
+    /// every backup
+    pub async fn validate_namespace(&self, client: Client) -> Result<()> {
+        let params = apply_params().dry_run();
+        for namespace in self.all_backup_namespaces() {
+            let cm_api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+            let mut probe = status_configmap(self, Utc::now());
+            probe.metadata.namespace = Some(namespace.to_string());
+            match cm_api
+                .patch(STATUS_CONFIGMAP_NAME, &params, &Patch::Apply(&probe))
+                .await
+            {
+                Ok(_) => {}
+                Err(kube::Error::Api(response)) if response.code == 404 => {
+                    return Err(Error::MissingNamespace(namespace.to_string()))
+                }
+                Err(e) => {
+                    return Err(Error::from_api(
+                        Operation::WriteBackup {
+                            namespace: namespace.to_string(),
+                        },
+                        e,
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,684 @@
+//! State shared between reconciles
+
+use crate::{
+    health::Health,
+    metrics::{Metrics, PoolBuckets},
+    naming::SERVICE_NAME,
+    reconcile::awaits_backup,
+    Backup, BackupDigests, BackupStore, BurstTracker, ControllerConfig, KubeLabelStore, LabelStore,
+    NodePatcher, RestoreOutcome, RestoredAnnotation, ShadowStats, RESTORED_ANNOTATION_KEY,
+};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    apimachinery::pkg::apis::meta::v1::Time,
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, ResourceExt},
+    runtime::{
+        events::{Event, EventType, Recorder, Reporter},
+        reflector::{ObjectRef, Store},
+        watcher,
+    },
+    Client, Resource,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, warn};
+
+/// How often to retry a node that is briefly not accepting patches
+pub(crate) const DEFERRAL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to keep retrying at DEFERRAL_INTERVAL before falling back to exponential backoff
+pub(crate) const DEFERRAL_WINDOW: Duration = Duration::from_secs(120);
+/// How soon to retry background work deferred behind pending restores
+pub(crate) const BACKGROUND_DEFERRAL_INTERVAL: Duration = Duration::from_secs(2);
+/// Background work deferred this long runs even while restores are pending, so it can't starve
+pub(crate) const MAX_BACKGROUND_DEFERRAL: Duration = Duration::from_secs(60);
+/// The API server rejects Event notes over 1kB
+const MAX_EVENT_NOTE_BYTES: usize = 1024;
+
+/// Paces restores while the controller warms up. After a restart the initial list delivers
+/// every node at once, and each unrestored node costs a ConfigMap read and a node patch, so
+/// restores are let through one at a time at a fixed rate until the warmup window has passed.
+/// Cleanups are never paced, so node deletions aren't held up behind the flood.
+pub struct StartupPacer {
+    rate: f64,
+    started: Instant,
+    window: Duration,
+    state: Mutex<PacerState>,
+}
+
+struct PacerState {
+    /// May go negative while restores are waiting for their turn
+    tokens: f64,
+    last_refill: Instant,
+    paced: u64,
+    waiting: u64,
+}
+
+/// How far the controller is through its startup warmup
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WarmupProgress {
+    pub in_warmup: bool,
+    pub elapsed_secs: u64,
+    pub window_secs: u64,
+    /// Restores that had to wait for their turn
+    pub paced: u64,
+    /// Restores waiting right now
+    pub waiting: u64,
+}
+
+impl StartupPacer {
+    /// Create a pacer allowing `rate` restores per second for `window` after `now`
+    pub fn new(rate: f64, window: Duration, now: Instant) -> Self {
+        Self {
+            rate,
+            started: now,
+            window,
+            state: Mutex::new(PacerState {
+                tokens: 1.0,
+                last_refill: now,
+                paced: 0,
+                waiting: 0,
+            }),
+        }
+    }
+
+    /// Take a turn at `now` and return how long to wait for it. Zero once the window is over.
+    pub fn reserve(&self, now: Instant) -> Duration {
+        let warmup_ends = self.started + self.window;
+        if self.rate <= 0.0 || now >= warmup_ends {
+            return Duration::ZERO;
+        }
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(1.0);
+        state.last_refill = state.last_refill.max(now);
+        state.tokens -= 1.0;
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        state.paced += 1;
+        Duration::from_secs_f64(-state.tokens / self.rate).min(warmup_ends - now)
+    }
+
+    /// Wait for a turn to restore a node
+    pub async fn acquire(&self) {
+        let wait = self.reserve(Instant::now());
+        if wait.is_zero() {
+            return;
+        }
+        self.state.lock().unwrap().waiting += 1;
+        tokio::time::sleep(wait).await;
+        self.state.lock().unwrap().waiting -= 1;
+    }
+
+    pub fn progress(&self, now: Instant) -> WarmupProgress {
+        let state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(self.started);
+        WarmupProgress {
+            in_warmup: self.rate > 0.0 && elapsed < self.window,
+            elapsed_secs: elapsed.as_secs(),
+            window_secs: self.window.as_secs(),
+            paced: state.paced,
+            waiting: state.waiting,
+        }
+    }
+}
+
+/// The retry state of a node whose last reconcile failed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BackoffState {
+    /// Consecutive failed attempts
+    pub attempt: u32,
+    /// When the next attempt is scheduled
+    pub next_retry: DateTime<Utc>,
+}
+
+/// Passed to the reconciler
+pub struct Context {
+    pub(crate) client: Client,
+    pub(crate) config: ControllerConfig,
+    /// Reads and writes backups in the configured layout in the default backup namespace
+    pub(crate) backups: BackupStore,
+    /// Backup stores of the other namespaces in [`ControllerConfig::namespace_map`], created on
+    /// first use
+    pub(crate) mapped_backups: Mutex<HashMap<String, BackupStore>>,
+    /// Reads and writes backups for reconciles, see [`Context::with_label_store`]
+    pub(crate) labels: Arc<dyn LabelStore>,
+    /// Patches nodes for reconciles, see [`Context::with_node_patcher`]
+    pub(crate) nodes: Arc<dyn NodePatcher>,
+    /// Bounds the backup ConfigMap writes in flight, see
+    /// [`ControllerConfig::max_concurrent_writes`]
+    pub(crate) backup_writes: Option<Semaphore>,
+    /// Retry state of nodes whose last reconcile failed, keyed by node name
+    pub(crate) backoff: Mutex<HashMap<String, BackoffState>>,
+    /// Sends nodes to the Controller to be reconciled immediately
+    pub(crate) reconcile_requests: UnboundedSender<ObjectRef<Node>>,
+    pub(crate) reconcile_requests_rx: Mutex<Option<UnboundedReceiver<ObjectRef<Node>>>>,
+    /// Nodes that are briefly not accepting patches, keyed by node name, with the time they
+    /// were first deferred
+    pub(crate) deferred: Mutex<HashMap<String, Instant>>,
+    pub(crate) bursts: BurstTracker,
+    /// How the candidate policy would have changed restores so far
+    pub(crate) shadow: Mutex<ShadowStats>,
+    pub(crate) pacer: StartupPacer,
+    pub(crate) metrics: Metrics,
+    /// What each node's backup holds, so early backups of unchanged nodes are skipped
+    pub(crate) backup_digests: BackupDigests,
+    /// The Controller's cache of nodes, once it is running
+    pub(crate) node_store: OnceLock<Store<Node>>,
+    /// Whether the circuit breaker is tripped
+    pub(crate) breaker: Mutex<bool>,
+    /// Restored labels whose values were rewritten on admission and are awaiting verification,
+    /// keyed by node name
+    pub(crate) rewrites: Mutex<HashMap<String, Rewrite>>,
+    /// The boot ID each node last reported, keyed by node name
+    pub(crate) boot_ids: Mutex<HashMap<String, String>>,
+    /// Restored nodes whose background work is deferred behind pending restores, keyed by node
+    /// name, with the time they were first deferred
+    pub(crate) background_deferred: Mutex<HashMap<String, Instant>>,
+    pub(crate) health: Health,
+    /// Publishes Events on nodes as this instance
+    pub(crate) recorder: Recorder,
+}
+
+/// Restored label values that landed differently than they were applied
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Rewrite {
+    /// The values we applied, by key
+    pub(crate) attempted: BTreeMap<String, String>,
+    /// The values last seen on the node, by key
+    pub(crate) observed: BTreeMap<String, String>,
+    pub(crate) verifications: u32,
+}
+
+impl Context {
+    /// Create a new Context with the default configuration
+    pub fn new(client: Client) -> Self {
+        Self::with_config(client, ControllerConfig::default())
+    }
+
+    /// Create a new Context with the given configuration
+    pub fn with_config(client: Client, config: ControllerConfig) -> Self {
+        let (reconcile_requests, reconcile_requests_rx) = mpsc::unbounded();
+        let backups = BackupStore::new(client.clone(), &config.namespace, config.backup_layout)
+            .with_encryption(config.encryption_keys.clone());
+        let reporter = Reporter {
+            controller: SERVICE_NAME.to_string(),
+            instance: Some(config.instance_id.clone()),
+        };
+        Self {
+            recorder: Recorder::new(client.clone(), reporter),
+            labels: Arc::new(KubeLabelStore),
+            nodes: Arc::new(Api::<Node>::all(client.clone())),
+            client,
+            backups,
+            mapped_backups: Mutex::new(HashMap::new()),
+            backup_writes: config.max_concurrent_writes.map(Semaphore::new),
+            backoff: Mutex::new(HashMap::new()),
+            reconcile_requests,
+            reconcile_requests_rx: Mutex::new(Some(reconcile_requests_rx)),
+            deferred: Mutex::new(HashMap::new()),
+            bursts: BurstTracker::new(config.burst_threshold, config.burst_window),
+            shadow: Mutex::new(ShadowStats::default()),
+            pacer: StartupPacer::new(config.startup_rate, config.startup_warmup, Instant::now()),
+            metrics: Metrics::new(PoolBuckets::new(
+                config.pool_label.clone(),
+                config.pool_limit.clone(),
+            )),
+            backup_digests: BackupDigests::default()
+                .with_encryption(config.encryption_keys.clone())
+                .with_layout(config.backup_layout),
+            node_store: OnceLock::new(),
+            breaker: Mutex::new(false),
+            rewrites: Mutex::new(HashMap::new()),
+            boot_ids: Mutex::new(HashMap::new()),
+            background_deferred: Mutex::new(HashMap::new()),
+            health: Health::new(Instant::now()),
+            config,
+        }
+    }
+
+    /// Read and write backups through `labels` instead of the API server
+    pub fn with_label_store(mut self, labels: Arc<dyn LabelStore>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Patch nodes through `nodes` instead of the API server. Finalizers are still added and
+    /// removed through the client.
+    pub fn with_node_patcher(mut self, nodes: Arc<dyn NodePatcher>) -> Self {
+        self.nodes = nodes;
+        self
+    }
+
+    /// Wait for a free backup write slot, held until the permit is dropped. Without
+    /// [`ControllerConfig::max_concurrent_writes`] there is always one.
+    pub(crate) async fn backup_write_slot(&self) -> Option<SemaphorePermit<'_>> {
+        let writes = self.backup_writes.as_ref()?;
+        Some(
+            writes
+                .acquire()
+                .await
+                .expect("the semaphore is never closed"),
+        )
+    }
+
+    /// Publish an Event on `node`, so `kubectl describe node` shows how its labels got there.
+    /// Failing to publish one is only logged.
+    pub(crate) async fn publish_event(
+        &self,
+        node: &Node,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        mut note: String,
+    ) {
+        if note.len() > MAX_EVENT_NOTE_BYTES {
+            let mut end = MAX_EVENT_NOTE_BYTES - "...".len();
+            while !note.is_char_boundary(end) {
+                end -= 1;
+            }
+            note.truncate(end);
+            note.push_str("...");
+        }
+        let event = Event {
+            type_,
+            reason: reason.to_string(),
+            note: Some(note),
+            action: action.to_string(),
+            secondary: None,
+        };
+        if let Err(e) = self.recorder.publish(&event, &node.object_ref(&())).await {
+            warn!(
+                "Failed to publish a {} Event on node '{}': {}",
+                reason,
+                node.name_any(),
+                e
+            );
+        }
+    }
+
+    /// Log the outcome of a restore. During a burst the per-node lines are demoted to debug and
+    /// a periodic summary is logged instead.
+    pub(crate) fn report_restore(&self, node: &Node, outcome: RestoreOutcome) {
+        let node_name = node.name_any();
+        self.metrics.record_restore(node, outcome);
+        let summary = self.bursts.record(outcome, Instant::now());
+        if self.bursts.in_burst() {
+            debug!("Restore outcome for node '{}': {:?}", node_name, outcome);
+        } else {
+            info!("Restore outcome for node '{}': {:?}", node_name, outcome);
+        }
+        if let Some(summary) = summary {
+            warn!(
+                restored = summary.restored,
+                conflicts = summary.conflicts,
+                failed = summary.failed,
+                window_secs = self.config.burst_window.as_secs(),
+                "Mass node restore in progress: {} restored, {} with conflicts, {} failed in the last {}s",
+                summary.restored,
+                summary.conflicts,
+                summary.failed,
+                self.config.burst_window.as_secs()
+            );
+        }
+    }
+
+    /// Compare what the candidate policy would restore onto `node` with the active policy and
+    /// record the difference. Only computes plans, so it never changes what is restored.
+    pub(crate) fn evaluate_candidate(&self, node: &Node, backup: &Backup) {
+        let Some(candidate) = &self.config.candidate_policy else {
+            return;
+        };
+        let now = Utc::now();
+        let diff = self
+            .config
+            .policy
+            .plan(backup, node, now)
+            .diff(&candidate.plan(backup, node, now));
+        let mut shadow = self.shadow.lock().unwrap();
+        shadow.nodes_evaluated += 1;
+        if diff.is_empty() {
+            return;
+        }
+        shadow.nodes_affected += 1;
+        shadow.keys_newly_restored += diff.newly_restored.len() as u64;
+        shadow.keys_no_longer_restored += diff.no_longer_restored.len() as u64;
+        info!(
+            node = node.name_any(),
+            newly_restored = ?diff.newly_restored,
+            no_longer_restored = ?diff.no_longer_restored,
+            "The candidate policy would restore node '{}' differently: {} more labels, {} fewer",
+            node.name_any(),
+            diff.newly_restored.len(),
+            diff.no_longer_restored.len()
+        );
+    }
+
+    /// The controller's counters in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        self.check_breaker(Utc::now());
+        self.metrics.set_queue_depths(
+            self.pending_restores(),
+            self.background_deferred.lock().unwrap().len(),
+        );
+        self.metrics.render()
+    }
+
+    /// Give the Controller's node cache to the circuit breaker, to count blocked nodes, and to
+    /// cleanups, to notice nodes recreated before the old node was cleaned up
+    pub fn set_node_store(&self, store: Store<Node>) {
+        let _ = self.node_store.set(store);
+    }
+
+    /// How many cached nodes our finalizer has kept in Terminating for longer than
+    /// `breaker_blocked_after` at `now`
+    pub fn blocked_nodes(&self, now: DateTime<Utc>) -> usize {
+        let Some(store) = self.node_store.get() else {
+            return 0;
+        };
+        store
+            .state()
+            .iter()
+            .filter(|node| node.finalizers().contains(&self.config.finalizer))
+            .filter_map(|node| node.metadata.deletion_timestamp.as_ref())
+            .filter(|Time(deleted_at)| {
+                (now - *deleted_at).to_std().unwrap_or_default() > self.config.breaker_blocked_after
+            })
+            .count()
+    }
+
+    /// The cached node with `node`'s name, if it is a different node that was created since.
+    /// The cache holds one node per name, so a node recreated before the old node's cleanup
+    /// finished already replaced the old node in it.
+    pub(crate) fn recreated_node(&self, node: &Node) -> Option<Arc<Node>> {
+        let cached = self
+            .node_store
+            .get()?
+            .get(&ObjectRef::new(&node.name_any()))?;
+        let (Some(uid), Some(cached_uid)) = (node.uid(), cached.uid()) else {
+            return None;
+        };
+        (uid != cached_uid && cached.metadata.deletion_timestamp.is_none()).then_some(cached)
+    }
+
+    /// Record the boot ID `node` reports and return whether it changed since we last saw the
+    /// node. A new boot ID on the same node object means its kubelet restarted and re-registered
+    /// after a reboot, which tooling reacting to the restart may follow by dropping labels. The
+    /// first boot ID seen for a node, e.g. after our own restart, is not a change.
+    pub fn observe_boot(&self, node: &Node) -> bool {
+        let Some(boot_id) = boot_id(node) else {
+            return false;
+        };
+        let previous = self
+            .boot_ids
+            .lock()
+            .unwrap()
+            .insert(node.name_any(), boot_id.clone());
+        previous.is_some_and(|previous| previous != boot_id)
+    }
+
+    /// Whether [`Context::observe_boot`] would report a new boot ID, without recording it
+    pub(crate) fn boot_changed(&self, node: &Node) -> bool {
+        let Some(boot_id) = boot_id(node) else {
+            return false;
+        };
+        self.boot_ids
+            .lock()
+            .unwrap()
+            .get(&node.name_any())
+            .is_some_and(|previous| *previous != boot_id)
+    }
+
+    /// The backup store of `namespace`
+    pub(crate) fn backups_in(&self, namespace: &str) -> BackupStore {
+        if namespace == self.backups.namespace() {
+            return self.backups.clone();
+        }
+        self.mapped_backups
+            .lock()
+            .unwrap()
+            .entry(namespace.to_string())
+            .or_insert_with(|| {
+                BackupStore::new(self.client.clone(), namespace, self.config.backup_layout)
+                    .with_encryption(self.config.encryption_keys.clone())
+            })
+            .clone()
+    }
+
+    /// The backup store `node`'s backup is written to, see
+    /// [`ControllerConfig::backup_namespace`]
+    pub(crate) fn backup_store(&self, node: &Node) -> BackupStore {
+        self.backups_in(self.config.backup_namespace(node))
+    }
+
+    /// The backup stores `node`'s backup is looked for in, in order, see
+    /// [`ControllerConfig::backup_namespaces`]
+    pub(crate) fn backup_stores(&self, node: &Node) -> Vec<BackupStore> {
+        self.config
+            .backup_namespaces(node)
+            .into_iter()
+            .map(|namespace| self.backups_in(namespace))
+            .collect()
+    }
+
+    /// Read backups in `namespace` from `store`, a cache of its backup ConfigMaps kept by the
+    /// backup watch, see [`BackupStore::set_cache`]
+    pub fn set_backup_cache(&self, namespace: &str, store: Store<ConfigMap>) {
+        self.backups_in(namespace).set_cache(store);
+    }
+
+    /// Note a change to a ConfigMap seen by the backup watch
+    pub fn observe_backup(&self, event: &watcher::Event<ConfigMap>) {
+        let namespace = match event {
+            watcher::Event::Apply(cm)
+            | watcher::Event::InitApply(cm)
+            | watcher::Event::Delete(cm) => cm.namespace(),
+            watcher::Event::Init | watcher::Event::InitDone => None,
+        };
+        let backup_namespaces = self.config.all_backup_namespaces();
+        match namespace.filter(|namespace| backup_namespaces.contains(&namespace.as_str())) {
+            Some(namespace) => self.backups_in(&namespace).observe(event),
+            None => self.backups.observe(event),
+        }
+        self.backup_digests.observe(event);
+    }
+
+    /// What each node's backup holds, kept current by [`BackupDigests::observe`]
+    pub fn backup_digests(&self) -> &BackupDigests {
+        &self.backup_digests
+    }
+
+    /// Whether `node` was restored from a backup whose labels changed since, e.g. because the
+    /// node was deleted and its backup rewritten before the restore finished. Only known once
+    /// the backup watch has seen the backup.
+    pub fn backup_changed_since_restore(&self, node: &Node) -> bool {
+        let Some(restored) = RestoredAnnotation::of(node) else {
+            return false;
+        };
+        self.backup_labels_digest(node)
+            .is_some_and(|digest| restored.is_outdated(&digest))
+    }
+
+    /// [`BackupDigests::labels_digest`] of `node`'s backup in the first namespace it is looked
+    /// for in that the backup watch saw one in
+    fn backup_labels_digest(&self, node: &Node) -> Option<String> {
+        let node_name = node.name_any();
+        self.config
+            .backup_namespaces(node)
+            .into_iter()
+            .find_map(|namespace| self.backup_digests.labels_digest(namespace, &node_name))
+    }
+
+    /// How many cached nodes are waiting for their first restore: ones with a backup, and new
+    /// ones whose backup may still be written, see [`awaits_backup`]. Their restores go ahead
+    /// of background work on restored nodes for at most a minute.
+    pub fn pending_restores(&self) -> usize {
+        let Some(store) = self.node_store.get() else {
+            return 0;
+        };
+        let now = Utc::now();
+        store
+            .state()
+            .iter()
+            .filter(|node| node.metadata.deletion_timestamp.is_none())
+            .filter(|node| !node.annotations().contains_key(RESTORED_ANNOTATION_KEY))
+            .filter(|node| awaits_backup(node, now) || self.backup_labels_digest(node).is_some())
+            .filter(|node| self.config.in_scope(&node.name_any()))
+            .count()
+    }
+
+    /// Defer a restored node's background work while restores are pending, unless it has
+    /// already been deferred for MAX_BACKGROUND_DEFERRAL. Returns how many restores it is
+    /// deferred behind, or None when the work should run now. The cache is scanned once, before
+    /// the lock is taken.
+    pub(crate) fn defer_background(&self, node_name: &str) -> Option<usize> {
+        let pending = self.pending_restores();
+        let mut deferred = self.background_deferred.lock().unwrap();
+        if pending == 0 {
+            deferred.remove(node_name);
+            return None;
+        }
+        let first_deferred = *deferred
+            .entry(node_name.to_string())
+            .or_insert_with(Instant::now);
+        if first_deferred.elapsed() >= MAX_BACKGROUND_DEFERRAL {
+            deferred.remove(node_name);
+            return None;
+        }
+        Some(pending)
+    }
+
+    /// Whether the circuit breaker is tripped at `now`. A systemic cleanup failure, e.g. revoked
+    /// RBAC, would otherwise block every node deletion in the cluster, which is worse than
+    /// losing some backups. The breaker trips when more than `breaker_threshold` nodes are
+    /// blocked, and resets once none are.
+    pub fn check_breaker(&self, now: DateTime<Utc>) -> bool {
+        let blocked = self.blocked_nodes(now);
+        let mut tripped = self.breaker.lock().unwrap();
+        if !*tripped && blocked > self.config.breaker_threshold {
+            *tripped = true;
+            error!(
+                blocked,
+                "Circuit breaker tripped: {} nodes have been blocked in Terminating by our finalizer \
+                for over {}s. Releasing finalizers after a best-effort backup until the backlog clears.",
+                blocked,
+                self.config.breaker_blocked_after.as_secs()
+            );
+        } else if *tripped && blocked == 0 {
+            *tripped = false;
+            info!("Circuit breaker reset: no nodes are blocked in Terminating anymore");
+        }
+        self.metrics.set_breaker(*tripped, blocked);
+        *tripped
+    }
+
+    /// Report ready once the node cache holds the node watch's initial list, or while this
+    /// replica waits for the leader election lease
+    pub fn mark_ready(&self) {
+        self.health.mark_ready();
+    }
+
+    /// Whether the node cache holds the node watch's initial list, or this replica is a
+    /// follower waiting for the leader election lease
+    pub fn is_ready(&self) -> bool {
+        self.health.is_ready()
+    }
+
+    /// Whether a node was reconciled within `liveness_window` of `now`. With no nodes cached
+    /// there is nothing to reconcile, so that counts as live too.
+    pub fn is_live(&self, now: Instant) -> bool {
+        let idle_for = self.health.idle_for(now);
+        if idle_for <= self.config.liveness_window
+            || self.node_store.get().is_some_and(|store| store.is_empty())
+        {
+            return true;
+        }
+        if self.health.first_stall(now) {
+            warn!(
+                "No node reconciled for {}s, reporting not live",
+                idle_for.as_secs()
+            );
+        }
+        false
+    }
+
+    /// How far the controller is through its startup warmup
+    pub fn warmup_progress(&self) -> WarmupProgress {
+        self.pacer.progress(Instant::now())
+    }
+
+    /// How the candidate policy would have changed restores since startup
+    pub fn shadow_stats(&self) -> ShadowStats {
+        self.shadow.lock().unwrap().clone()
+    }
+
+    /// Nodes to reconcile immediately, for the Controller's `reconcile_on`.
+    /// Returns None if the stream was already taken.
+    pub fn reconcile_requests(&self) -> Option<UnboundedReceiver<ObjectRef<Node>>> {
+        self.reconcile_requests_rx.lock().unwrap().take()
+    }
+
+    /// The retry state of every node whose last reconcile failed
+    pub fn backoff_states(&self) -> BTreeMap<String, BackoffState> {
+        self.backoff
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, state)| (name.clone(), state.clone()))
+            .collect()
+    }
+
+    /// Consecutive failed reconciles of the node
+    pub(crate) fn failed_attempts(&self, node_name: &str) -> u32 {
+        self.backoff
+            .lock()
+            .unwrap()
+            .get(node_name)
+            .map_or(0, |state| state.attempt)
+    }
+
+    /// Clear the node's retry state and reconcile it immediately. Returns the cleared state.
+    /// Safe to call while the node is being reconciled, since the Controller never runs two
+    /// reconciles of the same node at once and queues the request until the current one ends.
+    pub fn reset_backoff(&self, node_name: &str) -> Option<BackoffState> {
+        let previous = self.backoff.lock().unwrap().remove(node_name);
+        // Only fails if the Controller has shut down, in which case there's nothing to retry
+        let _ = self
+            .reconcile_requests
+            .unbounded_send(ObjectRef::new(node_name));
+        previous
+    }
+
+    /// Whether the node is currently in the deferral queue
+    pub fn is_deferred(&self, node_name: &str) -> bool {
+        self.deferred.lock().unwrap().contains_key(node_name)
+    }
+
+    /// Defer the node if it's still within its deferral window. Returns false once the window
+    /// has been exhausted.
+    pub(crate) fn defer(&self, node_name: &str) -> bool {
+        let mut deferred = self.deferred.lock().unwrap();
+        let first_deferred = *deferred
+            .entry(node_name.to_string())
+            .or_insert_with(Instant::now);
+        first_deferred.elapsed() < DEFERRAL_WINDOW
+    }
+}
+
+/// The boot ID `node` reports, if any
+fn boot_id(node: &Node) -> Option<String> {
+    node.status
+        .as_ref()
+        .and_then(|status| status.node_info.as_ref())
+        .map(|info| info.boot_id.clone())
+        .filter(|boot_id| !boot_id.is_empty())
+}
@@ -0,0 +1,311 @@
+//! A compact record of what each node's backup holds, so reconciles of unchanged nodes skip
+//! comparing labels and rewriting backups, and restored nodes notice a changed backup without
+//! reading it
+
+use crate::{
+    configmap_name, naming::BACKUP_CONFIGMAP_PREFIX, shard_key, Backup, BackupLayout,
+    EncryptionKeys, SHARD_CONFIGMAP_PREFIX,
+};
+use k8s_openapi::api::core::v1::{ConfigMap, Node};
+use kube::{runtime::watcher, ResourceExt};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Shards of the digest map, so reconciles of different nodes rarely wait on the same lock
+const SHARDS: usize = 16;
+/// The most digests kept. Past this, arbitrary digests are dropped, which only costs a
+/// comparison or backup that would have been skipped.
+pub(crate) const MAX_BACKUP_DIGESTS: usize = 16 * 1024;
+
+/// What a node's backup held when we last wrote or checked it
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Digest {
+    /// The node's resourceVersion when the labels were hashed
+    node_version: Option<String>,
+    /// Hash of the backed up labels
+    labels: u64,
+    /// The backup ConfigMap's resourceVersion as we wrote it
+    backup_version: Option<String>,
+}
+
+/// How often [`BackupDigests::is_current`] avoided work
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DigestStats {
+    /// The node hadn't changed since the last check, so nothing was compared
+    pub unchanged: u64,
+    /// The node changed, but its labels hash to what the backup holds
+    pub rehashed: u64,
+    /// No digest, or labels that differ from the backup
+    pub misses: u64,
+}
+
+/// The namespace of a backup, and the name of its per-node ConfigMap or its key in a shard
+type Key = (String, String);
+
+/// Digests of backups by namespace and ConfigMap name, or shard key under
+/// [`BackupLayout::Sharded`], so a backup moved to another namespace isn't confused with the one
+/// left behind. Entries are dropped when the backup ConfigMap
+/// changes under us, see [`BackupDigests::observe`].
+pub struct BackupDigests {
+    shards: Vec<Mutex<HashMap<Key, Digest>>>,
+    /// [`Backup::labels_digest`] of every backup ConfigMap as last seen by the watch, sharded
+    /// like `shards`
+    label_digests: Vec<Mutex<HashMap<Key, String>>>,
+    /// The keys of each shard ConfigMap as last seen by the watch, to tell which were removed
+    shard_keys: Mutex<HashMap<Key, HashSet<String>>>,
+    capacity: usize,
+    /// The keys backup labels are encrypted with
+    keys: EncryptionKeys,
+    /// Which backup ConfigMaps are digested
+    layout: BackupLayout,
+    unchanged: AtomicU64,
+    rehashed: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for BackupDigests {
+    fn default() -> Self {
+        Self::with_capacity(MAX_BACKUP_DIGESTS)
+    }
+}
+
+impl BackupDigests {
+    /// Keep at most about `capacity` digests
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            label_digests: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            shard_keys: Mutex::new(HashMap::new()),
+            capacity: capacity.div_ceil(SHARDS).max(1),
+            keys: EncryptionKeys::default(),
+            layout: BackupLayout::default(),
+            unchanged: AtomicU64::new(0),
+            rehashed: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Decrypt backup labels with `keys` to digest them
+    pub fn with_encryption(mut self, keys: EncryptionKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Digest the backups of `layout`: per-node ConfigMaps, or each node's key in the shards
+    pub fn with_layout(mut self, layout: BackupLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// The key of `node_name`'s backup in `namespace`
+    fn key(&self, namespace: &str, node_name: &str) -> Key {
+        let name = match self.layout {
+            BackupLayout::PerNode => configmap_name(node_name),
+            BackupLayout::Sharded => shard_key(node_name),
+        };
+        (namespace.to_string(), name)
+    }
+
+    fn shard_index(key: &Key) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % SHARDS
+    }
+
+    fn shard(&self, key: &Key) -> &Mutex<HashMap<Key, Digest>> {
+        &self.shards[Self::shard_index(key)]
+    }
+
+    fn label_shard(&self, key: &Key) -> &Mutex<HashMap<Key, String>> {
+        &self.label_digests[Self::shard_index(key)]
+    }
+
+    /// [`Backup::labels_digest`] of `node_name`'s backup in `namespace` as last seen by the
+    /// backup watch, or None if it has no backup there or the watch hasn't seen it
+    pub fn labels_digest(&self, namespace: &str, node_name: &str) -> Option<String> {
+        let key = self.key(namespace, node_name);
+        self.label_shard(&key).lock().unwrap().get(&key).cloned()
+    }
+
+    /// Whether `node`'s backup in `namespace` already holds its current labels
+    pub fn is_current(&self, namespace: &str, node: &Node) -> bool {
+        let key = self.key(namespace, &node.name_any());
+        let mut shard = self.shard(&key).lock().unwrap();
+        let Some(digest) = shard.get_mut(&key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        let node_version = node.resource_version();
+        if node_version.is_some() && digest.node_version == node_version {
+            self.unchanged.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        if digest.labels != hash_labels(node) {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        digest.node_version = node_version;
+        self.rehashed.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Note that `node`'s labels were just backed up in `namespace` to the ConfigMap version
+    /// `backup_version`
+    pub fn record(&self, namespace: &str, node: &Node, backup_version: Option<String>) {
+        let key = self.key(namespace, &node.name_any());
+        let mut shard = self.shard(&key).lock().unwrap();
+        if shard.len() >= self.capacity && !shard.contains_key(&key) {
+            if let Some(evicted) = shard.keys().next().cloned() {
+                shard.remove(&evicted);
+            }
+        }
+        shard.insert(
+            key,
+            Digest {
+                node_version: node.resource_version(),
+                labels: hash_labels(node),
+                backup_version,
+            },
+        );
+    }
+
+    /// Drop the digest of `node_name`'s backup in `namespace`
+    pub fn forget(&self, namespace: &str, node_name: &str) {
+        let key = self.key(namespace, node_name);
+        self.shard(&key).lock().unwrap().remove(&key);
+        self.label_shard(&key).lock().unwrap().remove(&key);
+    }
+
+    /// Drop the digest of a backup that changed other than by our own write, e.g. it was
+    /// edited, restored from a bundle, or deleted, and note the labels it now holds. Under
+    /// [`BackupLayout::Sharded`], each key of a shard is a backup, versioned by its entry.
+    pub fn observe(&self, event: &watcher::Event<ConfigMap>) {
+        let (configmap, deleted) = match event {
+            watcher::Event::Apply(cm) | watcher::Event::InitApply(cm) => (cm, false),
+            watcher::Event::Delete(cm) => (cm, true),
+            watcher::Event::Init | watcher::Event::InitDone => return,
+        };
+        let name = configmap.name_any();
+        let namespace = configmap.namespace().unwrap_or_default();
+        match self.layout {
+            BackupLayout::PerNode if name.starts_with(BACKUP_CONFIGMAP_PREFIX) => {
+                let labels_digest = Backup::from_configmap(configmap, &self.keys)
+                    .ok()
+                    .filter(|_| !deleted)
+                    .map(|backup| backup.labels_digest());
+                let version = configmap.resource_version();
+                self.observe_backup((namespace, name), labels_digest, version, deleted);
+            }
+            BackupLayout::Sharded if name.starts_with(SHARD_CONFIGMAP_PREFIX) => {
+                let entries = match &configmap.data {
+                    Some(data) if !deleted => data.clone(),
+                    _ => BTreeMap::new(),
+                };
+                let shard = (namespace.clone(), name);
+                let mut shard_keys = self.shard_keys.lock().unwrap();
+                let seen = entries.keys().cloned().collect();
+                let previous = if deleted {
+                    shard_keys.remove(&shard)
+                } else {
+                    shard_keys.insert(shard, seen)
+                };
+                drop(shard_keys);
+                for removed in previous
+                    .into_iter()
+                    .flatten()
+                    .filter(|key| !entries.contains_key(key))
+                {
+                    self.observe_backup((namespace.clone(), removed), None, None, true);
+                }
+                for (key, entry) in entries {
+                    let labels_digest = serde_json::from_str::<BTreeMap<String, String>>(&entry)
+                        .ok()
+                        .and_then(|data| Backup::from_configmap_data(&data).ok())
+                        .map(|backup| backup.labels_digest());
+                    self.observe_backup(
+                        (namespace.clone(), key),
+                        labels_digest,
+                        Some(entry),
+                        false,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Note the labels the backup at `key` holds as `version`, dropping its digest unless we
+    /// wrote that version
+    fn observe_backup(
+        &self,
+        key: Key,
+        labels_digest: Option<String>,
+        version: Option<String>,
+        deleted: bool,
+    ) {
+        let mut label_shard = self.label_shard(&key).lock().unwrap();
+        match labels_digest {
+            Some(digest) => {
+                if label_shard.len() >= self.capacity && !label_shard.contains_key(&key) {
+                    if let Some(evicted) = label_shard.keys().next().cloned() {
+                        label_shard.remove(&evicted);
+                    }
+                }
+                label_shard.insert(key.clone(), digest);
+            }
+            None => {
+                label_shard.remove(&key);
+            }
+        }
+        drop(label_shard);
+        let mut shard = self.shard(&key).lock().unwrap();
+        let ours = shard
+            .get(&key)
+            .is_some_and(|digest| digest.backup_version == version);
+        if deleted || !ours {
+            shard.remove(&key);
+        }
+    }
+
+    /// Drop every digest, e.g. when changes to backups may have been missed
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+        for shard in &self.label_digests {
+            shard.lock().unwrap().clear();
+        }
+        self.shard_keys.lock().unwrap().clear();
+    }
+
+    /// The number of digests kept
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> DigestStats {
+        DigestStats {
+            unchanged: self.unchanged.load(Ordering::Relaxed),
+            rehashed: self.rehashed.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A hash of the node's labels, in key order
+fn hash_labels(node: &Node) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.labels().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,145 @@
+//! Encrypting preserved labels at rest, so reading backup ConfigMaps doesn't reveal them
+
+use crate::{
+    store::{insert_labels_json, take_labels_json, CIPHER_KEY, SEALED_LABELS_KEY},
+    Error, Result,
+};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use k8s_openapi::{api::core::v1::ConfigMap, ByteString};
+use std::{collections::BTreeMap, path::Path};
+
+/// The cipher encrypted labels are marked with
+pub const CIPHER: &str = "chacha20poly1305";
+/// Bytes of nonce stored ahead of the ciphertext
+const NONCE_BYTES: usize = 12;
+/// Bytes of authentication tag at the end of the ciphertext
+const TAG_BYTES: usize = 16;
+
+/// A 256-bit key for encrypting labels
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Load a key from a file holding its 32 bytes, raw or as 64 hex digits, such as a mounted
+    /// Secret key
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let invalid = |reason: String| {
+            Error::InvalidEncryption(format!("key file '{}' {}", path.display(), reason))
+        };
+        let contents = std::fs::read(path).map_err(|e| invalid(format!("can't be read: {}", e)))?;
+        let hex_digits = contents.trim_ascii();
+        let key = match (contents.len(), hex_digits.len()) {
+            (32, _) => contents,
+            (_, 64) => hex::decode(hex_digits)
+                .map_err(|e| invalid(format!("doesn't hold a hex key: {}", e)))?,
+            (len, _) => {
+                return Err(invalid(format!(
+                    "holds {} bytes, expected 32 raw bytes or 64 hex digits",
+                    len
+                )))
+            }
+        };
+        Ok(Self(key.try_into().expect("the key is 32 bytes")))
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(<redacted>)")
+    }
+}
+
+/// The keys labels are encrypted with. The first encrypts new backups and every key is tried
+/// to decrypt, so a new key can be put first while backups under the old one are still read.
+/// Without keys, labels are stored in plain text.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncryptionKeys(pub Vec<EncryptionKey>);
+
+impl EncryptionKeys {
+    pub fn is_enabled(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// Encrypt the labels of a backup ConfigMap with the first key, moving them from its data
+    /// to its binary data. Does nothing without keys or labels.
+    pub fn seal(&self, configmap: &mut ConfigMap) -> Result<()> {
+        let Some(key) = self.0.first() else {
+            return Ok(());
+        };
+        let Some(data) = configmap.data.as_mut() else {
+            return Ok(());
+        };
+        let Some(json) = take_labels_json(data)? else {
+            return Ok(());
+        };
+        let cipher = ChaCha20Poly1305::new(&key.0.into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, json.as_bytes())
+            .expect("labels are far below the cipher's message size limit");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        data.insert(CIPHER_KEY.to_string(), CIPHER.to_string());
+        configmap
+            .binary_data
+            .get_or_insert_with(BTreeMap::new)
+            .insert(SEALED_LABELS_KEY.to_string(), ByteString(sealed));
+        Ok(())
+    }
+
+    /// The data of a backup ConfigMap with its labels decrypted, as read by
+    /// [`crate::Backup::from_configmap_data`]. Data without encrypted labels, such as backups
+    /// written before encryption was enabled, is returned as is.
+    pub fn open(&self, configmap: &ConfigMap) -> Result<BTreeMap<String, String>> {
+        let mut data = configmap.data.clone().unwrap_or_default();
+        let Some(cipher) = data.remove(CIPHER_KEY) else {
+            return Ok(data);
+        };
+        if cipher != CIPHER {
+            return Err(Error::Decryption(format!("unknown cipher '{}'", cipher)));
+        }
+        if !self.is_enabled() {
+            return Err(Error::Decryption(
+                "the labels are encrypted and no key is configured".to_string(),
+            ));
+        }
+        let sealed = configmap
+            .binary_data
+            .as_ref()
+            .and_then(|binary_data| binary_data.get(SEALED_LABELS_KEY))
+            .ok_or_else(|| Error::Decryption("the encrypted labels are missing".to_string()))?;
+        if sealed.0.len() < NONCE_BYTES + TAG_BYTES {
+            return Err(Error::Decryption(format!(
+                "the encrypted labels are truncated to {} bytes",
+                sealed.0.len()
+            )));
+        }
+        let (nonce, ciphertext) = sealed.0.split_at(NONCE_BYTES);
+        let plaintext = self
+            .0
+            .iter()
+            .find_map(|key| {
+                ChaCha20Poly1305::new(&key.0.into())
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .ok()
+            })
+            .ok_or_else(|| {
+                Error::Decryption(format!(
+                    "none of the {} keys decrypts the labels, or they were tampered with",
+                    self.0.len()
+                ))
+            })?;
+        let json = String::from_utf8(plaintext)
+            .map_err(|_| Error::Decryption("the decrypted labels aren't UTF-8".to_string()))?;
+        insert_labels_json(&mut data, json);
+        Ok(data)
+    }
+}
@@ -0,0 +1,219 @@
+//! The crate's error type and the API operations errors are attributed to
+
+use k8s_openapi::api::core::v1::Node;
+use kube::runtime::finalizer::Error as FinalizerError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to get node name: {0:?}")]
+    MissingNodeName(Box<Node>),
+    #[error("Kubernetes API error: {0}")]
+    Kube(#[from] kube::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Finalizer error: {0}")]
+    Finalizer(#[source] Box<FinalizerError<Error>>),
+    #[error("Invalid label expiry rule '{0}', expected <prefix>=<days>")]
+    InvalidExpiryRule(String),
+    #[error("Invalid namespace mapping '{0}', expected <label value>=<namespace>")]
+    InvalidNamespaceMapping(String),
+    #[error(
+        "Invalid identity mismatch policy '{0}', expected ignore, warn, skip, or machine-independent"
+    )]
+    InvalidIdentityPolicy(String),
+    #[error("Invalid unknown backup age policy '{0}', expected restore or skip")]
+    InvalidUnknownAgePolicy(String),
+    #[error("Invalid merge strategy '{0}', expected prefer-current or prefer-preserved")]
+    InvalidMergeStrategy(String),
+    #[error("Invalid backup layout '{0}', expected per-node or sharded")]
+    InvalidBackupLayout(String),
+    #[error("Invalid log format '{0}', expected text or json")]
+    InvalidLogFormat(String),
+    #[error("Invalid backup schema version '{0}'")]
+    InvalidSchemaVersion(String),
+    #[error("Backup of {size} bytes exceeds the {limit} byte limit even after degrading it")]
+    BackupTooLarge { size: usize, limit: usize },
+    #[error(
+        "Shard ConfigMap '{shard}' would grow to {size} bytes with this backup, past the {limit} byte object limit"
+    )]
+    ShardFull {
+        shard: String,
+        size: usize,
+        limit: usize,
+    },
+    #[error("Invalid node name in --node-names: {0}")]
+    InvalidNodeName(String),
+    #[error("Invalid node name '{0}' in the snapshot: {1}")]
+    InvalidSnapshotNode(String, String),
+    #[error("Sharded backups can't be exported, their shards only know nodes by hash")]
+    ShardedExport,
+    #[error("Failed to decrypt the backup's labels: {0}")]
+    Decryption(String),
+    #[error("Invalid encryption configuration: {0}")]
+    InvalidEncryption(String),
+    #[error("Namespace '{0}' for backups does not exist")]
+    MissingNamespace(String),
+    #[error("Node '{0}' does not exist")]
+    NodeNotFound(String),
+    #[error("Node '{0}' has no backup")]
+    BackupNotFound(String),
+    #[error("Invalid node selector: {0}")]
+    InvalidSelector(String),
+    #[error("Invalid output surface '{0}', expected logs, reports, or admin")]
+    InvalidSurface(String),
+    #[error("Failed to load the client configuration: {0}")]
+    ClientConfig(String),
+    #[error("Invalid User-Agent '{0}': {1}")]
+    InvalidUserAgent(String, String),
+    #[error("Forbidden from {operation}: {message}. Hint: {hint}", hint = .operation.rbac_hint())]
+    Forbidden {
+        operation: Operation,
+        message: String,
+    },
+}
+
+impl Error {
+    /// Wrap an API error from `operation`, turning 403s into [`Error::Forbidden`] so the
+    /// operator is told which RBAC rule is missing
+    pub fn from_api(operation: Operation, error: kube::Error) -> Self {
+        match error {
+            kube::Error::Api(response) if response.code == 403 => Error::Forbidden {
+                operation,
+                message: response.message,
+            },
+            e => Error::Kube(e),
+        }
+    }
+
+    /// The variant's name for metrics. Errors from our finalizer's apply or cleanup are
+    /// attributed to the error they wrap.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::MissingNodeName(_) => "missing_node_name",
+            Error::Kube(_) => "kube",
+            Error::Serialization(_) => "serialization",
+            Error::Io(_) => "io",
+            Error::Finalizer(e) => match e.as_ref() {
+                FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => e.kind(),
+                _ => "finalizer",
+            },
+            Error::InvalidExpiryRule(_) => "invalid_expiry_rule",
+            Error::InvalidNamespaceMapping(_) => "invalid_namespace_mapping",
+            Error::InvalidIdentityPolicy(_) => "invalid_identity_policy",
+            Error::InvalidUnknownAgePolicy(_) => "invalid_unknown_age_policy",
+            Error::InvalidMergeStrategy(_) => "invalid_merge_strategy",
+            Error::InvalidBackupLayout(_) => "invalid_backup_layout",
+            Error::InvalidLogFormat(_) => "invalid_log_format",
+            Error::InvalidSchemaVersion(_) => "invalid_schema_version",
+            Error::BackupTooLarge { .. } => "backup_too_large",
+            Error::ShardFull { .. } => "shard_full",
+            Error::InvalidNodeName(_) => "invalid_node_name",
+            Error::InvalidSnapshotNode(..) => "invalid_snapshot_node",
+            Error::ShardedExport => "sharded_export",
+            Error::Decryption(_) => "decryption",
+            Error::InvalidEncryption(_) => "invalid_encryption",
+            Error::MissingNamespace(_) => "missing_namespace",
+            Error::NodeNotFound(_) => "node_not_found",
+            Error::BackupNotFound(_) => "backup_not_found",
+            Error::InvalidSelector(_) => "invalid_selector",
+            Error::InvalidSurface(_) => "invalid_surface",
+            Error::ClientConfig(_) => "client_config",
+            Error::InvalidUserAgent(..) => "invalid_user_agent",
+            Error::Forbidden { .. } => "forbidden",
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The API operations the controller performs, for explaining permission failures
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    ReadBackup { namespace: String },
+    ListBackups { namespace: String },
+    WriteBackup { namespace: String },
+    DeleteBackup { namespace: String },
+    WriteStatus { namespace: String },
+    ListNodes,
+    PatchNode,
+    UpdateFinalizers,
+    ElectLeader { namespace: String },
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::ReadBackup { namespace } => {
+                write!(f, "reading backups in namespace {}", namespace)
+            }
+            Operation::ListBackups { namespace } => {
+                write!(f, "listing backups in namespace {}", namespace)
+            }
+            Operation::WriteBackup { namespace } => {
+                write!(f, "writing backups in namespace {}", namespace)
+            }
+            Operation::DeleteBackup { namespace } => {
+                write!(f, "deleting backups in namespace {}", namespace)
+            }
+            Operation::WriteStatus { namespace } => {
+                write!(f, "writing the status ConfigMap in namespace {}", namespace)
+            }
+            Operation::ListNodes => write!(f, "listing nodes"),
+            Operation::PatchNode => write!(f, "patching nodes"),
+            Operation::UpdateFinalizers => write!(f, "updating node finalizers"),
+            Operation::ElectLeader { namespace } => {
+                write!(f, "electing a leader in namespace {}", namespace)
+            }
+        }
+    }
+}
+
+impl Operation {
+    /// The RBAC rule the operation needs, phrased as an instruction for the operator
+    pub fn rbac_hint(&self) -> String {
+        match self {
+            Operation::ReadBackup { namespace } => format!(
+                "reading backups requires a Role in namespace {} for configmaps with verbs [get]",
+                namespace
+            ),
+            Operation::ListBackups { namespace } => format!(
+                "listing backups requires a Role in namespace {} for configmaps with verbs [list]",
+                namespace
+            ),
+            Operation::WriteBackup { namespace } => format!(
+                "writing backups requires a Role in namespace {} for configmaps with verbs \
+                [get, patch, create]",
+                namespace
+            ),
+            Operation::DeleteBackup { namespace } => format!(
+                "deleting backups requires a Role in namespace {} for configmaps with verbs \
+                [delete]",
+                namespace
+            ),
+            Operation::WriteStatus { namespace } => format!(
+                "writing the status requires a Role in namespace {} for configmaps with verbs \
+                [get, patch, create]",
+                namespace
+            ),
+            Operation::ListNodes => {
+                "listing nodes requires a ClusterRole rule for nodes with verbs [list, watch]"
+                    .to_string()
+            }
+            Operation::PatchNode => {
+                "patching nodes requires a ClusterRole rule for nodes with verbs [get, patch]"
+                    .to_string()
+            }
+            Operation::UpdateFinalizers => "updating finalizers requires a ClusterRole rule for \
+                nodes with verbs [patch] and nodes/finalizers with verbs [update]"
+                .to_string(),
+            Operation::ElectLeader { namespace } => format!(
+                "leader election requires a Role in namespace {} for leases in apiGroup \
+                coordination.k8s.io with verbs [get, create, update]",
+                namespace
+            ),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Readiness and liveness of a running controller, for the probes on the admin API
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Whether the node watch has started, and when the controller last made progress
+pub(crate) struct Health {
+    ready: AtomicBool,
+    started: Instant,
+    /// Milliseconds after `started` of the last reconcile
+    last_activity: AtomicU64,
+    /// When liveness last failed, so the failure is only logged once per stall
+    reported_stall: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            started: now,
+            last_activity: AtomicU64::new(0),
+            reported_stall: Mutex::new(None),
+        }
+    }
+
+    /// The node cache holds the node watch's initial list, or this replica is a follower
+    /// waiting for the leader election lease
+    pub(crate) fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_activity(&self, now: Instant) {
+        let millis = now.saturating_duration_since(self.started).as_millis() as u64;
+        self.last_activity.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    /// How long ago the last reconcile, or startup if there was none, was at `now`
+    pub(crate) fn idle_for(&self, now: Instant) -> Duration {
+        let last = self.started + Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        now.saturating_duration_since(last)
+    }
+
+    /// Whether this is the first liveness failure since the controller last made progress
+    pub(crate) fn first_stall(&self, now: Instant) -> bool {
+        let mut reported = self.reported_stall.lock().unwrap();
+        let idle_since = now - self.idle_for(now);
+        if reported.is_some_and(|at| at >= idle_since) {
+            return false;
+        }
+        *reported = Some(now);
+        true
+    }
+}
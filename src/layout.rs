@@ -0,0 +1,429 @@
+//! Where backups are kept: a ConfigMap per node, or the backups of many nodes as data keys of
+//! a bounded number of shard ConfigMaps
+
+use crate::{
+    apply_params, configmap_name,
+    naming::{hash_node_name, BACKUP_CONFIGMAP_PREFIX, MANAGED_BY_LABEL_KEY, SERVICE_NAME},
+    Backup, EncryptionKeys, Error, Operation, Result,
+};
+use k8s_openapi::{api::core::v1::ConfigMap, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{
+    api::{Api, DeleteParams, Patch, PatchParams, Preconditions, ResourceExt},
+    error::ErrorResponse,
+    runtime::{
+        reflector::{ObjectRef, Store},
+        watcher,
+    },
+    Client,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// Every shard ConfigMap's name starts with this. Unlike per-node names, it isn't under
+/// `node-labels-`, so a shard is never taken for one node's backup.
+pub const SHARD_CONFIGMAP_PREFIX: &str = "node-label-shard-";
+/// Hex digits of the node name's hash that pick its shard, so there are at most 256 shards
+const SHARD_HASH_LENGTH: usize = 2;
+
+/// How backups are laid out in ConfigMaps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackupLayout {
+    /// One ConfigMap per node, see [`configmap_name`]
+    #[default]
+    PerNode,
+    /// Each backup is a data key of its node's shard ConfigMap, see [`shard_configmap_name`].
+    /// Backups still in a per-node ConfigMap are moved into their shard on restore.
+    Sharded,
+}
+
+impl FromStr for BackupLayout {
+    type Err = Error;
+
+    fn from_str(layout: &str) -> Result<Self> {
+        match layout {
+            "per-node" => Ok(Self::PerNode),
+            "sharded" => Ok(Self::Sharded),
+            _ => Err(Error::InvalidBackupLayout(layout.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for BackupLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let layout = match self {
+            Self::PerNode => "per-node",
+            Self::Sharded => "sharded",
+        };
+        write!(f, "{}", layout)
+    }
+}
+
+/// The largest shard ConfigMap written, managedFields included: the API server's 1MiB object
+/// limit
+pub(crate) const MAX_SHARD_BYTES: usize = 1024 * 1024;
+
+/// Room left for the managedFields entry a node's field manager adds to its shard
+const SHARD_MANAGER_BYTES: usize = 512;
+
+/// The shard ConfigMap holding a node's backup under [`BackupLayout::Sharded`], picked by the
+/// first byte of the node name's hash
+pub fn shard_configmap_name(node_name: &str) -> String {
+    let hash = hash_node_name(node_name);
+    format!("{}{}", SHARD_CONFIGMAP_PREFIX, &hash[..SHARD_HASH_LENGTH])
+}
+
+/// The data key of a node's backup in its shard: the node name's hash
+pub fn shard_key(node_name: &str) -> String {
+    hash_node_name(node_name)
+}
+
+/// Each node's shard key is applied under its own field manager, so applies for different
+/// nodes own different fields and never remove or conflict with each other's keys
+fn shard_field_manager(node_name: &str) -> String {
+    format!("{}-{}", SERVICE_NAME, shard_key(node_name))
+}
+
+/// Whether `name` is a ConfigMap backups are read from, per-node or shard
+pub(crate) fn is_backup_configmap(name: &str) -> bool {
+    name.starts_with(BACKUP_CONFIGMAP_PREFIX) || name.starts_with(SHARD_CONFIGMAP_PREFIX)
+}
+
+/// What [`BackupStore::delete`] did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deletion {
+    Deleted,
+    /// There was no backup to delete
+    Missing,
+    /// The backup changed since it was read, or went away, so it was kept
+    Changed,
+}
+
+/// Backup ConfigMaps as last seen by the backup watch, see [`BackupStore::set_cache`]
+#[derive(Default)]
+struct Cache {
+    store: OnceLock<Store<ConfigMap>>,
+    /// Backup ConfigMaps we wrote, with the resourceVersion written, or None if we deleted
+    /// them, until the watch catches up. The cache is stale for these, so they are read from
+    /// the API server.
+    unseen: Mutex<HashMap<String, Option<String>>>,
+}
+
+/// Reads and writes node backups in the configured [`BackupLayout`]
+#[derive(Clone)]
+pub struct BackupStore {
+    api: Api<ConfigMap>,
+    namespace: String,
+    layout: BackupLayout,
+    /// The keys per-node backup labels are encrypted with
+    keys: EncryptionKeys,
+    /// Writes are validated by the API server without being persisted
+    dry_run: bool,
+    cache: Arc<Cache>,
+}
+
+impl BackupStore {
+    pub fn new(client: Client, namespace: &str, layout: BackupLayout) -> Self {
+        Self {
+            api: Api::namespaced(client, namespace),
+            namespace: namespace.to_string(),
+            layout,
+            keys: EncryptionKeys::default(),
+            dry_run: false,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Encrypt the labels of per-node backups written with `keys`, and decrypt them on read
+    pub fn with_encryption(mut self, keys: EncryptionKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Only validate writes with the API server, without persisting them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// `params`, as a dry run if writes are only validated
+    fn write_params(&self, params: PatchParams) -> PatchParams {
+        if self.dry_run {
+            params.dry_run()
+        } else {
+            params
+        }
+    }
+
+    /// Read backups from `store`, a cache of the backup ConfigMaps, instead of the API server.
+    /// ConfigMaps missing from it, or which we wrote since it last saw them, are still read from
+    /// the API server. Keep it current with [`BackupStore::observe`].
+    pub fn set_cache(&self, store: Store<ConfigMap>) {
+        let _ = self.cache.store.set(store);
+    }
+
+    /// Note what the backup watch saw, so ConfigMaps we wrote are read from the cache again
+    /// once it has caught up with our write
+    pub fn observe(&self, event: &watcher::Event<ConfigMap>) {
+        let (configmap, deleted) = match event {
+            watcher::Event::Apply(cm) | watcher::Event::InitApply(cm) => (cm, false),
+            watcher::Event::Delete(cm) => (cm, true),
+            watcher::Event::Init | watcher::Event::InitDone => return,
+        };
+        if configmap
+            .namespace()
+            .is_some_and(|namespace| namespace != self.namespace)
+        {
+            return;
+        }
+        let mut unseen = self.cache.unseen.lock().unwrap();
+        let caught_up = match unseen.get(&configmap.name_any()) {
+            Some(Some(version)) => {
+                !deleted && configmap.resource_version().as_ref() == Some(version)
+            }
+            Some(None) => deleted,
+            None => false,
+        };
+        if caught_up {
+            unseen.remove(&configmap.name_any());
+        }
+    }
+
+    /// `name` from the cache, if it is there and up to date with our writes
+    fn cached(&self, name: &str) -> Option<ConfigMap> {
+        let store = self.cache.store.get()?;
+        if self.cache.unseen.lock().unwrap().contains_key(name) {
+            return None;
+        }
+        let key = ObjectRef::new(name).within(&self.namespace);
+        store.get(&key).map(|cm| cm.as_ref().clone())
+    }
+
+    /// Note that we wrote `name` as `version`, or deleted it if None
+    fn wrote(&self, name: &str, version: Option<String>) {
+        if self.cache.store.get().is_some() {
+            self.cache
+                .unseen
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), version);
+        }
+    }
+
+    pub fn layout(&self) -> BackupLayout {
+        self.layout
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// The keys per-node backup labels are encrypted with
+    pub(crate) fn keys(&self) -> &EncryptionKeys {
+        &self.keys
+    }
+
+    /// The ConfigMaps of the store's namespace
+    pub(crate) fn api(&self) -> &Api<ConfigMap> {
+        &self.api
+    }
+
+    /// The ConfigMap `node_name`'s backup is written to
+    pub fn configmap_name(&self, node_name: &str) -> String {
+        match self.layout {
+            BackupLayout::PerNode => configmap_name(node_name),
+            BackupLayout::Sharded => shard_configmap_name(node_name),
+        }
+    }
+
+    /// The version of `node_name`'s backup in `configmap` as read or written: the ConfigMap's
+    /// resourceVersion, or in a shard, the node's entry itself, since the shard's
+    /// resourceVersion changes with every other node's backup
+    pub fn version(&self, node_name: &str, configmap: &ConfigMap) -> Option<String> {
+        match self.layout {
+            BackupLayout::PerNode => configmap.metadata.resource_version.clone(),
+            BackupLayout::Sharded => configmap.data.as_ref()?.get(&shard_key(node_name)).cloned(),
+        }
+    }
+
+    /// The backup of `node_name` and its version, see [`BackupStore::version`], or None if it
+    /// has none
+    pub async fn read(&self, node_name: &str) -> Result<Option<(Backup, Option<String>)>> {
+        let name = self.configmap_name(node_name);
+        if let Some(cm) = self.cached(&name) {
+            return self.parse(node_name, &cm);
+        }
+        let cm = match self.api.get(&name).await {
+            Ok(cm) => cm,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(None),
+            Err(e) => {
+                return Err(Error::from_api(
+                    Operation::ReadBackup {
+                        namespace: self.namespace.clone(),
+                    },
+                    e,
+                ))
+            }
+        };
+        self.parse(node_name, &cm)
+    }
+
+    /// The backup of `node_name` in `cm` and its version, or None if it has none
+    pub(crate) fn parse(
+        &self,
+        node_name: &str,
+        cm: &ConfigMap,
+    ) -> Result<Option<(Backup, Option<String>)>> {
+        let version = self.version(node_name, cm);
+        let backup = match self.layout {
+            BackupLayout::PerNode => Backup::from_configmap(cm, &self.keys)?,
+            BackupLayout::Sharded => {
+                let Some(entry) = &version else {
+                    return Ok(None);
+                };
+                let data: BTreeMap<String, String> = serde_json::from_str(entry)?;
+                Backup::from_configmap_data(&data)?
+            }
+        };
+        Ok(Some((backup, version)))
+    }
+
+    /// Write `configmap`, the per-node backup ConfigMap of `node_name`, replacing any earlier
+    /// backup. In a shard, its data is stored as a JSON object under the node's key, and the
+    /// shard is created on the first write. Returns the ConfigMap as written.
+    pub async fn write(&self, node_name: &str, configmap: &ConfigMap) -> Result<ConfigMap> {
+        let written = match self.layout {
+            BackupLayout::PerNode => {
+                let mut configmap = configmap.clone();
+                self.keys.seal(&mut configmap)?;
+                self.api
+                    .patch(
+                        &configmap.name_any(),
+                        &self.write_params(apply_params()),
+                        &Patch::Apply(&configmap),
+                    )
+                    .await
+            }
+            BackupLayout::Sharded => {
+                let name = shard_configmap_name(node_name);
+                let entry = Self::shard_entry(configmap)?;
+                self.check_shard_size(&name, node_name, &entry).await?;
+                let shard = ConfigMap {
+                    metadata: ObjectMeta {
+                        name: Some(name.clone()),
+                        namespace: Some(self.namespace.clone()),
+                        labels: Some(BTreeMap::from([(
+                            MANAGED_BY_LABEL_KEY.to_string(),
+                            SERVICE_NAME.to_string(),
+                        )])),
+                        ..Default::default()
+                    },
+                    data: Some(BTreeMap::from([(shard_key(node_name), entry)])),
+                    ..Default::default()
+                };
+                let params = PatchParams::apply(&shard_field_manager(node_name)).force();
+                let params = self.write_params(params);
+                self.api.patch(&name, &params, &Patch::Apply(&shard)).await
+            }
+        };
+        let written = written.map_err(|e| {
+            Error::from_api(
+                Operation::WriteBackup {
+                    namespace: self.namespace.clone(),
+                },
+                e,
+            )
+        })?;
+        if !self.dry_run {
+            self.wrote(&written.name_any(), written.resource_version());
+        }
+        Ok(written)
+    }
+
+    /// The data of `configmap`, a per-node backup ConfigMap, as stored under its node's key in
+    /// a shard
+    pub(crate) fn shard_entry(configmap: &ConfigMap) -> Result<String> {
+        Ok(serde_json::to_string(
+            &configmap.data.clone().unwrap_or_default(),
+        )?)
+    }
+
+    /// Fail with [`Error::ShardFull`] if storing `entry` as `node_name`'s backup would take
+    /// shard `name` past [`MAX_SHARD_BYTES`]. Every node adds its own field manager to the
+    /// shard's managedFields, so the shard is read from the API server, managedFields and all.
+    async fn check_shard_size(&self, name: &str, node_name: &str, entry: &str) -> Result<()> {
+        let shard = self.api.get_opt(name).await.map_err(|e| {
+            Error::from_api(
+                Operation::ReadBackup {
+                    namespace: self.namespace.clone(),
+                },
+                e,
+            )
+        })?;
+        let mut shard = shard.unwrap_or_default();
+        shard
+            .data
+            .get_or_insert_with(BTreeMap::new)
+            .insert(shard_key(node_name), entry.to_string());
+        let size = serde_json::to_vec(&shard)?.len() + SHARD_MANAGER_BYTES;
+        if size > MAX_SHARD_BYTES {
+            return Err(Error::ShardFull {
+                shard: name.to_string(),
+                size,
+                limit: MAX_SHARD_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Delete `node_name`'s backup, unless it changed since it was read as `version`. Without a
+    /// version, it is deleted whatever it holds. In a shard, only the node's key is removed.
+    pub async fn delete(&self, node_name: &str, version: Option<String>) -> Result<Deletion> {
+        let name = self.configmap_name(node_name);
+        let result = match self.layout {
+            BackupLayout::PerNode => {
+                let params = DeleteParams {
+                    preconditions: Some(Preconditions {
+                        resource_version: version,
+                        uid: None,
+                    }),
+                    ..Default::default()
+                };
+                self.api.delete(&name, &params).await.map(|_| None)
+            }
+            BackupLayout::Sharded => {
+                // The test and the removal apply atomically, and leave other keys alone
+                let path = format!("/data/{}", shard_key(node_name));
+                let mut ops = Vec::new();
+                if let Some(entry) = version {
+                    ops.push(serde_json::json!({ "op": "test", "path": path, "value": entry }));
+                }
+                ops.push(serde_json::json!({ "op": "remove", "path": path }));
+                let patch = Patch::Json::<()>(serde_json::from_value(ops.into())?);
+                self.api
+                    .patch(&name, &PatchParams::default(), &patch)
+                    .await
+                    .map(|shard| shard.resource_version())
+            }
+        };
+        match result {
+            Ok(version) => {
+                self.wrote(&name, version);
+                Ok(Deletion::Deleted)
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => Ok(Deletion::Missing),
+            // A failed precondition, or a failed test or removal of a key that is gone
+            Err(kube::Error::Api(ErrorResponse {
+                code: 409 | 422, ..
+            })) => Ok(Deletion::Changed),
+            Err(e) => Err(Error::from_api(
+                Operation::DeleteBackup {
+                    namespace: self.namespace.clone(),
+                },
+                e,
+            )),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Lease-based leader election, so only one of several replicas runs the controller
+
+use crate::{Error, Operation, Result};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::Utc,
+};
+use kube::{
+    api::{Api, PostParams},
+    error::ErrorResponse,
+    Client,
+};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Where replicas hold the lease, and for how long a lease that isn't renewed stays held
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderElection {
+    pub lease_name: String,
+    pub lease_namespace: String,
+    pub lease_duration: Duration,
+}
+
+impl Default for LeaderElection {
+    fn default() -> Self {
+        Self {
+            lease_name: "node-label-preserver".to_string(),
+            lease_namespace: "default".to_string(),
+            lease_duration: Duration::from_secs(15),
+        }
+    }
+}
+
+impl LeaderElection {
+    /// How often the leader renews the lease and followers try to take it
+    pub fn retry_period(&self) -> Duration {
+        self.lease_duration / 5
+    }
+
+    /// How long the leader keeps leading without a successful renewal, short enough that it
+    /// stops before a follower can take the lease over
+    pub fn renew_deadline(&self) -> Duration {
+        self.lease_duration * 2 / 3
+    }
+}
+
+/// The holder and renew time of a lease, and when we first saw them. A lease is expired once
+/// it went unchanged for its duration by our own clock, so skew between the replicas' clocks
+/// doesn't matter.
+struct Observed {
+    holder: Option<String>,
+    renew_time: Option<MicroTime>,
+    at: Instant,
+}
+
+/// Acquires, renews, and releases the lease as `identity`
+pub struct LeaderElector {
+    api: Api<Lease>,
+    settings: LeaderElection,
+    identity: String,
+    observed: Mutex<Option<Observed>>,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client, settings: &LeaderElection, identity: &str) -> Self {
+        Self {
+            api: Api::namespaced(client, &settings.lease_namespace),
+            settings: settings.clone(),
+            identity: identity.to_string(),
+            observed: Mutex::new(None),
+        }
+    }
+
+    fn operation(&self) -> Operation {
+        Operation::ElectLeader {
+            namespace: self.settings.lease_namespace.clone(),
+        }
+    }
+
+    /// Take the lease if it is free or expired, or renew it if we hold it. Returns whether we
+    /// hold it afterwards. Losing a race with another replica isn't an error.
+    pub async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let name = &self.settings.lease_name;
+        let now = MicroTime(Utc::now());
+        let lease = match self.api.get_opt(name).await {
+            Ok(lease) => lease,
+            Err(e) => return Err(Error::from_api(self.operation(), e)),
+        };
+        let Some(mut lease) = lease else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    namespace: Some(self.settings.lease_namespace.clone()),
+                    ..Default::default()
+                },
+                spec: Some(self.spec(now.clone(), now, 0)),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+                Err(e) => Err(Error::from_api(self.operation(), e)),
+            };
+        };
+        let spec = lease.spec.clone().unwrap_or_default();
+        let holder = spec.holder_identity.clone().filter(|h| !h.is_empty());
+        let ours = holder.as_deref() == Some(self.identity.as_str());
+        if !ours && holder.is_some() && !self.observe_expired(&spec) {
+            return Ok(false);
+        }
+        let (acquire_time, transitions) = match ours {
+            true => (
+                spec.acquire_time.unwrap_or_else(|| now.clone()),
+                spec.lease_transitions.unwrap_or(0),
+            ),
+            false => (now.clone(), spec.lease_transitions.unwrap_or(0) + 1),
+        };
+        lease.spec = Some(self.spec(acquire_time, now, transitions));
+        // The resourceVersion read above makes a concurrent takeover fail with a conflict
+        match self.api.replace(name, &PostParams::default(), &lease).await {
+            Ok(_) => {
+                if let (false, Some(previous)) = (ours, holder) {
+                    info!("Took over expired lease '{}' from '{}'", name, previous);
+                }
+                Ok(true)
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+            Err(e) => Err(Error::from_api(self.operation(), e)),
+        }
+    }
+
+    fn spec(&self, acquire_time: MicroTime, renew_time: MicroTime, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.settings.lease_duration.as_secs() as i32),
+            acquire_time: Some(acquire_time),
+            renew_time: Some(renew_time),
+            lease_transitions: Some(transitions),
+            ..Default::default()
+        }
+    }
+
+    /// Record the lease's holder and renew time, and whether they went unchanged for the
+    /// lease's duration
+    fn observe_expired(&self, spec: &LeaseSpec) -> bool {
+        let mut observed = self.observed.lock().unwrap();
+        let unchanged = observed.as_ref().is_some_and(|observed| {
+            observed.holder == spec.holder_identity && observed.renew_time == spec.renew_time
+        });
+        if !unchanged {
+            *observed = Some(Observed {
+                holder: spec.holder_identity.clone(),
+                renew_time: spec.renew_time.clone(),
+                at: Instant::now(),
+            });
+            return false;
+        }
+        let duration = spec
+            .lease_duration_seconds
+            .map(|secs| Duration::from_secs(secs.max(0) as u64))
+            .unwrap_or(self.settings.lease_duration);
+        observed.as_ref().unwrap().at.elapsed() > duration
+    }
+
+    /// Wait until we hold the lease, calling `waiting` after every attempt that didn't get it
+    pub async fn acquire(&self, mut waiting: impl FnMut()) {
+        let mut announced = false;
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!(
+                        "Acquired lease '{}' as '{}', leading",
+                        self.settings.lease_name, self.identity
+                    );
+                    return;
+                }
+                Ok(false) if !announced => {
+                    info!(
+                        "Lease '{}' is held by another replica, waiting to take over",
+                        self.settings.lease_name
+                    );
+                    announced = true;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Failed to acquire lease '{}': {}",
+                    self.settings.lease_name, e
+                ),
+            }
+            waiting();
+            tokio::time::sleep(self.settings.retry_period()).await;
+        }
+    }
+
+    /// Keep renewing the lease we hold. Returns why leadership was lost: another replica took
+    /// the lease, or it couldn't be renewed within the renew deadline.
+    pub async fn hold(&self) -> String {
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(self.settings.retry_period()).await;
+            match self.try_acquire_or_renew().await {
+                Ok(true) => renewed = Instant::now(),
+                Ok(false) => {
+                    return format!(
+                        "lease '{}' was taken by another replica",
+                        self.settings.lease_name
+                    )
+                }
+                Err(e) if renewed.elapsed() >= self.settings.renew_deadline() => {
+                    return format!(
+                        "failed to renew lease '{}' for {}s: {}",
+                        self.settings.lease_name,
+                        renewed.elapsed().as_secs(),
+                        e
+                    )
+                }
+                Err(e) => warn!(
+                    "Failed to renew lease '{}', retrying: {}",
+                    self.settings.lease_name, e
+                ),
+            }
+        }
+    }
+
+    /// Give up the lease we hold, so another replica takes over without waiting for it to
+    /// expire
+    pub async fn release(&self) -> Result<()> {
+        let name = &self.settings.lease_name;
+        let Some(mut lease) = self
+            .api
+            .get_opt(name)
+            .await
+            .map_err(|e| Error::from_api(self.operation(), e))?
+        else {
+            return Ok(());
+        };
+        let Some(spec) = lease.spec.as_mut() else {
+            return Ok(());
+        };
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(MicroTime(Utc::now()));
+        self.api
+            .replace(name, &PostParams::default(), &lease)
+            .await
+            .map_err(|e| Error::from_api(self.operation(), e))?;
+        info!("Released lease '{}'", name);
+        Ok(())
+    }
+}
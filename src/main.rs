@@ -1,40 +1,839 @@
-use futures::stream::StreamExt;
-use k8s_openapi::api::core::v1::Node;
-use kube::{
-    api::Api,
-    runtime::{controller::Controller, watcher},
-    Client,
+use clap::{Parser, Subcommand};
+use label_preserver::{
+    bundle, manual,
+    metrics::PoolLimit,
+    restore_all::{restore_all, RestoreAllOptions},
+    run,
+    transfer::{self, ImportOptions},
+    BackupLayout, ClientFactory, ClientOptions, ControllerConfig, DeletionMarkers, EncryptionKey,
+    EncryptionKeys, IdentityMismatchPolicy, KnownPrefixes, LabelExpiry, LabelFilter,
+    LeaderElection, LogFormat, MergeStrategy, NamespaceMapping, NamingScheme, Redactor,
+    RestorePolicy, Surface, UnknownBackupAgePolicy, CONFIGMAP_NAMESPACE,
 };
-use label_preserver::{error_policy, reconcile, Context, CONFIGMAP_NAMESPACE};
-use std::sync::Arc;
-use tracing::{info, warn};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tracing::{error, info, warn};
 use tracing_subscriber::prelude::*;
 
+/// Preserve Node labels across Node deletion and re-creation
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Write logs as text or json, one object per line with the reconciled node and other
+    /// context as fields [default: text]
+    #[arg(long, env = "LOG_FORMAT", global = true)]
+    log_format: Option<LogFormat>,
+
+    /// Identifies this replica in the User-Agent and status ConfigMap. Defaults to the pod name.
+    #[arg(long, env = "LABEL_PRESERVER_INSTANCE_ID")]
+    instance_id: Option<String>,
+
+    /// Namespace to store the backup and status ConfigMaps in. It must already exist.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_NAMESPACE",
+        default_value = CONFIGMAP_NAMESPACE,
+        global = true
+    )]
+    namespace: String,
+
+    /// Node label whose value picks the namespace each node's backup is kept in from
+    /// --namespace-map, e.g. node-pool
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_NAMESPACE_LABEL",
+        requires = "namespace_map"
+    )]
+    namespace_label: Option<String>,
+
+    /// Backup namespaces by namespace label value, e.g. gpu=team-ml,general=platform-infra.
+    /// Backups of other nodes are kept in --namespace.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_NAMESPACE_MAP",
+        value_delimiter = ',',
+        requires = "namespace_label"
+    )]
+    namespace_map: Vec<NamespaceMapping>,
+
+    /// Only manage these nodes, e.g. worker-1,worker-2. All nodes are managed when unset.
+    #[arg(long, env = "LABEL_PRESERVER_NODE_NAMES", value_delimiter = ',')]
+    node_names: Option<Vec<String>>,
+
+    /// Only watch nodes matching this label selector, e.g. pool=gpu
+    #[arg(long, env = "LABEL_PRESERVER_LABEL_SELECTOR")]
+    label_selector: Option<String>,
+
+    /// Only watch nodes matching this field selector, e.g. spec.unschedulable=false
+    #[arg(long, env = "LABEL_PRESERVER_FIELD_SELECTOR")]
+    field_selector: Option<String>,
+
+    /// Stop restoring labels with a key prefix some number of days after they were preserved,
+    /// e.g. maintenance.example.com/=7
+    #[arg(long, env = "LABEL_PRESERVER_LABEL_EXPIRY", value_delimiter = ',')]
+    label_expiry: Vec<LabelExpiry>,
+
+    /// Summarize restores instead of logging each one when more than this many happen within
+    /// the burst window [default: 20]
+    #[arg(long, env = "LABEL_PRESERVER_BURST_THRESHOLD")]
+    burst_threshold: Option<usize>,
+
+    /// The sliding window for burst detection in minutes [default: 5]
+    #[arg(long, env = "LABEL_PRESERVER_BURST_WINDOW_MINUTES")]
+    burst_window_minutes: Option<u64>,
+
+    /// Degrade backups to fit within this many bytes [default: 921600]
+    #[arg(long, env = "LABEL_PRESERVER_MAX_BACKUP_BYTES")]
+    max_backup_bytes: Option<usize>,
+
+    /// The most labels kept when a backup has to be degraded to fit [default: 1000]
+    #[arg(long, env = "LABEL_PRESERVER_MAX_LABELS")]
+    max_labels: Option<usize>,
+
+    /// Split backed up JSON values longer than this many bytes into chunks [default: 65536]
+    #[arg(long, env = "LABEL_PRESERVER_BACKUP_CHUNK_BYTES")]
+    backup_chunk_bytes: Option<usize>,
+
+    /// Don't set the nodelabelpreserver.example.com/managed label on managed nodes
+    #[arg(long, env = "LABEL_PRESERVER_NO_MANAGED_LABEL")]
+    no_managed_label: bool,
+
+    /// What to do when a recreated node's providerID or machine ID differs from its backup:
+    /// ignore, warn, skip, or machine-independent [default: ignore]
+    #[arg(long, env = "LABEL_PRESERVER_IDENTITY_MISMATCH")]
+    identity_mismatch: Option<IdentityMismatchPolicy>,
+
+    /// Label key prefixes still restored onto a different machine with the machine-independent
+    /// policy, e.g. team.example.com/
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_MACHINE_INDEPENDENT_PREFIXES",
+        value_delimiter = ','
+    )]
+    machine_independent_prefixes: Vec<String>,
+
+    /// Label key prefixes with a known owner, e.g. example.com. Restores and verify-bundle flag
+    /// backed up keys under any other prefix, and subdomains of these count as known. Prefixes
+    /// reserved for Kubernetes are always known. Unset disables the check.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_KNOWN_PREFIXES",
+        value_delimiter = ',',
+        global = true
+    )]
+    known_prefixes: Vec<String>,
+
+    /// Don't restore keys flagged by --known-prefixes. They stay in the backup for review.
+    #[arg(long, env = "LABEL_PRESERVER_EXCLUDE_UNKNOWN_PREFIXES")]
+    exclude_unknown_prefixes: bool,
+
+    /// Only preserve labels whose key starts with one of these prefixes, e.g. example.com/. All
+    /// labels are preserved when unset.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_PRESERVE_LABEL_PREFIXES",
+        value_delimiter = ','
+    )]
+    preserve_label_prefixes: Vec<String>,
+
+    /// Never preserve labels whose key starts with one of these prefixes, e.g.
+    /// topology.kubernetes.io/. Wins over --preserve-label-prefixes.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_IGNORE_LABEL_PREFIXES",
+        value_delimiter = ','
+    )]
+    ignore_label_prefixes: Vec<String>,
+
+    /// Restore labels under domains reserved for Kubernetes, e.g. kubernetes.io/hostname or
+    /// topology.kubernetes.io/zone. They are backed up either way.
+    #[arg(long, env = "LABEL_PRESERVER_ALLOW_RESERVED_LABEL_RESTORE")]
+    allow_reserved_label_restore: bool,
+
+    /// Restore nothing from backups written more than this many days ago, e.g. by an earlier
+    /// node that happened to have the same name
+    #[arg(long, env = "LABEL_PRESERVER_MAX_BACKUP_AGE_DAYS")]
+    max_backup_age_days: Option<u64>,
+
+    /// Whether backups written before their backup time was recorded are restored with
+    /// --max-backup-age-days: restore or skip [default: restore]
+    #[arg(long, env = "LABEL_PRESERVER_UNKNOWN_BACKUP_AGE")]
+    unknown_backup_age: Option<UnknownBackupAgePolicy>,
+
+    /// Delete backups refused by --max-backup-age-days instead of keeping them for review
+    #[arg(long, env = "LABEL_PRESERVER_DELETE_STALE_BACKUPS")]
+    delete_stale_backups: bool,
+
+    /// Candidate policy: --label-expiry to evaluate alongside the active one. Setting any
+    /// candidate option enables shadow evaluation, and unset candidate options match the active
+    /// policy. The candidate never changes what is restored.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_CANDIDATE_LABEL_EXPIRY",
+        value_delimiter = ','
+    )]
+    candidate_label_expiry: Option<Vec<LabelExpiry>>,
+
+    /// Candidate policy: --identity-mismatch to evaluate alongside the active one
+    #[arg(long, env = "LABEL_PRESERVER_CANDIDATE_IDENTITY_MISMATCH")]
+    candidate_identity_mismatch: Option<IdentityMismatchPolicy>,
+
+    /// Candidate policy: --machine-independent-prefixes to evaluate alongside the active one
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_CANDIDATE_MACHINE_INDEPENDENT_PREFIXES",
+        value_delimiter = ','
+    )]
+    candidate_machine_independent_prefixes: Option<Vec<String>>,
+
+    /// Restores per second allowed while warming up after startup, so the initial list of every
+    /// node doesn't flood the API server. Cleanups are not paced. 0 disables pacing [default: 10]
+    #[arg(long, env = "LABEL_PRESERVER_STARTUP_RATE")]
+    startup_rate: Option<f64>,
+
+    /// How long after startup restores are paced, in seconds [default: 300]
+    #[arg(long, env = "LABEL_PRESERVER_STARTUP_WARMUP_SECONDS")]
+    startup_warmup_seconds: Option<u64>,
+
+    /// Node label whose value is attached to metrics as the pool, e.g. pool.example.com/name
+    #[arg(long, env = "LABEL_PRESERVER_POOL_LABEL")]
+    pool_label: Option<String>,
+
+    /// Pool label values to keep distinct in metrics. Others are counted as "other".
+    #[arg(long, env = "LABEL_PRESERVER_POOL_VALUES", value_delimiter = ',')]
+    pool_values: Option<Vec<String>>,
+
+    /// Without --pool-values, keep the first this many distinct pools and count the rest as
+    /// "other" [default: 20]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_MAX_POOLS",
+        conflicts_with = "pool_values"
+    )]
+    max_pools: Option<usize>,
+
+    /// Taint keys that mark a node for removal and trigger an early backup. Pass an empty value
+    /// to disable. [default: ToBeDeletedByClusterAutoscaler,DeletionCandidateOfClusterAutoscaler,
+    /// karpenter.sh/disrupted]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_DELETION_MARKER_TAINTS",
+        value_delimiter = ','
+    )]
+    deletion_marker_taints: Option<Vec<String>>,
+
+    /// Annotation keys that mark a node for removal and trigger an early backup
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_DELETION_MARKER_ANNOTATIONS",
+        value_delimiter = ','
+    )]
+    deletion_marker_annotations: Vec<String>,
+
+    /// Release finalizers after a best-effort backup while more than this many nodes have been
+    /// blocked in Terminating by our finalizer for --breaker-blocked-minutes [default: 50]
+    #[arg(long, env = "LABEL_PRESERVER_BREAKER_THRESHOLD")]
+    breaker_threshold: Option<usize>,
+
+    /// How long a node must be blocked in Terminating to count towards --breaker-threshold
+    /// [default: 10]
+    #[arg(long, env = "LABEL_PRESERVER_BREAKER_BLOCKED_MINUTES")]
+    breaker_blocked_minutes: Option<u64>,
+
+    /// Fail /healthz once no node has been reconciled for this many minutes [default: 15]
+    #[arg(long, env = "LABEL_PRESERVER_LIVENESS_WINDOW_MINUTES")]
+    liveness_window_minutes: Option<u64>,
+
+    /// Delete a node's backup ConfigMap once its labels have been restored, instead of keeping
+    /// it until the node is deleted again
+    #[arg(long, env = "LABEL_PRESERVER_CLEANUP_AFTER_RESTORE")]
+    cleanup_after_restore: bool,
+
+    /// Which value wins when a backed up label is already on the node with a different value:
+    /// prefer-current keeps the node's, prefer-preserved overwrites it [default: prefer-current]
+    #[arg(long, env = "LABEL_PRESERVER_MERGE_STRATEGY")]
+    merge_strategy: Option<MergeStrategy>,
+
+    /// Write restored label values that a mutating webhook rewrote back into the backup once
+    /// they are stable
+    #[arg(long, env = "LABEL_PRESERVER_RECORD_REWRITES")]
+    record_rewrites: bool,
+
+    /// Only log what would be restored onto nodes and backed up from them, without adding our
+    /// finalizer or writing nodes and ConfigMaps
+    #[arg(long, env = "LABEL_PRESERVER_DRY_RUN")]
+    dry_run: bool,
+
+    /// Check every this many seconds that the backup of each restored node holds its current
+    /// labels, and rewrite it if not, so labels survive a node whose deletion isn't backed up
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_RESYNC_INTERVAL_SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    resync_interval_seconds: Option<u64>,
+
+    /// Prefixes of legacy backup ConfigMaps named `<prefix><node name>`, e.g. labels-, to look
+    /// for when a node has no backup under the current name. `node-labels-` is always checked.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_LEGACY_CONFIGMAP_PREFIXES",
+        value_delimiter = ','
+    )]
+    legacy_configmap_prefixes: Vec<String>,
+
+    /// How backups are stored: per-node gives each node its own ConfigMap, sharded keeps them
+    /// as keys of at most 256 shared ConfigMaps [default: per-node]
+    #[arg(long, env = "LABEL_PRESERVER_BACKUP_LAYOUT")]
+    backup_layout: Option<BackupLayout>,
+
+    /// Files holding 32-byte keys, raw or hex, to encrypt preserved labels with, e.g. mounted
+    /// Secret keys. The first encrypts and all decrypt, so a new key can be put first to rotate.
+    /// Labels are stored in plain text when unset.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_ENCRYPTION_KEY_FILES",
+        value_delimiter = ',',
+        value_parser = |path: &str| EncryptionKey::load(path),
+        global = true
+    )]
+    encryption_key_files: Vec<EncryptionKey>,
+
+    /// Don't add a finalizer to nodes. Instead, back each node up whenever its labels change, so
+    /// a stopped controller never blocks node deletion.
+    #[arg(long, env = "LABEL_PRESERVER_SNAPSHOT_MODE")]
+    snapshot_mode: bool,
+
+    /// Read every backup from the API server instead of the cache kept by the backup watch
+    #[arg(long, env = "LABEL_PRESERVER_NO_BACKUP_CACHE")]
+    no_backup_cache: bool,
+
+    /// How many nodes are reconciled at once. 0 is unbounded. [default: 0]
+    #[arg(long, env = "LABEL_PRESERVER_CONCURRENCY")]
+    concurrency: Option<u16>,
+
+    /// How many backup ConfigMap writes and deletes may be in flight at once. Unbounded when
+    /// unset.
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_MAX_CONCURRENT_WRITES",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_concurrent_writes: Option<u64>,
+
+    /// Seconds between sweeps listing every node for ones in scope that lack our finalizer,
+    /// which are reported with a Warning Event and reconciled to add it back [default: 600]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_FINALIZER_SWEEP_INTERVAL_SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    finalizer_sweep_interval_seconds: Option<u64>,
+
+    /// Don't sweep for nodes missing our finalizer
+    #[arg(long, env = "LABEL_PRESERVER_NO_FINALIZER_SWEEP")]
+    no_finalizer_sweep: bool,
+
+    /// Nodes a finalizer sweep reports per second. 0 doesn't limit the rate [default: 5]
+    #[arg(long, env = "LABEL_PRESERVER_FINALIZER_SWEEP_RATE")]
+    finalizer_sweep_rate: Option<f64>,
+
+    /// How many objects the node and backup watches list per request [default: 500]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_WATCH_PAGE_SIZE",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    watch_page_size: Option<u32>,
+
+    /// Seconds each list or watch call of the node and backup watches may take, under 295
+    /// [default: 290]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_WATCH_TIMEOUT_SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..295)
+    )]
+    watch_timeout_seconds: Option<u64>,
+
+    /// Elect one replica with a Lease to run the controller, while the others wait to take over
+    #[arg(long, env = "LABEL_PRESERVER_LEADER_ELECTION")]
+    leader_election: bool,
+
+    /// Name of the leader election Lease [default: node-label-preserver]
+    #[arg(long, env = "LABEL_PRESERVER_LEASE_NAME")]
+    lease_name: Option<String>,
+
+    /// Namespace of the leader election Lease [default: the backup namespace]
+    #[arg(long, env = "LABEL_PRESERVER_LEASE_NAMESPACE")]
+    lease_namespace: Option<String>,
+
+    /// Seconds a leader that stopped renewing the Lease keeps it before another replica takes
+    /// over [default: 15]
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_LEASE_DURATION_SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    lease_duration_seconds: Option<u64>,
+
+    /// Restore missing backed up labels onto a restored node again when its kubelet
+    /// re-registers after a reboot, detected by a new boot ID
+    #[arg(long, env = "LABEL_PRESERVER_REPAIR_ON_REREGISTRATION")]
+    repair_on_reregistration: bool,
+
+    /// Preserve node annotations alongside labels. Our own, kubectl's, and deletion marker
+    /// annotations are left out, and restores never overwrite annotations the new node has.
+    #[arg(long, env = "LABEL_PRESERVER_PRESERVE_ANNOTATIONS")]
+    preserve_annotations: bool,
+
+    /// Preserve node taints alongside labels. A taint is restored when the new node has none with
+    /// the same key and effect. Taints Kubernetes sets for the node's condition, like
+    /// node.kubernetes.io/not-ready, and deletion marker taints are left out.
+    #[arg(long, env = "LABEL_PRESERVER_PRESERVE_TAINTS")]
+    preserve_taints: bool,
+
+    /// Hide the values of label keys starting with these prefixes, or whose name after the '/'
+    /// does, in logs and other output. Pass an empty value to disable.
+    /// [default: customer,tenant,account,owner]
+    #[arg(long, env = "LABEL_PRESERVER_REDACT_PREFIXES", value_delimiter = ',')]
+    redact_prefixes: Option<Vec<String>>,
+
+    /// Hide the values of every label key
+    #[arg(long, env = "LABEL_PRESERVER_REDACT_ALL_VALUES")]
+    redact_all_values: bool,
+
+    /// Outputs that show label values anyway: logs, reports, or admin
+    #[arg(long, env = "LABEL_PRESERVER_SHOW_VALUES", value_delimiter = ',')]
+    show_values: Vec<Surface>,
+
+    /// Address to serve the admin API on
+    #[arg(
+        long,
+        env = "LABEL_PRESERVER_ADMIN_ADDR",
+        default_value = "0.0.0.0:8080"
+    )]
+    admin_addr: SocketAddr,
+
+    /// Restore every backup onto its node, print a JSON report, and exit instead of running the
+    /// controller. Exits 0 if all were restored, 6 if some were skipped, 7 if some failed.
+    #[arg(long)]
+    restore_all: bool,
+
+    /// With --restore-all, stop after the first node that fails to restore
+    #[arg(long, requires = "restore_all")]
+    stop_on_error: bool,
+
+    /// With --restore-all, how many nodes to restore at once
+    #[arg(long, default_value_t = 8, requires = "restore_all")]
+    restore_concurrency: usize,
+
+    #[command(flatten)]
+    cluster: ClusterArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Cluster targeting shared by every command, like kubectl's. Given after a subcommand, they
+/// apply to that subcommand.
+#[derive(clap::Args, Debug)]
+struct ClusterArgs {
+    /// Kubeconfig file to use instead of KUBECONFIG or ~/.kube/config
+    #[arg(long, global = true)]
+    kubeconfig: Option<PathBuf>,
+
+    /// Kubeconfig context to use instead of the current context
+    #[arg(long, global = true)]
+    context: Option<String>,
+
+    /// Kubeconfig cluster to use instead of the context's cluster
+    #[arg(long, global = true)]
+    cluster: Option<String>,
+
+    /// User to impersonate, e.g. system:serviceaccount:kube-system:label-preserver to check the
+    /// controller's RBAC
+    #[arg(long = "as", global = true)]
+    as_user: Option<String>,
+
+    /// Group to impersonate. Can be repeated.
+    #[arg(long = "as-group", global = true)]
+    as_groups: Vec<String>,
+}
+
+impl ClusterArgs {
+    fn client_options(&self) -> ClientOptions {
+        ClientOptions {
+            kubeconfig: self.kubeconfig.clone(),
+            context: self.context.clone(),
+            cluster: self.cluster.clone(),
+            impersonate: self.as_user.clone(),
+            impersonate_groups: self.as_groups.clone(),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the controller. The default without a subcommand.
+    Run,
+    /// Print the state preserved for a node, which may have been deleted. Exits 4 if it has no
+    /// backup.
+    Show {
+        /// The node's name
+        node: String,
+    },
+    /// Restore a node's backup onto it now, even if it was restored before. Exits 3 if the node
+    /// doesn't exist and 4 if it has no backup.
+    Restore {
+        /// The node's name
+        node: String,
+        /// Replace the node's current values with the backed up ones, as with
+        /// --merge-strategy prefer-preserved
+        #[arg(long)]
+        force: bool,
+    },
+    /// Delete a node's backup, so the node comes back without its preserved labels. Exits 4 if
+    /// it has no backup.
+    Purge {
+        /// The node's name
+        node: String,
+    },
+    /// Check a backup bundle file without a cluster: its version, checksums, schema versions,
+    /// label syntax, and duplicate entries. Exits 1 if any problem is found.
+    VerifyBundle {
+        /// The bundle JSON file
+        file: PathBuf,
+    },
+    /// Print every node's preserved labels as one JSON object of node name to labels, for
+    /// carrying them to another cluster with import
+    Export {
+        /// Write the snapshot to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Back up the labels in a snapshot written by export, as if each node had just been
+    /// deleted, so they are restored when the nodes join
+    Import {
+        /// The snapshot JSON file
+        file: PathBuf,
+        /// Replace backups nodes already have instead of skipping those nodes
+        #[arg(long)]
+        overwrite: bool,
+        /// Only report what would be imported, without writing ConfigMaps
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Args {
+    fn redactor(&self) -> Redactor {
+        let defaults = Redactor::default();
+        Redactor {
+            prefixes: self
+                .redact_prefixes
+                .as_ref()
+                .map(|prefixes| prefixes.iter().filter(|p| !p.is_empty()).cloned().collect())
+                .unwrap_or(defaults.prefixes),
+            all_keys: self.redact_all_values,
+            shown: self.show_values.iter().copied().collect(),
+        }
+    }
+
+    fn known_prefixes(&self) -> KnownPrefixes {
+        KnownPrefixes(
+            self.known_prefixes
+                .iter()
+                .filter(|p| !p.is_empty())
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn label_filter(&self) -> LabelFilter {
+        let non_empty =
+            |prefixes: &[String]| prefixes.iter().filter(|p| !p.is_empty()).cloned().collect();
+        LabelFilter {
+            preserve_prefixes: non_empty(&self.preserve_label_prefixes),
+            ignore_prefixes: non_empty(&self.ignore_label_prefixes),
+        }
+    }
+
+    fn controller_config(&self) -> ControllerConfig {
+        let defaults = ControllerConfig::default();
+        let policy = RestorePolicy {
+            label_expiry: self.label_expiry.clone(),
+            identity_mismatch: self
+                .identity_mismatch
+                .unwrap_or(defaults.policy.identity_mismatch),
+            machine_independent_prefixes: self.machine_independent_prefixes.clone(),
+            known_prefixes: self.known_prefixes(),
+            exclude_unknown_prefixes: self.exclude_unknown_prefixes,
+            label_filter: self.label_filter(),
+            allow_reserved_label_restore: self.allow_reserved_label_restore,
+            max_backup_age: self
+                .max_backup_age_days
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            unknown_backup_age: self.unknown_backup_age.unwrap_or_default(),
+        };
+        let candidate_policy = (self.candidate_label_expiry.is_some()
+            || self.candidate_identity_mismatch.is_some()
+            || self.candidate_machine_independent_prefixes.is_some())
+        .then(|| RestorePolicy {
+            label_expiry: self
+                .candidate_label_expiry
+                .clone()
+                .unwrap_or(policy.label_expiry.clone()),
+            identity_mismatch: self
+                .candidate_identity_mismatch
+                .unwrap_or(policy.identity_mismatch),
+            machine_independent_prefixes: self
+                .candidate_machine_independent_prefixes
+                .clone()
+                .unwrap_or(policy.machine_independent_prefixes.clone()),
+            known_prefixes: policy.known_prefixes.clone(),
+            exclude_unknown_prefixes: policy.exclude_unknown_prefixes,
+            label_filter: policy.label_filter.clone(),
+            allow_reserved_label_restore: policy.allow_reserved_label_restore,
+            max_backup_age: policy.max_backup_age,
+            unknown_backup_age: policy.unknown_backup_age,
+        });
+        ControllerConfig {
+            // Inside a pod HOSTNAME is the pod name
+            instance_id: self
+                .instance_id
+                .clone()
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .unwrap_or(defaults.instance_id.clone()),
+            namespace: self.namespace.clone(),
+            namespace_label: self.namespace_label.clone(),
+            namespace_map: self
+                .namespace_map
+                .iter()
+                .map(|mapping| (mapping.value.clone(), mapping.namespace.clone()))
+                .collect(),
+            finalizer: defaults.finalizer.clone(),
+            node_names: self
+                .node_names
+                .as_ref()
+                .map(|names| names.iter().cloned().collect()),
+            label_selector: self.label_selector.clone(),
+            field_selector: self.field_selector.clone(),
+            policy,
+            candidate_policy,
+            burst_threshold: self.burst_threshold.unwrap_or(defaults.burst_threshold),
+            burst_window: self
+                .burst_window_minutes
+                .map(|minutes| Duration::from_secs(minutes * 60))
+                .unwrap_or(defaults.burst_window),
+            max_backup_bytes: self.max_backup_bytes.unwrap_or(defaults.max_backup_bytes),
+            max_labels: self.max_labels.unwrap_or(defaults.max_labels),
+            backup_chunk_bytes: self
+                .backup_chunk_bytes
+                .unwrap_or(defaults.backup_chunk_bytes),
+            managed_label: !self.no_managed_label,
+            startup_rate: self.startup_rate.unwrap_or(defaults.startup_rate),
+            pool_label: self.pool_label.clone(),
+            pool_limit: match (&self.pool_values, self.max_pools) {
+                (Some(values), _) => PoolLimit::Allowlist(values.iter().cloned().collect()),
+                (None, Some(max)) => PoolLimit::FirstSeen(max),
+                (None, None) => defaults.pool_limit.clone(),
+            },
+            deletion_markers: DeletionMarkers {
+                taints: self
+                    .deletion_marker_taints
+                    .as_ref()
+                    .map(|taints| taints.iter().filter(|t| !t.is_empty()).cloned().collect())
+                    .unwrap_or(defaults.deletion_markers.taints.clone()),
+                annotations: self.deletion_marker_annotations.clone(),
+            },
+            breaker_threshold: self.breaker_threshold.unwrap_or(defaults.breaker_threshold),
+            breaker_blocked_after: self
+                .breaker_blocked_minutes
+                .map(|minutes| Duration::from_secs(minutes * 60))
+                .unwrap_or(defaults.breaker_blocked_after),
+            liveness_window: self
+                .liveness_window_minutes
+                .map(|minutes| Duration::from_secs(minutes * 60))
+                .unwrap_or(defaults.liveness_window),
+            cleanup_after_restore: self.cleanup_after_restore,
+            merge_strategy: self.merge_strategy.unwrap_or_default(),
+            record_rewrites: self.record_rewrites,
+            repair_on_reregistration: self.repair_on_reregistration,
+            preserve_annotations: self.preserve_annotations,
+            preserve_taints: self.preserve_taints,
+            redactor: self.redactor(),
+            startup_warmup: self
+                .startup_warmup_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.startup_warmup),
+            dry_run: self.dry_run,
+            resync_interval: self.resync_interval_seconds.map(Duration::from_secs),
+            legacy_naming_schemes: defaults
+                .legacy_naming_schemes
+                .iter()
+                .cloned()
+                .chain(
+                    self.legacy_configmap_prefixes
+                        .iter()
+                        .filter(|prefix| !prefix.is_empty())
+                        .map(|prefix| NamingScheme::Plain {
+                            prefix: prefix.clone(),
+                        }),
+                )
+                .collect(),
+            backup_layout: self.backup_layout.unwrap_or_default(),
+            encryption_keys: EncryptionKeys(self.encryption_key_files.clone()),
+            snapshot_mode: self.snapshot_mode,
+            backup_cache: !self.no_backup_cache,
+            delete_stale_backups: self.delete_stale_backups,
+            concurrency: self.concurrency.unwrap_or(defaults.concurrency),
+            max_concurrent_writes: self
+                .max_concurrent_writes
+                .map(|writes| writes as usize)
+                .or(defaults.max_concurrent_writes),
+            watch_page_size: self.watch_page_size,
+            watch_timeout: self.watch_timeout_seconds.map(Duration::from_secs),
+            finalizer_sweep_interval: match self.finalizer_sweep_interval_seconds {
+                _ if self.no_finalizer_sweep => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => defaults.finalizer_sweep_interval,
+            },
+            finalizer_sweep_rate: self
+                .finalizer_sweep_rate
+                .unwrap_or(defaults.finalizer_sweep_rate),
+            leader_election: self.leader_election.then(|| {
+                let election = LeaderElection::default();
+                LeaderElection {
+                    lease_name: self.lease_name.clone().unwrap_or(election.lease_name),
+                    lease_namespace: self
+                        .lease_namespace
+                        .clone()
+                        .unwrap_or(self.namespace.clone()),
+                    lease_duration: self
+                        .lease_duration_seconds
+                        .map(Duration::from_secs)
+                        .unwrap_or(election.lease_duration),
+                }
+            }),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     let filter = tracing_subscriber::filter::Targets::new()
         .with_target("label_preserver", tracing::Level::DEBUG);
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        // Logs go to stderr so stdout only carries the --restore-all report
+        .with(label_preserver::log_layer(
+            args.log_format.unwrap_or_default(),
+            std::io::stderr,
+        ))
         .with(filter)
         .init();
 
-    let client = Client::try_default().await?;
-    let node_api: Api<Node> = Api::all(client.clone());
-    let context = Arc::new(Context::new(client.clone()));
-    info!(
-        "Starting Node Label Preserver controller, storing in namespace {}...",
-        CONFIGMAP_NAMESPACE
-    );
-
-    Controller::new(node_api, watcher::Config::default())
-        .run(reconcile, error_policy, context)
-        .for_each(|res| async move {
-            match res {
-                Ok((obj, _action)) => info!("Reconciled Node '{}'", obj.name),
-                Err(e) => warn!("Reconciliation error: {:?}", e),
+    if let Some(Command::VerifyBundle { file }) = &args.command {
+        let verification = bundle::verify_json_against(
+            &std::fs::read_to_string(file)?,
+            &args.redactor(),
+            &args.known_prefixes(),
+        );
+        print!("{}", verification.summary());
+        std::process::exit(if verification.is_valid() { 0 } else { 1 });
+    }
+
+    let config = args.controller_config();
+    if let Some(
+        command @ (Command::Show { .. }
+        | Command::Restore { .. }
+        | Command::Purge { .. }
+        | Command::Export { .. }
+        | Command::Import { .. }),
+    ) = &args.command
+    {
+        let clients = ClientFactory::new(args.cluster.client_options(), &config.instance_id);
+        if let Err(e) = run_manual(command, &clients, &config).await {
+            error!(error.kind = e.kind(), "{}", e);
+            std::process::exit(manual::exit_code(&e));
+        }
+        std::process::exit(manual::EXIT_OK);
+    }
+    if config.dry_run {
+        warn!("DRY RUN: no finalizers are added and no nodes or ConfigMaps are written, restores and backups are only logged");
+    }
+    let clients = ClientFactory::new(args.cluster.client_options(), &config.instance_id);
+    if args.restore_all {
+        let client = clients.client().await?;
+        let options = RestoreAllOptions {
+            concurrency: args.restore_concurrency,
+            stop_on_error: args.stop_on_error,
+        };
+        let report = restore_all(client, &config, options).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        std::process::exit(report.exit_code());
+    }
+    let shutdown = run::run(&clients, config, Some(args.admin_addr), shutdown_signal()).await;
+    std::process::exit(shutdown.reason.exit_code());
+}
+
+/// Run a command on one node's backup, or on all of them for export and import, printing its
+/// result to stdout
+async fn run_manual(
+    command: &Command,
+    clients: &ClientFactory,
+    config: &ControllerConfig,
+) -> label_preserver::Result<()> {
+    let client = clients.client().await?;
+    match command {
+        Command::Show { node } => {
+            let state = manual::show(client, config, node).await?;
+            println!("{}", serde_json::to_string_pretty(&state)?);
+        }
+        Command::Restore { node, force } => {
+            let counts = manual::restore(client, config, node, *force).await?;
+            println!("{}", serde_json::to_string_pretty(&counts)?);
+        }
+        Command::Purge { node } => manual::purge(client, config, node).await?,
+        Command::Export { output } => {
+            let snapshot = transfer::export_all_backups_with(client, config).await?;
+            let json = serde_json::to_string_pretty(&snapshot)?;
+            match output {
+                Some(path) => std::fs::write(path, json + "\n")?,
+                None => println!("{}", json),
             }
-        })
-        .await;
+        }
+        Command::Import {
+            file,
+            overwrite,
+            dry_run,
+        } => {
+            let snapshot = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+            let options = ImportOptions {
+                overwrite: *overwrite,
+                dry_run: *dry_run || config.dry_run,
+            };
+            let report = transfer::import_backups_with(client, config, &snapshot, options).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Command::Run | Command::VerifyBundle { .. } => {}
+    }
     Ok(())
 }
+
+/// Completes on SIGINT or SIGTERM
+fn shutdown_signal() -> impl std::future::Future<Output = ()> + Send + Sync + 'static {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("installing a SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        }
+        let _ = tx.send(());
+    });
+    async move {
+        let _ = rx.await;
+    }
+}
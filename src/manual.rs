@@ -0,0 +1,166 @@
+//! Inspecting and restoring one node's backup on demand, outside the reconcile loop
+
+use crate::{
+    find_configmap_for_node, reconcile::restore_backup, types::PreservedState, Backup,
+    BackupLayout, BackupStore, ControllerConfig, Deletion, Error, MergeStrategy, Operation,
+    RestoreCounts, Result, Surface,
+};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+};
+use kube::{
+    api::{Api, ResourceExt},
+    Client,
+};
+use tracing::info;
+
+/// The command succeeded
+pub const EXIT_OK: i32 = 0;
+/// The command failed, e.g. the API server couldn't be reached or the backup couldn't be read
+pub const EXIT_FAILED: i32 = 1;
+/// The node doesn't exist
+pub const EXIT_NO_NODE: i32 = 3;
+/// The node has no backup
+pub const EXIT_NO_BACKUP: i32 = 4;
+
+/// The process exit code for a command that failed with `error`
+pub fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::NodeNotFound(_) => EXIT_NO_NODE,
+        Error::BackupNotFound(_) => EXIT_NO_BACKUP,
+        _ => EXIT_FAILED,
+    }
+}
+
+/// The state preserved for `node_name`, which need not exist, with values redacted for
+/// [`Surface::Reports`]
+pub async fn show(
+    client: Client,
+    config: &ControllerConfig,
+    node_name: &str,
+) -> Result<PreservedState> {
+    let backup = read(client, config, node_name).await?;
+    let mut state = PreservedState::from_backup(Some(node_name.to_string()), &backup);
+    state.labels = config.redactor.labels(Surface::Reports, &state.labels);
+    Ok(state)
+}
+
+/// Restore `node_name`'s backup onto it the way a created node is restored, whether or not it
+/// was restored before. With `force`, backed up values replace the node's current ones as with
+/// [`MergeStrategy::PreferPreserved`].
+pub async fn restore(
+    client: Client,
+    config: &ControllerConfig,
+    node_name: &str,
+    force: bool,
+) -> Result<RestoreCounts> {
+    let node_api: Api<Node> = Api::all(client.clone());
+    let node = get_node(&node_api, node_name)
+        .await?
+        .ok_or_else(|| Error::NodeNotFound(node_name.to_string()))?;
+    let backup = read_from(client, config, &node).await?;
+    let mut config = config.clone();
+    if force {
+        config.merge_strategy = MergeStrategy::PreferPreserved;
+    }
+    restore_backup(&node_api, &node, backup, &config).await
+}
+
+/// Delete `node_name`'s backup from every namespace it is looked for in, so the node comes back
+/// without its preserved labels
+pub async fn purge(client: Client, config: &ControllerConfig, node_name: &str) -> Result<()> {
+    let node = lookup(client.clone(), node_name).await?;
+    let mut deleted = false;
+    for namespace in config.backup_namespaces(&node) {
+        let store = BackupStore::new(client.clone(), namespace, config.backup_layout);
+        // Without a version, a sharded backup only fails to delete if it is gone
+        if store.delete(node_name, None).await? == Deletion::Deleted {
+            info!(
+                "Deleted the backup in ConfigMap '{}' in namespace '{}' of node '{}'",
+                store.configmap_name(node_name),
+                namespace,
+                node_name
+            );
+            deleted = true;
+        }
+    }
+    if !deleted {
+        return Err(Error::BackupNotFound(node_name.to_string()));
+    }
+    Ok(())
+}
+
+async fn get_node(node_api: &Api<Node>, node_name: &str) -> Result<Option<Node>> {
+    node_api
+        .get_opt(node_name)
+        .await
+        .map_err(|e| Error::from_api(Operation::PatchNode, e))
+}
+
+/// `node_name`, or if it doesn't exist, a node of that name without labels, whose backup is
+/// looked for in `namespace` and then every mapped namespace
+async fn lookup(client: Client, node_name: &str) -> Result<Node> {
+    let node = get_node(&Api::all(client), node_name).await?;
+    Ok(node.unwrap_or_else(|| Node {
+        metadata: ObjectMeta {
+            name: Some(node_name.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }))
+}
+
+async fn read(client: Client, config: &ControllerConfig, node_name: &str) -> Result<Backup> {
+    let node = lookup(client.clone(), node_name).await?;
+    read_from(client, config, &node).await
+}
+
+/// The backup of `node` in the first namespace it is looked for in that has one, see
+/// [`find_backup`]
+async fn read_from(client: Client, config: &ControllerConfig, node: &Node) -> Result<Backup> {
+    match find_backup(client, config, node).await? {
+        Some((_, backup)) => Ok(backup),
+        None => Err(Error::BackupNotFound(node.name_any())),
+    }
+}
+
+/// The backup of `node` and the ConfigMap it is in, from the first namespace it is looked for in
+/// that has one, see [`ControllerConfig::backup_namespaces`]. Sharded backups are read from
+/// their shard, falling back to a per-node backup that hasn't been moved into it yet. Backups
+/// still under a legacy naming scheme are found in `namespace` last.
+pub(crate) async fn find_backup(
+    client: Client,
+    config: &ControllerConfig,
+    node: &Node,
+) -> Result<Option<(String, Backup)>> {
+    let node_name = node.name_any();
+    for namespace in config.backup_namespaces(node) {
+        if config.backup_layout == BackupLayout::Sharded {
+            let store = BackupStore::new(client.clone(), namespace, config.backup_layout)
+                .with_encryption(config.encryption_keys.clone());
+            if let Some((backup, _)) = store.read(&node_name).await? {
+                return Ok(Some((store.configmap_name(&node_name), backup)));
+            }
+        }
+        if let Some(cm) = find_configmap_for_node(client.clone(), namespace, &node_name).await? {
+            let backup = Backup::from_configmap(&cm, &config.encryption_keys)?;
+            return Ok(Some((cm.name_any(), backup)));
+        }
+    }
+    let cm_api: Api<ConfigMap> = Api::namespaced(client, &config.namespace);
+    for scheme in &config.legacy_naming_schemes {
+        let Some(name) = scheme.configmap_name(&node_name) else {
+            continue;
+        };
+        let cm = cm_api.get_opt(&name).await.map_err(|e| {
+            let namespace = config.namespace.clone();
+            Error::from_api(Operation::ReadBackup { namespace }, e)
+        })?;
+        if let Some(cm) = cm {
+            let backup = Backup::from_configmap(&cm, &config.encryption_keys)?;
+            return Ok(Some((name, backup)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,429 @@
+//! Metrics served in the Prometheus text format on the admin API
+
+use crate::{Error, RestoreOutcome, SkipReason};
+use k8s_openapi::api::core::v1::Node;
+use kube::ResourceExt;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// The pool of nodes without the pool label
+pub const NO_POOL: &str = "none";
+/// The pool every value beyond the cardinality cap is counted under
+pub const OTHER_POOL: &str = "other";
+
+/// Upper bounds of the buckets for labels restored by one apply
+pub const RESTORED_LABELS_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0];
+/// Upper bounds in seconds of the buckets for reconcile durations
+pub const RECONCILE_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Which node pools get their own metric label value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolLimit {
+    /// Only these values. Everything else is "other".
+    Allowlist(BTreeSet<String>),
+    /// The first this many distinct values seen. Everything after is "other".
+    FirstSeen(usize),
+}
+
+/// Maps a node to its pool, reading the pool label from the node at the time of the event and
+/// keeping the number of distinct pools bounded
+pub struct PoolBuckets {
+    label: Option<String>,
+    limit: PoolLimit,
+    seen: Mutex<BTreeSet<String>>,
+}
+
+impl PoolBuckets {
+    /// Pools are read from the `label` node label, or every node is in NO_POOL if unset
+    pub fn new(label: Option<String>, limit: PoolLimit) -> Self {
+        Self {
+            label,
+            limit,
+            seen: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn pool(&self, node: &Node) -> String {
+        let Some(value) = self.label.as_ref().and_then(|key| node.labels().get(key)) else {
+            return NO_POOL.to_string();
+        };
+        let known = match &self.limit {
+            PoolLimit::Allowlist(values) => values.contains(value),
+            PoolLimit::FirstSeen(max) => {
+                let mut seen = self.seen.lock().unwrap();
+                seen.contains(value) || (seen.len() < *max && seen.insert(value.clone()))
+            }
+        };
+        if known {
+            value.clone()
+        } else {
+            OTHER_POOL.to_string()
+        }
+    }
+}
+
+/// The controller's counters summed over every pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    pub reconciles: u64,
+    /// Restores that succeeded, including those with conflicting labels
+    pub restores: u64,
+    pub backups: u64,
+    pub errors: u64,
+}
+
+/// Observations counted into buckets with the given upper bounds, plus an implicit +Inf
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Observations at or below each bound, not yet cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        self.buckets.resize(bounds.len(), 0);
+        if let Some(bucket) = bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Write the `_bucket`, `_sum`, and `_count` series of `name` for `pool`
+    fn render(&self, out: &mut String, name: &str, pool: &str, bounds: &[f64]) {
+        let pool = escape(pool);
+        let mut cumulative = 0;
+        for (bound, count) in bounds.iter().zip(&self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{pool=\"{}\",le=\"{}\"}} {}",
+                name, pool, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{pool=\"{}\",le=\"+Inf\"}} {}",
+            name, pool, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{pool=\"{}\"}} {}", name, pool, self.sum);
+        let _ = writeln!(out, "{}_count{{pool=\"{}\"}} {}", name, pool, self.count);
+    }
+}
+
+/// The controller's counters, labeled by node pool
+pub struct Metrics {
+    pools: PoolBuckets,
+    reconciles: Mutex<BTreeMap<String, u64>>,
+    restores: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Failed reconciles by pool and [`Error::kind`]
+    errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    restored_labels: Mutex<BTreeMap<String, Histogram>>,
+    reconcile_durations: Mutex<BTreeMap<String, Histogram>>,
+    backups: Mutex<BTreeMap<String, u64>>,
+    skipped: Mutex<BTreeMap<(String, SkipReason), u64>>,
+    unknown_prefix: Mutex<BTreeMap<String, u64>>,
+    /// Finalizers added back to nodes after something else removed them
+    finalizer_reinstalled: Mutex<BTreeMap<String, u64>>,
+    /// Whether the circuit breaker is tripped, and the nodes it last counted as blocked
+    breaker: Mutex<(bool, usize)>,
+    /// Nodes waiting for a restore, and nodes whose background work is deferred behind them
+    queue_depths: Mutex<(usize, usize)>,
+}
+
+impl Metrics {
+    pub fn new(pools: PoolBuckets) -> Self {
+        Self {
+            pools,
+            reconciles: Mutex::new(BTreeMap::new()),
+            restores: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            restored_labels: Mutex::new(BTreeMap::new()),
+            reconcile_durations: Mutex::new(BTreeMap::new()),
+            backups: Mutex::new(BTreeMap::new()),
+            skipped: Mutex::new(BTreeMap::new()),
+            unknown_prefix: Mutex::new(BTreeMap::new()),
+            finalizer_reinstalled: Mutex::new(BTreeMap::new()),
+            breaker: Mutex::new((false, 0)),
+            queue_depths: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn record_reconcile(&self, node: &Node) {
+        *self
+            .reconciles
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default() += 1;
+    }
+
+    pub fn record_restore(&self, node: &Node, outcome: RestoreOutcome) {
+        let outcome = match outcome {
+            RestoreOutcome::Restored => "restored",
+            RestoreOutcome::Conflict => "conflict",
+            RestoreOutcome::Failed => "failed",
+        };
+        *self
+            .restores
+            .lock()
+            .unwrap()
+            .entry((self.pools.pool(node), outcome))
+            .or_default() += 1;
+    }
+
+    pub fn record_error(&self, node: &Node, error: &Error) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry((self.pools.pool(node), error.kind()))
+            .or_default() += 1;
+    }
+
+    /// Observe the number of labels one apply restored onto `node`
+    pub fn record_restored_labels(&self, node: &Node, restored: usize) {
+        self.restored_labels
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default()
+            .observe(RESTORED_LABELS_BUCKETS, restored as f64);
+    }
+
+    /// Observe how long a reconcile of `node` took, whether it succeeded or not
+    pub fn record_reconcile_duration(&self, node: &Node, duration: Duration) {
+        self.reconcile_durations
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default()
+            .observe(RECONCILE_DURATION_BUCKETS, duration.as_secs_f64());
+    }
+
+    pub fn record_backup(&self, node: &Node) {
+        *self
+            .backups
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default() += 1;
+    }
+
+    /// Count `count` backed up labels not restored onto `node` for `reason`
+    pub fn record_skipped(&self, node: &Node, reason: SkipReason, count: usize) {
+        *self
+            .skipped
+            .lock()
+            .unwrap()
+            .entry((self.pools.pool(node), reason))
+            .or_default() += count as u64;
+    }
+
+    /// Count `count` backed up labels of `node` whose prefix has no known owner
+    pub fn record_unknown_prefix(&self, node: &Node, count: usize) {
+        if count == 0 {
+            return;
+        }
+        *self
+            .unknown_prefix
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default() += count as u64;
+    }
+
+    pub fn record_finalizer_reinstalled(&self, node: &Node) {
+        *self
+            .finalizer_reinstalled
+            .lock()
+            .unwrap()
+            .entry(self.pools.pool(node))
+            .or_default() += 1;
+    }
+
+    pub fn set_breaker(&self, tripped: bool, blocked: usize) {
+        *self.breaker.lock().unwrap() = (tripped, blocked);
+    }
+
+    pub fn set_queue_depths(&self, restore: usize, background: usize) {
+        *self.queue_depths.lock().unwrap() = (restore, background);
+    }
+
+    /// The counters summed over every pool
+    pub fn totals(&self) -> Totals {
+        let sum = |counts: &Mutex<BTreeMap<String, u64>>| counts.lock().unwrap().values().sum();
+        Totals {
+            reconciles: sum(&self.reconciles),
+            restores: self
+                .restores
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((_, outcome), _)| *outcome != "failed")
+                .map(|(_, count)| count)
+                .sum(),
+            backups: sum(&self.backups),
+            errors: self.errors.lock().unwrap().values().sum(),
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP label_preserver_reconciles_total Node reconciles started\n");
+        out.push_str("# TYPE label_preserver_reconciles_total counter\n");
+        for (pool, count) in self.reconciles.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_reconciles_total{{pool=\"{}\"}} {}",
+                escape(pool),
+                count
+            );
+        }
+        out.push_str("# HELP label_preserver_restores_total Restores by outcome\n");
+        out.push_str("# TYPE label_preserver_restores_total counter\n");
+        for ((pool, outcome), count) in self.restores.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_restores_total{{pool=\"{}\",outcome=\"{}\"}} {}",
+                escape(pool),
+                outcome,
+                count
+            );
+        }
+        out.push_str("# HELP label_preserver_errors_total Failed reconciles, by error kind\n");
+        out.push_str("# TYPE label_preserver_errors_total counter\n");
+        for ((pool, kind), count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_errors_total{{pool=\"{}\",kind=\"{}\"}} {}",
+                escape(pool),
+                kind,
+                count
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_restored_labels Labels restored onto a node by one apply\n",
+        );
+        out.push_str("# TYPE label_preserver_restored_labels histogram\n");
+        for (pool, histogram) in self.restored_labels.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "label_preserver_restored_labels",
+                pool,
+                RESTORED_LABELS_BUCKETS,
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_reconcile_duration_seconds Time taken by a node reconcile\n",
+        );
+        out.push_str("# TYPE label_preserver_reconcile_duration_seconds histogram\n");
+        for (pool, histogram) in self.reconcile_durations.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "label_preserver_reconcile_duration_seconds",
+                pool,
+                RECONCILE_DURATION_BUCKETS,
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_backups_total Backup ConfigMaps written, on cleanup or when \
+            a deletion marker appears\n",
+        );
+        out.push_str("# TYPE label_preserver_backups_total counter\n");
+        for (pool, count) in self.backups.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_backups_total{{pool=\"{}\"}} {}",
+                escape(pool),
+                count
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_skipped_labels_total Backed up labels not restored, by reason\n",
+        );
+        out.push_str("# TYPE label_preserver_skipped_labels_total counter\n");
+        for ((pool, reason), count) in self.skipped.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_skipped_labels_total{{pool=\"{}\",reason=\"{}\"}} {}",
+                escape(pool),
+                reason,
+                count
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_unknown_prefix_labels_total Backed up labels restored or \
+            skipped whose prefix has no known owner\n",
+        );
+        out.push_str("# TYPE label_preserver_unknown_prefix_labels_total counter\n");
+        for (pool, count) in self.unknown_prefix.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_unknown_prefix_labels_total{{pool=\"{}\"}} {}",
+                escape(pool),
+                count
+            );
+        }
+        out.push_str(
+            "# HELP label_preserver_finalizer_reinstalled_total Finalizers added back to nodes \
+            after something else removed them\n",
+        );
+        out.push_str("# TYPE label_preserver_finalizer_reinstalled_total counter\n");
+        for (pool, count) in self.finalizer_reinstalled.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "label_preserver_finalizer_reinstalled_total{{pool=\"{}\"}} {}",
+                escape(pool),
+                count
+            );
+        }
+        let (tripped, blocked) = *self.breaker.lock().unwrap();
+        out.push_str(
+            "# HELP label_preserver_breaker_tripped Whether finalizers are released without \
+            waiting for a backup\n",
+        );
+        out.push_str("# TYPE label_preserver_breaker_tripped gauge\n");
+        let _ = writeln!(out, "label_preserver_breaker_tripped {}", u8::from(tripped));
+        out.push_str(
+            "# HELP label_preserver_blocked_nodes Nodes blocked in Terminating by our finalizer\n",
+        );
+        out.push_str("# TYPE label_preserver_blocked_nodes gauge\n");
+        let _ = writeln!(out, "label_preserver_blocked_nodes {}", blocked);
+        let (restore, background) = *self.queue_depths.lock().unwrap();
+        out.push_str(
+            "# HELP label_preserver_queue_depth Nodes waiting for a restore, and restored nodes \
+            whose background work is deferred until those are done\n",
+        );
+        out.push_str("# TYPE label_preserver_queue_depth gauge\n");
+        let _ = writeln!(
+            out,
+            "label_preserver_queue_depth{{tier=\"restore\"}} {}",
+            restore
+        );
+        let _ = writeln!(
+            out,
+            "label_preserver_queue_depth{{tier=\"background\"}} {}",
+            background
+        );
+        out
+    }
+}
+
+/// Escape a label value for the text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,136 @@
+//! Names of the objects, labels, and annotations the controller reads and writes
+
+use sha2::{Digest, Sha256};
+
+/// The namespace backups and the status are stored in unless another is configured, see
+/// [`crate::ControllerConfig::namespace`]
+pub const CONFIGMAP_NAMESPACE: &str = "default";
+/// The finalizer that holds nodes until their labels are backed up unless another is configured,
+/// see [`crate::ControllerConfig::finalizer`]
+pub const FINALIZER_NAME: &str = "nodelabelpreserver.example.com/finalizer";
+pub(crate) const SERVICE_NAME: &str = "node-label-preserver";
+/// Every backup ConfigMap's name starts with this, see [`configmap_name`]
+pub(crate) const BACKUP_CONFIGMAP_PREFIX: &str = "node-labels-";
+/// Describes the running controller's effective configuration
+pub const STATUS_CONFIGMAP_NAME: &str = "node-label-preserver-status";
+/// Identifies a single preserve/restore cycle. Generated fresh on every snapshot.
+pub const CORRELATION_ID_KEY: &str = "correlation_id";
+/// Set after labels are restored, otherwise the key is missing from the Node.
+/// The value records the restored backup's correlation ID, see [`crate::RestoredAnnotation`].
+pub const RESTORED_ANNOTATION_KEY: &str = "nodelabelpreserver.example.com/labels-restored";
+/// Claims a node for restoring while the restore is in progress, so two replicas never restore
+/// the same node. The value is the instance ID of the claim holder.
+pub const RESTORE_CLAIM_KEY: &str = "nodelabelpreserver.example.com/restore-in-progress";
+/// When the restore claim was taken, as RFC 3339
+pub const RESTORE_CLAIMED_AT_KEY: &str = "nodelabelpreserver.example.com/restore-claimed-at";
+/// Set to "true" on a backup ConfigMap that couldn't be read, e.g. after a hand edit broke its
+/// JSON. Its node is restored as if it had no backup, and the ConfigMap is kept for inspection
+/// until the node's next backup replaces it.
+pub const CORRUPT_BACKUP_ANNOTATION_KEY: &str = "nodelabelpreserver.example.com/corrupt";
+/// Set to "true" on every node we manage so they can be found with a single label selector.
+/// Never preserved, since it is re-applied to recreated nodes anyway.
+pub const MANAGED_LABEL_KEY: &str = "nodelabelpreserver.example.com/managed";
+/// Labels each backup ConfigMap with its node's name, see [`node_name_label_value`], and
+/// annotates it with the full name
+pub const NODE_NAME_KEY: &str = "nodelabelpreserver.example.com/node-name";
+/// Annotates each backup ConfigMap with when it was written, as RFC 3339
+pub const PRESERVED_AT_ANNOTATION_KEY: &str = "nodelabelpreserver.example.com/preserved-at";
+/// Set to `"node-label-preserver"` on every backup ConfigMap
+pub const MANAGED_BY_LABEL_KEY: &str = "app.kubernetes.io/managed-by";
+/// The longest label value Kubernetes accepts
+const MAX_LABEL_VALUE_LENGTH: usize = 63;
+/// Hex digits of the node name's hash kept in a truncated [`NODE_NAME_KEY`] label value
+const LABEL_VALUE_HASH_LENGTH: usize = 16;
+/// Annotation key prefixes that are never preserved: our own bookkeeping, and kubectl's, which
+/// describes the deleted object rather than the node
+pub(crate) const UNPRESERVED_ANNOTATION_PREFIXES: &[&str] =
+    &["nodelabelpreserver.example.com/", "kubectl.kubernetes.io/"];
+/// Taint key prefixes that are never preserved. Kubernetes and cloud providers set these to
+/// reflect the node's current condition, e.g. `node.kubernetes.io/not-ready`.
+pub(crate) const UNPRESERVED_TAINT_PREFIXES: &[&str] =
+    &["node.kubernetes.io/", "node.cloudprovider.kubernetes.io/"];
+
+/// Label key prefixes reserved for Kubernetes. Their subdomains, e.g. `node.kubernetes.io` or
+/// `topology.kubernetes.io`, are reserved too.
+pub(crate) const RESERVED_LABEL_DOMAINS: &[&str] = &["kubernetes.io", "k8s.io"];
+
+/// The longest object name Kubernetes accepts
+const MAX_CONFIGMAP_NAME_LENGTH: usize = 253;
+
+/// How a node's backup ConfigMap is named. Backups are written under [`NamingScheme::Hashed`];
+/// the other schemes name backups written by older versions and forks, which are found and
+/// migrated on restore, see [`crate::ControllerConfig::legacy_naming_schemes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NamingScheme {
+    /// `node-labels-<sha256 of the node name>`. The hash has a fixed length, so the name fits
+    /// Kubernetes' limit whatever the node name.
+    Hashed,
+    /// The node name behind `prefix`, e.g. `node-labels-<node name>`
+    Plain { prefix: String },
+}
+
+impl NamingScheme {
+    /// `node-labels-<node name>`, the scheme this controller's earlier forks used
+    pub fn plain() -> Self {
+        Self::Plain {
+            prefix: BACKUP_CONFIGMAP_PREFIX.to_string(),
+        }
+    }
+
+    /// The backup ConfigMap name of `node_name` under this scheme, or None if it would be
+    /// longer than Kubernetes allows, so no backup can exist under it
+    pub fn configmap_name(&self, node_name: &str) -> Option<String> {
+        let name = match self {
+            // "node-labels-" + 64 hex chars
+            Self::Hashed => format!("{}{}", BACKUP_CONFIGMAP_PREFIX, hash_node_name(node_name)),
+            Self::Plain { prefix } => format!("{}{}", prefix, node_name),
+        };
+        (name.len() <= MAX_CONFIGMAP_NAME_LENGTH).then_some(name)
+    }
+}
+
+impl std::fmt::Display for NamingScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hashed => write!(f, "{}<sha256>", BACKUP_CONFIGMAP_PREFIX),
+            Self::Plain { prefix } => write!(f, "{}<node name>", prefix),
+        }
+    }
+}
+
+/// The ConfigMap name a node's backup is written under, see [`NamingScheme::Hashed`].
+/// Node names are otherwise only embedded in the [`NODE_NAME_KEY`] label value, see
+/// [`node_name_label_value`].
+pub fn configmap_name(node_name: &str) -> String {
+    NamingScheme::Hashed
+        .configmap_name(node_name)
+        .expect("hashed names have a fixed length")
+}
+
+/// The [`NODE_NAME_KEY`] label value for a node. Names too long for a label value are truncated
+/// and suffixed with a hash of the full name, which stays unique.
+pub fn node_name_label_value(node_name: &str) -> String {
+    if node_name.len() <= MAX_LABEL_VALUE_LENGTH {
+        return node_name.to_string();
+    }
+    let hash = &hash_node_name(node_name)[..LABEL_VALUE_HASH_LENGTH];
+    let mut end = MAX_LABEL_VALUE_LENGTH - LABEL_VALUE_HASH_LENGTH - 1;
+    while !node_name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let prefix = &node_name[..end];
+    format!("{}-{}", prefix, hash)
+}
+
+/// The SHA-256 of the node name, as 64 hex digits
+pub(crate) fn hash_node_name(node_name: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(node_name.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Each instance applies its claim under its own field manager, so the API server rejects a
+/// claim on a node another instance already holds with a conflict
+pub(crate) fn claim_field_manager(instance_id: &str) -> String {
+    format!("{}-claim-{}", SERVICE_NAME, instance_id)
+}
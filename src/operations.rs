@@ -0,0 +1,267 @@
+//! Deciding what to write for a node without writing it. Our reconciler executes these
+//! decisions itself; controllers that already watch nodes can instead merge them into their
+//! own apply calls.
+
+use crate::{
+    configmap_name,
+    naming::{
+        node_name_label_value, MANAGED_BY_LABEL_KEY, NODE_NAME_KEY, PRESERVED_AT_ANNOTATION_KEY,
+        SERVICE_NAME,
+    },
+    types::PreservedTaint,
+    Backup, ControllerConfig, Degradation, MachineIdentity, MergeStrategy, RestoreCounts,
+    RestorePlan, RestoredAnnotation, Result, SkipReason, MANAGED_LABEL_KEY,
+    RESTORED_ANNOTATION_KEY, SCHEMA_VERSION,
+};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{DateTime, Utc},
+};
+use kube::api::{PatchParams, ResourceExt};
+use std::collections::{btree_map::Entry, BTreeMap};
+
+/// Parameters for applying [`Preservation::configmap`] and [`Restoration::node`]: a forced
+/// server-side apply with our field manager, so later applies of ours own the same fields
+pub fn apply_params() -> PatchParams {
+    PatchParams::apply(SERVICE_NAME).force()
+}
+
+/// The backup to write for a node being deleted
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preservation {
+    /// The backup as it will be stored, which degradation may have left with fewer labels than
+    /// the node
+    pub backup: Backup,
+    /// How the backup was shrunk to fit the configured limits
+    pub degradations: Vec<Degradation>,
+    /// The backup ConfigMap to apply with [`apply_params`]
+    pub configmap: ConfigMap,
+}
+
+/// Back up `node`'s current labels that pass the label filter, and annotations and taints if
+/// they are preserved, as of `now` under `correlation_id`, replacing any earlier backup. A node
+/// without labels still gets an empty backup, otherwise an outdated one from a previous deletion
+/// would be restored. The ConfigMap is labeled with the node's name so it can be found without
+/// hashing it, see [`crate::find_configmap_for_node`].
+pub fn preserve_on_cleanup(
+    node: &Node,
+    config: &ControllerConfig,
+    correlation_id: &str,
+    now: DateTime<Utc>,
+) -> Result<Preservation> {
+    let labels = config.preserved_labels(node);
+    let backup = Backup {
+        schema_version: SCHEMA_VERSION,
+        preserved_at: labels.keys().map(|key| (key.clone(), now)).collect(),
+        backed_up_at: Some(now),
+        labels,
+        annotations: config.preserved_annotations(node),
+        taints: config.preserved_taints(node),
+        correlation_id: Some(correlation_id.to_string()),
+        identity: MachineIdentity::of(node),
+    };
+    let (data, degradations) = backup.to_configmap_data_within(
+        config.max_backup_bytes,
+        config.max_labels,
+        config.backup_chunk_bytes,
+    )?;
+    Ok(Preservation {
+        backup: Backup::from_configmap_data(&data)?,
+        degradations,
+        configmap: backup_configmap(&node.name_any(), config.backup_namespace(node), data, now),
+    })
+}
+
+/// The backup ConfigMap of `node_name` holding `data`, written at `now`
+pub(crate) fn backup_configmap(
+    node_name: &str,
+    namespace: &str,
+    data: BTreeMap<String, String>,
+    now: DateTime<Utc>,
+) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(configmap_name(node_name)),
+            namespace: Some(namespace.to_string()),
+            labels: Some(BTreeMap::from([
+                (NODE_NAME_KEY.to_string(), node_name_label_value(node_name)),
+                (MANAGED_BY_LABEL_KEY.to_string(), SERVICE_NAME.to_string()),
+            ])),
+            annotations: Some(BTreeMap::from([
+                (NODE_NAME_KEY.to_string(), node_name.to_string()),
+                (PRESERVED_AT_ANNOTATION_KEY.to_string(), now.to_rfc3339()),
+            ])),
+            ..Default::default()
+        },
+        data: Some(data),
+        ..Default::default()
+    }
+}
+
+/// The labels to restore onto a created node
+#[derive(Clone, Debug, PartialEq)]
+pub struct Restoration {
+    /// What the restore policy kept and left out of the backup
+    pub plan: RestorePlan,
+    /// What the restore does with each label in the backup. `rewritten` is only known once the
+    /// node has been applied.
+    pub counts: RestoreCounts,
+    /// Labels the node doesn't have yet, which the restore adds
+    pub added: BTreeMap<String, String>,
+    /// Labels the node has with a different value, which the restore replaces with the backed
+    /// up value under [`MergeStrategy::PreferPreserved`]
+    pub overwritten: BTreeMap<String, String>,
+    /// Annotations the node doesn't have yet, which the restore adds
+    pub added_annotations: BTreeMap<String, String>,
+    /// Taints without one of the same key and effect on the node. These are not part of `node`:
+    /// `spec.taints` is replaced as a whole, so applying it would take ownership of every taint
+    /// on the node. Add them to the node's current taints instead, guarded by its
+    /// resourceVersion.
+    pub added_taints: Vec<PreservedTaint>,
+    /// The node to apply with [`apply_params`]: its labels with the backup merged in, the
+    /// restored annotation, and backed up annotations the node has or gets with their backed up
+    /// values. Other annotations are left out so they stay owned by whoever set them.
+    pub node: Node,
+}
+
+/// Merge the labels the policy keeps from `backup` into `node`'s labels as of `now` with the
+/// configured [`MergeStrategy`]. Backed up annotations are merged the same way, and taints by key
+/// and effect, if they are preserved.
+pub fn restore_on_apply(
+    node: &Node,
+    backup: &Backup,
+    config: &ControllerConfig,
+    now: DateTime<Utc>,
+) -> Restoration {
+    let plan = config.policy.plan(backup, node, now);
+    let mut counts = RestoreCounts {
+        identity_mismatch: plan.identity_mismatch.len(),
+        skipped: plan.skipped(),
+        unknown_prefix: plan.unknown_prefix.clone(),
+        ..Default::default()
+    };
+    let LabelMerge {
+        mut labels,
+        added,
+        overwritten,
+        skipped,
+    } = merge_labels(node.labels(), &plan.labels, config.merge_strategy);
+    counts.restored = added.len();
+    counts.overwritten = overwritten.len();
+    for (key, reason) in skipped {
+        match reason {
+            SkipReason::ExistingValueKept => counts.unchanged += 1,
+            SkipReason::DeferToLive => counts.conflicts += 1,
+            _ => {}
+        }
+        counts.skipped.insert(key, reason);
+    }
+    if config.managed_label {
+        labels.insert(MANAGED_LABEL_KEY.to_string(), "true".to_string());
+    }
+    let mut annotations = BTreeMap::from([(
+        RESTORED_ANNOTATION_KEY.to_string(),
+        RestoredAnnotation::of_backup(backup).to_value(),
+    )]);
+    let mut added_annotations = BTreeMap::new();
+    if config.preserve_annotations {
+        for (key, value) in &plan.annotations {
+            // Applying a value we set before keeps it ours, so a later restore doesn't drop it
+            match node.annotations().get(key) {
+                None => {
+                    added_annotations.insert(key.clone(), value.clone());
+                    annotations.insert(key.clone(), value.clone());
+                }
+                Some(live) if live == value => {
+                    annotations.insert(key.clone(), value.clone());
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    let added_taints = if config.preserve_taints {
+        missing_taints(node, &plan.taints)
+    } else {
+        Vec::new()
+    };
+
+    let node = Node {
+        metadata: ObjectMeta {
+            name: Some(node.name_any()),
+            labels: Some(labels),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    Restoration {
+        plan,
+        counts,
+        added,
+        overwritten,
+        added_annotations,
+        added_taints,
+        node,
+    }
+}
+
+/// Backed up labels merged into a node's labels
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelMerge {
+    /// The node's labels with the backed up ones merged in
+    pub labels: BTreeMap<String, String>,
+    /// Backed up labels the node didn't have
+    pub added: BTreeMap<String, String>,
+    /// Backed up labels that replaced a different value on the node
+    pub overwritten: BTreeMap<String, String>,
+    /// Backed up labels that were left out, with why
+    pub skipped: BTreeMap<String, SkipReason>,
+}
+
+/// Merge `preserved` labels into `current` ones. Vacant keys are always added, and keys with a
+/// different value are kept or overwritten according to `strategy`.
+pub fn merge_labels(
+    current: &BTreeMap<String, String>,
+    preserved: &BTreeMap<String, String>,
+    strategy: MergeStrategy,
+) -> LabelMerge {
+    let mut merge = LabelMerge {
+        labels: current.clone(),
+        ..Default::default()
+    };
+    for (key, value) in preserved {
+        match merge.labels.entry(key.clone()) {
+            Entry::Vacant(entry) => {
+                merge.added.insert(key.clone(), value.clone());
+                entry.insert(value.clone());
+            }
+            Entry::Occupied(entry) if entry.get() == value => {
+                merge
+                    .skipped
+                    .insert(key.clone(), SkipReason::ExistingValueKept);
+            }
+            Entry::Occupied(mut entry) => match strategy {
+                MergeStrategy::PreferCurrent => {
+                    merge.skipped.insert(key.clone(), SkipReason::DeferToLive);
+                }
+                MergeStrategy::PreferPreserved => {
+                    merge.overwritten.insert(key.clone(), value.clone());
+                    entry.insert(value.clone());
+                }
+            },
+        }
+    }
+    merge
+}
+
+/// The taints in `taints` without one of the same key and effect on `node`
+pub fn missing_taints(node: &Node, taints: &[PreservedTaint]) -> Vec<PreservedTaint> {
+    let current = node.spec.as_ref().and_then(|spec| spec.taints.as_deref());
+    taints
+        .iter()
+        .filter(|taint| !current.unwrap_or_default().iter().any(|t| taint.matches(t)))
+        .cloned()
+        .collect()
+}
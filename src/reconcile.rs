@@ -0,0 +1,1853 @@
+//! The reconcile loop: restoring labels onto created nodes and backing them up from deleted
+//! nodes
+
+use crate::{
+    apply_params,
+    context::{Rewrite, BACKGROUND_DEFERRAL_INTERVAL, DEFERRAL_INTERVAL},
+    missing_taints,
+    naming::{claim_field_manager, SERVICE_NAME},
+    operations::backup_configmap,
+    preserve_on_cleanup, restore_on_apply,
+    types::PreservedTaint,
+    BackoffState, Backup, BackupLayout, BackupStore, Context, ControllerConfig, Deletion, Error,
+    MachineIdentity, MergeStrategy, NamingScheme, NodePatcher, Operation, Preservation,
+    Restoration, RestoreOutcome, RestoredAnnotation, Result, Surface,
+    CORRUPT_BACKUP_ANNOTATION_KEY, MANAGED_LABEL_KEY, RESTORED_ANNOTATION_KEY,
+    RESTORE_CLAIMED_AT_KEY, RESTORE_CLAIM_KEY,
+};
+use k8s_openapi::{
+    api::core::v1::Node,
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, DeleteParams, Patch, PatchParams, Preconditions, ResourceExt},
+    error::ErrorResponse,
+    runtime::{
+        controller::Action,
+        events::EventType,
+        finalizer::{finalizer, Error as FinalizerError, Event as FinalizerEvent},
+        reflector::ObjectRef,
+    },
+    Client,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, field, info, instrument, warn, Span};
+use uuid::Uuid;
+
+/// A claim older than this is assumed abandoned and can be taken over
+pub(crate) const RESTORE_CLAIM_TIMEOUT: Duration = Duration::from_secs(120);
+const REQUEUE_TIME: Duration = Duration::from_secs(2);
+const MAX_RETRY_TIME: Duration = Duration::from_secs(3600);
+/// How long to wait after the API server throttles us. kube's `ErrorResponse` keeps neither the
+/// Retry-After header nor the Status' `retryAfterSeconds`, so this stands in for them.
+pub(crate) const THROTTLED_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Failed cleanups after which we release our finalizer even if other finalizers remain
+pub(crate) const MAX_CLEANUP_ATTEMPTS: u32 = 12;
+/// How long after a restore whose values were rewritten on admission to check them again
+pub(crate) const REWRITE_VERIFY_INTERVAL: Duration = Duration::from_secs(5);
+/// Conflicting writes to a node's taints after which restoring them fails
+const TAINT_RESTORE_ATTEMPTS: u32 = 3;
+/// Conflicting applies of a node's restored labels after which restoring them fails
+pub(crate) const NODE_PATCH_ATTEMPTS: u32 = 3;
+/// How long to wait at least before applying a node again after a conflict
+const NODE_PATCH_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Verifications after which rewritten values that keep changing are given up on
+pub(crate) const MAX_REWRITE_VERIFICATIONS: u32 = 5;
+/// How long after a node registers to keep looking for a backup it doesn't have, in case the
+/// cleanup of the node it replaces is still writing it
+pub(crate) const MISSING_BACKUP_WINDOW: Duration = Duration::from_secs(120);
+/// How often a node within MISSING_BACKUP_WINDOW looks for its backup again
+pub(crate) const MISSING_BACKUP_INTERVAL: Duration = Duration::from_secs(5);
+/// How long after a node registers before a finalizer sweep reports it
+pub(crate) const FINALIZER_SWEEP_GRACE: Duration = Duration::from_secs(60);
+
+// Action to take on Node events
+#[instrument(
+    skip_all,
+    fields(
+        node = %node.name_any(),
+        reconcile_kind = reconcile_kind(&node),
+        attempt = ctx.failed_attempts(&node.name_any()) + 1,
+    )
+)]
+pub async fn reconcile(node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    ctx.metrics.record_reconcile(&node);
+    let started = Instant::now();
+    ctx.health.record_activity(started);
+    let result = reconcile_node(node.clone(), ctx.clone()).await;
+    match &result {
+        // The node's next failure backs off from the start again
+        Ok(_) => {
+            ctx.backoff.lock().unwrap().remove(&node.name_any());
+        }
+        Err(error) if !is_briefly_not_ready(error) => {
+            let (reason, action) = match node.metadata.deletion_timestamp {
+                Some(_) => ("PreserveFailed", "Preserve"),
+                None => ("RestoreFailed", "Restore"),
+            };
+            ctx.publish_event(&node, EventType::Warning, reason, action, error.to_string())
+                .await;
+        }
+        Err(_) => {}
+    }
+    ctx.metrics
+        .record_reconcile_duration(&node, started.elapsed());
+    result
+}
+
+/// Whether reconciling `node` restores it or backs it up, for logs
+fn reconcile_kind(node: &Node) -> &'static str {
+    match node.metadata.deletion_timestamp {
+        Some(_) => "cleanup",
+        None => "apply",
+    }
+}
+
+async fn reconcile_node(node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    let node_name = node
+        .metadata
+        .name
+        .as_deref()
+        .ok_or_else(|| Error::MissingNodeName(Box::new(node.as_ref().clone())))?
+        .to_string();
+    let node_api: Api<Node> = Api::all(ctx.client.clone());
+
+    // Before the finalizer is added, which would block node deletion behind a controller that
+    // does nothing
+    if ctx.config.dry_run {
+        if ctx.config.in_scope(&node_name) {
+            dry_run_node(&node, &ctx).await?;
+        }
+        return Ok(Action::await_change());
+    }
+    if !ctx.config.in_scope(&node_name) {
+        release_finalizer(&node_api, &node, &ctx.config.finalizer).await?;
+        return Ok(Action::await_change());
+    }
+    if ctx.config.snapshot_mode {
+        return snapshot_node(&node_api, node, ctx).await;
+    }
+
+    // kube's helper adds a missing finalizer back silently, so check for one that was stripped
+    // from a node we managed before handing it over
+    let stripped = finalizer_stripped(&node, &ctx.config.finalizer);
+    let managed = node.clone();
+    let action = finalizer(&node_api, &ctx.config.finalizer, node, |event| async {
+        match event {
+            FinalizerEvent::Apply(node) => apply_node(node, ctx.clone()).await,
+            FinalizerEvent::Cleanup(node) => cleanup_node(node, ctx.clone()).await,
+        }
+    })
+    .await
+    .map_err(|e| {
+        warn!("Finalizer error for node {}: {:?}", node_name, e);
+        match e {
+            FinalizerError::AddFinalizer(kube::Error::Api(response))
+            | FinalizerError::RemoveFinalizer(kube::Error::Api(response))
+                if response.code == 403 =>
+            {
+                Error::Forbidden {
+                    operation: Operation::UpdateFinalizers,
+                    message: response.message,
+                }
+            }
+            e => Error::Finalizer(Box::new(e)),
+        }
+    })?;
+    if stripped {
+        warn!(
+            "Finalizer '{}' was removed from node '{}' by something else, added it back",
+            ctx.config.finalizer, node_name
+        );
+        ctx.metrics.record_finalizer_reinstalled(&managed);
+    }
+    ctx.deferred.lock().unwrap().remove(&node_name);
+    Ok(action)
+}
+
+/// Whether `node` lacks `ours` although we managed it before, as its restored annotation or
+/// managed label show. A new node lacks it too until its first reconcile adds it.
+fn finalizer_stripped(node: &Node, ours: &str) -> bool {
+    node.metadata.deletion_timestamp.is_none()
+        && !node.finalizers().iter().any(|f| f == ours)
+        && (node.annotations().contains_key(RESTORED_ANNOTATION_KEY)
+            || node.labels().contains_key(MANAGED_LABEL_KEY))
+}
+
+/// List the nodes the node watch sees and report each one in scope that lacks our finalizer
+/// with a FinalizerMissing Event, at most [`ControllerConfig::finalizer_sweep_rate`] per
+/// second, and have the Controller reconcile it to add the finalizer back. Nodes being deleted
+/// can't get it back, and nodes registered within FINALIZER_SWEEP_GRACE may not have been
+/// reconciled yet, so neither is reported. Returns how many nodes were reported.
+pub async fn sweep_finalizers(ctx: &Context) -> Result<usize> {
+    let node_api: Api<Node> = Api::all(ctx.client.clone());
+    let nodes = node_api
+        .list(&ctx.config.node_list_params())
+        .await
+        .map_err(|e| Error::from_api(Operation::ListNodes, e))?;
+    let ours = &ctx.config.finalizer;
+    let now = Utc::now();
+    let mut reported = 0;
+    for node in nodes {
+        let node_name = node.name_any();
+        let registered_recently = node
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .and_then(|created| (now - created.0).to_std().ok())
+            .is_some_and(|age| age < FINALIZER_SWEEP_GRACE);
+        if !ctx.config.in_scope(&node_name)
+            || node.metadata.deletion_timestamp.is_some()
+            || node.finalizers().iter().any(|f| f == ours)
+            || registered_recently
+        {
+            continue;
+        }
+        if reported > 0 && ctx.config.finalizer_sweep_rate > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(
+                1.0 / ctx.config.finalizer_sweep_rate,
+            ))
+            .await;
+        }
+        warn!(
+            "Node '{}' is missing finalizer '{}', its labels aren't backed up if it is deleted",
+            node_name, ours
+        );
+        let note = format!(
+            "Finalizer '{}' is missing, so the node's labels aren't backed up if it is deleted. \
+            Something other than the controller removed it.",
+            ours
+        );
+        ctx.publish_event(&node, EventType::Warning, "FinalizerMissing", "Sweep", note)
+            .await;
+        // Only fails if the Controller has shut down, in which case there's nothing to repair
+        let _ = ctx
+            .reconcile_requests
+            .unbounded_send(ObjectRef::from_obj(&node));
+        reported += 1;
+    }
+    Ok(reported)
+}
+
+/// Log what reconciling `node` would restore onto it or back up from it, without writing
+/// anything
+async fn dry_run_node(node: &Node, ctx: &Context) -> Result<()> {
+    let node_name = node.name_any();
+    if node.metadata.deletion_timestamp.is_some() {
+        let correlation_id = Uuid::new_v4().to_string();
+        let Preservation { backup, .. } =
+            preserve_on_cleanup(node, &ctx.config, &correlation_id, Utc::now())?;
+        info!(
+            "Dry run: would write ConfigMap '{}' in namespace '{}' with {} labels for node '{}'",
+            ctx.backups.configmap_name(&node_name),
+            ctx.config.backup_namespace(node),
+            backup.labels.len(),
+            node_name
+        );
+        return Ok(());
+    }
+    if node.annotations().contains_key(RESTORED_ANNOTATION_KEY)
+        && !ctx.backup_changed_since_restore(node)
+    {
+        return Ok(());
+    }
+    let backup = match read_backup(node, ctx).await {
+        Ok(backup) => backup.unwrap_or_default(),
+        Err(error @ (Error::Serialization(_) | Error::InvalidSchemaVersion(_))) => {
+            warn!(
+                "Dry run: backup of node '{}' is corrupt, would restore the node as if it had none: {}",
+                node_name, error
+            );
+            Backup::default()
+        }
+        Err(e) => return Err(e),
+    };
+    restore_backup(ctx.nodes.as_ref(), node, backup, &ctx.config).await?;
+    Ok(())
+}
+
+/// Release our finalizer from every node outside the configured scope.
+/// Nodes excluded by a server-side field selector never reach [`reconcile`], so this runs once
+/// at startup to free nodes that were removed from the list.
+pub async fn release_out_of_scope_finalizers(
+    client: Client,
+    config: &ControllerConfig,
+) -> Result<()> {
+    if config.node_names.is_none() {
+        return Ok(());
+    }
+    let node_api: Api<Node> = Api::all(client);
+    let nodes = node_api
+        .list(&Default::default())
+        .await
+        .map_err(|e| Error::from_api(Operation::ListNodes, e))?;
+    for node in nodes {
+        if !config.in_scope(&node.name_any()) {
+            release_finalizer(&node_api, &node, &config.finalizer).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Back up nodes on every label change instead of holding deleted nodes with our finalizer, see
+/// [`ControllerConfig::snapshot_mode`]. A finalizer left from finalizer mode is removed, after a
+/// last backup if the node is being deleted. Removing it changes the node, so a live node is
+/// restored or backed up on the reconcile that follows.
+async fn snapshot_node(node_api: &Api<Node>, node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    let ours = &ctx.config.finalizer;
+    let held = node.finalizers().iter().any(|f| f == ours);
+    if node.metadata.deletion_timestamp.is_some() {
+        if held {
+            info!(
+                "Node '{}' is being deleted under our finalizer, backing it up before releasing it",
+                node.name_any()
+            );
+            write_backup(&node, &ctx).await?;
+            remove_finalizer(node_api, &node, ours, false).await?;
+        }
+        return Ok(Action::await_change());
+    }
+    if held {
+        info!(
+            "Releasing the finalizer of node '{}', snapshot mode doesn't hold nodes",
+            node.name_any()
+        );
+        remove_finalizer(node_api, &node, ours, false).await?;
+        return Ok(Action::await_change());
+    }
+    apply_node(node, ctx).await
+}
+
+/// Remove our finalizer from a node we no longer manage, leaving everything else untouched
+async fn release_finalizer(node_api: &Api<Node>, node: &Node, ours: &str) -> Result<()> {
+    if !node.finalizers().iter().any(|f| f == ours) {
+        return Ok(());
+    }
+    info!(
+        "Node '{}' is out of scope, releasing finalizer",
+        node.name_any()
+    );
+    remove_finalizer(node_api, node, ours, true).await
+}
+
+/// Remove our finalizer from `node`, and with `unmanage` our managed label
+async fn remove_finalizer(
+    node_api: &Api<Node>,
+    node: &Node,
+    ours: &str,
+    unmanage: bool,
+) -> Result<()> {
+    let finalizers: Vec<&String> = node.finalizers().iter().filter(|f| *f != ours).collect();
+    // resourceVersion guards against clobbering a concurrent finalizer change
+    let mut patch = serde_json::json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": node.resource_version(),
+        }
+    });
+    if unmanage && node.labels().contains_key(MANAGED_LABEL_KEY) {
+        patch["metadata"]["labels"] = serde_json::json!({ MANAGED_LABEL_KEY: null });
+    }
+    node_api
+        .patch(
+            &node.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(&patch),
+        )
+        .await
+        .map_err(|e| Error::from_api(Operation::UpdateFinalizers, e))?;
+    Ok(())
+}
+
+/// The underlying Kubernetes API error, if any
+fn kube_error(error: &Error) -> Option<&kube::Error> {
+    match error {
+        Error::Kube(e) => Some(e),
+        Error::Finalizer(e) => match e.as_ref() {
+            FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => kube_error(e),
+            FinalizerError::AddFinalizer(e) | FinalizerError::RemoveFinalizer(e) => Some(e),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether the error indicates a node that is briefly not accepting patches, such as a
+/// just-registered node the API server doesn't serve yet or a node admission webhook that is
+/// still warming up. These are retried quickly rather than with exponential backoff.
+pub fn is_briefly_not_ready(error: &Error) -> bool {
+    match kube_error(error) {
+        Some(kube::Error::Api(response)) => match response.code {
+            404 | 503 => true,
+            500 => response.message.contains("failed calling webhook"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether the error can't go away until the node or its backup changes, such as a corrupt
+/// backup or a patch the API server rejects as invalid. Changes trigger a reconcile anyway, so
+/// these are only retried after MAX_RETRY_TIME.
+pub fn is_permanent(error: &Error) -> bool {
+    if let Some(kube::Error::Api(response)) = kube_error(error) {
+        return matches!(response.code, 400 | 422);
+    }
+    match error {
+        Error::Finalizer(e) => match e.as_ref() {
+            FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => is_permanent(e),
+            FinalizerError::UnnamedObject | FinalizerError::InvalidFinalizer => true,
+            _ => false,
+        },
+        Error::MissingNodeName(_)
+        | Error::Serialization(_)
+        | Error::InvalidSchemaVersion(_)
+        | Error::BackupTooLarge { .. }
+        | Error::ShardFull { .. }
+        | Error::Decryption(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the API server throttled the request
+fn is_throttled(error: &Error) -> bool {
+    matches!(kube_error(error), Some(kube::Error::Api(response)) if response.code == 429)
+}
+
+/// The instance holding the restore claim on `node`, if another instance holds a claim that
+/// hasn't timed out at `now`
+pub fn foreign_restore_claim(node: &Node, instance_id: &str, now: DateTime<Utc>) -> Option<String> {
+    let holder = node.annotations().get(RESTORE_CLAIM_KEY)?;
+    if holder == instance_id {
+        return None;
+    }
+    // A claim without a readable time can't be proven stale, so treat it as fresh
+    let stale = node
+        .annotations()
+        .get(RESTORE_CLAIMED_AT_KEY)
+        .and_then(|claimed_at| DateTime::parse_from_rfc3339(claimed_at).ok())
+        .and_then(|claimed_at| (now - claimed_at.with_timezone(&Utc)).to_std().ok())
+        .is_some_and(|age| age > RESTORE_CLAIM_TIMEOUT);
+    (!stale).then(|| holder.clone())
+}
+
+enum Claim {
+    Won,
+    /// Held by the given instance
+    Lost(String),
+    /// Another instance finished restoring the node since we saw it
+    AlreadyRestored,
+}
+
+/// Claim `node` for restoring. A stale claim held by another instance is taken over.
+async fn claim_restore(nodes: &dyn NodePatcher, node: &Node, instance_id: &str) -> Result<Claim> {
+    let node_name = node.name_any();
+    let claim = Node {
+        metadata: ObjectMeta {
+            name: Some(node_name.clone()),
+            annotations: Some(BTreeMap::from([
+                (RESTORE_CLAIM_KEY.to_string(), instance_id.to_string()),
+                (RESTORE_CLAIMED_AT_KEY.to_string(), Utc::now().to_rfc3339()),
+            ])),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut patch_params = PatchParams::apply(&claim_field_manager(instance_id));
+    if node.annotations().contains_key(RESTORE_CLAIM_KEY) {
+        // Only reached when the existing claim is ours or stale
+        patch_params = patch_params.force();
+    }
+    let claimed = match nodes.apply(&claim, &patch_params).await {
+        Ok(claimed) => claimed,
+        Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {
+            return Ok(Claim::Lost("another instance".to_string()))
+        }
+        Err(e) => return Err(Error::from_api(Operation::PatchNode, e)),
+    };
+    // The patched node is the state right after our claim was written
+    if let Some(holder) = claimed.annotations().get(RESTORE_CLAIM_KEY) {
+        if holder != instance_id {
+            return Ok(Claim::Lost(holder.clone()));
+        }
+    }
+    let restored = |node: &Node| node.annotations().get(RESTORED_ANNOTATION_KEY).cloned();
+    if restored(&claimed).is_some() && restored(&claimed) != restored(node) {
+        return Ok(Claim::AlreadyRestored);
+    }
+    Ok(Claim::Won)
+}
+
+/// Drop our restore claim. Applying an empty configuration under the claim's field manager
+/// removes the claim annotations, unless another instance has since taken them over.
+async fn release_restore_claim(
+    nodes: &dyn NodePatcher,
+    node_name: &str,
+    instance_id: &str,
+) -> Result<()> {
+    let release = Node {
+        metadata: ObjectMeta {
+            name: Some(node_name.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let patch_params = PatchParams::apply(&claim_field_manager(instance_id)).force();
+    nodes
+        .apply(&release, &patch_params)
+        .await
+        .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+    Ok(())
+}
+
+/// Back up a node carrying a deletion marker ahead of its deletion. Only nodes that were already
+/// restored are backed up, so an early backup never replaces labels that weren't restored yet.
+async fn refresh_backup_early(node: &Node, ctx: &Context, marker: &str) -> Result<()> {
+    info!(
+        "Node '{}' is marked for deletion by '{}', backing up its labels early",
+        node.name_any(),
+        marker
+    );
+    write_backup(node, ctx).await?;
+    Ok(())
+}
+
+/// The note of a LabelsRestored Event: the backed up keys applied to the node, and why the
+/// others were not
+fn restored_note(
+    backup_labels: &BTreeMap<String, String>,
+    counts: &RestoreCounts,
+    config: &ControllerConfig,
+    correlation_id: Option<&str>,
+) -> String {
+    let applied: Vec<&str> = backup_labels
+        .keys()
+        .filter(|key| !counts.skipped.contains_key(*key))
+        .map(String::as_str)
+        .collect();
+    let skipped: Vec<String> = counts
+        .skipped
+        .iter()
+        .map(|(key, reason)| format!("{} ({})", key, reason))
+        .collect();
+    format!(
+        "Restored labels [{}], skipped [{}] under the '{}' merge strategy (correlation ID {})",
+        applied.join(", "),
+        skipped.join(", "),
+        config.merge_strategy,
+        correlation_id.unwrap_or("none")
+    )
+}
+
+/// A node's backup, where it was found
+pub(crate) struct FoundBackup {
+    pub(crate) backup: Backup,
+    /// See [`crate::BackupStore::version`]
+    pub(crate) version: Option<String>,
+    /// The store of the namespace it was found in
+    pub(crate) store: BackupStore,
+}
+
+/// Read the node's backup from its ConfigMap, or None if it has none
+pub(crate) async fn read_backup(node: &Node, ctx: &Context) -> Result<Option<Backup>> {
+    Ok(read_backup_version(node, ctx)
+        .await?
+        .map(|found| found.backup))
+}
+
+/// The backup of a node from the first namespace it is found in, see
+/// [`ControllerConfig::backup_namespaces`]
+async fn read_backup_version(node: &Node, ctx: &Context) -> Result<Option<FoundBackup>> {
+    let node_name = node.name_any();
+    for store in ctx.backup_stores(node) {
+        if let Some((backup, version)) = ctx.labels.read(&store, &node_name).await? {
+            return Ok(Some(FoundBackup {
+                backup,
+                version,
+                store,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// The backup of a node to restore from, and its version. A backup that can't be read is
+/// marked corrupt and treated as missing, so a hand-edited ConfigMap doesn't keep the node from
+/// being restored. Without a backup where it is written now, one under a legacy name, or in a
+/// per-node ConfigMap when backups are sharded, is migrated.
+async fn read_restorable_backup(node: &Node, ctx: &Context) -> Result<Option<FoundBackup>> {
+    let node_name = node.name_any();
+    for store in ctx.backup_stores(node) {
+        match ctx.labels.read(&store, &node_name).await {
+            Ok(Some((backup, version))) => {
+                return Ok(Some(FoundBackup {
+                    backup,
+                    version,
+                    store,
+                }))
+            }
+            Ok(None) => {}
+            Err(error @ (Error::Serialization(_) | Error::InvalidSchemaVersion(_))) => {
+                mark_backup_corrupt(ctx, node, &store, &error).await;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    migrate_legacy_backup(node, ctx).await
+}
+
+/// Move the node's backup from the first legacy name it is found under in the default backup
+/// namespace to where it is written now: write its data there, then delete the legacy
+/// ConfigMap. Returns the backup where it now lives, or None if there is none.
+async fn migrate_legacy_backup(node: &Node, ctx: &Context) -> Result<Option<FoundBackup>> {
+    let node_name = &node.name_any();
+    let namespace = &ctx.config.namespace;
+    let legacy_api = ctx.backups.api();
+    let per_node = match ctx.backups.layout() {
+        BackupLayout::PerNode => None,
+        BackupLayout::Sharded => Some(&NamingScheme::Hashed),
+    };
+    for scheme in per_node
+        .into_iter()
+        .chain(&ctx.config.legacy_naming_schemes)
+    {
+        let Some(legacy_name) = scheme.configmap_name(node_name) else {
+            continue;
+        };
+        let legacy = match legacy_api.get(&legacy_name).await {
+            Ok(legacy) => legacy,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => continue,
+            Err(e) => {
+                let namespace = namespace.clone();
+                return Err(Error::from_api(Operation::ReadBackup { namespace }, e));
+            }
+        };
+        let data = match ctx.config.encryption_keys.open(&legacy) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(
+                    "Legacy backup ConfigMap '{}' of node '{}' can't be decrypted, not migrating it: {}",
+                    legacy_name, node_name, e
+                );
+                continue;
+            }
+        };
+        let backup = match Backup::from_configmap_data(&data) {
+            Ok(backup) => backup,
+            Err(e) => {
+                warn!(
+                    "Legacy backup ConfigMap '{}' of node '{}' can't be read, not migrating it: {}",
+                    legacy_name, node_name, e
+                );
+                continue;
+            }
+        };
+        let store = ctx.backup_store(node);
+        let configmap = backup_configmap(node_name, store.namespace(), data, Utc::now());
+        let written = {
+            let _slot = ctx.backup_write_slot().await;
+            ctx.labels.write(&store, node_name, &configmap).await?
+        };
+        let cm_name = written.name_any();
+        info!(
+            "Migrated the backup of node '{}' from legacy ConfigMap '{}' ({}) to '{}'",
+            node_name, legacy_name, scheme, cm_name
+        );
+        // The backup is safe under its new name, so a legacy ConfigMap left behind only costs
+        // a lookup that never happens again
+        let params = DeleteParams {
+            preconditions: Some(Preconditions {
+                resource_version: legacy.metadata.resource_version.clone(),
+                uid: None,
+            }),
+            ..Default::default()
+        };
+        match legacy_api.delete(&legacy_name, &params).await {
+            Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {}
+            Err(e) => {
+                let namespace = namespace.clone();
+                let error = Error::from_api(Operation::DeleteBackup { namespace }, e);
+                warn!(
+                    "Failed to delete legacy backup ConfigMap '{}' of node '{}': {}",
+                    legacy_name, node_name, error
+                );
+            }
+        }
+        let version = store.version(node_name, &written);
+        return Ok(Some(FoundBackup {
+            backup,
+            version,
+            store,
+        }));
+    }
+    Ok(None)
+}
+
+/// Annotate a backup ConfigMap that couldn't be read, see [`CORRUPT_BACKUP_ANNOTATION_KEY`]. A
+/// shard holds other nodes' backups, so it is only reported.
+async fn mark_backup_corrupt(ctx: &Context, node: &Node, store: &BackupStore, error: &Error) {
+    let node_name = node.name_any();
+    let cm_name = store.configmap_name(&node_name);
+    warn!(
+        "Backup ConfigMap '{}' of node '{}' is corrupt, treating it as missing: {}",
+        cm_name, node_name, error
+    );
+    let note = format!(
+        "Backup ConfigMap '{}' is corrupt, treating it as missing: {}",
+        cm_name, error
+    );
+    ctx.publish_event(node, EventType::Warning, "CorruptBackup", "Restore", note)
+        .await;
+    if store.layout() == BackupLayout::Sharded {
+        return;
+    }
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { CORRUPT_BACKUP_ANNOTATION_KEY: "true" } }
+    });
+    if let Err(e) = store
+        .api()
+        .patch(&cm_name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        let error = Error::from_api(
+            Operation::WriteBackup {
+                namespace: store.namespace().to_string(),
+            },
+            e,
+        );
+        warn!(
+            "Failed to mark backup ConfigMap '{}' of node '{}' corrupt: {}",
+            cm_name, node_name, error
+        );
+    }
+}
+
+/// Handle Node Creation
+#[instrument(
+    skip_all,
+    fields(
+        configmap = %ctx.backups.configmap_name(&node.name_any()),
+        labels_restored = field::Empty,
+    )
+)]
+async fn apply_node(node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    let node_name = node.name_any();
+    // A backup we wrote from the node's current labels has nothing to restore
+    let namespace = ctx.config.backup_namespace(&node);
+    let outdated =
+        ctx.backup_changed_since_restore(&node) && !ctx.backup_digests.is_current(namespace, &node);
+    if outdated {
+        info!(
+            "Backup of node '{}' changed since the node was restored, restoring it again",
+            node_name
+        );
+    } else if node.annotations().contains_key(RESTORED_ANNOTATION_KEY) {
+        // Restored nodes only have background work, which waits for new nodes to be restored
+        let marker = ctx
+            .config
+            .deletion_markers
+            .find(&node)
+            .filter(|_| !ctx.backup_digests.is_current(namespace, &node));
+        let resync = (ctx.config.snapshot_mode || ctx.config.resync_interval.is_some())
+            && backup_outdated(&node, &ctx);
+        let has_work = marker.is_some()
+            || resync
+            || ctx.boot_changed(&node)
+            || ctx.rewrites.lock().unwrap().contains_key(&node_name);
+        if let Some(pending) = has_work.then(|| ctx.defer_background(&node_name)).flatten() {
+            debug!(
+                "Deferring background work on node '{}' behind {} pending restores",
+                node_name, pending
+            );
+            return Ok(Action::requeue(BACKGROUND_DEFERRAL_INTERVAL));
+        }
+        let rebooted = ctx.observe_boot(&node);
+        if let Some(marker) = marker {
+            refresh_backup_early(&node, &ctx, marker).await?;
+        } else if resync && !rebooted {
+            // A rebooted node may be missing labels until it is repaired, so it is backed up
+            // on its next reconcile
+            info!(
+                "Labels of node '{}' changed since its last backup, backing them up",
+                node_name
+            );
+            resync_backup(&node, &ctx).await?;
+        }
+        if rebooted {
+            repair_after_reregistration(&node, &ctx).await?;
+        }
+        if let Some(action) = verify_rewrites(&node, &ctx).await? {
+            return Ok(action);
+        }
+        return Ok(idle_action(&ctx.config));
+    }
+    ctx.observe_boot(&node);
+    let instance_id = &ctx.config.instance_id;
+    if let Some(holder) = foreign_restore_claim(&node, instance_id, Utc::now()) {
+        info!(
+            "Node '{}' is being restored by instance '{}', skipping",
+            node_name, holder
+        );
+        return Ok(Action::requeue(RESTORE_CLAIM_TIMEOUT));
+    }
+    ctx.pacer.acquire().await;
+    info!("Reconciling node '{}' (Apply)", node_name);
+
+    let Some(FoundBackup {
+        backup,
+        version: backup_version,
+        store,
+    }) = read_restorable_backup(&node, &ctx).await?
+    else {
+        return missing_backup(&node, &ctx).await;
+    };
+    let nodes = ctx.nodes.as_ref();
+    // Reading the backup is harmless, but only one instance may patch the node
+    match claim_restore(nodes, &node, instance_id).await? {
+        Claim::Won => {}
+        Claim::Lost(holder) => {
+            info!(
+                "Lost the restore claim on node '{}' to {}, skipping",
+                node_name, holder
+            );
+            return Ok(Action::requeue(RESTORE_CLAIM_TIMEOUT));
+        }
+        Claim::AlreadyRestored => {
+            info!(
+                "Node '{}' was already restored by another instance",
+                node_name
+            );
+            release_restore_claim(nodes, &node_name, instance_id).await?;
+            return Ok(idle_action(&ctx.config));
+        }
+    }
+    let correlation_id = backup.correlation_id.clone();
+    // A stale backup is restored like one with nothing left to restore, so the node is still
+    // marked restored and isn't retried
+    let stale = ctx.config.policy.is_stale(&backup, Utc::now());
+    if stale {
+        report_stale_backup(&node, &backup, &ctx).await;
+    }
+    ctx.evaluate_candidate(&node, &backup);
+    let backup_labels = backup.labels.clone();
+    let counts = restore_backup(nodes, &node, backup, &ctx.config).await?;
+    release_restore_claim(nodes, &node_name, instance_id).await?;
+    if stale && ctx.config.delete_stale_backups {
+        delete_backup(&ctx, &store, &node_name, backup_version).await;
+    } else if ctx.config.cleanup_after_restore {
+        if ctx.config.record_rewrites && !counts.rewritten.is_empty() {
+            debug!(
+                "Keeping the backup of node '{}' to record rewritten values into",
+                node_name
+            );
+        } else {
+            delete_backup(&ctx, &store, &node_name, backup_version).await;
+        }
+    }
+    Span::current().record("labels_restored", counts.restored);
+    if !ctx.bursts.in_burst() {
+        let skipped: Vec<String> = counts
+            .skip_breakdown()
+            .iter()
+            .map(|(reason, count)| format!("{}={}", reason, count))
+            .collect();
+        info!(
+            "Restored {} labels onto node '{}', {} already present, {} conflicting, and {} overwritten under the '{}' merge strategy, skipped [{}] (correlation ID {})",
+            counts.restored,
+            node_name,
+            counts.unchanged,
+            counts.conflicts,
+            counts.overwritten,
+            ctx.config.merge_strategy,
+            skipped.join(", "),
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    for (key, reason) in &counts.skipped {
+        debug!(
+            "Did not restore label '{}' onto node '{}': {}",
+            key, node_name, reason
+        );
+    }
+    if !backup_labels.is_empty() && !stale {
+        let note = restored_note(
+            &backup_labels,
+            &counts,
+            &ctx.config,
+            correlation_id.as_deref(),
+        );
+        ctx.publish_event(&node, EventType::Normal, "LabelsRestored", "Restore", note)
+            .await;
+    }
+    for (reason, count) in counts.skip_breakdown() {
+        ctx.metrics.record_skipped(&node, reason, count);
+    }
+    ctx.metrics
+        .record_unknown_prefix(&node, counts.unknown_prefix.len());
+    ctx.metrics.record_restored_labels(&node, counts.restored);
+
+    if counts.total() > 0 {
+        let outcome = if counts.conflicts > 0 {
+            RestoreOutcome::Conflict
+        } else {
+            RestoreOutcome::Restored
+        };
+        ctx.report_restore(&node, outcome);
+    }
+    if !counts.rewritten.is_empty() {
+        warn!(
+            "{} labels restored onto node '{}' landed with different values, verifying they are stable: {:?}",
+            counts.rewritten.len(),
+            node_name,
+            ctx.config.redactor.labels(Surface::Logs, &counts.rewritten)
+        );
+        let rewrite = Rewrite {
+            attempted: counts
+                .rewritten
+                .keys()
+                .map(|key| (key.clone(), backup_labels[key].clone()))
+                .collect(),
+            observed: counts.rewritten,
+            verifications: 0,
+        };
+        ctx.rewrites.lock().unwrap().insert(node_name, rewrite);
+        return Ok(Action::requeue(REWRITE_VERIFY_INTERVAL));
+    }
+    Ok(idle_action(&ctx.config))
+}
+
+/// A node without a backup has nothing to restore and isn't marked restored, so a backup that
+/// shows up later, e.g. from an import, is still restored. A new node's backup may still be
+/// on its way from the cleanup of the node it replaces, so it is looked for again until the
+/// node is MISSING_BACKUP_WINDOW old. After that, with background backups, the node's current
+/// labels are backed up. The managed label is still set.
+async fn missing_backup(node: &Node, ctx: &Context) -> Result<Action> {
+    let node_name = node.name_any();
+    if ctx.config.managed_label && !node.labels().contains_key(MANAGED_LABEL_KEY) {
+        let patch = serde_json::json!({ "metadata": { "labels": { MANAGED_LABEL_KEY: "true" } } });
+        ctx.nodes
+            .merge(&node_name, &patch)
+            .await
+            .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+    }
+    if awaits_backup(node, Utc::now()) {
+        debug!(
+            "Node '{}' has no backup yet, looking again in {}s",
+            node_name,
+            MISSING_BACKUP_INTERVAL.as_secs()
+        );
+        return Ok(Action::requeue(MISSING_BACKUP_INTERVAL));
+    }
+    if ctx.config.snapshot_mode || ctx.config.resync_interval.is_some() {
+        info!(
+            "Node '{}' has no backup, backing up its current labels",
+            node_name
+        );
+        resync_backup(node, ctx).await?;
+    } else {
+        info!("Node '{}' has no backup, nothing to restore", node_name);
+    }
+    Ok(idle_action(&ctx.config))
+}
+
+/// Whether `node` registered less than MISSING_BACKUP_WINDOW before `now`, so a backup it
+/// lacks may still be written
+pub fn awaits_backup(node: &Node, now: DateTime<Utc>) -> bool {
+    node.metadata
+        .creation_timestamp
+        .as_ref()
+        .is_some_and(|created| {
+            let age = now.signed_duration_since(created.0);
+            age.to_std().unwrap_or_default() < MISSING_BACKUP_WINDOW
+        })
+}
+
+/// Warn that `backup` is too old to restore anything from, see
+/// [`crate::RestorePolicy::is_stale`]
+async fn report_stale_backup(node: &Node, backup: &Backup, ctx: &Context) {
+    let policy = &ctx.config.policy;
+    let max_age_days = policy
+        .max_backup_age
+        .map_or(0, |age| age.as_secs() / (24 * 60 * 60));
+    let reason = match backup.backed_up_at {
+        Some(backed_up_at) => format!(
+            "it was written at {}, more than {} days ago",
+            backed_up_at.to_rfc3339(),
+            max_age_days
+        ),
+        None => format!(
+            "its age is unknown and the unknown backup age policy is '{}'",
+            policy.unknown_backup_age
+        ),
+    };
+    let cm_name = ctx.backups.configmap_name(&node.name_any());
+    warn!(
+        "Not restoring {} labels onto node '{}' from backup ConfigMap '{}': {} (correlation ID {})",
+        backup.labels.len(),
+        node.name_any(),
+        cm_name,
+        reason,
+        backup.correlation_id.as_deref().unwrap_or("none")
+    );
+    let note = format!("Not restoring backup ConfigMap '{}': {}", cm_name, reason);
+    ctx.publish_event(node, EventType::Warning, "StaleBackup", "Restore", note)
+        .await;
+}
+
+/// What to do with a node without pending work: wait for it to change, or with a resync
+/// interval, also check its backup again then
+fn idle_action(config: &ControllerConfig) -> Action {
+    match config.resync_interval {
+        Some(interval) => Action::requeue(interval),
+        None => Action::await_change(),
+    }
+}
+
+/// Whether the backup of a restored node lacks the labels it has now. Backups we wrote are
+/// remembered in the backup digests, others, e.g. ones written before a restart, are compared
+/// with the labels the backup watch last saw in them.
+fn backup_outdated(node: &Node, ctx: &Context) -> bool {
+    let namespace = ctx.config.backup_namespace(node);
+    if ctx.backup_digests.is_current(namespace, node) {
+        return false;
+    }
+    let current = Backup {
+        labels: ctx.config.preserved_labels(node),
+        ..Default::default()
+    };
+    ctx.backup_digests
+        .labels_digest(namespace, &node.name_any())
+        != Some(current.labels_digest())
+}
+
+/// Back up the current labels of a node, and mark the node restored from the new backup, so it
+/// isn't taken for a changed backup to restore again
+async fn resync_backup(node: &Node, ctx: &Context) -> Result<()> {
+    let node_name = node.name_any();
+    let backup = write_backup(node, ctx).await?;
+    let annotation = RestoredAnnotation::of_backup(&backup);
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { RESTORED_ANNOTATION_KEY: annotation.to_value() } }
+    });
+    ctx.nodes
+        .merge(&node_name, &patch)
+        .await
+        .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+    Ok(())
+}
+
+/// Delete the backup of a restored node, unless it changed since it was read as `version`, e.g.
+/// because the final backup of an earlier node of the same name was written in the meantime.
+/// The node is already restored, so a failure leaves the backup behind rather than failing the
+/// reconcile.
+async fn delete_backup(
+    ctx: &Context,
+    store: &BackupStore,
+    node_name: &str,
+    version: Option<String>,
+) {
+    let cm_name = store.configmap_name(node_name);
+    let deletion = {
+        let _slot = ctx.backup_write_slot().await;
+        ctx.labels.delete(store, node_name, version).await
+    };
+    match deletion {
+        Ok(Deletion::Deleted) => {
+            ctx.backup_digests.forget(store.namespace(), node_name);
+            info!(
+                "Deleted the backup in ConfigMap '{}' of restored node '{}'",
+                cm_name, node_name
+            );
+        }
+        Ok(Deletion::Missing) => {}
+        Ok(Deletion::Changed) => info!(
+            "Backup in ConfigMap '{}' changed since node '{}' was restored from it, keeping it",
+            cm_name, node_name
+        ),
+        Err(error) => warn!(
+            "Failed to delete the backup of restored node '{}': {}",
+            node_name, error
+        ),
+    }
+}
+
+/// Check restored labels that were rewritten on admission, e.g. by a mutating webhook, again.
+/// Values unchanged since the last check have converged: the rewrite is accepted instead of
+/// fighting the webhook, and with `record_rewrites` written back into the backup. Values that
+/// are still changing are checked again later, up to MAX_REWRITE_VERIFICATIONS times.
+async fn verify_rewrites(node: &Node, ctx: &Context) -> Result<Option<Action>> {
+    let node_name = node.name_any();
+    let Some(mut rewrite) = ctx.rewrites.lock().unwrap().get(&node_name).cloned() else {
+        return Ok(None);
+    };
+    let observed: BTreeMap<String, String> = rewrite
+        .attempted
+        .keys()
+        .filter_map(|key| Some((key.clone(), node.labels().get(key)?.clone())))
+        .collect();
+    if observed == rewrite.observed {
+        let redactor = &ctx.config.redactor;
+        for (key, value) in &observed {
+            info!(
+                "Accepting label '{}' on node '{}' as rewritten on admission from '{}' to '{}'",
+                key,
+                node_name,
+                redactor.value(Surface::Logs, key, &rewrite.attempted[key]),
+                redactor.value(Surface::Logs, key, value)
+            );
+        }
+        if ctx.config.record_rewrites {
+            record_rewrites(node, &observed, ctx).await?;
+        }
+        ctx.rewrites.lock().unwrap().remove(&node_name);
+        return Ok(None);
+    }
+
+    rewrite.verifications += 1;
+    if rewrite.verifications >= MAX_REWRITE_VERIFICATIONS {
+        warn!(
+            "Restored labels on node '{}' were still changing after {} checks, no longer verifying them",
+            node_name, rewrite.verifications
+        );
+        ctx.rewrites.lock().unwrap().remove(&node_name);
+        return Ok(None);
+    }
+    rewrite.observed = observed;
+    ctx.rewrites.lock().unwrap().insert(node_name, rewrite);
+    Ok(Some(Action::requeue(REWRITE_VERIFY_INTERVAL)))
+}
+
+/// A restored node re-registered after its kubelet restarted. Restore backed up labels that
+/// went missing right away instead of leaving them off until the node is recreated.
+async fn repair_after_reregistration(node: &Node, ctx: &Context) -> Result<()> {
+    let node_name = node.name_any();
+    if !ctx.config.repair_on_reregistration {
+        info!(
+            "Node '{}' re-registered with a new boot ID, not repairing its labels",
+            node_name
+        );
+        return Ok(());
+    }
+    let Some(FoundBackup { backup, .. }) = read_restorable_backup(node, ctx).await? else {
+        return Ok(());
+    };
+    if backup
+        .labels
+        .keys()
+        .all(|key| node.labels().contains_key(key))
+    {
+        debug!(
+            "Node '{}' re-registered with a new boot ID and kept all its backed up labels",
+            node_name
+        );
+        return Ok(());
+    }
+    let counts = restore_backup(ctx.nodes.as_ref(), node, backup, &ctx.config).await?;
+    info!(
+        "Node '{}' re-registered with a new boot ID, restored {} missing labels",
+        node_name, counts.restored
+    );
+    Ok(())
+}
+
+/// Replace backed up label values with the values they were rewritten to on admission, so
+/// the next restore applies what actually lands
+async fn record_rewrites(
+    node: &Node,
+    rewritten: &BTreeMap<String, String>,
+    ctx: &Context,
+) -> Result<()> {
+    let node_name = &node.name_any();
+    let Some(FoundBackup {
+        mut backup, store, ..
+    }) = read_backup_version(node, ctx).await?
+    else {
+        return Ok(());
+    };
+    for (key, value) in rewritten {
+        if let Some(backed_up) = backup.labels.get_mut(key) {
+            *backed_up = value.clone();
+        }
+    }
+    let (cm_data, _) = backup.to_configmap_data_within(
+        ctx.config.max_backup_bytes,
+        ctx.config.max_labels,
+        ctx.config.backup_chunk_bytes,
+    )?;
+    let cm = backup_configmap(node_name, store.namespace(), cm_data, Utc::now());
+    {
+        let _slot = ctx.backup_write_slot().await;
+        ctx.labels.write(&store, node_name, &cm).await?;
+    }
+    info!(
+        "Recorded {} rewritten label values in the backup of node '{}'",
+        rewritten.len(),
+        node_name
+    );
+    Ok(())
+}
+
+/// Why a backed up label was not added to the node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum SkipReason {
+    /// Left out by the restore policy, e.g. because the node is a different machine
+    FilteredByPolicy,
+    /// Already on the node with the backed up value
+    ExistingValueKept,
+    /// Outlived its expiry rule
+    Expired,
+    /// Not a valid Kubernetes label
+    InvalidSyntax,
+    /// The key's domain is reserved for Kubernetes, see [`crate::filter_restorable_labels`]
+    ReservedDomain,
+    /// The key's prefix has no known owner and the policy excludes such keys
+    UnknownPrefix,
+    /// The backup is older than the maximum backup age, or of unknown age and those are skipped
+    StaleBackup,
+    /// The node already has a different value, which wins
+    DeferToLive,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::FilteredByPolicy => "FilteredByPolicy",
+            SkipReason::ExistingValueKept => "ExistingValueKept",
+            SkipReason::Expired => "Expired",
+            SkipReason::InvalidSyntax => "InvalidSyntax",
+            SkipReason::ReservedDomain => "ReservedDomain",
+            SkipReason::UnknownPrefix => "UnknownPrefix",
+            SkipReason::StaleBackup => "StaleBackup",
+            SkipReason::DeferToLive => "DeferToLive",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a restore did with each label in the backup
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RestoreCounts {
+    /// Added to the node
+    pub restored: usize,
+    /// Already on the node with the backed up value
+    pub unchanged: usize,
+    /// Left alone because the node already has a different value
+    pub conflicts: usize,
+    /// Replaced a different value on the node, under [`crate::MergeStrategy::PreferPreserved`]
+    pub overwritten: usize,
+    /// Not restored because the node is a different machine than the backup
+    pub identity_mismatch: usize,
+    /// Why each backed up label that wasn't added to the node was left out, by key
+    pub skipped: BTreeMap<String, SkipReason>,
+    /// Backed up keys whose prefix has no known owner, whether or not they were restored
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_prefix: Vec<String>,
+    /// Restored labels that landed with a different value, e.g. rewritten by a mutating
+    /// webhook, with the value that landed
+    pub rewritten: BTreeMap<String, String>,
+}
+
+impl RestoreCounts {
+    /// The number of labels the backup wanted to restore
+    pub fn total(&self) -> usize {
+        self.restored + self.unchanged + self.conflicts + self.overwritten + self.identity_mismatch
+    }
+
+    /// How many labels were left out for each reason
+    pub fn skip_breakdown(&self) -> BTreeMap<SkipReason, usize> {
+        let mut breakdown = BTreeMap::new();
+        for reason in self.skipped.values() {
+            *breakdown.entry(*reason).or_default() += 1;
+        }
+        breakdown
+    }
+}
+
+/// Merge the unexpired labels in `backup` into the node's labels with the configured merge
+/// strategy, and apply the result along with the restored annotation
+pub(crate) async fn restore_backup(
+    nodes: &dyn NodePatcher,
+    node: &Node,
+    backup: Backup,
+    config: &ControllerConfig,
+) -> Result<RestoreCounts> {
+    let node_name = node.name_any();
+    let correlation_id = backup.correlation_id.clone();
+    let policy = &config.policy;
+    let mut restoration = restore_on_apply(node, &backup, config, Utc::now());
+    let plan = &restoration.plan;
+    let counts = &restoration.counts;
+    for (key, reason) in &plan.invalid {
+        warn!(
+            "Not restoring invalid label '{}' onto node '{}': {} (correlation ID {})",
+            key,
+            node_name,
+            config
+                .redactor
+                .text(Surface::Logs, key, &backup.labels[key], reason),
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    for key in &plan.unknown_prefix {
+        warn!(
+            "Label '{}' for node '{}' has a prefix with no known owner, {} (correlation ID {})",
+            key,
+            node_name,
+            if policy.exclude_unknown_prefixes {
+                "not restoring it, it stays in the backup"
+            } else {
+                "restoring it anyway"
+            },
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    for key in &plan.expired {
+        info!(
+            "Not restoring expired label '{}' onto node '{}', preserved at {} (correlation ID {})",
+            key,
+            node_name,
+            backup.preserved_at[key].to_rfc3339(),
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    if plan.machine_changed {
+        let identity = MachineIdentity::of(node);
+        warn!(
+            node = node_name,
+            policy = %policy.identity_mismatch,
+            backup_provider_id = backup.identity.provider_id.as_deref(),
+            node_provider_id = identity.provider_id.as_deref(),
+            backup_machine_id = backup.identity.machine_id.as_deref(),
+            node_machine_id = identity.machine_id.as_deref(),
+            not_restored = counts.identity_mismatch,
+            "Node '{}' is a different machine than its backup, applying the '{}' policy: {} of {} labels not restored (correlation ID {})",
+            node_name,
+            policy.identity_mismatch,
+            counts.identity_mismatch,
+            plan.labels.len() + counts.identity_mismatch,
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+
+    if config.dry_run {
+        let keys: Vec<&String> = restoration
+            .added
+            .keys()
+            .chain(restoration.overwritten.keys())
+            .collect();
+        info!(
+            "Dry run: would restore keys {:?} onto node '{}' (correlation ID {})",
+            keys,
+            node_name,
+            correlation_id.as_deref().unwrap_or("none")
+        );
+        return Ok(restoration.counts);
+    }
+
+    // Before the restored annotation is applied, so a failure retries the whole restore
+    if !restoration.added_taints.is_empty() {
+        let restored = restore_taints(nodes, &node_name, &restoration.added_taints).await?;
+        info!(
+            "Restored {} taints onto node '{}' (correlation ID {})",
+            restored,
+            node_name,
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    let mut attempt = 1;
+    let patched = loop {
+        let error = match nodes
+            .apply(&restoration.node, &restore_params(config))
+            .await
+        {
+            Ok(patched) => break patched,
+            Err(kube::Error::Api(response)) if response.code == 409 => response,
+            Err(e) => return Err(Error::from_api(Operation::PatchNode, e)),
+        };
+        let managers = conflicting_field_managers(&error.message);
+        if attempt >= NODE_PATCH_ATTEMPTS {
+            warn!(
+                "Restoring labels onto node '{}' conflicted with field managers [{}] {} times, giving up: {} (correlation ID {})",
+                node_name,
+                managers.join(", "),
+                attempt,
+                error.message,
+                correlation_id.as_deref().unwrap_or("none")
+            );
+            return Err(Error::from_api(
+                Operation::PatchNode,
+                kube::Error::Api(error),
+            ));
+        }
+        warn!(
+            "Restoring labels onto node '{}' conflicted with field managers [{}], reading the node again and retrying: {} (correlation ID {})",
+            node_name,
+            managers.join(", "),
+            error.message,
+            correlation_id.as_deref().unwrap_or("none")
+        );
+        tokio::time::sleep(node_patch_retry_delay()).await;
+        let node = nodes
+            .get(&node_name)
+            .await
+            .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+        restoration = restore_on_apply(&node, &backup, config, Utc::now());
+        attempt += 1;
+    };
+    let Restoration {
+        mut counts,
+        mut added,
+        overwritten,
+        added_annotations,
+        ..
+    } = restoration;
+    if !added_annotations.is_empty() {
+        info!(
+            "Restored {} annotations onto node '{}' (correlation ID {})",
+            added_annotations.len(),
+            node_name,
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    if !overwritten.is_empty() {
+        info!(
+            "Overwrote {} labels on node '{}' with their backed up values under the '{}' merge strategy: {:?} (correlation ID {})",
+            overwritten.len(),
+            node_name,
+            config.merge_strategy,
+            config.redactor.labels(Surface::Logs, &overwritten),
+            correlation_id.as_deref().unwrap_or("none")
+        );
+    }
+    // The response is the node as admitted, after any mutating webhooks
+    let landed = patched.labels();
+    added.extend(overwritten);
+    counts.rewritten = added
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let landed = landed.get(&key)?;
+            (*landed != value).then(|| (key, landed.clone()))
+        })
+        .collect();
+    Ok(counts)
+}
+
+/// Parameters for applying a restore. The apply is only forced under
+/// [`MergeStrategy::PreferPreserved`], whose point is to replace values other field managers
+/// set. Otherwise a label another field manager, e.g. the kubelet, changed since the node was
+/// read conflicts instead of being reverted to the value read, and the restore is merged into
+/// the node's current labels again.
+fn restore_params(config: &ControllerConfig) -> PatchParams {
+    match config.merge_strategy {
+        MergeStrategy::PreferPreserved => apply_params(),
+        MergeStrategy::PreferCurrent => PatchParams::apply(SERVICE_NAME),
+    }
+}
+
+/// The field managers a server-side apply conflicted with, as the API server names them, e.g.
+/// `kubelet` in `Apply failed with 1 conflict: conflict with "kubelet" using v1:
+/// .metadata.labels.zone`
+pub fn conflicting_field_managers(message: &str) -> Vec<&str> {
+    let mut managers = Vec::new();
+    for (start, with) in message.match_indices(" with \"") {
+        let before = &message[..start];
+        if !before.ends_with("conflict") && !before.ends_with("conflicts") {
+            continue;
+        }
+        if let Some((manager, _)) = message[start + with.len()..].split_once('"') {
+            if !managers.contains(&manager) {
+                managers.push(manager);
+            }
+        }
+    }
+    managers
+}
+
+/// How long to wait before applying a node again after a conflict: NODE_PATCH_RETRY_DELAY and
+/// up to as long again at random, so instances conflicting with each other don't retry in
+/// lockstep
+fn node_patch_retry_delay() -> Duration {
+    let jitter = Uuid::new_v4().as_u128() % NODE_PATCH_RETRY_DELAY.as_millis();
+    NODE_PATCH_RETRY_DELAY + Duration::from_millis(jitter as u64)
+}
+
+/// Add those of `taints` the node lacks, matching by key and effect, to its current taints, and
+/// return how many were added. The whole list is written, so the node's resourceVersion
+/// guards against dropping a taint someone else, e.g. the node lifecycle controller, added in
+/// the meantime. On a conflict the node is read again, up to TAINT_RESTORE_ATTEMPTS times.
+async fn restore_taints(
+    nodes: &dyn NodePatcher,
+    node_name: &str,
+    taints: &[PreservedTaint],
+) -> Result<usize> {
+    let mut attempt = 1;
+    loop {
+        let node = nodes
+            .get(node_name)
+            .await
+            .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+        let missing = missing_taints(&node, taints);
+        if missing.is_empty() {
+            return Ok(0);
+        }
+        let mut all = node
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.taints.clone())
+            .unwrap_or_default();
+        all.extend(missing.iter().map(PreservedTaint::to_taint));
+        let patch = serde_json::json!({
+            "metadata": { "resourceVersion": node.resource_version() },
+            "spec": { "taints": all },
+        });
+        match nodes.merge(node_name, &patch).await {
+            Ok(_) => return Ok(missing.len()),
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. }))
+                if attempt < TAINT_RESTORE_ATTEMPTS =>
+            {
+                debug!(
+                    "Taints on node '{}' changed while restoring them, retrying",
+                    node_name
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(Error::from_api(Operation::PatchNode, e)),
+        }
+    }
+}
+
+/// Handle Node Deletion
+#[instrument(
+    skip_all,
+    fields(
+        configmap = %ctx.backups.configmap_name(&node.name_any()),
+        labels_preserved = field::Empty,
+    )
+)]
+async fn cleanup_node(node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    let node_name = node.name_any();
+    info!("Cleaning up node '{}' (Cleanup)", node_name);
+
+    // Check if deletion has been pending for too long before anything that could fail, so
+    // our finalizer can't indefinitely prevent the node from being deleted while our cleanup
+    // is failing in a loop. Returning Ok lets the finalizer helper remove the finalizer.
+    if should_force_release(
+        &node,
+        &ctx.config.finalizer,
+        ctx.failed_attempts(&node_name),
+        Utc::now(),
+    ) {
+        warn!(
+            "Node '{}' termination cleanup failed for over {}s. Forcing finalizer removal.",
+            node_name,
+            MAX_RETRY_TIME.as_secs()
+        );
+        let note = format!(
+            "Cleanup failed for over {}s, released the node without preserving its labels",
+            MAX_RETRY_TIME.as_secs()
+        );
+        ctx.publish_event(
+            &node,
+            EventType::Warning,
+            "LabelsNotPreserved",
+            "Preserve",
+            note,
+        )
+        .await;
+        return Ok(Action::await_change());
+    }
+    if ctx.check_breaker(Utc::now()) {
+        // Best effort: the node from the cache is the latest state we have
+        if let Err(e) = write_backup(&node, &ctx).await {
+            warn!(
+                "Circuit breaker tripped, releasing node '{}' without a backup: {}",
+                node_name, e
+            );
+        }
+        return Ok(Action::await_change());
+    }
+
+    // A node recreated with the same name may already have been restored from the backup we
+    // are about to replace, so keep it to tell which of its labels came from there
+    let recreated = ctx.recreated_node(&node);
+    let stale = match &recreated {
+        Some(_) => read_restorable_backup(&node, &ctx)
+            .await?
+            .map(|found| found.backup),
+        None => None,
+    };
+    let backup = write_backup(&node, &ctx).await?;
+    ctx.backup_digests
+        .forget(ctx.config.backup_namespace(&node), &node_name);
+    ctx.boot_ids.lock().unwrap().remove(&node_name);
+    ctx.background_deferred.lock().unwrap().remove(&node_name);
+    if let (Some(recreated), Some(stale)) = (recreated, stale) {
+        update_recreated_node(&recreated, &stale, &backup, &ctx).await?;
+    }
+    Ok(Action::await_change())
+}
+
+/// Bring a node that was recreated and restored while the old node's cleanup was still running
+/// up to date with the old node's final backup. Labels the recreated node got from the stale
+/// backup are updated or removed to match, missing labels are added, and labels set any other
+/// way are left alone.
+async fn update_recreated_node(
+    recreated: &Node,
+    stale: &Backup,
+    backup: &Backup,
+    ctx: &Context,
+) -> Result<()> {
+    let node_name = recreated.name_any();
+    let restored_from = RestoredAnnotation::of(recreated);
+    if !restored_from.is_some_and(|restored| restored.is_from(stale.correlation_id.as_deref())) {
+        // Not restored yet, or not from the stale backup: its restore reads the final backup
+        return Ok(());
+    }
+    let current = recreated.labels();
+    let mut labels = serde_json::Map::new();
+    for (key, stale_value) in &stale.labels {
+        if current.get(key) != Some(stale_value) {
+            continue;
+        }
+        match backup.labels.get(key) {
+            Some(value) if value != stale_value => {
+                labels.insert(key.clone(), value.clone().into());
+            }
+            Some(_) => {}
+            None => {
+                labels.insert(key.clone(), serde_json::Value::Null);
+            }
+        }
+    }
+    for (key, value) in &backup.labels {
+        if !stale.labels.contains_key(key) && !current.contains_key(key) {
+            labels.insert(key.clone(), value.clone().into());
+        }
+    }
+    let correlation_id = backup.correlation_id.as_deref().unwrap_or("none");
+    let annotation = RestoredAnnotation::of_backup(backup);
+    info!(
+        "Node '{}' was recreated and restored from an outdated backup while the old node was \
+        being cleaned up, updating {} labels to the final backup (correlation ID {})",
+        node_name,
+        labels.len(),
+        correlation_id
+    );
+    let patch = serde_json::json!({
+        "metadata": {
+            "labels": labels,
+            "annotations": { RESTORED_ANNOTATION_KEY: annotation.to_value() },
+        }
+    });
+    ctx.nodes
+        .merge(&node_name, &patch)
+        .await
+        .map_err(|e| Error::from_api(Operation::PatchNode, e))?;
+    Ok(())
+}
+
+/// Back up `node`'s current labels, replacing any earlier backup. Returns the backup as written.
+pub(crate) async fn write_backup(node: &Node, ctx: &Context) -> Result<Backup> {
+    let node_name = node.name_any();
+    let correlation_id = Uuid::new_v4().to_string();
+    let Preservation {
+        backup,
+        degradations,
+        configmap,
+    } = preserve_on_cleanup(node, &ctx.config, &correlation_id, Utc::now())?;
+    debug!(
+        "Labels to preserve for node '{}': {:?}",
+        node_name,
+        ctx.config.redactor.labels(Surface::Logs, &backup.labels)
+    );
+    let store = ctx.backup_store(node);
+    let cm_name = store.configmap_name(&node_name);
+    // A no-op outside of cleanup_node's span, the only one with the field
+    Span::current().record("labels_preserved", backup.labels.len());
+    info!(
+        "Preserving {} labels and {} annotations for node '{}' in ConfigMap '{}' in namespace '{}' (correlation ID {})",
+        backup.labels.len(),
+        backup.annotations.len(),
+        node_name,
+        cm_name,
+        store.namespace(),
+        correlation_id
+    );
+    for degradation in degradations {
+        warn!(
+            "Backup for node '{}' is over {} bytes, degraded it: {:?}",
+            node_name, ctx.config.max_backup_bytes, degradation
+        );
+    }
+
+    let written = {
+        let _slot = ctx.backup_write_slot().await;
+        ctx.labels.write(&store, &node_name, &configmap).await?
+    };
+    if written
+        .annotations()
+        .contains_key(CORRUPT_BACKUP_ANNOTATION_KEY)
+    {
+        clear_corrupt_mark(&store, &cm_name).await;
+    }
+    if !ctx.config.namespace_map.is_empty() {
+        delete_moved_backups(node, &store, ctx).await;
+    }
+    ctx.backup_digests
+        .record(store.namespace(), node, store.version(&node_name, &written));
+    ctx.metrics.record_backup(node);
+    let note = format!(
+        "Preserved {} labels and {} annotations in ConfigMap '{}' (correlation ID {})",
+        backup.labels.len(),
+        backup.annotations.len(),
+        cm_name,
+        correlation_id
+    );
+    ctx.publish_event(node, EventType::Normal, "LabelsPreserved", "Preserve", note)
+        .await;
+    Ok(backup)
+}
+
+/// Delete `node`'s backups outside `current`'s namespace, left behind when its namespace label
+/// changed, so a later restore can't find an outdated one first. The new backup is already
+/// written, so a failure is only reported.
+async fn delete_moved_backups(node: &Node, current: &BackupStore, ctx: &Context) {
+    let node_name = node.name_any();
+    for store in ctx.backup_stores(node) {
+        if store.namespace() == current.namespace() {
+            continue;
+        }
+        let deletion = {
+            let _slot = ctx.backup_write_slot().await;
+            ctx.labels.delete(&store, &node_name, None).await
+        };
+        match deletion {
+            Ok(Deletion::Deleted) => info!(
+                "Deleted the backup of node '{}' in namespace '{}', it is now kept in namespace '{}'",
+                node_name,
+                store.namespace(),
+                current.namespace()
+            ),
+            Ok(Deletion::Missing | Deletion::Changed) => {}
+            Err(error) => warn!(
+                "Failed to delete the backup of node '{}' in namespace '{}': {}",
+                node_name,
+                store.namespace(),
+                error
+            ),
+        }
+    }
+}
+
+/// Drop the corrupt mark from a backup ConfigMap that was just rewritten
+async fn clear_corrupt_mark(store: &BackupStore, cm_name: &str) {
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { CORRUPT_BACKUP_ANNOTATION_KEY: null } }
+    });
+    match store
+        .api()
+        .patch(cm_name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        Ok(_) => info!(
+            "Backup ConfigMap '{}' was rewritten, no longer corrupt",
+            cm_name
+        ),
+        Err(e) => warn!(
+            "Failed to clear the corrupt mark of rewritten backup ConfigMap '{}': {}",
+            cm_name,
+            Error::from_api(
+                Operation::WriteBackup {
+                    namespace: store.namespace().to_string(),
+                },
+                e,
+            )
+        ),
+    }
+}
+
+/// Whether to give up on preserving a terminating node's labels and release our finalizer.
+/// We only give up once deletion has been pending for MAX_RETRY_TIME and our own cleanup has
+/// actually been failing, so a controller that was down for a while still gets its snapshot.
+/// While other finalizers keep the node around anyway, releasing ours early gains nothing, so
+/// we keep retrying until MAX_CLEANUP_ATTEMPTS. `ours` is our finalizer, see
+/// [`ControllerConfig::finalizer`].
+pub fn should_force_release(
+    node: &Node,
+    ours: &str,
+    failed_attempts: u32,
+    now: DateTime<Utc>,
+) -> bool {
+    let Some(Time(deletion_time)) = node.metadata.deletion_timestamp else {
+        return false;
+    };
+    let pending = (now - deletion_time).to_std().unwrap_or_default();
+    if pending <= MAX_RETRY_TIME || failed_attempts == 0 {
+        return false;
+    }
+    let other_finalizers = node.finalizers().iter().any(|f| f != ours);
+    !other_finalizers || failed_attempts >= MAX_CLEANUP_ATTEMPTS
+}
+
+/// Fixed short retries for nodes that are briefly not ready, MAX_RETRY_TIME for permanent
+/// errors, THROTTLED_RETRY_INTERVAL when throttled, otherwise exponential backoff
+pub fn error_policy(node: Arc<Node>, error: &Error, ctx: Arc<Context>) -> Action {
+    error!(
+        node = %node.name_any(),
+        error.kind = error.kind(),
+        "Reconciliation failed: {:?}",
+        error
+    );
+    ctx.metrics.record_error(&node, error);
+    if let Error::Finalizer(e) = error {
+        if let FinalizerError::ApplyFailed(_) = e.as_ref() {
+            ctx.report_restore(&node, RestoreOutcome::Failed);
+        }
+    }
+    if let Some(store) = ctx.node_store.get() {
+        // Nodes deleted while failing are never reconciled again to clear their state
+        let exists = |name: &String| store.get(&ObjectRef::new(name)).is_some();
+        ctx.backoff.lock().unwrap().retain(|name, _| exists(name));
+        ctx.deferred.lock().unwrap().retain(|name, _| exists(name));
+    }
+    if is_briefly_not_ready(error) && ctx.defer(&node.name_any()) {
+        debug!(
+            "Node '{}' is not accepting patches yet, retrying in {}s",
+            node.name_any(),
+            DEFERRAL_INTERVAL.as_secs()
+        );
+        return Action::requeue(DEFERRAL_INTERVAL);
+    }
+    let mut backoff = ctx.backoff.lock().unwrap();
+    let state = backoff.entry(node.name_any()).or_insert(BackoffState {
+        attempt: 0,
+        next_retry: Utc::now(),
+    });
+    // Permanent and throttled failures still count, so a failing cleanup can be released
+    state.attempt += 1;
+    let delay = if is_permanent(error) {
+        MAX_RETRY_TIME
+    } else if is_throttled(error) {
+        THROTTLED_RETRY_INTERVAL
+    } else {
+        let base_secs = REQUEUE_TIME.as_secs();
+        let max_secs = MAX_RETRY_TIME.as_secs();
+        // 2**attempt
+        let factor = 2u64.checked_pow(state.attempt).unwrap_or(u64::MAX);
+        Duration::from_secs(base_secs.saturating_mul(factor).min(max_secs))
+    };
+    state.next_retry = Utc::now() + delay;
+    Action::requeue(delay)
+}
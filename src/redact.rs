@@ -0,0 +1,116 @@
+//! Hiding label values in everything we output
+
+use crate::{Error, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+/// Where label values are rendered, so values can be shown in some outputs and not others
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Surface {
+    Logs,
+    /// Reports printed by commands, e.g. verify-bundle
+    Reports,
+    /// Admin API responses
+    Admin,
+}
+
+impl FromStr for Surface {
+    type Err = Error;
+
+    fn from_str(surface: &str) -> Result<Self> {
+        match surface {
+            "logs" => Ok(Self::Logs),
+            "reports" => Ok(Self::Reports),
+            "admin" => Ok(Self::Admin),
+            _ => Err(Error::InvalidSurface(surface.to_string())),
+        }
+    }
+}
+
+/// A short, stable stand-in for `value`: the first 12 hex digits of its SHA-256. Equal values
+/// get equal stand-ins, so they can still be correlated across outputs without being readable.
+pub fn hash_value(value: &str) -> String {
+    let digest = hex::encode(Sha256::digest(value.as_bytes()));
+    format!("sha256:{}", &digest[..12])
+}
+
+/// Decides which label values are replaced by their [`hash_value`] in each [`Surface`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Redactor {
+    /// Values of keys starting with one of these, or whose name after the `/` does, are redacted
+    pub prefixes: Vec<String>,
+    /// Redact the values of every key
+    pub all_keys: bool,
+    /// Surfaces that show values anyway
+    pub shown: BTreeSet<Surface>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self {
+            prefixes: ["customer", "tenant", "account", "owner"]
+                .map(str::to_string)
+                .to_vec(),
+            all_keys: false,
+            shown: BTreeSet::new(),
+        }
+    }
+}
+
+impl Redactor {
+    /// A redactor that shows every value everywhere
+    pub fn disabled() -> Self {
+        Self {
+            prefixes: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the value of `key` is hidden in `surface`
+    pub fn redacts(&self, surface: Surface, key: &str) -> bool {
+        if self.shown.contains(&surface) {
+            return false;
+        }
+        let name = key.rsplit_once('/').map_or(key, |(_, name)| name);
+        self.all_keys
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix) || name.starts_with(prefix))
+    }
+
+    /// `value` as it may be shown for `key` in `surface`
+    pub fn value(&self, surface: Surface, key: &str, value: &str) -> String {
+        if self.redacts(surface, key) {
+            hash_value(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// `labels` with the values hidden in `surface` replaced
+    pub fn labels(
+        &self,
+        surface: Surface,
+        labels: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        labels
+            .iter()
+            .map(|(key, value)| (key.clone(), self.value(surface, key, value)))
+            .collect()
+    }
+
+    /// `text` mentioning `key`'s `value`, e.g. an error message, with the value replaced if it
+    /// is hidden in `surface`
+    pub fn text(&self, surface: Surface, key: &str, value: &str, text: &str) -> String {
+        if value.is_empty() || !self.redacts(surface, key) {
+            return text.to_string();
+        }
+        text.replace(value, &hash_value(value))
+    }
+}
@@ -0,0 +1,277 @@
+//! Restore every backup onto its node in one run, e.g. after a disaster
+
+use crate::{
+    configmap_name, manual::find_backup, naming::BACKUP_CONFIGMAP_PREFIX,
+    reconcile::restore_backup, shard_configmap_name, shard_key, Backup, BackupLayout,
+    ControllerConfig, Error, Operation, RestoreCounts, Result, NODE_NAME_KEY,
+    SHARD_CONFIGMAP_PREFIX,
+};
+use futures::{stream, StreamExt};
+use k8s_openapi::api::core::v1::{ConfigMap, Node};
+use kube::{
+    api::{Api, ListParams, ResourceExt},
+    Client,
+};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use tracing::{info, warn};
+
+/// Every backup was restored
+pub const EXIT_OK: i32 = 0;
+/// At least one backup was skipped, and none failed. Past the controller's exit codes, so it
+/// isn't mistaken for invalid flags or a failed startup check.
+pub const EXIT_SKIPPED: i32 = 6;
+/// At least one backup failed to restore
+pub const EXIT_FAILED: i32 = 7;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RestoreAllOptions {
+    /// How many nodes to restore at once
+    pub concurrency: usize,
+    /// Stop starting new restores after the first failure
+    pub stop_on_error: bool,
+}
+
+/// The result of restoring one backup
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum NodeResult {
+    Restored {
+        #[serde(flatten)]
+        counts: RestoreCounts,
+    },
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NodeReport {
+    /// The backup ConfigMap
+    pub configmap: String,
+    /// The node the backup belongs to, or None if no current node matches it
+    pub node: Option<String>,
+    #[serde(flatten)]
+    pub result: NodeResult,
+}
+
+/// The outcome of a restore-all run, sorted by ConfigMap name
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RestoreReport {
+    pub nodes: Vec<NodeReport>,
+}
+
+impl RestoreReport {
+    /// The process exit code for this report. Failures take precedence over skips.
+    pub fn exit_code(&self) -> i32 {
+        let any = |f: fn(&NodeResult) -> bool| self.nodes.iter().any(|n| f(&n.result));
+        if any(|r| matches!(r, NodeResult::Failed { .. })) {
+            EXIT_FAILED
+        } else if any(|r| matches!(r, NodeResult::Skipped { .. })) {
+            EXIT_SKIPPED
+        } else {
+            EXIT_OK
+        }
+    }
+}
+
+/// Restore every backup onto its current node, ignoring the restored annotation.
+/// Each node's backup is looked up as by [`crate::manual::restore`], across its backup
+/// namespaces, shards, and legacy names. Each node is restored independently, so one bad backup
+/// doesn't stop the others unless `stop_on_error` is set. Backup names are hashes of node
+/// names, so a backup whose node no longer exists can only be reported by its ConfigMap name.
+pub async fn restore_all(
+    client: Client,
+    config: &ControllerConfig,
+    options: RestoreAllOptions,
+) -> Result<RestoreReport> {
+    let node_api: Api<Node> = Api::all(client.clone());
+    let nodes = node_api
+        .list(&ListParams::default())
+        .await
+        .map_err(|e| Error::from_api(Operation::ListNodes, e))?
+        .items;
+    let concurrency = options.concurrency.max(1);
+
+    let mut report = orphaned_backups(client.clone(), config, &nodes).await?;
+    let mut lookups = stream::iter(nodes)
+        .map(|node| {
+            let client = client.clone();
+            async move {
+                let found = find_backup(client, config, &node).await;
+                (node, found)
+            }
+        })
+        .buffer_unordered(concurrency);
+    let mut pending = Vec::new();
+    let mut failed = false;
+    while let Some((node, found)) = lookups.next().await {
+        let node_name = node.name_any();
+        let (configmap, backup) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => continue,
+            Err(e) => {
+                failed = true;
+                let configmap = backup_configmap_name(config, &node_name);
+                report.nodes.push(node_report(configmap, node_name, Err(e)));
+                continue;
+            }
+        };
+        if !config.in_scope(&node_name) {
+            report.nodes.push(NodeReport {
+                configmap,
+                node: Some(node_name),
+                result: NodeResult::Skipped {
+                    reason: "node is out of scope".to_string(),
+                },
+            });
+            continue;
+        }
+        pending.push((configmap, node, backup));
+    }
+
+    let mut unfinished: BTreeSet<(String, String)> = pending
+        .iter()
+        .map(|(configmap, node, _)| (configmap.clone(), node.name_any()))
+        .collect();
+    if failed && options.stop_on_error {
+        warn!("Stopping restore-all after the first failure");
+        pending.clear();
+    }
+    info!("Restoring {} backups", pending.len());
+    let mut results = stream::iter(pending)
+        .map(|(configmap, node, backup)| restore_one(&node_api, configmap, node, backup, config))
+        .buffer_unordered(concurrency);
+    while let Some(node_report) = results.next().await {
+        let failed = matches!(node_report.result, NodeResult::Failed { .. });
+        unfinished.remove(&(
+            node_report.configmap.clone(),
+            node_report.node.clone().unwrap_or_default(),
+        ));
+        report.nodes.push(node_report);
+        if failed && options.stop_on_error {
+            warn!("Stopping restore-all after the first failure");
+            break;
+        }
+    }
+    // Restores still in flight when we stopped are cancelled
+    for (configmap, node) in unfinished {
+        report.nodes.push(NodeReport {
+            configmap,
+            node: Some(node),
+            result: NodeResult::Skipped {
+                reason: "stopped after an earlier failure".to_string(),
+            },
+        });
+    }
+    report
+        .nodes
+        .sort_by(|a, b| (&a.configmap, &a.node).cmp(&(&b.configmap, &b.node)));
+    Ok(report)
+}
+
+/// The ConfigMap a node's backup would be read from first, to report a failed lookup under
+fn backup_configmap_name(config: &ControllerConfig, node_name: &str) -> String {
+    match config.backup_layout {
+        BackupLayout::PerNode => configmap_name(node_name),
+        BackupLayout::Sharded => shard_configmap_name(node_name),
+    }
+}
+
+/// Skipped reports for the backups in every backup namespace that no node matches: per-node
+/// ConfigMaps under none of the nodes' names, and shard entries under none of their keys
+async fn orphaned_backups(
+    client: Client,
+    config: &ControllerConfig,
+    nodes: &[Node],
+) -> Result<RestoreReport> {
+    let node_names: HashSet<String> = nodes.iter().map(ResourceExt::name_any).collect();
+    let mut names: HashSet<String> = HashSet::new();
+    let mut shard_keys: HashSet<String> = HashSet::new();
+    for node_name in &node_names {
+        names.insert(configmap_name(node_name));
+        names.extend(
+            config
+                .legacy_naming_schemes
+                .iter()
+                .filter_map(|scheme| scheme.configmap_name(node_name)),
+        );
+        shard_keys.insert(shard_key(node_name));
+    }
+    let orphan = |configmap: String| NodeReport {
+        configmap,
+        node: None,
+        result: NodeResult::Skipped {
+            reason: "no node matches this backup".to_string(),
+        },
+    };
+
+    let mut report = RestoreReport::default();
+    for namespace in config.all_backup_namespaces() {
+        let cm_api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+        let backups = cm_api.list(&ListParams::default()).await.map_err(|e| {
+            let namespace = namespace.to_string();
+            Error::from_api(Operation::ListBackups { namespace }, e)
+        })?;
+        for cm in backups.items {
+            let configmap = cm.name_any();
+            if configmap.starts_with(SHARD_CONFIGMAP_PREFIX) {
+                let entries = cm.data.unwrap_or_default().into_keys();
+                let orphans = entries.filter(|key| !shard_keys.contains(key)).count();
+                report
+                    .nodes
+                    .extend((0..orphans).map(|_| orphan(configmap.clone())));
+                continue;
+            }
+            let owner = cm.annotations().get(NODE_NAME_KEY);
+            if configmap.starts_with(BACKUP_CONFIGMAP_PREFIX)
+                && !names.contains(&configmap)
+                && !owner.is_some_and(|owner| node_names.contains(owner))
+            {
+                report.nodes.push(orphan(configmap));
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn restore_one(
+    node_api: &Api<Node>,
+    configmap: String,
+    node: Node,
+    backup: Backup,
+    config: &ControllerConfig,
+) -> NodeReport {
+    let node_name = node.name_any();
+    if backup.labels.is_empty() {
+        return NodeReport {
+            configmap,
+            node: Some(node_name),
+            result: NodeResult::Skipped {
+                reason: "backup has no labels".to_string(),
+            },
+        };
+    }
+    let result = restore_backup(node_api, &node, backup, config).await;
+    node_report(configmap, node_name, result)
+}
+
+fn node_report(configmap: String, node_name: String, result: Result<RestoreCounts>) -> NodeReport {
+    let result = match result {
+        Ok(counts) => NodeResult::Restored { counts },
+        Err(e) => {
+            warn!("Failed to restore node '{}': {}", node_name, e);
+            NodeResult::Failed {
+                error: e.to_string(),
+            }
+        }
+    };
+    NodeReport {
+        configmap,
+        node: Some(node_name),
+        result,
+    }
+}
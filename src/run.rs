@@ -0,0 +1,484 @@
+//! Running the controller until it stops, and reporting why it did with a stable exit code
+
+use crate::{
+    admin, error_policy, layout::is_backup_configmap, metrics::Totals, reconcile,
+    release_out_of_scope_finalizers, sweep_finalizers, write_status, ClientFactory, Context,
+    ControllerConfig, Error, LeaderElector,
+};
+use futures::{Future, FutureExt, StreamExt};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, ResourceExt},
+    runtime::{
+        controller,
+        reflector::{self, store::Writer},
+        watcher, Controller, WatchStreamExt,
+    },
+    Client,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, warn};
+
+/// Shut down on request
+pub const EXIT_CLEAN: i32 = 0;
+/// The configuration is invalid, e.g. a malformed selector or kubeconfig. Matches the exit code
+/// of invalid flags.
+pub const EXIT_CONFIG: i32 = 2;
+/// A check against the cluster failed before the controller started, e.g. missing RBAC
+pub const EXIT_PREFLIGHT: i32 = 3;
+/// Watching nodes failed in a way retrying doesn't fix, see [`WATCH_FAILURE_TIMEOUT`]
+pub const EXIT_WATCH_FAILED: i32 = 4;
+/// Another replica took over the leader election lease, or it couldn't be renewed
+pub const EXIT_LOST_LEADERSHIP: i32 = 5;
+
+/// Watch errors without a successful reconcile in between for this long are unrecoverable
+pub const WATCH_FAILURE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Watch errors further apart than this are separate failures rather than one ongoing one
+const WATCH_FAILURE_GAP: Duration = Duration::from_secs(2 * 60);
+/// How long to wait for the backup cache's initial list before starting without it. Reads miss
+/// the cache until it is filled, so they still work, only at the API server's expense.
+const BACKUP_CACHE_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why the controller stopped
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", content = "message", rename_all = "snake_case")]
+pub enum ShutdownReason {
+    Clean,
+    Config(String),
+    Preflight(String),
+    WatchFailed(String),
+    LostLeadership(String),
+}
+
+impl ShutdownReason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Clean => EXIT_CLEAN,
+            Self::Config(_) => EXIT_CONFIG,
+            Self::Preflight(_) => EXIT_PREFLIGHT,
+            Self::WatchFailed(_) => EXIT_WATCH_FAILED,
+            Self::LostLeadership(_) => EXIT_LOST_LEADERSHIP,
+        }
+    }
+
+    /// A startup error, as a configuration error if fixing the configuration fixes it
+    fn startup(error: Error) -> Self {
+        match error {
+            Error::ClientConfig(_)
+            | Error::InvalidNodeName(_)
+            | Error::MissingNamespace(_)
+            | Error::InvalidSelector(_)
+            | Error::InvalidUserAgent(..)
+            | Error::InvalidEncryption(_) => Self::Config(error.to_string()),
+            error => Self::Preflight(error.to_string()),
+        }
+    }
+}
+
+/// How a run ended and what it did
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Shutdown {
+    #[serde(flatten)]
+    pub reason: ShutdownReason,
+    pub exit_code: i32,
+    pub uptime_seconds: u64,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+impl Shutdown {
+    fn new(reason: ShutdownReason, started: Instant, context: Option<&Context>) -> Self {
+        Self {
+            exit_code: reason.exit_code(),
+            reason,
+            uptime_seconds: started.elapsed().as_secs(),
+            totals: context.map(|ctx| ctx.metrics.totals()).unwrap_or_default(),
+        }
+    }
+
+    /// Log the final record supervisors parse, with every field of the summary
+    pub fn log(&self) {
+        let summary = serde_json::to_string(self).unwrap_or_default();
+        let Totals {
+            reconciles,
+            restores,
+            backups,
+            errors,
+        } = self.totals;
+        if self.reason == ShutdownReason::Clean {
+            info!(
+                exit_code = self.exit_code,
+                uptime_seconds = self.uptime_seconds,
+                reconciles,
+                restores,
+                backups,
+                errors,
+                "Controller stopped: {}",
+                summary
+            );
+        } else {
+            error!(
+                exit_code = self.exit_code,
+                uptime_seconds = self.uptime_seconds,
+                reconciles,
+                restores,
+                backups,
+                errors,
+                "Controller stopped: {}",
+                summary
+            );
+        }
+    }
+}
+
+/// Whether a watch error will keep happening however often it is retried
+fn is_unrecoverable(error: &watcher::Error) -> bool {
+    let code = match error {
+        watcher::Error::InitialListFailed(kube::Error::Api(response))
+        | watcher::Error::WatchStartFailed(kube::Error::Api(response))
+        | watcher::Error::WatchFailed(kube::Error::Api(response)) => response.code,
+        watcher::Error::WatchError(response) => response.code,
+        watcher::Error::NoResourceVersion => return true,
+        _ => return false,
+    };
+    matches!(code, 401 | 403 | 404)
+}
+
+/// Run the controller with clients from `clients` until `shutdown` completes or it fails, then
+/// log and return why it stopped. The admin API is served on `admin_addr` if given.
+pub async fn run(
+    clients: &ClientFactory,
+    config: ControllerConfig,
+    admin_addr: Option<SocketAddr>,
+    shutdown: impl Future<Output = ()> + Send + Sync + 'static,
+) -> Shutdown {
+    let started = Instant::now();
+    let (reason, context) = run_until_stopped(clients, config, admin_addr, shutdown).await;
+    let shutdown = Shutdown::new(reason, started, context.as_deref());
+    shutdown.log();
+    shutdown
+}
+
+async fn run_until_stopped(
+    clients: &ClientFactory,
+    config: ControllerConfig,
+    admin_addr: Option<SocketAddr>,
+    shutdown: impl Future<Output = ()> + Send + Sync + 'static,
+) -> (ShutdownReason, Option<Arc<Context>>) {
+    let started_at = Utc::now();
+    if let Err(e) = config.validate_node_names() {
+        return (ShutdownReason::startup(e), None);
+    }
+    if let Err(e) = config.validate_encryption() {
+        return (ShutdownReason::startup(e), None);
+    }
+    let client = match clients.client().await {
+        Ok(client) => client,
+        Err(e) => return (ShutdownReason::startup(e), None),
+    };
+    if let Some(names) = &config.node_names {
+        info!("Managing only nodes: {:?}", names);
+    }
+    if let Err(e) = config.validate_selectors(client.clone()).await {
+        return (ShutdownReason::startup(e), None);
+    }
+    if let Err(e) = config.validate_namespace(client.clone()).await {
+        return (ShutdownReason::startup(e), None);
+    }
+    let context = Arc::new(Context::with_config(client.clone(), config));
+    let config = &context.config;
+
+    if let Some(admin_addr) = admin_addr {
+        let listener = match tokio::net::TcpListener::bind(admin_addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                let message = format!("Failed to serve the admin API on {}: {}", admin_addr, e);
+                return (ShutdownReason::Preflight(message), Some(context));
+            }
+        };
+        info!("Serving admin API on {}", admin_addr);
+        let admin_router = admin::router(context.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, admin_router).await {
+                warn!("Admin API stopped: {:?}", e);
+            }
+        });
+    }
+
+    // Shared so waiting for the lease and the controller can both stop on it
+    let shutdown = shutdown.shared();
+    let elector = config
+        .leader_election
+        .as_ref()
+        .map(|election| LeaderElector::new(client.clone(), election, &config.instance_id));
+    if let Some(elector) = &elector {
+        // A follower is ready and live while it keeps reaching the API server for the lease
+        let waiting = elector.acquire(|| {
+            context.mark_ready();
+            context.health.record_activity(Instant::now());
+        });
+        tokio::select! {
+            _ = waiting => {}
+            _ = shutdown.clone() => return (ShutdownReason::Clean, Some(context)),
+        }
+    }
+    let reason = run_controller(
+        client,
+        context.clone(),
+        started_at,
+        shutdown,
+        elector.as_ref(),
+    )
+    .await;
+    // A lost lease is held by another replica already
+    let lost = matches!(reason, ShutdownReason::LostLeadership(_));
+    if let Some(elector) = elector.as_ref().filter(|_| !lost) {
+        if let Err(e) = elector.release().await {
+            warn!("Failed to release the leader election lease: {}", e);
+        }
+    }
+    (reason, Some(context))
+}
+
+/// Run the controller until `shutdown` completes or it fails, renewing the lease held by
+/// `elector` if any
+async fn run_controller(
+    client: Client,
+    context: Arc<Context>,
+    started_at: DateTime<Utc>,
+    shutdown: impl Future<Output = ()> + Clone + Send + Sync + 'static,
+    elector: Option<&LeaderElector>,
+) -> ShutdownReason {
+    let config = &context.config;
+    if !config.dry_run {
+        if let Err(e) = release_out_of_scope_finalizers(client.clone(), config).await {
+            return ShutdownReason::startup(e);
+        }
+        write_status(client.clone(), config, started_at).await;
+    }
+    info!(
+        "Starting Node Label Preserver controller, storing in namespace {} with the '{}' merge strategy...",
+        config.namespace, config.merge_strategy
+    );
+    let unbounded = || "unbounded".to_string();
+    info!(
+        "Reconciling at most {} nodes and writing at most {} backups at once, watching with page size {} and timeout {}",
+        Some(config.concurrency)
+            .filter(|concurrency| *concurrency > 0)
+            .map_or_else(unbounded, |concurrency| concurrency.to_string()),
+        config
+            .max_concurrent_writes
+            .map_or_else(unbounded, |writes| writes.to_string()),
+        config
+            .watch_page_size
+            .map_or("default".to_string(), |size| size.to_string()),
+        config
+            .watch_timeout
+            .map_or("default".to_string(), |timeout| format!("{}s", timeout.as_secs()))
+    );
+    if config.snapshot_mode {
+        info!(
+            "Snapshot mode: no finalizer is added, so node deletion never waits on this controller, \
+            but labels changed while it is down, or just before a node is deleted, may not be backed up"
+        );
+    }
+    let lost_leadership = async {
+        match elector {
+            Some(elector) => elector.hold().await,
+            None => futures::future::pending().await,
+        }
+    };
+    futures::pin_mut!(lost_leadership);
+
+    // A cache per namespace, since a relist of one namespace replaces the whole cache
+    let (backup_caches, cache_writers): (Vec<_>, HashMap<_, _>) = config
+        .all_backup_namespaces()
+        .into_iter()
+        .filter(|_| config.backup_cache)
+        .map(|namespace| {
+            let (cache, writer) = reflector::store();
+            (
+                (namespace.to_string(), cache),
+                (namespace.to_string(), writer),
+            )
+        })
+        .unzip();
+    let cache_failed = Arc::new(Notify::new());
+    let backup_watch = tokio::spawn(watch_backups(
+        client.clone(),
+        context.clone(),
+        cache_writers,
+        cache_failed.clone(),
+    ));
+    if config.backup_cache {
+        for (namespace, cache) in &backup_caches {
+            context.set_backup_cache(namespace, cache.clone());
+        }
+        let filled = futures::future::join_all(
+            backup_caches
+                .iter()
+                .map(|(_, cache)| cache.wait_until_ready()),
+        );
+        // Before the Controller starts, so the first restores don't all miss the cache. The
+        // lease is renewed meanwhile, and a failing watch doesn't hold up the start.
+        let unfilled = tokio::select! {
+            ready = filled => ready.iter().any(Result::is_err),
+            _ = cache_failed.notified() => true,
+            _ = tokio::time::sleep(BACKUP_CACHE_SYNC_TIMEOUT) => true,
+            _ = shutdown.clone() => {
+                backup_watch.abort();
+                return ShutdownReason::Clean;
+            }
+            reason = &mut lost_leadership => {
+                backup_watch.abort();
+                return ShutdownReason::LostLeadership(reason);
+            }
+        };
+        if unfilled {
+            warn!("Backup cache not filled, reading backups from the API server until it is");
+        } else {
+            let cached: usize = backup_caches.iter().map(|(_, cache)| cache.len()).sum();
+            info!("Backup cache filled with {} ConfigMaps", cached);
+        }
+    }
+    // Snapshot mode and dry runs never add the finalizer, so every node would lack it
+    let finalizer_sweep = config
+        .finalizer_sweep_interval
+        .filter(|_| !config.snapshot_mode && !config.dry_run)
+        .map(|interval| tokio::spawn(sweep_periodically(context.clone(), interval)));
+    let reconcile_requests = context
+        .reconcile_requests()
+        .expect("reconcile requests are only taken once");
+    let node_api: Api<Node> = Api::all(client);
+    let controller =
+        Controller::new(node_api, config.watcher_config()).with_config(config.reconciler_config());
+    let store = controller.store();
+    context.set_node_store(store.clone());
+    let ready_context = context.clone();
+    let readiness = tokio::spawn(async move {
+        if store.wait_until_ready().await.is_ok() {
+            info!("Node watch completed its initial list");
+            ready_context.mark_ready();
+        }
+    });
+    let mut results = controller
+        .reconcile_on(reconcile_requests)
+        .graceful_shutdown_on(shutdown)
+        .run(reconcile, error_policy, context.clone())
+        .boxed();
+
+    let reason = 'watching: {
+        // When the current streak of watch errors started, and when the last one happened
+        let mut watch_failing: Option<(Instant, Instant)> = None;
+        loop {
+            let result = tokio::select! {
+                result = results.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                reason = &mut lost_leadership => {
+                    break 'watching ShutdownReason::LostLeadership(reason);
+                }
+            };
+            match result {
+                Ok((obj, _action)) => {
+                    info!("Reconciled Node '{}'", obj.name);
+                    watch_failing = None;
+                }
+                Err(controller::Error::QueueError(e)) => {
+                    warn!("Watch error: {:?}", e);
+                    let now = Instant::now();
+                    let since = match watch_failing {
+                        Some((since, last)) if now - last < WATCH_FAILURE_GAP => since,
+                        _ => now,
+                    };
+                    watch_failing = Some((since, now));
+                    if is_unrecoverable(&e) || now - since >= WATCH_FAILURE_TIMEOUT {
+                        break 'watching ShutdownReason::WatchFailed(e.to_string());
+                    }
+                }
+                Err(e) => warn!("Reconciliation error: {:?}", e),
+            }
+        }
+        ShutdownReason::Clean
+    };
+    backup_watch.abort();
+    readiness.abort();
+    if let Some(finalizer_sweep) = finalizer_sweep {
+        finalizer_sweep.abort();
+    }
+    reason
+}
+
+/// Report nodes missing our finalizer every `interval`, see [`sweep_finalizers`]
+async fn sweep_periodically(context: Arc<Context>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match sweep_finalizers(&context).await {
+            Ok(0) => debug!("Finalizer sweep found every node in scope holding our finalizer"),
+            Ok(missing) => warn!(
+                "Finalizer sweep found {} nodes missing our finalizer, reconciling them",
+                missing
+            ),
+            Err(e) => warn!("Finalizer sweep failed: {}", e),
+        }
+    }
+}
+
+/// Keep the backup digests current with changes to backup ConfigMaps made by anyone else, and
+/// the cache of each backup namespace in `caches` with the backup ConfigMaps themselves. Watch
+/// errors are reported to `failed`.
+async fn watch_backups(
+    client: Client,
+    context: Arc<Context>,
+    mut caches: HashMap<String, Writer<ConfigMap>>,
+    failed: Arc<Notify>,
+) {
+    let watches = context
+        .config
+        .all_backup_namespaces()
+        .into_iter()
+        .map(|namespace| {
+            let cm_api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+            let namespace = namespace.to_string();
+            watcher::watcher(cm_api, context.config.backup_watcher_config())
+                .default_backoff()
+                .map(move |event| (namespace.clone(), event))
+                .boxed()
+        });
+    let mut events = futures::stream::select_all(watches);
+    while let Some((namespace, event)) = events.next().await {
+        match event {
+            Ok(event) => {
+                let backup = match &event {
+                    watcher::Event::Apply(cm)
+                    | watcher::Event::InitApply(cm)
+                    | watcher::Event::Delete(cm) => is_backup_configmap(&cm.name_any()),
+                    watcher::Event::Init | watcher::Event::InitDone => true,
+                };
+                if let Some(cache) = caches.get_mut(&namespace).filter(|_| backup) {
+                    cache.apply_watcher_event(&event);
+                }
+                context.observe_backup(&event);
+            }
+            Err(e) => {
+                warn!(
+                    "Backup ConfigMap watch error, dropping every backup digest: {}",
+                    e
+                );
+                context.backup_digests().clear();
+                failed.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! The backup format stored in each node's ConfigMap
+
+use crate::{
+    configmap_name,
+    naming::{node_name_label_value, NODE_NAME_KEY},
+    types::PreservedTaint,
+    EncryptionKeys, Error, LabelExpiry, Operation, Result, CORRELATION_ID_KEY,
+};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, ListParams, ResourceExt},
+    Client,
+};
+use serde::{de::DeserializeOwned, de::Error as _, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// The backup payload format written by this version. Bump on any change to the stored keys.
+/// Version 1 had no version key and no preserved-at map. Version 2 had no machine identity.
+/// Version 3 had no annotations. Version 4 had no taints. Version 5 never split values into
+/// chunks. Version 6 had no backup time. Version 7 never encrypted labels.
+pub const SCHEMA_VERSION: u32 = 8;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const JSON_STORAGE_KEY: &str = "preserved_labels_json";
+const ANNOTATIONS_KEY: &str = "preserved_annotations_json";
+const TAINTS_KEY: &str = "preserved_taints_json";
+/// JSON map of label key to the RFC 3339 time it was preserved
+const PRESERVED_AT_KEY: &str = "preserved_at_json";
+/// The RFC 3339 time the backup was written
+const BACKED_UP_AT_KEY: &str = "preserved_at";
+/// The identity of the machine behind the node when it was backed up, see [`MachineIdentity`]
+const PROVIDER_ID_KEY: &str = "provider_id";
+const MACHINE_ID_KEY: &str = "machine_id";
+/// The cipher the labels are encrypted with, if they are, see [`crate::EncryptionKeys`]
+pub(crate) const CIPHER_KEY: &str = "labels_cipher";
+/// The binaryData key holding encrypted labels: the nonce followed by the ciphertext
+pub(crate) const SEALED_LABELS_KEY: &str = "preserved_labels_json.sealed";
+/// A JSON value longer than the chunk size is stored as `<key>_0`, `<key>_1`, ..., with the
+/// number of chunks under `<key>_chunks`, so no single value is too long to inspect
+const CHUNKS_SUFFIX: &str = "_chunks";
+
+/// Identifies the machine behind a node, which can change while the node name stays the same
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineIdentity {
+    /// `spec.providerID`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_id: Option<String>,
+    /// `status.nodeInfo.machineID`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+}
+
+impl MachineIdentity {
+    pub fn of(node: &Node) -> Self {
+        let non_empty = |id: &String| (!id.is_empty()).then(|| id.clone());
+        Self {
+            provider_id: node
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.provider_id.as_ref())
+                .and_then(non_empty),
+            machine_id: node
+                .status
+                .as_ref()
+                .and_then(|status| status.node_info.as_ref())
+                .and_then(|info| non_empty(&info.machine_id)),
+        }
+    }
+
+    /// Whether `other` is a different machine. Only IDs known on both sides are compared, since
+    /// old backups have none and a new node may not have reported them yet.
+    pub fn differs_from(&self, other: &Self) -> bool {
+        let differs =
+            |a: &Option<String>, b: &Option<String>| matches!((a, b), (Some(a), Some(b)) if a != b);
+        differs(&self.provider_id, &other.provider_id)
+            || differs(&self.machine_id, &other.machine_id)
+    }
+}
+
+/// A step taken to shrink a backup that would otherwise be too large to store
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Degradation {
+    /// Labels beyond the label cap were dropped
+    CappedLabels { dropped: usize },
+    /// Every annotation was dropped, since labels matter more
+    DroppedAnnotations { dropped: usize },
+}
+
+/// The serialized size of ConfigMap data in bytes
+pub fn payload_size(data: &BTreeMap<String, String>) -> usize {
+    serde_json::to_vec(data).map_or(usize::MAX, |bytes| bytes.len())
+}
+
+/// Why a label is not a valid Kubernetes label, see [`validate_label`]
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum LabelValidationError {
+    /// The part of the key before the '/' is not a DNS subdomain of at most 253 characters
+    #[error("key prefix '{0}' is not a DNS subdomain")]
+    InvalidPrefix(String),
+    /// The key without its prefix is not 1 to 63 alphanumerics, '-', '_', or '.', starting and
+    /// ending with an alphanumeric
+    #[error("key name '{0}' is not a valid label name")]
+    InvalidName(String),
+    /// The value is neither empty nor a valid label name
+    #[error("value '{0}' is not a valid label value")]
+    InvalidValue(String),
+}
+
+/// Check `key=value` against the Kubernetes label rules. The API server rejects a whole patch
+/// over one invalid label, so restores leave such labels out.
+pub fn validate_label(key: &str, value: &str) -> std::result::Result<(), LabelValidationError> {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+    if let Some(prefix) = prefix {
+        if prefix.is_empty() || prefix.len() > 253 || !prefix.split('.').all(is_dns_label) {
+            return Err(LabelValidationError::InvalidPrefix(prefix.to_string()));
+        }
+    }
+    if name.len() > 63 || !is_label_name(name) {
+        return Err(LabelValidationError::InvalidName(name.to_string()));
+    }
+    if value.len() > 63 || !(value.is_empty() || is_label_name(value)) {
+        return Err(LabelValidationError::InvalidValue(value.to_string()));
+    }
+    Ok(())
+}
+
+/// Why `key=value` is not a valid Kubernetes label, or None if it is, see [`validate_label`]
+pub fn label_error(key: &str, value: &str) -> Option<String> {
+    validate_label(key, value).err().map(|e| e.to_string())
+}
+
+/// Why `name` is not a valid node name, or None if it is. Node names are DNS subdomains: at
+/// most 253 characters of dot-separated DNS labels.
+pub fn node_name_error(name: &str) -> Option<String> {
+    if name.is_empty() || name.len() > 253 {
+        return Some(format!(
+            "node name of {} characters is not 1 to 253 characters long",
+            name.len()
+        ));
+    }
+    if !name.split('.').all(is_dns_label) {
+        return Some(format!("node name '{}' is not a DNS subdomain", name));
+    }
+    None
+}
+
+/// The backup ConfigMap of `node_name` in `namespace`, or None if it has none. Found by its
+/// node name label, falling back to the hashed name for ConfigMaps written before they were
+/// labeled.
+pub async fn find_configmap_for_node(
+    client: Client,
+    namespace: &str,
+    node_name: &str,
+) -> Result<Option<ConfigMap>> {
+    let cm_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let selector = format!("{}={}", NODE_NAME_KEY, node_name_label_value(node_name));
+    let labeled = cm_api
+        .list(&ListParams::default().labels(&selector))
+        .await
+        .map_err(|e| {
+            let namespace = namespace.to_string();
+            Error::from_api(Operation::ListBackups { namespace }, e)
+        })?;
+    // A truncated label value is shared by every name with the same prefix and hash prefix, so
+    // the full name in the annotation decides
+    let found = labeled
+        .items
+        .into_iter()
+        .find(|cm| cm.annotations().get(NODE_NAME_KEY).map(String::as_str) == Some(node_name));
+    if found.is_some() {
+        return Ok(found);
+    }
+    cm_api
+        .get_opt(&configmap_name(node_name))
+        .await
+        .map_err(|e| {
+            let namespace = namespace.to_string();
+            Error::from_api(Operation::ReadBackup { namespace }, e)
+        })
+}
+
+/// Alphanumerics, '-', '_', and '.', starting and ending with an alphanumeric
+fn is_label_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Lowercase alphanumerics and '-', starting and ending with an alphanumeric
+fn is_dns_label(label: &str) -> bool {
+    label.len() <= 63
+        && label.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && label.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// The labels, and optionally annotations and taints, preserved for a node, as stored in its
+/// ConfigMap
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Backup {
+    /// The payload format the backup was read from
+    pub schema_version: u32,
+    pub labels: BTreeMap<String, String>,
+    /// Empty before version 4, and unless annotations are preserved, see
+    /// [`crate::ControllerConfig::preserve_annotations`]
+    pub annotations: BTreeMap<String, String>,
+    /// Empty before version 5, and unless taints are preserved, see
+    /// [`crate::ControllerConfig::preserve_taints`]
+    pub taints: Vec<PreservedTaint>,
+    /// When each label was preserved. Empty for version 1 backups.
+    pub preserved_at: BTreeMap<String, DateTime<Utc>>,
+    /// When the backup was written. `None` before version 7.
+    pub backed_up_at: Option<DateTime<Utc>>,
+    pub correlation_id: Option<String>,
+    /// The machine the labels were preserved from. Empty before version 3.
+    pub identity: MachineIdentity,
+}
+
+impl Backup {
+    /// The hex SHA-256 of the backed up labels serialized as JSON, in key order. A backup
+    /// without labels has a digest too, so restoring it is recorded like any other.
+    pub fn labels_digest(&self) -> String {
+        let json = serde_json::to_string(&self.labels).expect("labels always serialize");
+        hex::encode(Sha256::digest(json))
+    }
+
+    /// Read a backup from its ConfigMap, decrypting its labels with `keys` if they are
+    /// encrypted
+    pub fn from_configmap(configmap: &ConfigMap, keys: &EncryptionKeys) -> Result<Self> {
+        if configmap.data.is_none() {
+            return Ok(Self::default());
+        }
+        Self::from_configmap_data(&keys.open(configmap)?)
+    }
+
+    /// Read a backup from ConfigMap data. Backups without a version key are version 1.
+    pub fn from_configmap_data(data: &BTreeMap<String, String>) -> Result<Self> {
+        let schema_version = match data.get(SCHEMA_VERSION_KEY) {
+            Some(version) => version
+                .parse()
+                .map_err(|_| Error::InvalidSchemaVersion(version.clone()))?,
+            None => 1,
+        };
+        let labels = read_json(data, JSON_STORAGE_KEY)?;
+        let annotations = read_json(data, ANNOTATIONS_KEY)?;
+        let taints = read_json(data, TAINTS_KEY)?;
+        let preserved_at = read_json(data, PRESERVED_AT_KEY)?;
+        let backed_up_at = data
+            .get(BACKED_UP_AT_KEY)
+            .map(|time| DateTime::parse_from_rfc3339(time).map(|time| time.to_utc()))
+            .transpose()
+            .map_err(|e| {
+                serde_json::Error::custom(format!("invalid {}: {}", BACKED_UP_AT_KEY, e))
+            })?;
+        Ok(Self {
+            schema_version,
+            labels,
+            annotations,
+            taints,
+            preserved_at,
+            backed_up_at,
+            correlation_id: data.get(CORRELATION_ID_KEY).cloned(),
+            identity: MachineIdentity {
+                provider_id: data.get(PROVIDER_ID_KEY).cloned(),
+                machine_id: data.get(MACHINE_ID_KEY).cloned(),
+            },
+        })
+    }
+
+    /// Serialize the backup as ConfigMap data in the current schema version, with every value in
+    /// one piece
+    pub fn to_configmap_data(&self) -> Result<BTreeMap<String, String>> {
+        self.to_configmap_data_chunked(usize::MAX)
+    }
+
+    /// Serialize the backup as ConfigMap data in the current schema version, splitting JSON
+    /// values longer than `chunk_bytes` into chunks
+    pub fn to_configmap_data_chunked(
+        &self,
+        chunk_bytes: usize,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut data = BTreeMap::new();
+        data.insert(SCHEMA_VERSION_KEY.to_string(), SCHEMA_VERSION.to_string());
+        if let Some(correlation_id) = &self.correlation_id {
+            data.insert(CORRELATION_ID_KEY.to_string(), correlation_id.clone());
+        }
+        if let Some(backed_up_at) = &self.backed_up_at {
+            data.insert(BACKED_UP_AT_KEY.to_string(), backed_up_at.to_rfc3339());
+        }
+        if let Some(provider_id) = &self.identity.provider_id {
+            data.insert(PROVIDER_ID_KEY.to_string(), provider_id.clone());
+        }
+        if let Some(machine_id) = &self.identity.machine_id {
+            data.insert(MACHINE_ID_KEY.to_string(), machine_id.clone());
+        }
+        let mut insert_json = |key: &str, json: String| {
+            insert_chunked(&mut data, key, json, chunk_bytes);
+        };
+        if !self.labels.is_empty() {
+            insert_json(JSON_STORAGE_KEY, serde_json::to_string(&self.labels)?);
+            insert_json(PRESERVED_AT_KEY, serde_json::to_string(&self.preserved_at)?);
+        }
+        if !self.annotations.is_empty() {
+            insert_json(ANNOTATIONS_KEY, serde_json::to_string(&self.annotations)?);
+        }
+        if !self.taints.is_empty() {
+            insert_json(TAINTS_KEY, serde_json::to_string(&self.taints)?);
+        }
+        Ok(data)
+    }
+
+    /// Serialize the backup as ConfigMap data no larger than `max_bytes`, degrading it step by
+    /// step until it fits. A backup the API server rejects would block node deletion at the
+    /// worst possible time, so a smaller backup is better than none. Returns the steps taken,
+    /// or [`Error::BackupTooLarge`] if even the most degraded payload doesn't fit. JSON values
+    /// longer than `chunk_bytes` are split into chunks.
+    pub fn to_configmap_data_within(
+        &self,
+        max_bytes: usize,
+        max_labels: usize,
+        chunk_bytes: usize,
+    ) -> Result<(BTreeMap<String, String>, Vec<Degradation>)> {
+        let mut degradations = Vec::new();
+        let data = self.to_configmap_data_chunked(chunk_bytes)?;
+        if payload_size(&data) <= max_bytes {
+            return Ok((data, degradations));
+        }
+
+        // Annotations go first, since labels matter more
+        let mut degraded = self.clone();
+        if !degraded.annotations.is_empty() {
+            degradations.push(Degradation::DroppedAnnotations {
+                dropped: degraded.annotations.len(),
+            });
+            degraded.annotations.clear();
+            let data = degraded.to_configmap_data_chunked(chunk_bytes)?;
+            if payload_size(&data) <= max_bytes {
+                return Ok((data, degradations));
+            }
+        }
+        // Keep the first max_labels keys in key order so repeated attempts keep the same labels
+        if degraded.labels.len() > max_labels {
+            let dropped: Vec<String> = degraded.labels.keys().skip(max_labels).cloned().collect();
+            for key in &dropped {
+                degraded.labels.remove(key);
+                degraded.preserved_at.remove(key);
+            }
+            degradations.push(Degradation::CappedLabels {
+                dropped: dropped.len(),
+            });
+            let data = degraded.to_configmap_data_chunked(chunk_bytes)?;
+            if payload_size(&data) <= max_bytes {
+                return Ok((data, degradations));
+            }
+        }
+        Err(Error::BackupTooLarge {
+            size: payload_size(&degraded.to_configmap_data_chunked(chunk_bytes)?),
+            limit: max_bytes,
+        })
+    }
+
+    /// The labels the API server would reject, with the reason each is invalid
+    pub fn invalid_labels(&self) -> Vec<(String, String)> {
+        self.labels
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), label_error(key, value)?)))
+            .collect()
+    }
+
+    /// The label keys that have outlived their expiry rule at `now`. When several rules match,
+    /// the one with the longest prefix applies. Keys without a matching rule or without a
+    /// preserved-at time never expire.
+    pub fn expired_labels(&self, rules: &[LabelExpiry], now: DateTime<Utc>) -> Vec<String> {
+        self.labels
+            .keys()
+            .filter(|key| {
+                let Some(rule) = rules
+                    .iter()
+                    .filter(|rule| key.starts_with(&rule.prefix))
+                    .max_by_key(|rule| rule.prefix.len())
+                else {
+                    return false;
+                };
+                let Some(preserved_at) = self.preserved_at.get(*key) else {
+                    return false;
+                };
+                let age = (now - *preserved_at).to_std().unwrap_or_default();
+                age >= rule.ttl
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Store `json` under `key`, or split it at character boundaries into chunks of at most
+/// `chunk_bytes` if it is longer, see [`CHUNKS_SUFFIX`]
+fn insert_chunked(
+    data: &mut BTreeMap<String, String>,
+    key: &str,
+    json: String,
+    chunk_bytes: usize,
+) {
+    if json.len() <= chunk_bytes {
+        data.insert(key.to_string(), json);
+        return;
+    }
+    let mut rest = json.as_str();
+    let mut chunks = 0;
+    while !rest.is_empty() {
+        let mut end = chunk_bytes.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // A chunk holds at least one character, however small the chunk size
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        data.insert(format!("{}_{}", key, chunks), rest[..end].to_string());
+        rest = &rest[end..];
+        chunks += 1;
+    }
+    data.insert(format!("{}{}", key, CHUNKS_SUFFIX), chunks.to_string());
+}
+
+/// Remove the labels from ConfigMap data, whole or in chunks, returning their JSON, or None if
+/// there are none
+pub(crate) fn take_labels_json(data: &mut BTreeMap<String, String>) -> Result<Option<String>> {
+    let chunks_key = format!("{}{}", JSON_STORAGE_KEY, CHUNKS_SUFFIX);
+    if !data.contains_key(JSON_STORAGE_KEY) && !data.contains_key(&chunks_key) {
+        return Ok(None);
+    }
+    let labels: BTreeMap<String, String> = read_json(data, JSON_STORAGE_KEY)?;
+    let chunk_prefix = format!("{}_", JSON_STORAGE_KEY);
+    data.retain(|key, _| key != JSON_STORAGE_KEY && !key.starts_with(&chunk_prefix));
+    Ok(Some(serde_json::to_string(&labels)?))
+}
+
+/// Store labels JSON where a backup with unencrypted labels keeps it
+pub(crate) fn insert_labels_json(data: &mut BTreeMap<String, String>, json: String) {
+    data.insert(JSON_STORAGE_KEY.to_string(), json);
+}
+
+/// Parse the JSON value stored under `key`, whole or in chunks, or the default if there is
+/// none. A missing chunk makes the backup unreadable like invalid JSON does.
+fn read_json<T: DeserializeOwned + Default>(
+    data: &BTreeMap<String, String>,
+    key: &str,
+) -> Result<T> {
+    if let Some(json) = data.get(key) {
+        return Ok(serde_json::from_str(json)?);
+    }
+    let Some(chunks) = data.get(&format!("{}{}", key, CHUNKS_SUFFIX)) else {
+        return Ok(T::default());
+    };
+    let chunks: usize = chunks
+        .parse()
+        .map_err(|_| serde_json::Error::custom(format!("'{}' is not a chunk count", chunks)))?;
+    let mut json = String::new();
+    for index in 0..chunks {
+        let chunk_key = format!("{}_{}", key, index);
+        let chunk = data.get(&chunk_key).ok_or_else(|| {
+            serde_json::Error::custom(format!("chunk '{}' of {} is missing", chunk_key, chunks))
+        })?;
+        json.push_str(chunk);
+    }
+    Ok(serde_json::from_str(&json)?)
+}
@@ -0,0 +1,253 @@
+//! Reporting on what the controller is doing: logs, the status ConfigMap, and the User-Agent
+
+use crate::{
+    naming::SERVICE_NAME, ControllerConfig, Error, Operation, Result, STATUS_CONFIGMAP_NAME,
+};
+use http::header::{HeaderValue, USER_AGENT};
+use k8s_openapi::{
+    api::core::v1::ConfigMap,
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, Patch, PatchParams},
+    Client, Config,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{warn, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, registry::LookupSpan, Layer};
+
+/// How log lines are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the event's fields at the top level and the fields of
+    /// the spans it happened in, e.g. the node being reconciled, under `spans`
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::InvalidLogFormat(format.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        };
+        write!(f, "{}", format)
+    }
+}
+
+/// The layer writing log lines in `format` to `writer`
+pub fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+/// How often to repeat the summary while a burst of restores continues
+pub(crate) const BURST_SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Running totals of how the candidate policy would have changed restores
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ShadowStats {
+    /// Restores the candidate policy was evaluated for
+    pub nodes_evaluated: u64,
+    /// Restores the candidate policy would have changed
+    pub nodes_affected: u64,
+    pub keys_newly_restored: u64,
+    pub keys_no_longer_restored: u64,
+}
+
+/// Describes what a running controller is doing: its resolved configuration, identity, leader
+/// election lease and whether it holds it, version, and startup time
+pub fn status_configmap(config: &ControllerConfig, started_at: DateTime<Utc>) -> ConfigMap {
+    let mut data = BTreeMap::new();
+    data.insert(
+        "config".to_string(),
+        serde_json::to_string_pretty(&config.to_json()).expect("config serializes"),
+    );
+    data.insert("instance_id".to_string(), config.instance_id.clone());
+    // Only the replica holding the lease runs the controller, which writes the status
+    let (leader_election, leader) = match &config.leader_election {
+        Some(election) => (
+            format!("{}/{}", election.lease_namespace, election.lease_name),
+            true,
+        ),
+        None => ("disabled".to_string(), false),
+    };
+    data.insert("leader_election".to_string(), leader_election);
+    data.insert("leader".to_string(), leader.to_string());
+    data.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    data.insert("started_at".to_string(), started_at.to_rfc3339());
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(STATUS_CONFIGMAP_NAME.to_string()),
+            namespace: Some(config.namespace.clone()),
+            ..Default::default()
+        },
+        data: Some(data),
+        ..Default::default()
+    }
+}
+
+/// Write the [`status_configmap`]. Failures are logged rather than returned because the status
+/// is informational and must never stop reconciliation.
+pub async fn write_status(client: Client, config: &ControllerConfig, started_at: DateTime<Utc>) {
+    let cm_api = Api::<ConfigMap>::namespaced(client, &config.namespace);
+    let cm = status_configmap(config, started_at);
+    let patch_params = PatchParams::apply(SERVICE_NAME).force();
+    if let Err(e) = cm_api
+        .patch(STATUS_CONFIGMAP_NAME, &patch_params, &Patch::Apply(&cm))
+        .await
+    {
+        let e = Error::from_api(
+            Operation::WriteStatus {
+                namespace: config.namespace.clone(),
+            },
+            e,
+        );
+        warn!(
+            "Failed to write status ConfigMap '{}': {}",
+            STATUS_CONFIGMAP_NAME, e
+        );
+    }
+}
+
+/// The User-Agent we present to the API server so cluster admins can attribute our requests
+/// in audit logs. `instance_id` identifies this replica, and `suffix` lets users embedding the
+/// library append their own identity.
+pub fn user_agent(instance_id: &str, suffix: Option<&str>) -> String {
+    let mut user_agent = format!(
+        "{}/{} (instance={})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        instance_id
+    );
+    if let Some(suffix) = suffix {
+        user_agent.push(' ');
+        user_agent.push_str(suffix);
+    }
+    user_agent
+}
+
+/// Build a Client from `config` that sends our [`user_agent`] with every request
+pub fn client_with_user_agent(
+    mut config: Config,
+    instance_id: &str,
+    suffix: Option<&str>,
+) -> Result<Client> {
+    let user_agent = user_agent(instance_id, suffix);
+    let header_value = HeaderValue::from_str(&user_agent)
+        .map_err(|e| Error::InvalidUserAgent(user_agent.clone(), e.to_string()))?;
+    config.headers.retain(|(name, _)| name != USER_AGENT);
+    config.headers.push((USER_AGENT, header_value));
+    Ok(Client::try_from(config)?)
+}
+
+/// The result of restoring labels onto a node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    /// Every preserved label was restored
+    Restored,
+    /// Labels were restored, but some preserved labels conflicted with existing values
+    Conflict,
+    /// The restore failed and will be retried
+    Failed,
+}
+
+/// Counts of restore outcomes within a burst
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BurstSummary {
+    pub restored: usize,
+    pub conflicts: usize,
+    pub failed: usize,
+}
+
+/// Detects bursts of restores, such as a cluster upgrade recreating every node, so they can be
+/// reported as one summary instead of one log line per node
+pub struct BurstTracker {
+    threshold: usize,
+    window: Duration,
+    state: Mutex<BurstState>,
+}
+
+#[derive(Default)]
+struct BurstState {
+    /// Outcomes within the sliding window, oldest first
+    outcomes: VecDeque<(Instant, RestoreOutcome)>,
+    /// When the current burst was last summarized
+    last_summary: Option<Instant>,
+}
+
+impl BurstTracker {
+    /// Create a tracker where more than `threshold` outcomes within `window` is a burst
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            state: Mutex::new(BurstState::default()),
+        }
+    }
+
+    /// Record an outcome at `now`. Returns a summary when a burst starts, and again at most
+    /// every BURST_SUMMARY_INTERVAL while it continues.
+    pub fn record(&self, outcome: RestoreOutcome, now: Instant) -> Option<BurstSummary> {
+        let mut state = self.state.lock().unwrap();
+        state.outcomes.push_back((now, outcome));
+        while let Some((time, _)) = state.outcomes.front() {
+            if now.duration_since(*time) <= self.window {
+                break;
+            }
+            state.outcomes.pop_front();
+        }
+        if state.outcomes.len() <= self.threshold {
+            state.last_summary = None;
+            return None;
+        }
+        if let Some(last_summary) = state.last_summary {
+            if now.duration_since(last_summary) < BURST_SUMMARY_INTERVAL {
+                return None;
+            }
+        }
+        state.last_summary = Some(now);
+        let mut summary = BurstSummary::default();
+        for (_, outcome) in &state.outcomes {
+            match outcome {
+                RestoreOutcome::Restored => summary.restored += 1,
+                RestoreOutcome::Conflict => summary.conflicts += 1,
+                RestoreOutcome::Failed => summary.failed += 1,
+            }
+        }
+        Some(summary)
+    }
+
+    /// Whether a burst is in progress
+    pub fn in_burst(&self) -> bool {
+        self.state.lock().unwrap().outcomes.len() > self.threshold
+    }
+}
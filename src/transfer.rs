@@ -0,0 +1,201 @@
+//! Carrying every node's preserved labels to another cluster as one JSON snapshot: a map of
+//! node name to labels
+
+use crate::{
+    naming::{BACKUP_CONFIGMAP_PREFIX, NODE_NAME_KEY},
+    node_name_error, preserve_on_cleanup, Backup, BackupLayout, BackupStore, ControllerConfig,
+    Error, Operation, Result,
+};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Node},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::Utc,
+};
+use kube::{
+    api::{Api, ListParams, ResourceExt},
+    Client,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Preserved labels by node name
+pub type LabelSnapshot = BTreeMap<String, BTreeMap<String, String>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Replace backups the target namespace already has for a node
+    pub overwrite: bool,
+    /// Only report what would be written, validated by the API server without persisting it
+    pub dry_run: bool,
+}
+
+/// What an import wrote, sorted by node name
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    /// Nodes whose backup was written, or would be in a dry run
+    pub imported: Vec<String>,
+    /// Nodes left alone because they already have a backup and overwriting is off
+    pub skipped: Vec<String>,
+}
+
+/// The labels preserved in every per-node backup ConfigMap in `namespace`, by node name. The
+/// node name is read from the ConfigMap's node name annotation, or from its node name label if
+/// that wasn't truncated. Backups without either, written before ConfigMaps were labeled, are
+/// left out with a warning.
+pub async fn export_all_backups(client: Client, namespace: &str) -> Result<LabelSnapshot> {
+    let config = ControllerConfig {
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+    export_all_backups_with(client, &config).await
+}
+
+/// [`export_all_backups`] from every namespace `config` keeps backups in, decrypting labels
+/// with its keys. A node backed up in more than one keeps the first, `namespace` first. Shards
+/// only know their nodes by hash, so the sharded layout can't be exported.
+pub async fn export_all_backups_with(
+    client: Client,
+    config: &ControllerConfig,
+) -> Result<LabelSnapshot> {
+    if config.backup_layout == BackupLayout::Sharded {
+        return Err(Error::ShardedExport);
+    }
+    let mut snapshot = LabelSnapshot::new();
+    for namespace in config.all_backup_namespaces() {
+        let cm_api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+        let configmaps = cm_api.list(&ListParams::default()).await.map_err(|e| {
+            let namespace = namespace.to_string();
+            Error::from_api(Operation::ListBackups { namespace }, e)
+        })?;
+        let mut exported = 0;
+        for cm in configmaps.items {
+            let cm_name = cm.name_any();
+            if !cm_name.starts_with(BACKUP_CONFIGMAP_PREFIX) {
+                continue;
+            }
+            let Some(node_name) = exported_node_name(&cm) else {
+                warn!(
+                    "Backup ConfigMap '{}' in namespace '{}' doesn't name its node, not exporting it",
+                    cm_name, namespace
+                );
+                continue;
+            };
+            if snapshot.contains_key(&node_name) {
+                warn!(
+                    "Node '{}' has another backup in namespace '{}', not exporting it",
+                    node_name, namespace
+                );
+                continue;
+            }
+            let backup = Backup::from_configmap(&cm, &config.encryption_keys)?;
+            snapshot.insert(node_name, backup.labels);
+            exported += 1;
+        }
+        info!(
+            "Exported the backups of {} nodes from namespace '{}'",
+            exported, namespace
+        );
+    }
+    Ok(snapshot)
+}
+
+/// The node a backup ConfigMap belongs to, by its annotation, or by its label if that holds the
+/// whole name rather than a truncated one
+fn exported_node_name(cm: &ConfigMap) -> Option<String> {
+    if let Some(node_name) = cm.annotations().get(NODE_NAME_KEY) {
+        return Some(node_name.clone());
+    }
+    cm.labels()
+        .get(NODE_NAME_KEY)
+        .filter(|node_name| crate::configmap_name(node_name) == cm.name_any())
+        .cloned()
+}
+
+/// Write a backup of each node's labels in `snapshot` to `namespace`, the way a node deleted
+/// now would be backed up. Nodes that already have a backup are left alone unless `overwrite`
+/// is set. Every node name is checked before anything is written.
+pub async fn import_backups(
+    client: Client,
+    namespace: &str,
+    snapshot: &LabelSnapshot,
+    overwrite: bool,
+) -> Result<ImportReport> {
+    let config = ControllerConfig {
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+    let options = ImportOptions {
+        overwrite,
+        ..Default::default()
+    };
+    import_backups_with(client, &config, snapshot, options).await
+}
+
+/// [`import_backups`] as `config` backs nodes up: filtered, encrypted, and laid out the same,
+/// in the namespace each node's labels map to, optionally as a dry run. A node already has a
+/// backup if one is found in any namespace it is looked for in.
+pub async fn import_backups_with(
+    client: Client,
+    config: &ControllerConfig,
+    snapshot: &LabelSnapshot,
+    options: ImportOptions,
+) -> Result<ImportReport> {
+    for node_name in snapshot.keys() {
+        if let Some(e) = node_name_error(node_name) {
+            return Err(Error::InvalidSnapshotNode(node_name.clone(), e));
+        }
+    }
+    let store = |namespace: &str| {
+        BackupStore::new(client.clone(), namespace, config.backup_layout)
+            .with_encryption(config.encryption_keys.clone())
+            .with_dry_run(options.dry_run)
+    };
+    let mut report = ImportReport::default();
+    for (node_name, labels) in snapshot {
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some(node_name.clone()),
+                labels: Some(labels.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut existing = None;
+        for namespace in config.backup_namespaces(&node) {
+            if store(namespace).read(node_name).await?.is_some() {
+                existing = Some(namespace);
+                break;
+            }
+        }
+        if let Some(namespace) = existing.filter(|_| !options.overwrite) {
+            info!(
+                "Node '{}' already has a backup in namespace '{}', not importing it",
+                node_name, namespace
+            );
+            report.skipped.push(node_name.clone());
+            continue;
+        }
+        let correlation_id = Uuid::new_v4().to_string();
+        let preservation = preserve_on_cleanup(&node, config, &correlation_id, Utc::now())?;
+        let written = store(config.backup_namespace(&node))
+            .write(node_name, &preservation.configmap)
+            .await?;
+        info!(
+            "{} {} labels for node '{}' into ConfigMap '{}' in namespace '{}' (correlation ID {})",
+            if options.dry_run {
+                "Dry run: would import"
+            } else {
+                "Imported"
+            },
+            preservation.backup.labels.len(),
+            node_name,
+            written.name_any(),
+            config.backup_namespace(&node),
+            correlation_id
+        );
+        report.imported.push(node_name.clone());
+    }
+    Ok(report)
+}
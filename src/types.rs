@@ -0,0 +1,139 @@
+//! The preserved state of a node as a stable JSON schema, shared by everything that shows or
+//! moves backups outside their ConfigMap. Fields are only ever added, each with a default, so
+//! older payloads keep deserializing. Removing or renaming a field requires bumping
+//! [`SCHEMA_VERSION`].
+
+use crate::{Backup, MachineIdentity, Result, SCHEMA_VERSION};
+use k8s_openapi::{
+    api::core::v1::Taint,
+    chrono::{DateTime, Utc},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Everything preserved for a node. `annotations` and `taints` are empty in states read from a
+/// ConfigMap unless they are preserved.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PreservedState {
+    /// The backup payload format the state was read from. Payloads without one are version 1.
+    #[serde(default = "first_version")]
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub taints: Vec<PreservedTaint>,
+    /// When each label was preserved
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub preserved_at: BTreeMap<String, DateTime<Utc>>,
+    /// When the state was backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backed_up_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// The machine the state was preserved from
+    #[serde(default)]
+    pub identity: MachineIdentity,
+}
+
+/// A preserved node taint. Taints are identified by key and effect, like the API server does.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreservedTaint {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    pub effect: String,
+}
+
+impl PreservedTaint {
+    pub fn of(taint: &Taint) -> Self {
+        Self {
+            key: taint.key.clone(),
+            value: taint.value.clone(),
+            effect: taint.effect.clone(),
+        }
+    }
+
+    pub fn to_taint(&self) -> Taint {
+        Taint {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            effect: self.effect.clone(),
+            time_added: None,
+        }
+    }
+
+    /// Whether `taint` has the same key and effect, whatever its value
+    pub fn matches(&self, taint: &Taint) -> bool {
+        self.key == taint.key && self.effect == taint.effect
+    }
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+impl Default for PreservedState {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            node_name: None,
+            labels: BTreeMap::new(),
+            annotations: BTreeMap::new(),
+            taints: Vec::new(),
+            preserved_at: BTreeMap::new(),
+            backed_up_at: None,
+            correlation_id: None,
+            identity: MachineIdentity::default(),
+        }
+    }
+}
+
+impl PreservedState {
+    pub fn from_backup(node_name: Option<String>, backup: &Backup) -> Self {
+        Self {
+            schema_version: backup.schema_version,
+            node_name,
+            labels: backup.labels.clone(),
+            annotations: backup.annotations.clone(),
+            taints: backup.taints.clone(),
+            preserved_at: backup.preserved_at.clone(),
+            backed_up_at: backup.backed_up_at,
+            correlation_id: backup.correlation_id.clone(),
+            identity: backup.identity.clone(),
+        }
+    }
+
+    /// The part of the state a backup ConfigMap stores
+    pub fn to_backup(&self) -> Backup {
+        Backup {
+            schema_version: self.schema_version,
+            labels: self.labels.clone(),
+            annotations: self.annotations.clone(),
+            taints: self.taints.clone(),
+            preserved_at: self.preserved_at.clone(),
+            backed_up_at: self.backed_up_at,
+            correlation_id: self.correlation_id.clone(),
+            identity: self.identity.clone(),
+        }
+    }
+
+    /// Read the state from a backup ConfigMap's data
+    pub fn from_configmap_data(
+        node_name: Option<String>,
+        data: &BTreeMap<String, String>,
+    ) -> Result<Self> {
+        Ok(Self::from_backup(
+            node_name,
+            &Backup::from_configmap_data(data)?,
+        ))
+    }
+
+    /// Serialize the state as backup ConfigMap data in the current schema version
+    pub fn to_configmap_data(&self) -> Result<BTreeMap<String, String>> {
+        self.to_backup().to_configmap_data()
+    }
+}
@@ -3,7 +3,7 @@
 APP_NAME="node-label-preserver"
 
 # Kill the pod on exit
-trap "kubectl delete deployment ${APP_NAME} && kubectl get pods --no-headers -o custom-columns=\":metadata.name\" | grep ${APP_NAME} | xargs -I {} kubectl delete pod {} --grace-period=0 --force --wait=true" EXIT
+trap "kubectl delete deployment ${APP_NAME} --ignore-not-found && kubectl get pods --no-headers -o custom-columns=\":metadata.name\" | grep ${APP_NAME} | xargs -I {} kubectl delete pod {} --grace-period=0 --force --wait=true" EXIT
 
 set -e
 
@@ -30,4 +30,6 @@ kubectl describe pod
 kubectl get pods -l app=$APP_NAME
 # Wait for the pod to be ready
 kubectl wait --for=condition=ready pod -l app=$APP_NAME --timeout=60s
+# The tests run their own controller, so the deployed one must not manage their nodes too
+kubectl delete deployment ${APP_NAME} --wait=true
 cargo test
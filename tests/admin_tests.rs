@@ -0,0 +1,91 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{finalized_node, status_json, MockApiServer};
+    use axum::body::Body;
+    use futures::StreamExt;
+    use http::{Request, StatusCode};
+    use http_body_util::BodyExt;
+
+    use kube::runtime::{controller::Action, reflector::ObjectRef};
+    use label_preserver::{admin, error_policy, reconcile, Context};
+    use std::{sync::Arc, time::Duration};
+    use tower::ServiceExt;
+
+    async fn send(ctx: Arc<Context>, method: &str, uri: &str) -> serde_json::Value {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = admin::router(ctx).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// A node driven into backoff is listed, and resetting it clears the state and triggers an
+    /// immediate reconcile
+    #[tokio::test]
+    async fn test_backoff_reset() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                status_json(500, "etcdserver: request timed out"),
+            )
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut reconcile_requests = ctx.reconcile_requests().unwrap();
+        let node = Arc::new(finalized_node("node-a"));
+
+        for expected_delay in [4, 8] {
+            let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+            assert_eq!(
+                error_policy(node.clone(), &error, ctx.clone()),
+                Action::requeue(Duration::from_secs(expected_delay))
+            );
+        }
+
+        let backoff = send(ctx.clone(), "GET", "/backoff").await;
+        assert_eq!(backoff["node-a"]["attempt"], 2);
+        assert!(backoff["node-a"]["next_retry"].is_string());
+
+        let reset = send(ctx.clone(), "POST", "/backoff/node-a/reset").await;
+        assert_eq!(reset["node"], "node-a");
+        assert_eq!(reset["previous"]["attempt"], 2);
+        assert_eq!(
+            send(ctx.clone(), "GET", "/backoff").await,
+            serde_json::json!({})
+        );
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), reconcile_requests.next())
+                .await
+                .unwrap(),
+            Some(ObjectRef::new("node-a"))
+        );
+
+        // The next failure starts the backoff over
+        let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        assert_eq!(
+            error_policy(node.clone(), &error, ctx.clone()),
+            Action::requeue(Duration::from_secs(4))
+        );
+    }
+
+    /// Resetting a node that isn't in backoff still reconciles it
+    #[tokio::test]
+    async fn test_reset_without_backoff() {
+        let server = MockApiServer::start(|_| (StatusCode::OK, serde_json::json!({}))).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut reconcile_requests = ctx.reconcile_requests().unwrap();
+
+        let reset = send(ctx.clone(), "POST", "/backoff/node-b/reset").await;
+        assert_eq!(reset["previous"], serde_json::Value::Null);
+        assert_eq!(
+            reconcile_requests.next().await,
+            Some(ObjectRef::new("node-b"))
+        );
+    }
+}
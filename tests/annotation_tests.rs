@@ -0,0 +1,186 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::{reflector, watcher};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, RestoredAnnotation, FINALIZER_NAME,
+        RESTORED_ANNOTATION_FORMATS, RESTORED_ANNOTATION_KEY, RESTORED_ANNOTATION_VERSION,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// A value written by a controller newer than this one
+    const FUTURE: &str = r#"{"v":3,"backup":{"correlationId":"corr-1","configMap":"x"}}"#;
+
+    fn node(name: &str, restored: Option<&str>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                annotations: restored.map(|value| {
+                    BTreeMap::from([(RESTORED_ANNOTATION_KEY.to_string(), value.to_string())])
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Every supported format reads its example, and only the newest is still written
+    #[test]
+    fn test_compatibility_table() {
+        for format in RESTORED_ANNOTATION_FORMATS {
+            let parsed = RestoredAnnotation::parse(format.example);
+            assert_eq!(parsed.version, Some(format.version), "{:?}", format);
+            assert_eq!(
+                parsed.correlation_id.as_deref(),
+                Some("5f0c7c4e-8a0b-4d8e-9f3a-2f6f3f1c9b7e")
+            );
+            assert_eq!(
+                format.last_written.is_none(),
+                format.version == RESTORED_ANNOTATION_VERSION
+            );
+            if format.last_written.is_some() {
+                assert!(format.read_until.is_some(), "{:?}", format);
+            }
+        }
+        let newest = RESTORED_ANNOTATION_FORMATS.last().unwrap();
+        assert_eq!(newest.version, RESTORED_ANNOTATION_VERSION);
+        let written = RestoredAnnotation::parse(newest.example).to_value();
+        assert_eq!(written, newest.example);
+    }
+
+    /// Version 1 is the bare correlation ID, with 1 standing for none
+    #[test]
+    fn test_read_version_1() {
+        let parsed = RestoredAnnotation::parse("corr-1");
+        assert_eq!(parsed.version, Some(1));
+        assert!(parsed.is_from(Some("corr-1")));
+        assert!(!parsed.is_from(Some("corr-2")));
+
+        let parsed = RestoredAnnotation::parse("1");
+        assert_eq!(parsed.version, Some(1));
+        assert_eq!(parsed.correlation_id, None);
+        assert!(!parsed.is_from(None));
+    }
+
+    /// Version 2 round-trips, with or without a correlation ID or labels digest, and ignores
+    /// unknown fields
+    #[test]
+    fn test_read_version_2() {
+        for correlation_id in [Some("corr-1"), None] {
+            let annotation = RestoredAnnotation::new(correlation_id);
+            assert_eq!(
+                RestoredAnnotation::parse(&annotation.to_value()),
+                annotation
+            );
+        }
+        let backup = Backup {
+            labels: BTreeMap::from([("zone".to_string(), "a".to_string())]),
+            ..Default::default()
+        };
+        let annotation = RestoredAnnotation::of_backup(&backup);
+        assert_eq!(
+            RestoredAnnotation::parse(&annotation.to_value()),
+            annotation
+        );
+        assert!(!annotation.is_outdated(&backup.labels_digest()));
+        assert!(annotation.is_outdated(&Backup::default().labels_digest()));
+        let parsed = RestoredAnnotation::parse(r#"{"v":2,"correlationId":"corr-1","extra":1}"#);
+        assert_eq!(parsed.version, Some(2));
+        assert!(parsed.is_from(Some("corr-1")));
+    }
+
+    /// Values from newer controllers and corrupt values are read as an unknown format that
+    /// matches no backup
+    #[test]
+    fn test_read_unknown() {
+        for value in [FUTURE, "{not json", r#"{"correlationId":"corr-1"}"#] {
+            let parsed = RestoredAnnotation::parse(value);
+            assert_eq!(parsed.version, None, "{value}");
+            assert!(!parsed.is_from(Some("corr-1")), "{value}");
+        }
+    }
+
+    /// A node restored by a controller of any version, older or newer, is never restored again
+    #[tokio::test]
+    async fn test_restored_by_any_version() {
+        let server =
+            MockApiServer::start(|_| (StatusCode::NOT_FOUND, status_json(404, "not found"))).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let v2 = RestoredAnnotation::new(Some("corr-1")).to_value();
+        for value in ["corr-1", "1", v2.as_str(), FUTURE, "{not json"] {
+            reconcile(Arc::new(node("node-a", Some(value))), ctx.clone())
+                .await
+                .unwrap();
+        }
+        assert!(server.requests().is_empty(), "{:?}", server.requests());
+        assert!(RestoredAnnotation::of(&node("node-a", None)).is_none());
+    }
+
+    /// A node recreated while the old node was cleaned up is only brought up to date when its
+    /// annotation, in any readable format, names the outdated backup
+    #[tokio::test]
+    async fn test_recreated_node_by_version() {
+        let stale = Backup {
+            labels: BTreeMap::from([("zone".to_string(), "a".to_string())]),
+            correlation_id: Some("corr-old".to_string()),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-0"), "namespace": "default" },
+            "data": stale.to_configmap_data().unwrap(),
+        });
+        let v2 = RestoredAnnotation::new(Some("corr-old")).to_value();
+        for (value, updated) in [("corr-old", true), (v2.as_str(), true), (FUTURE, false)] {
+            let configmap = configmap.clone();
+            let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+                (&Method::GET, path) if path.contains("/configmaps/") => {
+                    (StatusCode::OK, configmap.clone())
+                }
+                (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                    (StatusCode::OK, req.json())
+                }
+                (&Method::PATCH, _) => (StatusCode::OK, node_json("node-0")),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            })
+            .await;
+            let ctx = Arc::new(Context::new(server.client()));
+            let (store, mut writer) = reflector::store();
+            ctx.set_node_store(store);
+            let mut recreated = node("node-0", Some(value));
+            recreated.metadata.uid = Some("uid-2".to_string());
+            recreated.metadata.labels = Some(stale.labels.clone());
+            writer.apply_watcher_event(&watcher::Event::Apply(recreated));
+
+            let mut deleted = node("node-0", None);
+            deleted.metadata.uid = Some("uid-1".to_string());
+            deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+            deleted.metadata.labels = Some(BTreeMap::from([("zone".to_string(), "b".to_string())]));
+            reconcile(Arc::new(deleted), ctx).await.unwrap();
+
+            let update = server
+                .requests()
+                .iter()
+                .filter(|r| r.uri.path().starts_with("/api/v1/nodes/"))
+                .map(|r| r.json())
+                .find(|body| body["metadata"]["labels"].is_object());
+            assert_eq!(update.is_some(), updated, "{value}");
+            if let Some(update) = update {
+                assert_eq!(update["metadata"]["labels"]["zone"], "b");
+                let annotation = update["metadata"]["annotations"][RESTORED_ANNOTATION_KEY]
+                    .as_str()
+                    .unwrap();
+                let annotation = RestoredAnnotation::parse(annotation);
+                assert_eq!(annotation.version, Some(RESTORED_ANNOTATION_VERSION));
+                assert!(!annotation.is_from(Some("corr-old")));
+            }
+        }
+    }
+}
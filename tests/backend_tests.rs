@@ -0,0 +1,246 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{finalized_node, labels, node, node_json, status_json};
+    use http::{Method, Request, Response, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::{api::ResourceExt, client::Body, Client};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, BackupLayout, BackupStore, Context, ControllerConfig,
+        MemoryLabelStore, MemoryNodePatcher, RestoredAnnotation, CONFIGMAP_NAMESPACE,
+        MANAGED_LABEL_KEY, RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    /// A client with an empty cluster behind it, answered in-process: reads find nothing, the
+    /// finalizer patches of kube's finalizer helper are accepted, and everything else fails
+    fn offline_client() -> Client {
+        let service = tower::service_fn(|req: Request<Body>| async move {
+            let path = req.uri().path();
+            let (status, body) = match *req.method() {
+                Method::GET => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                Method::PATCH if path.starts_with("/api/v1/nodes/") => {
+                    let name = path.rsplit('/').next().unwrap_or_default();
+                    (StatusCode::OK, node_json(name))
+                }
+                _ => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    status_json(503, "ServiceUnavailable"),
+                ),
+            };
+            let body = Body::from(serde_json::to_vec(&body).unwrap());
+            Ok::<_, Infallible>(Response::builder().status(status).body(body).unwrap())
+        });
+        Client::new(service, CONFIGMAP_NAMESPACE)
+    }
+
+    /// A context reading backups from `store` and patching nodes through `nodes`
+    fn context(
+        config: ControllerConfig,
+        store: &Arc<MemoryLabelStore>,
+        nodes: &Arc<MemoryNodePatcher>,
+    ) -> Arc<Context> {
+        let config = ControllerConfig {
+            startup_rate: 0.0,
+            ..config
+        };
+        let context = Context::with_config(offline_client(), config)
+            .with_label_store(store.clone())
+            .with_node_patcher(nodes.clone());
+        Arc::new(context)
+    }
+
+    /// The per-node backup ConfigMap of `node_name` holding `data`
+    fn configmap(node_name: &str, data: BTreeMap<String, String>) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name(node_name)),
+                namespace: Some(CONFIGMAP_NAMESPACE.to_string()),
+                ..Default::default()
+            },
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+
+    /// Set up `node` with `backup` as its backup ConfigMap, and reconcile it
+    async fn restore(
+        node: Node,
+        backup: Option<ConfigMap>,
+    ) -> (Arc<MemoryLabelStore>, Arc<MemoryNodePatcher>) {
+        let store = Arc::new(MemoryLabelStore::new());
+        let nodes = Arc::new(MemoryNodePatcher::new());
+        if let Some(backup) = backup {
+            store.insert(backup);
+        }
+        nodes.insert(node.clone());
+        let ctx = context(ControllerConfig::default(), &store, &nodes);
+        reconcile(Arc::new(node), ctx).await.unwrap();
+        (store, nodes)
+    }
+
+    /// The restored annotation of `node`, if it was restored
+    fn restored_from(nodes: &MemoryNodePatcher, node_name: &str) -> Option<RestoredAnnotation> {
+        RestoredAnnotation::of(&nodes.node(node_name).unwrap())
+    }
+
+    /// A backup's labels are restored onto the node, which is marked restored from it
+    #[tokio::test]
+    async fn test_restore() {
+        let backup = Backup {
+            labels: labels(&[("team", "ml")]),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        let (_, nodes) = restore(finalized_node("node-a"), Some(configmap("node-a", data))).await;
+        let node = nodes.node("node-a").unwrap();
+        assert_eq!(
+            node.labels(),
+            &labels(&[("team", "ml"), (MANAGED_LABEL_KEY, "true")])
+        );
+        assert_eq!(
+            restored_from(&nodes, "node-a"),
+            Some(RestoredAnnotation::of_backup(&backup))
+        );
+    }
+
+    /// Without a backup ConfigMap the node is left unrestored
+    #[tokio::test]
+    async fn test_missing_backup() {
+        let (_, nodes) = restore(finalized_node("node-a"), None).await;
+        assert_eq!(restored_from(&nodes, "node-a"), None);
+        assert!(nodes
+            .patches()
+            .iter()
+            .all(|(_, patch)| patch["metadata"]["annotations"][RESTORED_ANNOTATION_KEY].is_null()));
+    }
+
+    /// A backup with corrupt JSON is treated as missing, and kept for inspection
+    #[tokio::test]
+    async fn test_corrupt_backup() {
+        let data = BTreeMap::from([("preserved_labels_json".to_string(), "{not json".to_string())]);
+        let (store, nodes) =
+            restore(finalized_node("node-a"), Some(configmap("node-a", data))).await;
+        assert_eq!(restored_from(&nodes, "node-a"), None);
+        assert!(store
+            .get(CONFIGMAP_NAMESPACE, &configmap_name("node-a"))
+            .is_some());
+    }
+
+    /// An empty backup restores no labels, but still marks the node restored
+    #[tokio::test]
+    async fn test_empty_backup() {
+        let data = Backup::default().to_configmap_data().unwrap();
+        let (_, nodes) = restore(finalized_node("node-a"), Some(configmap("node-a", data))).await;
+        let node = nodes.node("node-a").unwrap();
+        assert_eq!(node.labels(), &labels(&[(MANAGED_LABEL_KEY, "true")]));
+        assert_eq!(
+            restored_from(&nodes, "node-a"),
+            Some(RestoredAnnotation::of_backup(&Backup::default()))
+        );
+    }
+
+    /// A node that already carries the restored annotation isn't patched again
+    #[tokio::test]
+    async fn test_already_restored() {
+        let backup = Backup {
+            labels: labels(&[("team", "ml")]),
+            ..Default::default()
+        };
+        let mut node = finalized_node("node-a");
+        node.annotations_mut().insert(
+            RESTORED_ANNOTATION_KEY.to_string(),
+            RestoredAnnotation::of_backup(&backup).to_value(),
+        );
+        let data = backup.to_configmap_data().unwrap();
+        let (_, nodes) = restore(node, Some(configmap("node-a", data))).await;
+        assert!(nodes.patches().is_empty());
+        assert!(!nodes.node("node-a").unwrap().labels().contains_key("team"));
+    }
+
+    /// A failed backup read fails the reconcile before the node is touched
+    #[tokio::test]
+    async fn test_label_store_error() {
+        let store = Arc::new(MemoryLabelStore::new());
+        let nodes = Arc::new(MemoryNodePatcher::new());
+        nodes.insert(finalized_node("node-a"));
+        store.fail_next(500);
+        let ctx = context(ControllerConfig::default(), &store, &nodes);
+        let error = reconcile(Arc::new(finalized_node("node-a")), ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert!(nodes.patches().is_empty());
+    }
+
+    /// A rejected node patch fails the reconcile without marking the node restored
+    #[tokio::test]
+    async fn test_node_patch_error() {
+        let store = Arc::new(MemoryLabelStore::new());
+        let nodes = Arc::new(MemoryNodePatcher::new());
+        let data = Backup::default().to_configmap_data().unwrap();
+        store.insert(configmap("node-a", data));
+        nodes.insert(finalized_node("node-a"));
+        nodes.fail_next(500);
+        let ctx = context(ControllerConfig::default(), &store, &nodes);
+        let error = reconcile(Arc::new(finalized_node("node-a")), ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert_eq!(restored_from(&nodes, "node-a"), None);
+    }
+
+    /// A deleted node's labels are backed up, empty or not, and restored onto its replacement
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        for layout in [BackupLayout::PerNode, BackupLayout::Sharded] {
+            for preserved in [labels(&[]), labels(&[("team", "ml")])] {
+                let store = Arc::new(MemoryLabelStore::new());
+                let nodes = Arc::new(MemoryNodePatcher::new());
+                let config = ControllerConfig {
+                    backup_layout: layout,
+                    ..Default::default()
+                };
+                let ctx = context(config, &store, &nodes);
+                let deleted = node("node-a", preserved.clone(), true);
+                reconcile(Arc::new(deleted), ctx.clone()).await.unwrap();
+
+                let backups = BackupStore::new(offline_client(), CONFIGMAP_NAMESPACE, layout);
+                let name = backups.configmap_name("node-a");
+                let written = store.get(CONFIGMAP_NAMESPACE, &name).unwrap();
+                assert_eq!(written.name_any(), name);
+
+                nodes.insert(finalized_node("node-a"));
+                reconcile(Arc::new(finalized_node("node-a")), ctx)
+                    .await
+                    .unwrap();
+                let mut expected = preserved;
+                expected.insert(MANAGED_LABEL_KEY.to_string(), "true".to_string());
+                assert_eq!(
+                    nodes.node("node-a").unwrap().labels(),
+                    &expected,
+                    "{layout}"
+                );
+            }
+        }
+    }
+
+    /// A deleted node whose backup can't be written keeps our finalizer
+    #[tokio::test]
+    async fn test_backup_write_error() {
+        let store = Arc::new(MemoryLabelStore::new());
+        let nodes = Arc::new(MemoryNodePatcher::new());
+        store.fail_next(500);
+        let ctx = context(ControllerConfig::default(), &store, &nodes);
+        let deleted = node("node-a", labels(&[("team", "ml")]), true);
+        let error = reconcile(Arc::new(deleted), ctx).await.unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert!(store
+            .get(CONFIGMAP_NAMESPACE, &configmap_name("node-a"))
+            .is_none());
+    }
+}
@@ -0,0 +1,250 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::{DateTime, Duration as ChronoDuration, Utc};
+    use label_preserver::{
+        configmap_name, preserve_on_cleanup, reconcile, restore_on_apply, Backup, Context,
+        ControllerConfig, Error, RestorePolicy, RestoredAnnotation, SkipReason,
+        UnknownBackupAgePolicy, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+    };
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// A backup of `workload=database` written at `backed_up_at`, None for one written before
+    /// the backup time was recorded
+    fn backup(backed_up_at: Option<DateTime<Utc>>) -> Backup {
+        Backup {
+            labels: labels(&[("workload", "database")]),
+            backed_up_at,
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn days_ago(days: i64) -> Option<DateTime<Utc>> {
+        Some(Utc::now() - ChronoDuration::days(days))
+    }
+
+    fn config(unknown_backup_age: UnknownBackupAgePolicy, delete: bool) -> ControllerConfig {
+        ControllerConfig {
+            policy: RestorePolicy {
+                max_backup_age: Some(30 * DAY),
+                unknown_backup_age,
+                ..Default::default()
+            },
+            delete_stale_backups: delete,
+            startup_rate: 0.0,
+            ..Default::default()
+        }
+    }
+
+    fn node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Serve `backup` as the node's backup ConfigMap, and accept patches, deletes, and Events
+    async fn cluster(backup: &Backup) -> MockApiServer {
+        let configmap = serde_json::json!({
+            "metadata": {
+                "name": configmap_name("node-a"),
+                "namespace": "default",
+                "resourceVersion": "5",
+            },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::DELETE, _) => (StatusCode::OK, configmap.clone()),
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// The reasons of the Events published
+    fn event_reasons(server: &MockApiServer) -> Vec<String> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.is_event() && r.method == Method::POST)
+            .map(|r| r.json()["reason"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_unknown_backup_age_names() {
+        assert_eq!(
+            "skip".parse::<UnknownBackupAgePolicy>().unwrap(),
+            UnknownBackupAgePolicy::Skip
+        );
+        assert_eq!(
+            "restore".parse::<UnknownBackupAgePolicy>().unwrap(),
+            UnknownBackupAgePolicy::Restore
+        );
+        assert!(matches!(
+            "never".parse::<UnknownBackupAgePolicy>(),
+            Err(Error::InvalidUnknownAgePolicy(_))
+        ));
+        assert_eq!(UnknownBackupAgePolicy::default().to_string(), "restore");
+    }
+
+    /// Cleanup records when the backup was written, and it survives the ConfigMap
+    #[test]
+    fn test_backup_time_recorded() {
+        let now = Utc::now();
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                labels: Some(labels(&[("zone", "a")])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let preservation =
+            preserve_on_cleanup(&node, &ControllerConfig::default(), "corr-1", now).unwrap();
+        assert_eq!(preservation.backup.backed_up_at, Some(now));
+        let data = preservation.configmap.data.unwrap();
+        assert_eq!(data["preserved_at"], now.to_rfc3339());
+
+        // Version 6 backups have no backup time
+        let mut data = backup(None).to_configmap_data().unwrap();
+        data.insert("schema_version".to_string(), "6".to_string());
+        assert_eq!(
+            Backup::from_configmap_data(&data).unwrap().backed_up_at,
+            None
+        );
+
+        data.insert("preserved_at".to_string(), "yesterday".to_string());
+        assert!(Backup::from_configmap_data(&data).is_err());
+    }
+
+    /// Fresh backups are restored, stale ones aren't, and ones of unknown age follow their
+    /// policy. Without a maximum age every backup is fresh.
+    #[test]
+    fn test_is_stale() {
+        let now = Utc::now();
+        let restore = config(UnknownBackupAgePolicy::Restore, false).policy;
+        let skip = config(UnknownBackupAgePolicy::Skip, false).policy;
+        assert!(!restore.is_stale(&backup(days_ago(29)), now));
+        assert!(restore.is_stale(&backup(days_ago(31)), now));
+        assert!(!restore.is_stale(&backup(None), now));
+        assert!(!skip.is_stale(&backup(days_ago(29)), now));
+        assert!(skip.is_stale(&backup(None), now));
+
+        let unlimited = RestorePolicy::default();
+        assert!(!unlimited.is_stale(&backup(days_ago(3650)), now));
+        assert!(!unlimited.is_stale(&backup(None), now));
+    }
+
+    /// Every label of a stale backup is skipped, and the node is still marked restored from it
+    #[test]
+    fn test_stale_plan() {
+        let node = node();
+        let config = config(UnknownBackupAgePolicy::Restore, false);
+        let stale = backup(days_ago(180));
+        let restoration = restore_on_apply(&node, &stale, &config, Utc::now());
+        assert!(restoration.added.is_empty());
+        assert_eq!(restoration.plan.stale, vec!["workload".to_string()]);
+        assert_eq!(
+            restoration.counts.skipped["workload"],
+            SkipReason::StaleBackup
+        );
+        let annotations = restoration.node.metadata.annotations.unwrap();
+        assert_eq!(
+            annotations[RESTORED_ANNOTATION_KEY],
+            RestoredAnnotation::of_backup(&stale).to_value()
+        );
+
+        let restoration = restore_on_apply(&node, &backup(days_ago(1)), &config, Utc::now());
+        assert_eq!(restoration.added, labels(&[("workload", "database")]));
+    }
+
+    /// A fresh backup is restored as usual and kept
+    #[tokio::test]
+    async fn test_fresh_backup_restored() {
+        let server = cluster(&backup(days_ago(1))).await;
+        let ctx = Arc::new(Context::with_config(
+            server.client(),
+            config(UnknownBackupAgePolicy::Skip, true),
+        ));
+        reconcile(Arc::new(node()), ctx).await.unwrap();
+
+        let restore = server.requests().into_iter().find(|r| r.is_restore());
+        assert_eq!(
+            restore.unwrap().json()["metadata"]["labels"]["workload"],
+            "database"
+        );
+        assert_eq!(event_reasons(&server), vec!["LabelsRestored".to_string()]);
+        assert!(server.requests().iter().all(|r| r.method != Method::DELETE));
+    }
+
+    /// A stale backup restores nothing, warns with an Event, marks the node restored so it isn't
+    /// retried, and is deleted if so configured
+    #[tokio::test]
+    async fn test_stale_backup_refused() {
+        let stale = backup(days_ago(180));
+        for delete in [false, true] {
+            let server = cluster(&stale).await;
+            let ctx = Arc::new(Context::with_config(
+                server.client(),
+                config(UnknownBackupAgePolicy::Restore, delete),
+            ));
+            reconcile(Arc::new(node()), ctx).await.unwrap();
+
+            let restore = server.requests().into_iter().find(|r| r.is_restore());
+            let metadata = restore.expect("the node is marked restored").json()["metadata"].clone();
+            assert!(metadata["labels"].get("workload").is_none());
+            assert_eq!(
+                metadata["annotations"][RESTORED_ANNOTATION_KEY],
+                RestoredAnnotation::of_backup(&stale).to_value()
+            );
+            assert_eq!(event_reasons(&server), vec!["StaleBackup".to_string()]);
+            let deleted = server.requests().iter().any(|r| r.method == Method::DELETE);
+            assert_eq!(deleted, delete);
+        }
+    }
+
+    /// A backup without a backup time is restored or refused by the unknown backup age policy
+    #[tokio::test]
+    async fn test_unknown_age_backup() {
+        let mut data = backup(None).to_configmap_data().unwrap();
+        data.insert("schema_version".to_string(), "6".to_string());
+        let legacy = Backup::from_configmap_data(&data).unwrap();
+        for (policy, restored) in [
+            (UnknownBackupAgePolicy::Restore, true),
+            (UnknownBackupAgePolicy::Skip, false),
+        ] {
+            let server = cluster(&legacy).await;
+            let ctx = Arc::new(Context::with_config(server.client(), config(policy, false)));
+            reconcile(Arc::new(node()), ctx).await.unwrap();
+
+            let restore = server.requests().into_iter().find(|r| r.is_restore());
+            let labels = restore.unwrap().json()["metadata"]["labels"].clone();
+            assert_eq!(labels.get("workload").is_some(), restored, "{}", policy);
+            let reason = if restored {
+                "LabelsRestored"
+            } else {
+                "StaleBackup"
+            };
+            assert_eq!(event_reasons(&server), vec![reason.to_string()]);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::{
+        reflector::{self, store::Writer},
+        watcher,
+    };
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, ControllerConfig, FINALIZER_NAME,
+    };
+
+    use std::sync::{Arc, Mutex};
+
+    type StoredBackup = Arc<Mutex<Option<serde_json::Value>>>;
+
+    fn backup_configmap(pairs: &[(&str, &str)]) -> ConfigMap {
+        let backup = Backup {
+            labels: labels(pairs),
+            ..Default::default()
+        };
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name("node-a")),
+                namespace: Some("default".to_string()),
+                resource_version: Some("1".to_string()),
+                ..Default::default()
+            },
+            data: Some(backup.to_configmap_data().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn node(deleted: bool, pairs: &[(&str, &str)]) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: deleted.then(|| Time(Utc::now())),
+                labels: Some(labels(pairs)),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Store the backup of `node-a`, starting from `initial`, and accept node patches
+    async fn cluster(initial: &ConfigMap) -> (MockApiServer, StoredBackup) {
+        let stored: StoredBackup =
+            Arc::new(Mutex::new(Some(serde_json::to_value(initial).unwrap())));
+        let backup = stored.clone();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                match backup.lock().unwrap().clone() {
+                    Some(cm) => (StatusCode::OK, cm),
+                    None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                }
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                let mut cm = req.json();
+                cm["metadata"]["resourceVersion"] = "2".into();
+                *backup.lock().unwrap() = Some(cm.clone());
+                (StatusCode::OK, cm)
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        (server, stored)
+    }
+
+    /// A context reading backups from a cache holding `cached`, and the cache's writer
+    fn cached_context(
+        server: &MockApiServer,
+        cached: &[&ConfigMap],
+    ) -> (Arc<Context>, Writer<ConfigMap>) {
+        let config = ControllerConfig {
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let (store, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Init);
+        for cm in cached {
+            writer.apply_watcher_event(&watcher::Event::InitApply((*cm).clone()));
+        }
+        writer.apply_watcher_event(&watcher::Event::InitDone);
+        ctx.set_backup_cache("default", store);
+        (ctx, writer)
+    }
+
+    fn backup_reads(server: &MockApiServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::GET && r.uri.path().contains("/configmaps/"))
+            .count()
+    }
+
+    fn restored_zone(server: &MockApiServer) -> serde_json::Value {
+        let restore = server.requests().into_iter().rfind(|r| r.is_restore());
+        restore.expect("the node is restored").json()["metadata"]["labels"]["zone"].clone()
+    }
+
+    /// A backup in the cache is restored without reading it from the API server
+    #[tokio::test]
+    async fn test_cache_hit_skips_api() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(&initial).await;
+        let (ctx, _writer) = cached_context(&server, &[&initial]);
+        reconcile(Arc::new(node(false, &[])), ctx).await.unwrap();
+        assert_eq!(restored_zone(&server), "a");
+        assert_eq!(backup_reads(&server), 0);
+    }
+
+    /// A backup missing from the cache, e.g. one written before the watch saw it, is read from
+    /// the API server
+    #[tokio::test]
+    async fn test_cache_miss_reads_api() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(&initial).await;
+        let (ctx, _writer) = cached_context(&server, &[]);
+        reconcile(Arc::new(node(false, &[])), ctx).await.unwrap();
+        assert_eq!(restored_zone(&server), "a");
+        assert_eq!(backup_reads(&server), 1);
+    }
+
+    /// A backup we wrote is read from the API server until the watch brings the cache up to
+    /// date with it, so a quickly recreated node isn't restored from the stale cached backup
+    #[tokio::test]
+    async fn test_own_write_bypasses_cache() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, stored) = cluster(&initial).await;
+        let (ctx, mut writer) = cached_context(&server, &[&initial]);
+        reconcile(Arc::new(node(true, &[("zone", "b")])), ctx.clone())
+            .await
+            .unwrap();
+
+        reconcile(Arc::new(node(false, &[])), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(restored_zone(&server), "b");
+        assert_eq!(backup_reads(&server), 1);
+
+        // The watch catches up with the write
+        let written: ConfigMap =
+            serde_json::from_value(stored.lock().unwrap().clone().unwrap()).unwrap();
+        let event = watcher::Event::Apply(written);
+        writer.apply_watcher_event(&event);
+        ctx.observe_backup(&event);
+        reconcile(Arc::new(node(false, &[])), ctx).await.unwrap();
+        let restores = server.requests().iter().filter(|r| r.is_restore()).count();
+        assert_eq!(restores, 2);
+        assert_eq!(restored_zone(&server), "b");
+        assert_eq!(backup_reads(&server), 1);
+    }
+
+    /// Without a cache every backup is read from the API server
+    #[tokio::test]
+    async fn test_uncached_reads_api() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(&initial).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(node(false, &[])), ctx).await.unwrap();
+        assert_eq!(backup_reads(&server), 1);
+    }
+}
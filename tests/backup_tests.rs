@@ -0,0 +1,369 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::chrono::{DateTime, Duration as ChronoDuration, Utc};
+    use label_preserver::{
+        payload_size, types::PreservedTaint, Backup, Degradation, Error, LabelExpiry,
+        MachineIdentity, SCHEMA_VERSION,
+    };
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn backup_preserved_at(pairs: &[(&str, &str)], preserved_at: DateTime<Utc>) -> Backup {
+        let labels = labels(pairs);
+        Backup {
+            schema_version: SCHEMA_VERSION,
+            preserved_at: labels.keys().map(|k| (k.clone(), preserved_at)).collect(),
+            backed_up_at: Some(preserved_at),
+            labels,
+            annotations: BTreeMap::new(),
+            taints: Vec::new(),
+            correlation_id: Some("abc".to_string()),
+            identity: MachineIdentity {
+                provider_id: Some("aws:///us-east-1a/i-0123".to_string()),
+                machine_id: None,
+            },
+        }
+    }
+
+    /// A backup survives a round trip through ConfigMap data
+    #[test]
+    fn test_backup_round_trip() {
+        let mut backup = backup_preserved_at(&[("a/b", "1"), ("c", "2")], Utc::now());
+        let data = backup.to_configmap_data().unwrap();
+        assert_eq!(data.get("schema_version").unwrap(), "8");
+        assert_eq!(
+            data["preserved_at"],
+            backup.backed_up_at.unwrap().to_rfc3339()
+        );
+        assert!(!data.contains_key("preserved_annotations_json"));
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+
+        backup.annotations = labels(&[("example.com/owner", "ml-infra")]);
+        let data = backup.to_configmap_data().unwrap();
+        assert_eq!(
+            data["preserved_annotations_json"],
+            r#"{"example.com/owner":"ml-infra"}"#
+        );
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+
+        backup.taints = vec![PreservedTaint {
+            key: "dedicated".to_string(),
+            value: Some("gpu".to_string()),
+            effect: "NoSchedule".to_string(),
+        }];
+        let data = backup.to_configmap_data().unwrap();
+        assert_eq!(
+            data["preserved_taints_json"],
+            r#"[{"key":"dedicated","value":"gpu","effect":"NoSchedule"}]"#
+        );
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+    }
+
+    /// Payloads written before versioning are read as version 1 with no preserved-at times
+    #[test]
+    fn test_version_1_backup() {
+        let mut data = BTreeMap::new();
+        data.insert(
+            "preserved_labels_json".to_string(),
+            r#"{"a/b":"1"}"#.to_string(),
+        );
+        let backup = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(backup.schema_version, 1);
+        assert_eq!(backup.labels, labels(&[("a/b", "1")]));
+        assert!(backup.preserved_at.is_empty());
+        assert_eq!(backup.correlation_id, None);
+        assert_eq!(backup.identity, MachineIdentity::default());
+    }
+
+    /// Expiry rules parse from `<prefix>=<days>`
+    #[test]
+    fn test_parse_expiry_rule() {
+        assert_eq!(
+            "maintenance.example.com/=7".parse::<LabelExpiry>().unwrap(),
+            LabelExpiry {
+                prefix: "maintenance.example.com/".to_string(),
+                ttl: 7 * DAY,
+            }
+        );
+        assert!("maintenance.example.com/".parse::<LabelExpiry>().is_err());
+        assert!("maintenance.example.com/=soon"
+            .parse::<LabelExpiry>()
+            .is_err());
+    }
+
+    /// A label expires exactly when its age reaches the TTL
+    #[test]
+    fn test_expiry_boundaries() {
+        let preserved_at = Utc::now();
+        let backup =
+            backup_preserved_at(&[("maintenance.example.com/ticket", "T-1")], preserved_at);
+        let rules = vec![LabelExpiry {
+            prefix: "maintenance.example.com/".to_string(),
+            ttl: 7 * DAY,
+        }];
+
+        let just_before = preserved_at + ChronoDuration::days(7) - ChronoDuration::seconds(1);
+        assert!(backup.expired_labels(&rules, just_before).is_empty());
+        let at_ttl = preserved_at + ChronoDuration::days(7);
+        assert_eq!(
+            backup.expired_labels(&rules, at_ttl),
+            vec!["maintenance.example.com/ticket".to_string()]
+        );
+        // Clock skew making the backup appear to be from the future doesn't expire anything
+        let before_preservation = preserved_at - ChronoDuration::days(1);
+        assert!(backup
+            .expired_labels(&rules, before_preservation)
+            .is_empty());
+    }
+
+    /// Keys without a matching rule, or without a preserved-at time, never expire
+    #[test]
+    fn test_keys_without_policies_never_expire() {
+        let preserved_at = Utc::now() - ChronoDuration::days(365);
+        let mut backup = backup_preserved_at(
+            &[("maintenance.example.com/ticket", "T-1"), ("team", "ml")],
+            preserved_at,
+        );
+        let rules = vec![LabelExpiry {
+            prefix: "maintenance.example.com/".to_string(),
+            ttl: DAY,
+        }];
+        assert_eq!(
+            backup.expired_labels(&rules, Utc::now()),
+            vec!["maintenance.example.com/ticket".to_string()]
+        );
+        assert!(backup.expired_labels(&[], Utc::now()).is_empty());
+
+        backup.preserved_at.clear();
+        assert!(backup.expired_labels(&rules, Utc::now()).is_empty());
+    }
+
+    /// The rule with the longest matching prefix applies
+    #[test]
+    fn test_longest_prefix_rule_wins() {
+        let preserved_at = Utc::now() - ChronoDuration::days(3);
+        let backup = backup_preserved_at(
+            &[
+                ("maintenance.example.com/ticket", "T-1"),
+                ("maintenance.example.com/owner", "bob"),
+            ],
+            preserved_at,
+        );
+        let rules = vec![
+            LabelExpiry {
+                prefix: "maintenance.example.com/".to_string(),
+                ttl: DAY,
+            },
+            LabelExpiry {
+                prefix: "maintenance.example.com/owner".to_string(),
+                ttl: 30 * DAY,
+            },
+        ];
+        assert_eq!(
+            backup.expired_labels(&rules, Utc::now()),
+            vec!["maintenance.example.com/ticket".to_string()]
+        );
+    }
+
+    /// A backup of `count` labels with 63 character values
+    fn large_backup(count: usize) -> Backup {
+        let value = "v".repeat(63);
+        let pairs: Vec<(String, String)> = (0..count)
+            .map(|i| (format!("example.com/label-{i:04}"), value.clone()))
+            .collect();
+        let pairs: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        backup_preserved_at(&pairs, Utc::now())
+    }
+
+    /// Backups within the limit are stored unchanged
+    #[test]
+    fn test_backup_within_limit_is_not_degraded() {
+        let backup = large_backup(10);
+        let (data, degradations) = backup
+            .to_configmap_data_within(usize::MAX, 1, usize::MAX)
+            .unwrap();
+        assert!(degradations.is_empty());
+        assert_eq!(data, backup.to_configmap_data().unwrap());
+    }
+
+    /// Oversized backups are capped to the first labels in key order
+    #[test]
+    fn test_oversized_backup_is_capped() {
+        let backup = large_backup(100);
+        let full_size = payload_size(&backup.to_configmap_data().unwrap());
+        let (data, degradations) = backup
+            .to_configmap_data_within(full_size / 2, 20, usize::MAX)
+            .unwrap();
+        assert_eq!(
+            degradations,
+            vec![Degradation::CappedLabels { dropped: 80 }]
+        );
+        let stored = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(stored.labels.len(), 20);
+        assert_eq!(stored.preserved_at.len(), 20);
+        assert_eq!(
+            stored.labels.keys().next_back().unwrap(),
+            "example.com/label-0019"
+        );
+        assert!(payload_size(&data) <= full_size / 2);
+    }
+
+    /// Annotations are dropped before a backup fails, keeping the labels
+    #[test]
+    fn test_annotations_dropped_to_fit() {
+        let mut backup = large_backup(10);
+        let labels_size = payload_size(&backup.to_configmap_data().unwrap());
+        backup.annotations = labels(&[("example.com/notes", &"n".repeat(2000))]);
+        let (data, degradations) = backup
+            .to_configmap_data_within(labels_size, 10, usize::MAX)
+            .unwrap();
+        assert_eq!(
+            degradations,
+            vec![Degradation::DroppedAnnotations { dropped: 1 }]
+        );
+        let stored = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(stored.labels.len(), 10);
+        assert!(stored.annotations.is_empty());
+    }
+
+    /// Annotations are dropped before the label cap applies, so a backup that fits without
+    /// them keeps every label
+    #[test]
+    fn test_annotations_dropped_before_labels_capped() {
+        let mut backup = large_backup(10);
+        let labels_size = payload_size(&backup.to_configmap_data().unwrap());
+        backup.annotations = labels(&[("example.com/notes", &"n".repeat(2000))]);
+        let (data, degradations) = backup
+            .to_configmap_data_within(labels_size, 3, usize::MAX)
+            .unwrap();
+        assert_eq!(
+            degradations,
+            vec![Degradation::DroppedAnnotations { dropped: 1 }]
+        );
+        let stored = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(stored.labels, backup.labels);
+        assert!(stored.annotations.is_empty());
+
+        let (data, degradations) = backup
+            .to_configmap_data_within(labels_size - 1, 3, usize::MAX)
+            .unwrap();
+        assert_eq!(
+            degradations,
+            vec![
+                Degradation::DroppedAnnotations { dropped: 1 },
+                Degradation::CappedLabels { dropped: 7 },
+            ]
+        );
+        assert_eq!(Backup::from_configmap_data(&data).unwrap().labels.len(), 3);
+    }
+
+    /// A backup that doesn't fit even after every degradation step is an error
+    #[test]
+    fn test_backup_too_large_after_degradation() {
+        let backup = large_backup(100);
+        match backup.to_configmap_data_within(1024, 50, usize::MAX) {
+            Err(Error::BackupTooLarge { size, limit }) => {
+                assert_eq!(limit, 1024);
+                assert!(size > 1024);
+            }
+            other => panic!("Expected BackupTooLarge, got {:?}", other),
+        }
+    }
+
+    /// Long values are split into chunks no longer than the chunk size and read back whole,
+    /// including values with multi-byte characters
+    #[test]
+    fn test_chunked_round_trip() {
+        let mut backup = large_backup(250);
+        backup.annotations = labels(&[("example.com/notes", &"ü".repeat(500))]);
+        let data = backup.to_configmap_data_chunked(4096).unwrap();
+        assert!(!data.contains_key("preserved_labels_json"));
+        let chunks: usize = data["preserved_labels_json_chunks"].parse().unwrap();
+        assert!(chunks > 1);
+        for index in 0..chunks {
+            assert!(data[&format!("preserved_labels_json_{index}")].len() <= 4096);
+        }
+        assert!(data.contains_key("preserved_at_json_chunks"));
+        assert!(data.contains_key("preserved_annotations_json"));
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+
+        let data = backup.to_configmap_data_chunked(7).unwrap();
+        assert_eq!(data["preserved_annotations_json_chunks"], "170");
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+
+        // Short values stay in one piece
+        let small = large_backup(2);
+        assert_eq!(
+            small.to_configmap_data_chunked(4096).unwrap(),
+            small.to_configmap_data().unwrap()
+        );
+    }
+
+    /// A backup missing a chunk, or with a bad chunk count, doesn't parse
+    #[test]
+    fn test_incomplete_chunks() {
+        let data = large_backup(250).to_configmap_data_chunked(4096).unwrap();
+        let mut missing = data.clone();
+        missing.remove("preserved_labels_json_1");
+        assert!(matches!(
+            Backup::from_configmap_data(&missing),
+            Err(Error::Serialization(_))
+        ));
+        let mut bad_count = data;
+        bad_count.insert(
+            "preserved_labels_json_chunks".to_string(),
+            "many".to_string(),
+        );
+        assert!(matches!(
+            Backup::from_configmap_data(&bad_count),
+            Err(Error::Serialization(_))
+        ));
+    }
+
+    /// Version 5 backups, which stored every value whole, still read
+    #[test]
+    fn test_version_5_backup() {
+        let data = BTreeMap::from([
+            ("schema_version".to_string(), "5".to_string()),
+            (
+                "preserved_labels_json".to_string(),
+                r#"{"zone":"a"}"#.to_string(),
+            ),
+            (
+                "preserved_at_json".to_string(),
+                r#"{"zone":"2024-01-01T00:00:00Z"}"#.to_string(),
+            ),
+        ]);
+        let backup = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(backup.schema_version, 5);
+        assert_eq!(backup.labels, labels(&[("zone", "a")]));
+        assert_eq!(backup.preserved_at.len(), 1);
+    }
+
+    /// Degradation accounts for the keys chunking adds
+    #[test]
+    fn test_chunked_backup_within_limit() {
+        let backup = large_backup(100);
+        let chunked_size = payload_size(&backup.to_configmap_data_chunked(1024).unwrap());
+        let (data, degradations) = backup
+            .to_configmap_data_within(chunked_size, 100, 1024)
+            .unwrap();
+        assert!(degradations.is_empty());
+        assert_eq!(Backup::from_configmap_data(&data).unwrap(), backup);
+        let (_, degradations) = backup
+            .to_configmap_data_within(chunked_size - 1, 50, 1024)
+            .unwrap();
+        assert_eq!(
+            degradations,
+            vec![Degradation::CappedLabels { dropped: 50 }]
+        );
+    }
+}
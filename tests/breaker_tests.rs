@@ -0,0 +1,109 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use kube::runtime::{reflector, watcher};
+    use label_preserver::{reconcile, ControllerConfig, FINALIZER_NAME};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A node our finalizer has kept in Terminating for `minutes`
+    fn terminating(name: &str, minutes: i64) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: Some(Time(Utc::now() - ChronoDuration::minutes(minutes))),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The namespace RBAC was revoked: every backup write is forbidden
+    async fn revoked_rbac_server() -> MockApiServer {
+        MockApiServer::start(|req| {
+            if req.uri.path().contains("/configmaps/") {
+                (StatusCode::FORBIDDEN, status_json(403, "forbidden"))
+            } else if req.method == Method::PATCH {
+                (StatusCode::OK, node_json("node-0"))
+            } else {
+                (StatusCode::NOT_FOUND, status_json(404, "not found"))
+            }
+        })
+        .await
+    }
+
+    /// Trip after two blocked nodes, counting nodes blocked for ten minutes
+    fn breaker_config(config: &mut ControllerConfig) {
+        config.breaker_threshold = 2;
+        config.breaker_blocked_after = Duration::from_secs(10 * 60);
+    }
+
+    /// A mass cleanup failure trips the breaker, which releases finalizers, and the breaker
+    /// resets once the backlog is gone
+    #[tokio::test]
+    async fn test_breaker_trips_and_recovers() {
+        let server = revoked_rbac_server().await;
+        let ctx = context_with(&server, breaker_config);
+        let (store, mut writer) = reflector::store();
+        ctx.set_node_store(store);
+
+        // Two blocked nodes, and one deleted too recently to count, are within the threshold
+        let mut nodes: Vec<Node> = (0..2)
+            .map(|i| terminating(&format!("node-{i}"), 20))
+            .collect();
+        nodes.push(terminating("node-new", 1));
+        for node in &nodes {
+            writer.apply_watcher_event(&watcher::Event::Apply(node.clone()));
+        }
+        assert_eq!(ctx.blocked_nodes(Utc::now()), 2);
+        assert!(!ctx.check_breaker(Utc::now()));
+        assert!(reconcile(Arc::new(nodes[0].clone()), ctx.clone())
+            .await
+            .is_err());
+
+        // A third blocked node trips it
+        let third = terminating("node-2", 20);
+        writer.apply_watcher_event(&watcher::Event::Apply(third.clone()));
+        reconcile(Arc::new(third), ctx.clone()).await.unwrap();
+        let requests = server.requests();
+        let backup = requests
+            .iter()
+            .rposition(|r| r.uri.path().contains("/configmaps/"))
+            .unwrap();
+        let release = requests
+            .iter()
+            .rposition(|r| r.uri.path() == "/api/v1/nodes/node-2")
+            .unwrap();
+        assert!(backup < release, "the backup is attempted before releasing");
+        let metrics = ctx.render_metrics();
+        assert!(metrics.contains("label_preserver_breaker_tripped 1\n"));
+        assert!(metrics.contains("label_preserver_blocked_nodes 3\n"));
+
+        // Dropping below the threshold isn't enough, the backlog has to clear
+        for node in nodes.iter().take(2) {
+            writer.apply_watcher_event(&watcher::Event::Delete(node.clone()));
+        }
+        assert!(ctx.check_breaker(Utc::now()));
+        writer.apply_watcher_event(&watcher::Event::Delete(terminating("node-2", 20)));
+        assert!(!ctx.check_breaker(Utc::now()));
+        assert!(ctx
+            .render_metrics()
+            .contains("label_preserver_breaker_tripped 0\n"));
+    }
+
+    /// Without the Controller's cache nothing counts as blocked
+    #[tokio::test]
+    async fn test_no_store() {
+        let server = revoked_rbac_server().await;
+        let ctx = context_with(&server, breaker_config);
+        assert_eq!(ctx.blocked_nodes(Utc::now()), 0);
+        assert!(!ctx.check_breaker(Utc::now()));
+    }
+}
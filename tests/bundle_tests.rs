@@ -0,0 +1,214 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        bundle::{checksum, verify, verify_json, Bundle, BundleEntry, BUNDLE_VERSION},
+        configmap_name, label_error, validate_label, Backup, LabelValidationError, RestorePolicy,
+    };
+
+    /// A checksummed entry backing up `pairs` from `node`
+    fn entry(node: &str, pairs: &[(&str, &str)]) -> BundleEntry {
+        let data = Backup {
+            labels: labels(pairs),
+            ..Default::default()
+        }
+        .to_configmap_data()
+        .unwrap();
+        BundleEntry {
+            configmap: configmap_name(node),
+            node: Some(node.to_string()),
+            checksum: Some(checksum(&data)),
+            data,
+        }
+    }
+
+    fn bundle(backups: Vec<BundleEntry>) -> Bundle {
+        Bundle {
+            version: BUNDLE_VERSION,
+            backups,
+        }
+    }
+
+    fn messages(bundle: &Bundle) -> Vec<String> {
+        verify(bundle)
+            .problems
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_bundle() {
+        let valid = bundle(vec![
+            entry(
+                "node-a",
+                &[("topology.kubernetes.io/zone", "a"), ("team", "")],
+            ),
+            entry("node-b", &[("pool", "gpu")]),
+        ]);
+        let verification = verify_json(&serde_json::to_string(&valid).unwrap());
+        assert!(verification.is_valid(), "{}", verification.summary());
+        assert_eq!(verification.backups, 2);
+        assert_eq!(verification.labels, 3);
+    }
+
+    /// Entries without a node or checksum are still verified
+    #[test]
+    fn test_minimal_entry() {
+        let json = serde_json::json!({
+            "version": BUNDLE_VERSION,
+            "backups": [{
+                "configmap": "node-labels-x",
+                "data": { "preserved_labels_json": "{\"pool\":\"gpu\"}" }
+            }]
+        });
+        assert!(verify_json(&json.to_string()).is_valid());
+    }
+
+    #[test]
+    fn test_unreadable_file() {
+        let verification = verify_json("{\"backups\": ");
+        assert!(!verification.is_valid());
+        assert!(verification.problems[0]
+            .message
+            .starts_with("not a backup bundle"));
+    }
+
+    #[test]
+    fn test_unsupported_bundle_version() {
+        let mut future = bundle(vec![]);
+        future.version = BUNDLE_VERSION + 1;
+        assert_eq!(
+            messages(&future),
+            [format!(
+                "unsupported bundle version {}, expected {}",
+                BUNDLE_VERSION + 1,
+                BUNDLE_VERSION
+            )]
+        );
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut tampered = entry("node-a", &[("pool", "gpu")]);
+        tampered.data.insert(
+            "preserved_labels_json".to_string(),
+            "{\"pool\":\"cpu\"}".to_string(),
+        );
+        let problems = messages(&bundle(vec![tampered]));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("checksum mismatch"), "{problems:?}");
+    }
+
+    #[test]
+    fn test_bad_schema_version() {
+        let mut unparsable = entry("node-a", &[]);
+        unparsable
+            .data
+            .insert("schema_version".to_string(), "three".to_string());
+        unparsable.checksum = None;
+        let mut future = entry("node-b", &[]);
+        future
+            .data
+            .insert("schema_version".to_string(), "99".to_string());
+        future.checksum = None;
+        let problems = messages(&bundle(vec![unparsable, future]));
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("Invalid backup schema version 'three'"));
+        assert!(problems[1].contains("schema version 99 is newer"));
+    }
+
+    #[test]
+    fn test_invalid_labels() {
+        let problems = messages(&bundle(vec![entry(
+            "node-a",
+            &[("Bad_Prefix.com/name", "v"), ("ok", "-dash"), ("fine", "x")],
+        )]));
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("invalid label 'Bad_Prefix.com/name'"));
+        assert!(problems[1].contains("invalid label 'ok'"));
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut misnamed = entry("node-a", &[]);
+        misnamed.configmap = configmap_name("node-b");
+        let problems = messages(&bundle(vec![
+            entry("node-a", &[]),
+            entry("node-a", &[]),
+            misnamed,
+        ]));
+        let a = configmap_name("node-a");
+        let b = configmap_name("node-b");
+        assert_eq!(
+            problems,
+            [
+                format!("{a}: duplicate ConfigMap"),
+                format!("{a}: duplicate backup of node 'node-a'"),
+                format!("{b}: duplicate backup of node 'node-a'"),
+                format!("{b}: is not the backup ConfigMap of node 'node-a'"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_error() {
+        assert_eq!(label_error("example.com/a-b_c.d", "V1"), None);
+        assert_eq!(label_error("a", ""), None);
+        assert!(label_error("", "v").is_some());
+        assert!(label_error("/a", "v").is_some());
+        assert!(label_error("a/b/c", "v").is_some());
+        assert!(label_error(&"a".repeat(64), "v").is_some());
+        assert!(label_error("a", &"v".repeat(64)).is_some());
+        assert!(label_error("a", "has space").is_some());
+    }
+
+    #[test]
+    fn test_validate_label() {
+        assert_eq!(validate_label("example.com/pool", ""), Ok(()));
+        assert_eq!(validate_label("pool", &"v".repeat(63)), Ok(()));
+        assert_eq!(
+            validate_label("pool", &"v".repeat(64)),
+            Err(LabelValidationError::InvalidValue("v".repeat(64)))
+        );
+        assert_eq!(
+            validate_label("Example.com/pool", "gpu"),
+            Err(LabelValidationError::InvalidPrefix(
+                "Example.com".to_string()
+            ))
+        );
+        assert_eq!(
+            validate_label("example.com//pool", "gpu"),
+            Err(LabelValidationError::InvalidName("/pool".to_string()))
+        );
+        assert_eq!(
+            validate_label("example.com/", "gpu"),
+            Err(LabelValidationError::InvalidName(String::new()))
+        );
+        assert_eq!(
+            validate_label("pool", "gpu;a100"),
+            Err(LabelValidationError::InvalidValue("gpu;a100".to_string()))
+        );
+        assert_eq!(
+            label_error("pool", "-gpu"),
+            Some("value '-gpu' is not a valid label value".to_string())
+        );
+    }
+
+    /// Restores use the same rules and leave invalid labels out
+    #[test]
+    fn test_restore_plan_skips_invalid_labels() {
+        let backup = Backup {
+            labels: labels(&[("pool", "gpu"), ("bad key", "x")]),
+            ..Default::default()
+        };
+        let plan = RestorePolicy::default().plan(&backup, &Node::default(), Utc::now());
+        assert_eq!(plan.labels, labels(&[("pool", "gpu")]));
+        assert_eq!(plan.invalid.len(), 1);
+        assert_eq!(plan.invalid[0].0, "bad key");
+    }
+}
@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod tests {
+    use label_preserver::{BurstSummary, BurstTracker, RestoreOutcome};
+    use std::time::{Duration, Instant};
+
+    /// How often a continuing burst is summarized
+    const BURST_SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    /// A burst of restores produces one aggregate summary instead of one record per node
+    #[test]
+    fn test_burst_produces_single_summary() {
+        let tracker = BurstTracker::new(10, WINDOW);
+        let start = Instant::now();
+        let mut summaries = Vec::new();
+        for i in 0..50 {
+            let outcome = match i % 10 {
+                0 => RestoreOutcome::Conflict,
+                1 => RestoreOutcome::Failed,
+                _ => RestoreOutcome::Restored,
+            };
+            let now = start + Duration::from_millis(i * 10);
+            if let Some(summary) = tracker.record(outcome, now) {
+                summaries.push(summary);
+            }
+        }
+        assert_eq!(
+            summaries,
+            vec![BurstSummary {
+                restored: 8,
+                conflicts: 2,
+                failed: 1,
+            }]
+        );
+        assert!(tracker.in_burst());
+    }
+
+    /// The summary is updated at most once per interval while the burst continues
+    #[test]
+    fn test_burst_summary_updates_as_burst_continues() {
+        let tracker = BurstTracker::new(2, WINDOW);
+        let start = Instant::now();
+        assert_eq!(tracker.record(RestoreOutcome::Restored, start), None);
+        assert_eq!(tracker.record(RestoreOutcome::Restored, start), None);
+        assert!(tracker.record(RestoreOutcome::Restored, start).is_some());
+        assert_eq!(tracker.record(RestoreOutcome::Failed, start), None);
+
+        let later = start + BURST_SUMMARY_INTERVAL;
+        assert_eq!(
+            tracker.record(RestoreOutcome::Conflict, later),
+            Some(BurstSummary {
+                restored: 3,
+                conflicts: 1,
+                failed: 1,
+            })
+        );
+    }
+
+    /// Restores spread out over more than the window never form a burst
+    #[test]
+    fn test_no_burst_outside_window() {
+        let tracker = BurstTracker::new(2, WINDOW);
+        let start = Instant::now();
+        for i in 0..10 {
+            let now = start + WINDOW * i;
+            assert_eq!(tracker.record(RestoreOutcome::Restored, now), None);
+        }
+        assert!(!tracker.in_burst());
+    }
+
+    /// Once a burst ends, the next burst is summarized immediately
+    #[test]
+    fn test_new_burst_after_quiet_period() {
+        let tracker = BurstTracker::new(1, WINDOW);
+        let start = Instant::now();
+        tracker.record(RestoreOutcome::Restored, start);
+        assert!(tracker.record(RestoreOutcome::Restored, start).is_some());
+
+        let next_burst = start + WINDOW * 2;
+        assert_eq!(tracker.record(RestoreOutcome::Restored, next_burst), None);
+        assert!(!tracker.in_burst());
+        assert!(tracker
+            .record(RestoreOutcome::Restored, next_burst)
+            .is_some());
+    }
+}
@@ -0,0 +1,207 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use kube::runtime::controller::Action;
+    use label_preserver::{
+        foreign_restore_claim, reconcile, Backup, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+        RESTORE_CLAIMED_AT_KEY, RESTORE_CLAIM_KEY,
+    };
+    use std::time::Duration;
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    /// How long another replica's restore claim is honored
+    const RESTORE_CLAIM_TIMEOUT: Duration = Duration::from_secs(120);
+
+    fn claimed_node(claim: Option<(&str, String)>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                annotations: claim.map(|(holder, claimed_at)| {
+                    BTreeMap::from([
+                        (RESTORE_CLAIM_KEY.to_string(), holder.to_string()),
+                        (RESTORE_CLAIMED_AT_KEY.to_string(), claimed_at),
+                    ])
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn is_forced(req: &RecordedRequest) -> bool {
+        req.uri.query().is_some_and(|q| q.contains("force=true"))
+    }
+
+    /// Serve a backup and a node whose annotations behave like server-side apply: a claim held
+    /// by one field manager conflicts with an unforced claim from another
+    async fn claim_server(claim: Option<(&str, String)>) -> MockApiServer {
+        let data = Backup {
+            labels: [("team.example.com/owner".to_string(), "storage".to_string())].into(),
+            ..Default::default()
+        }
+        .to_configmap_data()
+        .unwrap();
+        let annotations: Arc<Mutex<BTreeMap<String, String>>> = Arc::new(Mutex::new(
+            claimed_node(claim).metadata.annotations.unwrap_or_default(),
+        ));
+        let owner: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(
+            annotations
+                .lock()
+                .unwrap()
+                .get(RESTORE_CLAIM_KEY)
+                .map(|holder| format!("node-label-preserver-claim-{holder}")),
+        ));
+        MockApiServer::start(move |req| {
+            if req.method == Method::GET {
+                return (
+                    StatusCode::OK,
+                    serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+                );
+            }
+            if req.method != Method::PATCH || req.uri.path().contains("/configmaps/") {
+                return (StatusCode::NOT_FOUND, status_json(404, "not found"));
+            }
+            let manager = req.field_manager().unwrap_or_default().to_string();
+            let body = req.json();
+            let applied = body["metadata"]["annotations"].as_object().cloned();
+            let mut annotations = annotations.lock().unwrap();
+            let mut owner = owner.lock().unwrap();
+            if manager.starts_with("node-label-preserver-claim-") {
+                match applied {
+                    Some(applied) => {
+                        if owner.as_ref().is_some_and(|o| *o != manager) && !is_forced(req) {
+                            return (StatusCode::CONFLICT, status_json(409, "conflict"));
+                        }
+                        for (key, value) in applied {
+                            annotations.insert(key, value.as_str().unwrap().to_string());
+                        }
+                        *owner = Some(manager);
+                    }
+                    None if owner.as_ref() == Some(&manager) => {
+                        annotations.remove(RESTORE_CLAIM_KEY);
+                        annotations.remove(RESTORE_CLAIMED_AT_KEY);
+                        *owner = None;
+                    }
+                    None => {}
+                }
+            } else if let Some(applied) = applied {
+                for (key, value) in applied {
+                    annotations.insert(key, value.as_str().unwrap_or_default().to_string());
+                }
+            }
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "metadata": { "name": "node-a", "annotations": *annotations }
+                }),
+            )
+        })
+        .await
+    }
+
+    /// Two instances reconciling the same node at once restore it exactly once
+    #[tokio::test]
+    async fn test_concurrent_instances_restore_once() {
+        let server = claim_server(None).await;
+        let node = Arc::new(claimed_node(None));
+        let (a, b) = tokio::join!(
+            reconcile(
+                node.clone(),
+                context_with(&server, |config| config.instance_id =
+                    "replica-a".to_string())
+            ),
+            reconcile(
+                node.clone(),
+                context_with(&server, |config| config.instance_id =
+                    "replica-b".to_string())
+            ),
+        );
+        a.unwrap();
+        b.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|r| r.is_restore()).count(), 1);
+        let restore = requests.iter().find(|r| r.is_restore()).unwrap();
+        assert!(restore.json()["metadata"]["annotations"]
+            .get(RESTORED_ANNOTATION_KEY)
+            .is_some());
+    }
+
+    /// A claim abandoned by a crashed instance is taken over
+    #[tokio::test]
+    async fn test_stale_claim_is_taken_over() {
+        let claimed_at = (Utc::now() - ChronoDuration::hours(1)).to_rfc3339();
+        let server = claim_server(Some(("crashed", claimed_at.clone()))).await;
+        let node = Arc::new(claimed_node(Some(("crashed", claimed_at))));
+        reconcile(
+            node,
+            context_with(&server, |config| {
+                config.instance_id = "replica-a".to_string()
+            }),
+        )
+        .await
+        .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|r| r.is_restore()).count(), 1);
+        let claim = requests
+            .iter()
+            .find(|r| r.field_manager() == Some("node-label-preserver-claim-replica-a"))
+            .unwrap();
+        assert!(is_forced(claim));
+    }
+
+    /// A fresh claim held by another instance is left alone without touching the API server
+    #[tokio::test]
+    async fn test_fresh_foreign_claim_requeues() {
+        let claimed_at = Utc::now().to_rfc3339();
+        let server = claim_server(Some(("replica-b", claimed_at.clone()))).await;
+        let node = Arc::new(claimed_node(Some(("replica-b", claimed_at))));
+        let action = reconcile(
+            node,
+            context_with(&server, |config| {
+                config.instance_id = "replica-a".to_string()
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(action, Action::requeue(RESTORE_CLAIM_TIMEOUT));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn test_foreign_restore_claim() {
+        let now = Utc::now();
+        let fresh = now.to_rfc3339();
+        let stale = (now - ChronoDuration::hours(1)).to_rfc3339();
+        let node = |holder, claimed_at| claimed_node(Some((holder, claimed_at)));
+        assert_eq!(
+            foreign_restore_claim(&node("replica-b", fresh.clone()), "replica-a", now),
+            Some("replica-b".to_string())
+        );
+        assert_eq!(
+            foreign_restore_claim(&node("replica-a", fresh), "replica-a", now),
+            None
+        );
+        assert_eq!(
+            foreign_restore_claim(&node("replica-b", stale), "replica-a", now),
+            None
+        );
+        assert_eq!(
+            foreign_restore_claim(&node("replica-b", "garbage".to_string()), "replica-a", now),
+            Some("replica-b".to_string())
+        );
+        assert_eq!(
+            foreign_restore_claim(&claimed_node(None), "replica-a", now),
+            None
+        );
+    }
+}
@@ -0,0 +1,112 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, finalized_node, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+
+    use label_preserver::{configmap_name, reconcile, Backup};
+    use std::sync::Arc;
+
+    /// Serve a backup of `zone` at resourceVersion 5, if `has_backup`, answering deletes with
+    /// `delete_status`
+    async fn cluster(has_backup: bool, delete_status: StatusCode) -> MockApiServer {
+        let backup = Backup {
+            labels: [("zone".to_string(), "a".to_string())].into(),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": {
+                "name": configmap_name("node-a"),
+                "namespace": "default",
+                "resourceVersion": "5",
+            },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") && has_backup => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::DELETE, _) if delete_status == StatusCode::OK => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::DELETE, _) => (
+                delete_status,
+                status_json(delete_status.as_u16(), "delete failed"),
+            ),
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// The backup is deleted after the node was patched, unless it changed since it was read
+    #[tokio::test]
+    async fn test_deleted_after_restore() {
+        let server = cluster(true, StatusCode::OK).await;
+        reconcile(
+            Arc::new(finalized_node("node-a")),
+            context_with(&server, |config| config.cleanup_after_restore = true),
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        let restore = requests.iter().position(|r| r.is_restore()).unwrap();
+        let delete = requests
+            .iter()
+            .position(|r| r.method == Method::DELETE)
+            .unwrap();
+        assert!(restore < delete);
+        assert_eq!(
+            requests[delete].uri.path(),
+            format!(
+                "/api/v1/namespaces/default/configmaps/{}",
+                configmap_name("node-a")
+            )
+        );
+        assert_eq!(
+            requests[delete].json()["preconditions"]["resourceVersion"],
+            "5"
+        );
+    }
+
+    /// Backups are kept by default, and there is nothing to delete for a node without one
+    #[tokio::test]
+    async fn test_kept() {
+        let server = cluster(true, StatusCode::OK).await;
+        reconcile(
+            Arc::new(finalized_node("node-a")),
+            context_with(&server, |config| config.cleanup_after_restore = false),
+        )
+        .await
+        .unwrap();
+        assert!(server.requests().iter().all(|r| r.method != Method::DELETE));
+
+        let server = cluster(false, StatusCode::OK).await;
+        reconcile(
+            Arc::new(finalized_node("node-a")),
+            context_with(&server, |config| config.cleanup_after_restore = true),
+        )
+        .await
+        .unwrap();
+        assert!(server.requests().iter().all(|r| r.method != Method::DELETE));
+    }
+
+    /// A backup rewritten since the restore, or one that can't be deleted, doesn't fail the
+    /// restore
+    #[tokio::test]
+    async fn test_delete_failures_are_not_errors() {
+        for status in [StatusCode::CONFLICT, StatusCode::FORBIDDEN] {
+            let server = cluster(true, status).await;
+            reconcile(
+                Arc::new(finalized_node("node-a")),
+                context_with(&server, |config| config.cleanup_after_restore = true),
+            )
+            .await
+            .unwrap();
+            let requests = server.requests();
+            assert!(requests.iter().any(|r| r.method == Method::DELETE));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, MockApiServer};
+    use http::StatusCode;
+    use k8s_openapi::api::core::v1::Node;
+    use kube::api::Api;
+    use label_preserver::{ClientFactory, ClientOptions};
+    use std::path::PathBuf;
+
+    /// Two clusters, each with a context, and `alpha` as the current context
+    struct Clusters {
+        alpha: MockApiServer,
+        beta: MockApiServer,
+        kubeconfig: PathBuf,
+    }
+
+    impl Drop for Clusters {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.kubeconfig);
+        }
+    }
+
+    async fn clusters() -> Clusters {
+        let alpha = MockApiServer::start(|_| (StatusCode::OK, node_json("node-a"))).await;
+        let beta = MockApiServer::start(|_| (StatusCode::OK, node_json("node-a"))).await;
+        let kubeconfig = std::env::temp_dir().join(format!("kubeconfig-{}", uuid::Uuid::new_v4()));
+        let contents = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Config",
+            "current-context": "alpha",
+            "clusters": [
+                { "name": "alpha", "cluster": { "server": alpha.config().cluster_url.to_string() } },
+                { "name": "beta", "cluster": { "server": beta.config().cluster_url.to_string() } },
+            ],
+            "contexts": [
+                { "name": "alpha", "context": { "cluster": "alpha", "user": "admin" } },
+                { "name": "beta", "context": { "cluster": "beta", "user": "admin" } },
+            ],
+            "users": [{ "name": "admin", "user": {} }],
+        });
+        std::fs::write(&kubeconfig, contents.to_string()).unwrap();
+        Clusters {
+            alpha,
+            beta,
+            kubeconfig,
+        }
+    }
+
+    /// Send one request with a client from `factory`
+    async fn get_node(factory: &ClientFactory) {
+        let nodes: Api<Node> = Api::all(factory.client().await.unwrap());
+        nodes.get("node-a").await.unwrap();
+    }
+
+    /// The current context is used unless a context or cluster is given, and a cluster wins
+    /// over the context's cluster
+    #[tokio::test]
+    async fn test_context_precedence() {
+        let clusters = clusters().await;
+        let options = ClientOptions {
+            kubeconfig: Some(clusters.kubeconfig.clone()),
+            ..Default::default()
+        };
+        let hits = |clusters: &Clusters| {
+            (
+                clusters.alpha.requests().len(),
+                clusters.beta.requests().len(),
+            )
+        };
+
+        get_node(&ClientFactory::new(options.clone(), "test")).await;
+        assert_eq!(hits(&clusters), (1, 0));
+
+        let context = ClientOptions {
+            context: Some("beta".to_string()),
+            ..options.clone()
+        };
+        get_node(&ClientFactory::new(context.clone(), "test")).await;
+        assert_eq!(hits(&clusters), (1, 1));
+
+        let cluster = ClientOptions {
+            cluster: Some("alpha".to_string()),
+            ..context
+        };
+        get_node(&ClientFactory::new(cluster, "test")).await;
+        assert_eq!(hits(&clusters), (2, 1));
+    }
+
+    /// A subcommand's own options win over the global ones, and unset ones fall back to them
+    #[tokio::test]
+    async fn test_subcommand_overrides() {
+        let clusters = clusters().await;
+        let global = ClientFactory::new(
+            ClientOptions {
+                kubeconfig: Some(clusters.kubeconfig.clone()),
+                context: Some("alpha".to_string()),
+                impersonate: Some("alice".to_string()),
+                ..Default::default()
+            },
+            "test",
+        );
+        let subcommand = global.overridden_by(&ClientOptions {
+            context: Some("beta".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(subcommand.options().kubeconfig, global.options().kubeconfig);
+        assert_eq!(subcommand.options().impersonate.as_deref(), Some("alice"));
+
+        get_node(&subcommand).await;
+        assert_eq!(clusters.alpha.requests().len(), 0);
+        assert_eq!(clusters.beta.requests().len(), 1);
+    }
+
+    /// Impersonation adds the impersonation headers to every request
+    #[tokio::test]
+    async fn test_impersonation_headers() {
+        let server = MockApiServer::start(|_| (StatusCode::OK, node_json("node-a"))).await;
+        let options = ClientOptions {
+            impersonate: Some("system:serviceaccount:kube-system:label-preserver".to_string()),
+            impersonate_groups: vec!["system:serviceaccounts".to_string(), "ops".to_string()],
+            ..Default::default()
+        };
+        get_node(&ClientFactory::with_base_config(
+            server.config(),
+            options,
+            "test",
+        ))
+        .await;
+
+        let requests = server.requests();
+        let headers = &requests[0].headers;
+        assert_eq!(
+            headers.get("impersonate-user").unwrap(),
+            "system:serviceaccount:kube-system:label-preserver"
+        );
+        let groups: Vec<_> = headers.get_all("impersonate-group").iter().collect();
+        assert_eq!(groups, ["system:serviceaccounts", "ops"]);
+    }
+
+    /// A missing kubeconfig is reported instead of silently falling back
+    #[tokio::test]
+    async fn test_missing_kubeconfig() {
+        let options = ClientOptions {
+            kubeconfig: Some(PathBuf::from("/nonexistent/kubeconfig")),
+            ..Default::default()
+        };
+        let error = ClientFactory::new(options, "test")
+            .config()
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Failed to load the client configuration"));
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, MockApiServer};
+    use http::StatusCode;
+    use k8s_openapi::api::core::v1::Node;
+    use kube::api::Api;
+    use label_preserver::{client_with_user_agent, user_agent};
+
+    /// The User-Agent contains the crate name, version, instance identity, and optional suffix
+    #[test]
+    fn test_user_agent_format() {
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            user_agent("replica-0", None),
+            format!("label-preserver/{} (instance=replica-0)", version)
+        );
+        assert_eq!(
+            user_agent("replica-0", Some("my-operator/2.1")),
+            format!(
+                "label-preserver/{} (instance=replica-0) my-operator/2.1",
+                version
+            )
+        );
+    }
+
+    /// Requests sent by the client carry our User-Agent header
+    #[tokio::test]
+    async fn test_requests_carry_user_agent() {
+        let server = MockApiServer::start(|_| (StatusCode::OK, node_json("node-a"))).await;
+        let client = client_with_user_agent(server.config(), "replica-1", Some("embedder/1.0"))
+            .expect("client should build");
+
+        let nodes: Api<Node> = Api::all(client);
+        nodes.get("node-a").await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].headers.get(http::header::USER_AGENT).unwrap(),
+            user_agent("replica-1", Some("embedder/1.0")).as_str()
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! A mock Kubernetes API server for tests that don't need a real cluster.
+//! Requests are answered by a handler closure and recorded so tests can assert on them.
+//! Also the fixtures most tests share: label maps, managed nodes, contexts, and a cluster
+//! keeping ConfigMaps.
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, StatusCode, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::{server::conn::http1, service::service_fn, Request, Response};
+use hyper_util::rt::TokioIo;
+use k8s_openapi::api::core::v1::Node;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use k8s_openapi::chrono::Utc;
+use kube::{Client, Config};
+use label_preserver::{Context, ControllerConfig, FINALIZER_NAME};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::net::TcpListener;
+
+/// A request received by the mock API server
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl RecordedRequest {
+    /// The request body parsed as JSON, or Null if there was none.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or(serde_json::Value::Null)
+    }
+
+    /// The `fieldManager` query parameter, if any
+    pub fn field_manager(&self) -> Option<&str> {
+        self.uri
+            .query()?
+            .split('&')
+            .find_map(|param| param.strip_prefix("fieldManager="))
+    }
+
+    /// Whether this publishes or updates an Event
+    pub fn is_event(&self) -> bool {
+        self.uri.path().starts_with("/apis/events.k8s.io/")
+    }
+
+    /// Whether this is the server-side apply that restores labels, as opposed to the restore
+    /// claim applied around it
+    pub fn is_restore(&self) -> bool {
+        self.method == Method::PATCH
+            && self.uri.path().starts_with("/api/v1/nodes/")
+            && self.field_manager() == Some("node-label-preserver")
+    }
+}
+
+type Handler = dyn Fn(&RecordedRequest) -> (StatusCode, serde_json::Value) + Send + Sync;
+
+/// An HTTP server on localhost that stands in for the Kubernetes API server
+pub struct MockApiServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockApiServer {
+    /// Start serving, answering every request with `handler`
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&RecordedRequest) -> (StatusCode, serde_json::Value) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let handler = handler.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let handler = handler.clone();
+                        let requests = requests.clone();
+                        async move {
+                            let (parts, body) = req.into_parts();
+                            let body = body.collect().await.unwrap().to_bytes();
+                            let recorded = RecordedRequest {
+                                method: parts.method,
+                                uri: parts.uri,
+                                headers: parts.headers,
+                                body,
+                            };
+                            let (status, json) = handler(&recorded);
+                            requests.lock().unwrap().push(recorded);
+                            let response = Response::builder()
+                                .status(status)
+                                .header("content-type", "application/json")
+                                .body(Full::new(Bytes::from(json.to_string())))
+                                .unwrap();
+                            Ok::<_, Infallible>(response)
+                        }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    /// A Config pointing at this server
+    pub fn config(&self) -> Config {
+        Config::new(format!("http://{}", self.addr).parse().unwrap())
+    }
+
+    /// A Client talking to this server
+    pub fn client(&self) -> Client {
+        Client::try_from(self.config()).unwrap()
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A minimal Node object as the API server would return it
+pub fn node_json(name: &str) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Node",
+        "metadata": { "name": name },
+    })
+}
+
+/// A Status object as the API server returns for failed requests
+pub fn status_json(code: u16, reason: &str) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "status": "Failure",
+        "message": reason,
+        "reason": reason,
+        "code": code,
+    })
+}
+
+pub fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// A node holding our finalizer, being deleted if `deleted`
+pub fn node(name: &str, labels: BTreeMap<String, String>, deleted: bool) -> Node {
+    Node {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+            deletion_timestamp: deleted.then(|| Time(Utc::now())),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// A node that already carries our finalizer, so reconcile goes straight to Apply
+pub fn finalized_node(name: &str) -> Node {
+    Node {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// A context on `server` with `config`, without startup pacing so reconciles don't wait
+pub fn context(server: &MockApiServer, config: ControllerConfig) -> Arc<Context> {
+    let config = ControllerConfig {
+        startup_rate: 0.0,
+        ..config
+    };
+    Arc::new(Context::with_config(server.client(), config))
+}
+
+/// A [`context`] on `server` with the default configuration as changed by `configure`
+pub fn context_with(
+    server: &MockApiServer,
+    configure: impl FnOnce(&mut ControllerConfig),
+) -> Arc<Context> {
+    let mut config = ControllerConfig::default();
+    configure(&mut config);
+    context(server, config)
+}
+
+/// ConfigMaps by name, as JSON
+pub type ConfigMaps = Arc<Mutex<BTreeMap<String, Value>>>;
+
+/// `initial` by name
+pub fn configmaps(initial: &[Value]) -> ConfigMaps {
+    let configmaps = initial
+        .iter()
+        .map(|cm| {
+            (
+                cm["metadata"]["name"].as_str().unwrap().to_string(),
+                cm.clone(),
+            )
+        })
+        .collect();
+    Arc::new(Mutex::new(configmaps))
+}
+
+/// The `key=value` of a request's single label selector, if any
+fn label_selector(query: Option<&str>) -> Option<(String, String)> {
+    let selector = query?
+        .split('&')
+        .find_map(|param| param.strip_prefix("labelSelector="))?
+        .replace("%3D", "=")
+        .replace("%2F", "/");
+    let (key, value) = selector.split_once('=')?;
+    Some((key.to_string(), value.to_string()))
+}
+
+/// A cluster keeping ConfigMaps in `configmaps`, read, listed by a single label, applied, and
+/// deleted by name. Dry runs aren't kept. Node patches and Events are accepted.
+pub async fn cluster(configmaps: ConfigMaps) -> MockApiServer {
+    MockApiServer::start(move |req| {
+        let mut configmaps = configmaps.lock().unwrap();
+        let path = req.uri.path();
+        let name = path.rsplit('/').next().unwrap().to_string();
+        match req.method {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            Method::GET if path.ends_with("/configmaps") => {
+                let selector = label_selector(req.uri.query());
+                let items: Vec<Value> = configmaps
+                    .values()
+                    .filter(|cm| match &selector {
+                        Some((key, value)) => cm["metadata"]["labels"][key] == **value,
+                        None => true,
+                    })
+                    .cloned()
+                    .collect();
+                let list = json!({
+                    "apiVersion": "v1",
+                    "kind": "ConfigMapList",
+                    "metadata": { "resourceVersion": "1" },
+                    "items": items,
+                });
+                (StatusCode::OK, list)
+            }
+            Method::GET if path.contains("/configmaps/") => match configmaps.get(&name) {
+                Some(cm) => (StatusCode::OK, cm.clone()),
+                None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            },
+            Method::PATCH if path.contains("/configmaps/") => {
+                let mut cm = req.json();
+                cm["metadata"]["resourceVersion"] = "1".into();
+                if !req.uri.query().unwrap_or("").contains("dryRun=All") {
+                    configmaps.insert(name, cm.clone());
+                }
+                (StatusCode::OK, cm)
+            }
+            Method::DELETE if path.contains("/configmaps/") => match configmaps.remove(&name) {
+                Some(cm) => (StatusCode::OK, cm),
+                None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            },
+            Method::PATCH if req.is_restore() => (StatusCode::OK, req.json()),
+            Method::PATCH => (StatusCode::OK, node_json(&name)),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+        }
+    })
+    .await
+}
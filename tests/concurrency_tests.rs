@@ -0,0 +1,126 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{reconcile, Context, ControllerConfig, FINALIZER_NAME};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const NODES: usize = 8;
+
+    /// Backup ConfigMap writes in flight, and the most seen at once
+    #[derive(Default)]
+    struct Writes {
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    fn deleted_node(name: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: Some(Time(Utc::now())),
+                labels: Some(BTreeMap::from([("zone".to_string(), "a".to_string())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Accept every request, holding each backup write for a while so concurrent ones overlap
+    async fn cluster(writes: Arc<Writes>) -> MockApiServer {
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                let in_flight = writes.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                writes.peak.fetch_max(in_flight, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                writes.in_flight.fetch_sub(1, Ordering::SeqCst);
+                (StatusCode::OK, req.json())
+            }
+            (&Method::PATCH, path) => {
+                let name = path.rsplit('/').next().unwrap();
+                (StatusCode::OK, node_json(name))
+            }
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// Back up NODES deleted nodes at once, returning the most backup writes seen in flight
+    async fn peak_writes(max_concurrent_writes: Option<usize>) -> usize {
+        let writes = Arc::new(Writes::default());
+        let server = cluster(writes.clone()).await;
+        let config = ControllerConfig {
+            max_concurrent_writes,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let cleanups = (0..NODES).map(|i| {
+            let node = Arc::new(deleted_node(&format!("node-{}", i)));
+            tokio::spawn(reconcile(node, ctx.clone()))
+        });
+        for cleanup in futures::future::join_all(cleanups).await {
+            cleanup.unwrap().unwrap();
+        }
+        let backups = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .count();
+        assert_eq!(backups, NODES);
+        writes.peak.load(Ordering::SeqCst)
+    }
+
+    /// No more backup writes than the limit are in flight at once, however many nodes are
+    /// cleaned up concurrently
+    #[tokio::test(flavor = "multi_thread", worker_threads = 16)]
+    async fn test_write_limit_honored() {
+        let peak = peak_writes(Some(2)).await;
+        assert!((1..=2).contains(&peak), "peak {}", peak);
+
+        let peak = peak_writes(Some(1)).await;
+        assert_eq!(peak, 1);
+    }
+
+    /// Without a limit, writes are only bounded by the reconciles in flight
+    #[tokio::test(flavor = "multi_thread", worker_threads = 16)]
+    async fn test_writes_unbounded_by_default() {
+        assert_eq!(ControllerConfig::default().max_concurrent_writes, None);
+        let peak = peak_writes(None).await;
+        assert!(peak > 2, "peak {}", peak);
+    }
+
+    /// Watch settings apply to the node watch and the backup watch, on top of the selectors
+    #[test]
+    fn test_watcher_config() {
+        let config = ControllerConfig {
+            label_selector: Some("pool=gpu".to_string()),
+            watch_page_size: Some(100),
+            watch_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let watcher = config.watcher_config();
+        assert_eq!(watcher.page_size, Some(100));
+        assert_eq!(watcher.timeout, Some(60));
+        assert_eq!(watcher.label_selector.as_deref(), Some("pool=gpu"));
+        let backups = config.backup_watcher_config();
+        assert_eq!(backups.page_size, Some(100));
+        assert_eq!(backups.label_selector, None);
+
+        let defaults = ControllerConfig::default();
+        assert_eq!(
+            defaults.watcher_config().page_size,
+            kube::runtime::watcher::Config::default().page_size
+        );
+        assert_eq!(defaults.concurrency, 0);
+    }
+}
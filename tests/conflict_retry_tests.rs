@@ -0,0 +1,177 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, labels, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        conflicting_field_managers, is_permanent, reconcile, Backup, MergeStrategy, FINALIZER_NAME,
+        MANAGED_LABEL_KEY,
+    };
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Applies of a restore before a conflict fails the reconcile
+    const NODE_PATCH_ATTEMPTS: u32 = 3;
+
+    const CONFLICT: &str = "Apply failed with 1 conflict: conflict with \"kubelet\" using v1: \
+        .metadata.labels.zone";
+
+    /// A node that was just created, without labels yet
+    fn new_node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A cluster holding a backup of node-a, whose `zone` label the kubelet keeps changing:
+    /// each read of node-a returns the current value, then the kubelet sets the next one, up
+    /// to `zones` values. Like the API server, an apply that isn't forced and sets another
+    /// value conflicts.
+    async fn cluster(zones: usize, restores: Arc<AtomicU32>) -> MockApiServer {
+        let backup = Backup {
+            labels: labels(&[("zone", "backed-up"), ("tenant", "acme")]),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        let zone = Arc::new(AtomicUsize::new(1));
+        let current = move |zone: &AtomicUsize| format!("live-{}", zone.load(Ordering::SeqCst));
+        MockApiServer::start(move |req| match req.method {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            Method::GET if req.uri.path().contains("/configmaps/") => {
+                (StatusCode::OK, json!({ "data": data }))
+            }
+            Method::GET if req.uri.path() == "/api/v1/nodes/node-a" => {
+                let mut node = node_json("node-a");
+                node["metadata"]["labels"] = json!({ "zone": current(&zone), "fresh": "yes" });
+                if zone.load(Ordering::SeqCst) < zones {
+                    zone.fetch_add(1, Ordering::SeqCst);
+                }
+                (StatusCode::OK, node)
+            }
+            Method::PATCH if req.is_restore() => {
+                restores.fetch_add(1, Ordering::SeqCst);
+                let forced = req.uri.query().unwrap_or("").contains("force=true");
+                let applied = req.json()["metadata"]["labels"]["zone"].clone();
+                if !forced && applied != current(&zone).as_str() {
+                    let conflict = json!({
+                        "apiVersion": "v1",
+                        "kind": "Status",
+                        "status": "Failure",
+                        "message": CONFLICT,
+                        "reason": "Conflict",
+                        "code": 409,
+                    });
+                    return (StatusCode::CONFLICT, conflict);
+                }
+                (StatusCode::OK, req.json())
+            }
+            Method::PATCH => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+        })
+        .await
+    }
+
+    /// The labels each restore applied, in order
+    fn applied_labels(server: &MockApiServer) -> Vec<Value> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.is_restore())
+            .map(|r| r.json()["metadata"]["labels"].clone())
+            .collect()
+    }
+
+    /// A restore that conflicts with the kubelet changing a label reads the node again and
+    /// merges the backup into its fresh labels, twice here, and succeeds within the reconcile
+    #[tokio::test]
+    async fn test_conflicts_then_success() {
+        let restores = Arc::new(AtomicU32::new(0));
+        let server = cluster(2, restores.clone()).await;
+        reconcile(
+            Arc::new(new_node()),
+            context_with(&server, |config| {
+                config.merge_strategy = MergeStrategy::PreferCurrent
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(restores.load(Ordering::SeqCst), 3);
+        let applied = applied_labels(&server);
+        assert_eq!(applied[0]["zone"], "backed-up");
+        assert_eq!(applied[1]["zone"], "live-1");
+        assert_eq!(
+            applied[2],
+            json!({
+                "zone": "live-2",
+                "fresh": "yes",
+                "tenant": "acme",
+                MANAGED_LABEL_KEY: "true",
+            })
+        );
+        let forced = server
+            .requests()
+            .iter()
+            .any(|r| r.is_restore() && r.uri.query().unwrap_or("").contains("force=true"));
+        assert!(!forced);
+    }
+
+    /// A node that keeps conflicting fails the reconcile after NODE_PATCH_ATTEMPTS applies, with
+    /// an error that is retried like any other
+    #[tokio::test]
+    async fn test_conflicts_exhausted() {
+        let restores = Arc::new(AtomicU32::new(0));
+        let server = cluster(usize::MAX, restores.clone()).await;
+        let error = reconcile(
+            Arc::new(new_node()),
+            context_with(&server, |config| {
+                config.merge_strategy = MergeStrategy::PreferCurrent
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(restores.load(Ordering::SeqCst), NODE_PATCH_ATTEMPTS);
+        assert!(error.to_string().contains("conflict with \"kubelet\""));
+        assert!(!is_permanent(&error));
+    }
+
+    /// Under prefer-preserved the restore is forced, replacing the kubelet's value without a
+    /// conflict
+    #[tokio::test]
+    async fn test_prefer_preserved_forces() {
+        let restores = Arc::new(AtomicU32::new(0));
+        let server = cluster(usize::MAX, restores.clone()).await;
+        reconcile(
+            Arc::new(new_node()),
+            context_with(&server, |config| {
+                config.merge_strategy = MergeStrategy::PreferPreserved
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(restores.load(Ordering::SeqCst), 1);
+        assert_eq!(applied_labels(&server)[0]["zone"], "backed-up");
+    }
+
+    /// Field managers are read from single and multiple conflicts, each named once
+    #[test]
+    fn test_conflicting_field_managers() {
+        assert_eq!(conflicting_field_managers(CONFLICT), vec!["kubelet"]);
+        let several = "Apply failed with 3 conflicts: conflicts with \"kubectl-label\" using v1:\n\
+            - .metadata.labels.a\n- .metadata.labels.b\nconflict with \"cluster-autoscaler\" \
+            using v1: .metadata.labels.c";
+        assert_eq!(
+            conflicting_field_managers(several),
+            vec!["kubectl-label", "cluster-autoscaler"]
+        );
+        assert!(conflicting_field_managers("the object has been modified").is_empty());
+    }
+}
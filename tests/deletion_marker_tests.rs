@@ -0,0 +1,161 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeSpec, Taint};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        reconcile, Backup, Context, ControllerConfig, DeletionMarkers, FINALIZER_NAME,
+        RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// A restored node labeled `pool=<pool>`, tainted with `taint` if given
+    fn node(pool: &str, taint: Option<&str>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some([("pool".to_string(), pool.to_string())].into()),
+                annotations: Some([(RESTORED_ANNOTATION_KEY.to_string(), "1".to_string())].into()),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                taints: taint.map(|key| {
+                    vec![Taint {
+                        key: key.to_string(),
+                        effect: "NoSchedule".to_string(),
+                        ..Default::default()
+                    }]
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn server() -> MockApiServer {
+        MockApiServer::start(
+            |req| match (&req.method, req.uri.path().contains("/configmaps/")) {
+                (&Method::PATCH, true) => (StatusCode::OK, req.json()),
+                (&Method::PATCH, false) => (StatusCode::OK, node_json("node-a")),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            },
+        )
+        .await
+    }
+
+    /// The labels in each backup written
+    fn backups(requests: &[RecordedRequest]) -> Vec<BTreeMap<String, String>> {
+        requests
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .map(|r| {
+                let data = serde_json::from_value(r.json()["data"].clone()).unwrap();
+                Backup::from_configmap_data(&data).unwrap().labels
+            })
+            .collect()
+    }
+
+    fn pool(value: &str) -> BTreeMap<String, String> {
+        [("pool".to_string(), value.to_string())].into()
+    }
+
+    /// A marker appearing backs the node up before its deletion starts, once per set of labels
+    #[tokio::test]
+    async fn test_marker_backs_up_before_deletion() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+
+        reconcile(Arc::new(node("gpu", None)), ctx.clone())
+            .await
+            .unwrap();
+        assert!(server.requests().is_empty());
+
+        let marked = node("gpu", Some("ToBeDeletedByClusterAutoscaler"));
+        reconcile(Arc::new(marked.clone()), ctx.clone())
+            .await
+            .unwrap();
+        reconcile(Arc::new(marked), ctx.clone()).await.unwrap();
+        assert_eq!(backups(&server.requests()), [pool("gpu")]);
+
+        let relabeled = node("cpu", Some("ToBeDeletedByClusterAutoscaler"));
+        reconcile(Arc::new(relabeled.clone()), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backups(&server.requests()), [pool("gpu"), pool("cpu")]);
+
+        let mut deleting = relabeled;
+        deleting.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(deleting), ctx.clone()).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(backups(&requests), [pool("gpu"), pool("cpu"), pool("cpu")]);
+        // Only the finalizer release touches the node, after every backup
+        let node_patches: Vec<_> = requests
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.uri.path() == "/api/v1/nodes/node-a")
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(node_patches, [requests.len() - 1]);
+    }
+
+    /// Markers are configurable, including annotations
+    #[tokio::test]
+    async fn test_configured_markers() {
+        let server = server().await;
+        let config = ControllerConfig {
+            deletion_markers: DeletionMarkers {
+                taints: vec![],
+                annotations: vec!["scale-down.example.com/candidate".to_string()],
+            },
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+
+        reconcile(
+            Arc::new(node("gpu", Some("ToBeDeletedByClusterAutoscaler"))),
+            ctx.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(server.requests().is_empty());
+
+        let mut annotated = node("gpu", None);
+        annotated.metadata.annotations.as_mut().unwrap().insert(
+            "scale-down.example.com/candidate".to_string(),
+            "true".to_string(),
+        );
+        reconcile(Arc::new(annotated), ctx.clone()).await.unwrap();
+        assert_eq!(backups(&server.requests()), [pool("gpu")]);
+    }
+
+    /// A marked node that hasn't been restored yet isn't backed up, so a backup of it still on
+    /// its way isn't replaced. Without a backup it isn't marked restored either.
+    #[tokio::test]
+    async fn test_unrestored_node_is_not_backed_up() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut unrestored = node("gpu", Some("ToBeDeletedByClusterAutoscaler"));
+        unrestored.metadata.annotations = None;
+        reconcile(Arc::new(unrestored), ctx).await.unwrap();
+        let requests = server.requests();
+        assert!(backups(&requests).is_empty());
+        assert!(!requests.iter().any(|r| r.is_restore()));
+    }
+
+    #[test]
+    fn test_find_marker() {
+        let markers = DeletionMarkers::default();
+        assert_eq!(markers.find(&node("gpu", None)), None);
+        assert_eq!(
+            markers.find(&node("gpu", Some("karpenter.sh/disrupted"))),
+            Some("karpenter.sh/disrupted")
+        );
+        assert_eq!(markers.find(&node("gpu", Some("other"))), None);
+    }
+}
@@ -0,0 +1,226 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node, NodeSpec, Taint};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::runtime::watcher;
+    use label_preserver::{
+        configmap_name, reconcile, shard_configmap_name, shard_key, BackupDigests, BackupLayout,
+        Context, DigestStats, CONFIGMAP_NAMESPACE, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    const HEARTBEATS: usize = 1000;
+
+    /// A restored node marked for removal by the autoscaler, at `resource_version`
+    fn node(resource_version: usize, zone: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                resource_version: Some(resource_version.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some(BTreeMap::from([("zone".to_string(), zone.to_string())])),
+                annotations: Some(BTreeMap::from([(
+                    RESTORED_ANNOTATION_KEY.to_string(),
+                    "1".to_string(),
+                )])),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                taints: Some(vec![Taint {
+                    key: "ToBeDeletedByClusterAutoscaler".to_string(),
+                    effect: "NoSchedule".to_string(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn configmap(name: &str, resource_version: &str) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some(CONFIGMAP_NAMESPACE.to_string()),
+                resource_version: Some(resource_version.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn backup_writes(server: &MockApiServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .count()
+    }
+
+    /// Heartbeats of a node marked for removal back it up once, and only hash its labels when
+    /// the node changed
+    #[tokio::test]
+    async fn test_heartbeats_skip_work() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::PATCH => {
+                let mut cm = req.json();
+                cm["metadata"]["resourceVersion"] = "7".into();
+                (StatusCode::OK, cm)
+            }
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        for version in 0..HEARTBEATS {
+            // Every status heartbeat is a new resourceVersion, and each is seen twice
+            for _ in 0..2 {
+                reconcile(Arc::new(node(version, "a")), ctx.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(backup_writes(&server), 1);
+        assert_eq!(
+            ctx.backup_digests().stats(),
+            DigestStats {
+                unchanged: HEARTBEATS as u64,
+                rehashed: HEARTBEATS as u64 - 1,
+                misses: 1,
+            }
+        );
+
+        // A label change is backed up again
+        reconcile(Arc::new(node(HEARTBEATS, "b")), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backup_writes(&server), 2);
+
+        // So is a backup changed by someone else, but not our own write coming back
+        let name = configmap_name("node-a");
+        let ours = watcher::Event::Apply(configmap(&name, "7"));
+        ctx.backup_digests().observe(&ours);
+        reconcile(Arc::new(node(HEARTBEATS + 1, "b")), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backup_writes(&server), 2);
+        let edited = watcher::Event::Apply(configmap(&name, "8"));
+        ctx.backup_digests().observe(&edited);
+        reconcile(Arc::new(node(HEARTBEATS + 1, "b")), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backup_writes(&server), 3);
+    }
+
+    /// Edits, deletions, and relists of other versions drop a digest, other ConfigMaps are
+    /// ignored
+    #[test]
+    fn test_invalidation() {
+        let digests = BackupDigests::default();
+        let name = configmap_name("node-a");
+        let recorded = || {
+            let digests = BackupDigests::default();
+            digests.record(CONFIGMAP_NAMESPACE, &node(1, "a"), Some("5".to_string()));
+            digests
+        };
+
+        digests.record(CONFIGMAP_NAMESPACE, &node(1, "a"), Some("5".to_string()));
+        digests.observe(&watcher::Event::Apply(configmap(&name, "5")));
+        digests.observe(&watcher::Event::Apply(configmap("unrelated", "9")));
+        assert!(digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")));
+        assert!(digests.is_current(CONFIGMAP_NAMESPACE, &node(2, "a")));
+        assert!(!digests.is_current(CONFIGMAP_NAMESPACE, &node(3, "b")));
+
+        for event in [
+            watcher::Event::Apply(configmap(&name, "6")),
+            watcher::Event::InitApply(configmap(&name, "6")),
+            watcher::Event::Delete(configmap(&name, "5")),
+        ] {
+            let digests = recorded();
+            digests.observe(&event);
+            assert!(
+                !digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")),
+                "{event:?}"
+            );
+        }
+
+        let digests = recorded();
+        digests.forget(CONFIGMAP_NAMESPACE, "node-a");
+        assert!(digests.is_empty());
+        let digests = recorded();
+        digests.clear();
+        assert!(!digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")));
+    }
+
+    /// The cache never grows past its capacity
+    #[test]
+    fn test_bounded() {
+        let digests = BackupDigests::with_capacity(32);
+        for i in 0..1000 {
+            let mut node = node(1, "a");
+            node.metadata.name = Some(format!("node-{i}"));
+            digests.record(CONFIGMAP_NAMESPACE, &node, None);
+        }
+        assert!(digests.len() <= 32, "{}", digests.len());
+        assert!(!digests.is_empty());
+    }
+
+    /// Under the sharded layout, each key of a shard is digested on its own: our own write of a
+    /// node's entry keeps its digest, while an edit or removal of the entry drops it
+    #[test]
+    fn test_sharded_invalidation() {
+        let shard = |entries: &[(&str, &str)]| {
+            let mut cm = configmap(&shard_configmap_name("node-a"), "9");
+            cm.data = Some(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            );
+            cm
+        };
+        let key = shard_key("node-a");
+        let recorded = || {
+            let digests = BackupDigests::default().with_layout(BackupLayout::Sharded);
+            digests.record(CONFIGMAP_NAMESPACE, &node(1, "a"), Some("{}".to_string()));
+            digests.observe(&watcher::Event::Apply(shard(&[(&key, "{}")])));
+            digests
+        };
+
+        let digests = recorded();
+        digests.observe(&watcher::Event::Apply(shard(&[
+            (&key, "{}"),
+            ("other", "{}"),
+        ])));
+        assert!(digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")));
+        assert!(digests
+            .labels_digest(CONFIGMAP_NAMESPACE, "node-a")
+            .is_some());
+        // Per-node ConfigMaps aren't backups under this layout
+        digests.observe(&watcher::Event::Delete(configmap(
+            &configmap_name("node-a"),
+            "9",
+        )));
+        assert!(digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")));
+
+        for event in [
+            watcher::Event::Apply(shard(&[(&key, r#"{"labels":"{}"}"#)])),
+            watcher::Event::Apply(shard(&[("other", "{}")])),
+            watcher::Event::Delete(shard(&[(&key, "{}")])),
+        ] {
+            let digests = recorded();
+            digests.observe(&event);
+            assert!(
+                !digests.is_current(CONFIGMAP_NAMESPACE, &node(1, "a")),
+                "{event:?}"
+            );
+        }
+        let digests = recorded();
+        digests.observe(&watcher::Event::Apply(shard(&[("other", "{}")])));
+        assert_eq!(digests.labels_digest(CONFIGMAP_NAMESPACE, "node-a"), None);
+    }
+}
@@ -0,0 +1,105 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{cluster, labels, node, ConfigMaps, MockApiServer};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::chrono::DateTime;
+    use kube::api::{Api, ListParams, ResourceExt};
+    use label_preserver::{
+        configmap_name, find_configmap_for_node, label_error, node_name_label_value, reconcile,
+        Backup, Context, MANAGED_BY_LABEL_KEY, NODE_NAME_KEY, PRESERVED_AT_ANNOTATION_KEY,
+    };
+    use std::sync::Arc;
+
+    async fn delete_node(server: &MockApiServer, name: &str) {
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(node(name, labels(&[("zone", "a")]), true)), ctx)
+            .await
+            .unwrap();
+    }
+
+    /// A deleted node's backup can be listed by its node name label, and is found by name
+    #[tokio::test]
+    async fn test_list_by_node_name() {
+        let server = cluster(ConfigMaps::default()).await;
+        delete_node(&server, "node-a").await;
+        delete_node(&server, "node-b").await;
+
+        let cm_api: Api<ConfigMap> = Api::namespaced(server.client(), "default");
+        let selector = format!("{}=node-a", NODE_NAME_KEY);
+        let listed = cm_api
+            .list(&ListParams::default().labels(&selector))
+            .await
+            .unwrap();
+        assert_eq!(listed.items.len(), 1);
+        let cm = &listed.items[0];
+        assert_eq!(cm.name_any(), configmap_name("node-a"));
+        assert_eq!(
+            cm.labels().get(MANAGED_BY_LABEL_KEY).map(String::as_str),
+            Some("node-label-preserver")
+        );
+        assert_eq!(cm.annotations()[NODE_NAME_KEY], "node-a");
+        let preserved_at = &cm.annotations()[PRESERVED_AT_ANNOTATION_KEY];
+        assert!(DateTime::parse_from_rfc3339(preserved_at).is_ok());
+
+        let found = find_configmap_for_node(server.client(), "default", "node-a")
+            .await
+            .unwrap()
+            .expect("the backup is found");
+        assert_eq!(found.name_any(), configmap_name("node-a"));
+        let backup = Backup::from_configmap_data(found.data.as_ref().unwrap()).unwrap();
+        assert_eq!(backup.labels["zone"], "a");
+        let missing = find_configmap_for_node(server.client(), "default", "node-c").await;
+        assert!(missing.unwrap().is_none());
+    }
+
+    /// Names too long for a label value are truncated to unique, valid values, and the full
+    /// name still finds the backup
+    #[tokio::test]
+    async fn test_long_node_names() {
+        let first = format!("{}.first", "a".repeat(63));
+        let second = format!("{}.second", "a".repeat(63));
+        let (first_value, second_value) = (
+            node_name_label_value(&first),
+            node_name_label_value(&second),
+        );
+        assert_eq!(first_value.len(), 63);
+        assert_ne!(first_value, second_value);
+        assert_eq!(label_error(NODE_NAME_KEY, &first_value), None);
+        assert_eq!(node_name_label_value("node-a"), "node-a");
+
+        let stored = ConfigMaps::default();
+        let server = cluster(stored.clone()).await;
+        delete_node(&server, &first).await;
+        delete_node(&server, &second).await;
+        let cm = stored.lock().unwrap()[&configmap_name(&first)].clone();
+        assert_eq!(cm["metadata"]["labels"][NODE_NAME_KEY], first_value);
+        assert_eq!(cm["metadata"]["annotations"][NODE_NAME_KEY], first);
+        let found = find_configmap_for_node(server.client(), "default", &second)
+            .await
+            .unwrap()
+            .expect("the backup is found");
+        assert_eq!(found.name_any(), configmap_name(&second));
+    }
+
+    /// Backups written before ConfigMaps were labeled are found by their hashed name
+    #[tokio::test]
+    async fn test_unlabeled_backup() {
+        let stored = ConfigMaps::default();
+        let server = cluster(stored.clone()).await;
+        let name = configmap_name("node-a");
+        stored.lock().unwrap().insert(
+            name.clone(),
+            serde_json::json!({
+                "metadata": { "name": name, "namespace": "default" },
+                "data": Backup::default().to_configmap_data().unwrap(),
+            }),
+        );
+        let found = find_configmap_for_node(server.client(), "default", "node-a")
+            .await
+            .unwrap()
+            .expect("the backup is found");
+        assert_eq!(found.name_any(), name);
+    }
+}
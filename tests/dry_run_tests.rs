@@ -0,0 +1,117 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        configmap_name, reconcile, Backup, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::Arc;
+
+    fn node(finalizers: &[&str]) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(finalizers.iter().map(|f| f.to_string()).collect()),
+                labels: Some(BTreeMap::from([("zone".to_string(), "a".to_string())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Serve a backup with a `team` label, and accept every write
+    async fn cluster() -> MockApiServer {
+        let backup = Backup {
+            labels: BTreeMap::from([("team".to_string(), "ml".to_string())]),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::GET, _) => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            _ => (StatusCode::OK, req.json()),
+        })
+        .await
+    }
+
+    /// The methods of every request made to nodes and ConfigMaps, by kind
+    fn requests(server: &MockApiServer) -> BTreeSet<(String, &'static str)> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| !r.is_event())
+            .map(|r| {
+                let kind = if r.uri.path().contains("/configmaps") {
+                    "configmaps"
+                } else {
+                    "nodes"
+                };
+                (r.method.to_string(), kind)
+            })
+            .collect()
+    }
+
+    /// A new node's backup is read, but the node is neither restored nor given our finalizer
+    #[tokio::test]
+    async fn test_restore_not_applied() {
+        let server = cluster().await;
+        let ctx = context_with(&server, |config| config.dry_run = true);
+        reconcile(Arc::new(node(&[])), ctx).await.unwrap();
+        assert_eq!(
+            requests(&server),
+            BTreeSet::from([("GET".to_string(), "configmaps")])
+        );
+    }
+
+    /// A deleted node is not backed up
+    #[tokio::test]
+    async fn test_backup_not_written() {
+        let server = cluster().await;
+        let ctx = context_with(&server, |config| config.dry_run = true);
+        let mut deleted = node(&["example.com/other"]);
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(deleted), ctx).await.unwrap();
+        assert!(requests(&server).is_empty());
+    }
+
+    /// Nodes already carrying our finalizer, in scope or not, keep it
+    #[tokio::test]
+    async fn test_finalizer_left_alone() {
+        for node_names in [None, Some(BTreeSet::from(["node-b".to_string()]))] {
+            let server = cluster().await;
+            let ctx = context_with(&server, |config| {
+                config.dry_run = true;
+                config.node_names = node_names;
+            });
+            reconcile(Arc::new(node(&[FINALIZER_NAME])), ctx)
+                .await
+                .unwrap();
+            assert!(!server.requests().iter().any(|r| r.method == Method::PATCH));
+        }
+    }
+
+    /// A restored node whose backup is unchanged isn't looked at again
+    #[tokio::test]
+    async fn test_restored_node() {
+        let server = cluster().await;
+        let ctx = context_with(&server, |config| config.dry_run = true);
+        let mut restored = node(&[]);
+        restored.metadata.annotations = Some(BTreeMap::from([(
+            RESTORED_ANNOTATION_KEY.to_string(),
+            "true".to_string(),
+        )]));
+        reconcile(Arc::new(restored), ctx).await.unwrap();
+        assert!(server.requests().is_empty());
+    }
+}
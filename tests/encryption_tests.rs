@@ -0,0 +1,253 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{cluster, context, labels, node, ConfigMaps};
+
+    use k8s_openapi::api::core::v1::ConfigMap;
+
+    use k8s_openapi::chrono::Utc;
+    use k8s_openapi::ByteString;
+    use label_preserver::{
+        configmap_name, is_permanent, preserve_on_cleanup, reconcile, Backup, BackupLayout,
+        ControllerConfig, EncryptionKey, EncryptionKeys, Error, CIPHER,
+    };
+
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    const SEALED_KEY: &str = "preserved_labels_json.sealed";
+
+    fn keys(bytes: &[u8]) -> EncryptionKeys {
+        EncryptionKeys(bytes.iter().map(|b| EncryptionKey::new([*b; 32])).collect())
+    }
+
+    /// The backup ConfigMap of a node labeled with a tenant
+    fn backup_configmap() -> ConfigMap {
+        let node = node("node-a", labels(&[("tenant", "acme-secret")]), true);
+        preserve_on_cleanup(&node, &ControllerConfig::default(), "corr-1", Utc::now())
+            .unwrap()
+            .configmap
+    }
+
+    fn sealed(configmap: &ConfigMap) -> &[u8] {
+        &configmap.binary_data.as_ref().unwrap()[SEALED_KEY].0
+    }
+
+    /// Sealed labels are only in the binary data, marked with the cipher, and read back as they
+    /// were. The rest of the backup stays readable.
+    #[test]
+    fn test_round_trip() {
+        let plain = backup_configmap();
+        let mut configmap = plain.clone();
+        keys(&[1]).seal(&mut configmap).unwrap();
+
+        let data = configmap.data.as_ref().unwrap();
+        assert!(!data.contains_key("preserved_labels_json"));
+        assert_eq!(data["labels_cipher"], CIPHER);
+        assert_eq!(data["correlation_id"], "corr-1");
+        assert!(!serde_json::to_string(&configmap)
+            .unwrap()
+            .contains("acme-secret"));
+
+        let backup = Backup::from_configmap(&configmap, &keys(&[1])).unwrap();
+        let expected = Backup::from_configmap(&plain, &EncryptionKeys::default()).unwrap();
+        assert_eq!(backup, expected);
+        assert_eq!(backup.labels, labels(&[("tenant", "acme-secret")]));
+
+        // Each seal uses a fresh nonce
+        let mut again = plain.clone();
+        keys(&[1]).seal(&mut again).unwrap();
+        assert_ne!(sealed(&again), sealed(&configmap));
+    }
+
+    /// Labels long enough to be stored in chunks are sealed whole
+    #[test]
+    fn test_chunked_labels() {
+        let backup = Backup {
+            labels: (0..50)
+                .map(|i| (format!("tenant-{}", i), "x".repeat(40)))
+                .collect(),
+            ..Default::default()
+        };
+        let mut configmap = ConfigMap {
+            data: Some(backup.to_configmap_data_chunked(256).unwrap()),
+            ..Default::default()
+        };
+        assert!(configmap
+            .data
+            .as_ref()
+            .unwrap()
+            .contains_key("preserved_labels_json_chunks"));
+        keys(&[1]).seal(&mut configmap).unwrap();
+        let data = configmap.data.as_ref().unwrap();
+        assert!(data
+            .keys()
+            .all(|key| !key.starts_with("preserved_labels_json")));
+        let read = Backup::from_configmap(&configmap, &keys(&[1])).unwrap();
+        assert_eq!(read.labels, backup.labels);
+    }
+
+    /// Labels sealed with another key, or without any key configured, fail to decrypt
+    #[test]
+    fn test_wrong_key() {
+        let mut configmap = backup_configmap();
+        keys(&[1]).seal(&mut configmap).unwrap();
+        for wrong in [keys(&[2]), keys(&[2, 3]), EncryptionKeys::default()] {
+            let result = Backup::from_configmap(&configmap, &wrong);
+            assert!(matches!(result, Err(Error::Decryption(_))), "{:?}", result);
+        }
+    }
+
+    /// Truncated, tampered, or missing ciphertext fails to decrypt, as does an unknown cipher
+    #[test]
+    fn test_damaged_ciphertext() {
+        let mut configmap = backup_configmap();
+        keys(&[1]).seal(&mut configmap).unwrap();
+        let with_sealed = |bytes: Option<Vec<u8>>| {
+            let mut damaged = configmap.clone();
+            let binary_data = damaged.binary_data.as_mut().unwrap();
+            match bytes {
+                Some(bytes) => binary_data.insert(SEALED_KEY.to_string(), ByteString(bytes)),
+                None => binary_data.remove(SEALED_KEY),
+            };
+            damaged
+        };
+        let full = sealed(&configmap).to_vec();
+        let mut flipped = full.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let mut unknown_cipher = configmap.clone();
+        unknown_cipher
+            .data
+            .as_mut()
+            .unwrap()
+            .insert("labels_cipher".to_string(), "rot13".to_string());
+        for damaged in [
+            with_sealed(Some(full[..full.len() - 1].to_vec())),
+            with_sealed(Some(full[..8].to_vec())),
+            with_sealed(Some(Vec::new())),
+            with_sealed(Some(flipped)),
+            with_sealed(None),
+            unknown_cipher,
+        ] {
+            let result = Backup::from_configmap(&damaged, &keys(&[1]));
+            assert!(matches!(result, Err(Error::Decryption(_))), "{:?}", result);
+        }
+        assert!(is_permanent(&Error::Decryption(String::new())));
+    }
+
+    /// Backups written before encryption was enabled are read as they are
+    #[test]
+    fn test_legacy_plaintext() {
+        let configmap = backup_configmap();
+        let backup = Backup::from_configmap(&configmap, &keys(&[1])).unwrap();
+        assert_eq!(backup.labels, labels(&[("tenant", "acme-secret")]));
+        let empty = ConfigMap::default();
+        assert_eq!(
+            Backup::from_configmap(&empty, &keys(&[1])).unwrap(),
+            Backup::default()
+        );
+    }
+
+    /// The first key seals, and every key is tried to open
+    #[test]
+    fn test_key_rotation() {
+        let mut old = backup_configmap();
+        keys(&[1]).seal(&mut old).unwrap();
+        let rotated = keys(&[2, 1]);
+        let backup = Backup::from_configmap(&old, &rotated).unwrap();
+        assert_eq!(backup.labels, labels(&[("tenant", "acme-secret")]));
+
+        let mut new = backup_configmap();
+        rotated.seal(&mut new).unwrap();
+        assert!(Backup::from_configmap(&new, &keys(&[2])).is_ok());
+        assert!(Backup::from_configmap(&new, &keys(&[1])).is_err());
+    }
+
+    /// Key files hold 32 raw bytes or 64 hex digits
+    #[test]
+    fn test_load_key() {
+        let path = std::env::temp_dir().join(format!("label-key-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, [7u8; 32]).unwrap();
+        assert_eq!(
+            EncryptionKey::load(&path).unwrap(),
+            EncryptionKey::new([7; 32])
+        );
+        std::fs::write(&path, format!("{}\n", "07".repeat(32))).unwrap();
+        assert_eq!(
+            EncryptionKey::load(&path).unwrap(),
+            EncryptionKey::new([7; 32])
+        );
+        assert_eq!(
+            format!("{:?}", EncryptionKey::new([7; 32])),
+            "EncryptionKey(<redacted>)"
+        );
+        for invalid in ["too short".as_bytes(), "zz".repeat(32).as_bytes()] {
+            std::fs::write(&path, invalid).unwrap();
+            let result = EncryptionKey::load(&path);
+            assert!(matches!(result, Err(Error::InvalidEncryption(_))));
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            EncryptionKey::load(&path),
+            Err(Error::InvalidEncryption(_))
+        ));
+    }
+
+    /// Shard entries are stored as plain text, so encryption is refused for them
+    #[test]
+    fn test_sharded_layout_refused() {
+        let config = ControllerConfig {
+            encryption_keys: keys(&[1]),
+            backup_layout: BackupLayout::Sharded,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate_encryption(),
+            Err(Error::InvalidEncryption(_))
+        ));
+        let per_node = ControllerConfig {
+            backup_layout: BackupLayout::PerNode,
+            ..config
+        };
+        assert!(per_node.validate_encryption().is_ok());
+    }
+
+    fn encrypted(encryption_keys: EncryptionKeys) -> ControllerConfig {
+        ControllerConfig {
+            encryption_keys,
+            ..Default::default()
+        }
+    }
+
+    /// Cleanup writes the labels encrypted, and the recreated node gets them back. Without the
+    /// key, the reconcile fails permanently and nothing is restored.
+    #[tokio::test]
+    async fn test_encrypted_restore() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(Arc::clone(&configmaps)).await;
+        let deleted = node("node-a", labels(&[("tenant", "acme-secret")]), true);
+        reconcile(Arc::new(deleted), context(&server, encrypted(keys(&[1]))))
+            .await
+            .unwrap();
+        let stored = configmaps.lock().unwrap()[&configmap_name("node-a")].clone();
+        assert_eq!(stored["data"]["labels_cipher"], CIPHER);
+        assert!(!stored.to_string().contains("acme-secret"));
+
+        let recreated = Arc::new(node("node-a", BTreeMap::new(), false));
+        let error = reconcile(recreated.clone(), context(&server, encrypted(keys(&[2]))))
+            .await
+            .unwrap_err();
+        assert!(is_permanent(&error), "{}", error);
+        assert!(server.requests().iter().all(|r| !r.is_restore()));
+
+        reconcile(recreated, context(&server, encrypted(keys(&[2, 1]))))
+            .await
+            .unwrap();
+        let restore = server.requests().into_iter().find(|r| r.is_restore());
+        assert_eq!(
+            restore.expect("the node is restored").json()["metadata"]["labels"]["tenant"],
+            "acme-secret"
+        );
+    }
+}
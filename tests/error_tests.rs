@@ -0,0 +1,157 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::StatusCode;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::error::ErrorResponse;
+    use label_preserver::{reconcile, Context, Error, Operation, FINALIZER_NAME};
+    use std::sync::Arc;
+
+    fn api_error(code: u16, message: &str) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: message.to_string(),
+            reason: String::new(),
+            code,
+        })
+    }
+
+    fn namespace() -> String {
+        "node-backups".to_string()
+    }
+
+    /// Every operation the controller performs explains the rule it needs
+    #[test]
+    fn test_rbac_hints() {
+        assert_eq!(
+            Operation::ReadBackup {
+                namespace: namespace()
+            }
+            .rbac_hint(),
+            "reading backups requires a Role in namespace node-backups for configmaps with verbs [get]"
+        );
+        assert_eq!(
+            Operation::ListBackups {
+                namespace: namespace()
+            }
+            .rbac_hint(),
+            "listing backups requires a Role in namespace node-backups for configmaps with verbs [list]"
+        );
+        assert_eq!(
+            Operation::WriteBackup {
+                namespace: namespace()
+            }
+            .rbac_hint(),
+            "writing backups requires a Role in namespace node-backups for configmaps with verbs [get, patch, create]"
+        );
+        assert_eq!(
+            Operation::WriteStatus {
+                namespace: namespace()
+            }
+            .rbac_hint(),
+            "writing the status requires a Role in namespace node-backups for configmaps with verbs [get, patch, create]"
+        );
+        assert_eq!(
+            Operation::ListNodes.rbac_hint(),
+            "listing nodes requires a ClusterRole rule for nodes with verbs [list, watch]"
+        );
+        assert_eq!(
+            Operation::PatchNode.rbac_hint(),
+            "patching nodes requires a ClusterRole rule for nodes with verbs [get, patch]"
+        );
+        assert_eq!(
+            Operation::UpdateFinalizers.rbac_hint(),
+            "updating finalizers requires a ClusterRole rule for nodes with verbs [patch] and nodes/finalizers with verbs [update]"
+        );
+        assert_eq!(
+            Operation::ElectLeader {
+                namespace: "node-backups".to_string()
+            }
+            .rbac_hint(),
+            "leader election requires a Role in namespace node-backups for leases in apiGroup coordination.k8s.io with verbs [get, create, update]"
+        );
+    }
+
+    /// Only 403s become Forbidden, and the message includes the hint
+    #[test]
+    fn test_from_api() {
+        let error = Error::from_api(
+            Operation::PatchNode,
+            api_error(403, "nodes \"a\" is forbidden"),
+        );
+        assert_eq!(
+            error.to_string(),
+            "Forbidden from patching nodes: nodes \"a\" is forbidden. Hint: patching nodes requires a ClusterRole rule for nodes with verbs [get, patch]"
+        );
+        assert!(matches!(
+            Error::from_api(Operation::PatchNode, api_error(500, "oops")),
+            Error::Kube(_)
+        ));
+    }
+
+    /// A 403 reading the backup during reconcile surfaces as Forbidden for that operation
+    #[tokio::test]
+    async fn test_reconcile_forbidden() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::FORBIDDEN,
+                status_json(403, "configmaps is forbidden"),
+            )
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let error = reconcile(Arc::new(node), ctx).await.unwrap_err();
+        let Error::Finalizer(error) = error else {
+            panic!("Expected a finalizer error, got {:?}", error);
+        };
+        let kube::runtime::finalizer::Error::ApplyFailed(error) = *error else {
+            panic!("Expected an apply failure, got {:?}", error);
+        };
+        assert!(matches!(
+            error,
+            Error::Forbidden {
+                operation: Operation::ReadBackup { .. },
+                ..
+            }
+        ));
+    }
+
+    /// A 403 adding the finalizer surfaces as Forbidden for finalizer updates
+    #[tokio::test]
+    async fn test_add_finalizer_forbidden() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::FORBIDDEN,
+                status_json(403, "nodes is forbidden"),
+            )
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let error = reconcile(Arc::new(node), ctx).await.unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Forbidden {
+                operation: Operation::UpdateFinalizers,
+                ..
+            }
+        ));
+    }
+}
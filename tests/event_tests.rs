@@ -0,0 +1,211 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{
+        context_with, labels, node_json, status_json, MockApiServer, RecordedRequest,
+    };
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{configmap_name, reconcile, Backup, ControllerConfig, FINALIZER_NAME};
+
+    use std::sync::Arc;
+
+    /// A node carrying our finalizer and a `zone` label
+    fn node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                uid: Some("uid-1".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some(labels(&[("zone", "b")])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Publish Events as replica-1
+    fn as_replica(config: &mut ControllerConfig) {
+        config.instance_id = "replica-1".to_string();
+    }
+
+    /// Serve `data` as the node's backup, and accept patches and Events
+    async fn cluster(data: serde_json::Value) -> MockApiServer {
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": data,
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => (StatusCode::OK, req.json()),
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// The Events published, as (type, reason, note)
+    fn events(server: &MockApiServer) -> Vec<(String, String, String)> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.is_event() && r.method == Method::POST)
+            .map(RecordedRequest::json)
+            .map(|event| {
+                assert_eq!(
+                    event["regarding"],
+                    serde_json::json!({
+                        "apiVersion": "v1", "kind": "Node", "name": "node-a", "uid": "uid-1",
+                    })
+                );
+                assert_eq!(event["reportingController"], "node-label-preserver");
+                assert_eq!(event["reportingInstance"], "replica-1");
+                (
+                    event["type"].as_str().unwrap().to_string(),
+                    event["reason"].as_str().unwrap().to_string(),
+                    event["note"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn backup_data(backup_labels: &[(&str, &str)]) -> serde_json::Value {
+        let backup = Backup {
+            labels: labels(backup_labels),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        serde_json::to_value(backup.to_configmap_data().unwrap()).unwrap()
+    }
+
+    /// A restore names the labels it applied and the ones it left out
+    #[tokio::test]
+    async fn test_labels_restored() {
+        let server = cluster(backup_data(&[("team", "ml"), ("zone", "a")])).await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        assert_eq!(
+            events(&server),
+            vec![(
+                "Normal".to_string(),
+                "LabelsRestored".to_string(),
+                "Restored labels [team], skipped [zone (DeferToLive)] under the 'prefer-current' \
+                merge strategy (correlation ID corr-1)"
+                    .to_string()
+            )]
+        );
+    }
+
+    /// A node without a backup is restored without an Event
+    #[tokio::test]
+    async fn test_nothing_restored() {
+        let server = cluster(backup_data(&[])).await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        assert!(events(&server).is_empty());
+    }
+
+    /// A backup names the ConfigMap it was written to
+    #[tokio::test]
+    async fn test_labels_preserved() {
+        let server = cluster(serde_json::Value::Null).await;
+        let mut deleted = node();
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(deleted), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        let events = events(&server);
+        assert_eq!(events.len(), 1);
+        let (type_, reason, note) = &events[0];
+        assert_eq!(
+            (type_.as_str(), reason.as_str()),
+            ("Normal", "LabelsPreserved")
+        );
+        assert!(
+            note.starts_with(&format!(
+                "Preserved 1 labels and 0 annotations in ConfigMap '{}'",
+                configmap_name("node-a")
+            )),
+            "{note}"
+        );
+    }
+
+    /// Corrupt backups and failed reconciles are Warnings
+    #[tokio::test]
+    async fn test_warnings() {
+        let server = cluster(serde_json::json!({ "preserved_labels_json": "{not json" })).await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        let reasons: Vec<_> = events(&server)
+            .into_iter()
+            .map(|(type_, reason, _)| (type_, reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![("Warning".to_string(), "CorruptBackup".to_string())]
+        );
+
+        let server = MockApiServer::start(|req| match req.method {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                status_json(500, "etcd unavailable"),
+            ),
+        })
+        .await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap_err();
+        let events = events(&server);
+        assert_eq!(events.len(), 1);
+        let (type_, reason, note) = &events[0];
+        assert_eq!(
+            (type_.as_str(), reason.as_str()),
+            ("Warning", "RestoreFailed")
+        );
+        assert!(note.contains("etcd unavailable"), "{note}");
+    }
+
+    /// Long notes are cut to the 1kB the API server accepts
+    #[tokio::test]
+    async fn test_long_note_truncated() {
+        let many: Vec<(String, String)> = (0..100)
+            .map(|i| (format!("example.com/label-{i:03}"), "x".to_string()))
+            .collect();
+        let many: Vec<(&str, &str)> = many.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let server = cluster(backup_data(&many)).await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        let (_, _, note) = &events(&server)[0];
+        assert_eq!(note.len(), 1024);
+        assert!(note.ends_with("..."));
+    }
+
+    /// Failing to publish an Event doesn't fail the reconcile
+    #[tokio::test]
+    async fn test_publish_failure() {
+        let backup = backup_data(&[("team", "ml")]);
+        let configmap = serde_json::json!({ "metadata": { "name": "x" }, "data": backup });
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::FORBIDDEN, status_json(403, "forbidden")),
+            (&Method::GET, _) => (StatusCode::OK, configmap.clone()),
+            _ => (StatusCode::OK, req.json()),
+        })
+        .await;
+        reconcile(Arc::new(node()), context_with(&server, as_replica))
+            .await
+            .unwrap();
+        assert!(server.requests().iter().any(|r| r.is_restore()));
+        assert!(server.requests().iter().any(|r| r.is_event()));
+    }
+}
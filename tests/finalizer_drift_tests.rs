@@ -0,0 +1,251 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, status_json, MockApiServer};
+    use futures::StreamExt;
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use kube::api::{Api, Patch, PatchParams, ResourceExt};
+    use label_preserver::{
+        reconcile, sweep_finalizers, Context, ControllerConfig, FINALIZER_NAME, MANAGED_LABEL_KEY,
+    };
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Nodes by name, as JSON
+    type Nodes = Arc<Mutex<BTreeMap<String, Value>>>;
+
+    /// A node registered an hour ago, with our finalizer if `held`, marked managed if `managed`
+    fn node_json(name: &str, held: bool, managed: bool) -> Value {
+        let labels = if managed {
+            json!({ MANAGED_LABEL_KEY: "true" })
+        } else {
+            json!({})
+        };
+        let mut node = json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": {
+                "name": name,
+                "creationTimestamp": (Utc::now() - ChronoDuration::hours(1)).to_rfc3339(),
+                "labels": labels,
+            },
+        });
+        if held {
+            node["metadata"]["finalizers"] = json!([FINALIZER_NAME]);
+        }
+        node
+    }
+
+    /// Apply the JSON patch operations kube's finalizer helper and these tests send: test, add,
+    /// and remove. Like the API server, an emptied finalizer list is dropped. Returns false if a
+    /// test fails.
+    fn apply_json_patch(doc: &mut Value, operations: &Value) -> bool {
+        for operation in operations.as_array().unwrap() {
+            let path = operation["path"].as_str().unwrap();
+            let (parent, last) = path.rsplit_once('/').unwrap();
+            match operation["op"].as_str().unwrap() {
+                "test" => {
+                    if doc.pointer(path).unwrap_or(&Value::Null) != &operation["value"] {
+                        return false;
+                    }
+                }
+                "add" => match doc.pointer_mut(parent).unwrap() {
+                    Value::Array(items) if last == "-" => items.push(operation["value"].clone()),
+                    Value::Object(fields) => {
+                        fields.insert(last.to_string(), operation["value"].clone());
+                    }
+                    _ => return false,
+                },
+                "remove" => match doc.pointer_mut(parent).unwrap() {
+                    Value::Array(items) => {
+                        items.remove(last.parse().unwrap());
+                    }
+                    Value::Object(fields) => {
+                        fields.remove(last);
+                    }
+                    _ => return false,
+                },
+                _ => return false,
+            }
+        }
+        let metadata = doc["metadata"].as_object_mut().unwrap();
+        if metadata.get("finalizers") == Some(&json!([])) {
+            metadata.remove("finalizers");
+        }
+        true
+    }
+
+    /// A cluster keeping `nodes`, patched with JSON patches, and accepting Events
+    async fn cluster(nodes: Nodes) -> MockApiServer {
+        MockApiServer::start(move |req| {
+            let mut nodes = nodes.lock().unwrap();
+            let path = req.uri.path();
+            let name = path.strip_prefix("/api/v1/nodes/").unwrap_or_default();
+            match req.method {
+                _ if req.is_event() => (StatusCode::CREATED, req.json()),
+                Method::GET if path == "/api/v1/nodes" => {
+                    let items: Vec<Value> = nodes.values().cloned().collect();
+                    let list = json!({
+                        "apiVersion": "v1",
+                        "kind": "NodeList",
+                        "metadata": {},
+                        "items": items,
+                    });
+                    (StatusCode::OK, list)
+                }
+                Method::GET if nodes.contains_key(name) => (StatusCode::OK, nodes[name].clone()),
+                Method::PATCH if nodes.contains_key(name) => {
+                    let node = nodes.get_mut(name).unwrap();
+                    if apply_json_patch(node, &req.json()) {
+                        (StatusCode::OK, node.clone())
+                    } else {
+                        (
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            status_json(422, "Invalid"),
+                        )
+                    }
+                }
+                _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            }
+        })
+        .await
+    }
+
+    fn finalizers(nodes: &Nodes, name: &str) -> Value {
+        nodes.lock().unwrap()[name]["metadata"]["finalizers"].clone()
+    }
+
+    fn reinstalled_metric(ctx: &Context) -> Option<String> {
+        ctx.render_metrics()
+            .lines()
+            .find(|line| line.starts_with("label_preserver_finalizer_reinstalled_total{"))
+            .map(str::to_string)
+    }
+
+    /// A finalizer stripped from a managed node is added back on its next reconcile, and counted
+    #[tokio::test]
+    async fn test_stripped_finalizer_reinstalled() {
+        let nodes = Nodes::default();
+        nodes
+            .lock()
+            .unwrap()
+            .insert("node-a".to_string(), node_json("node-a", true, true));
+        let server = cluster(nodes.clone()).await;
+        let ctx = context_with(&server, |config| config.finalizer_sweep_rate = 0.0);
+
+        let node_api: Api<Node> = Api::all(server.client());
+        let strip = json!([
+            { "op": "test", "path": "/metadata/finalizers/0", "value": FINALIZER_NAME },
+            { "op": "remove", "path": "/metadata/finalizers/0" },
+        ]);
+        let stripped = node_api
+            .patch(
+                "node-a",
+                &PatchParams::default(),
+                &Patch::Json::<()>(serde_json::from_value(strip).unwrap()),
+            )
+            .await
+            .unwrap();
+        assert!(stripped.finalizers().is_empty());
+
+        reconcile(Arc::new(stripped), ctx.clone()).await.unwrap();
+        assert_eq!(finalizers(&nodes, "node-a"), json!([FINALIZER_NAME]));
+        assert_eq!(
+            reinstalled_metric(&ctx).as_deref(),
+            Some("label_preserver_finalizer_reinstalled_total{pool=\"none\"} 1")
+        );
+    }
+
+    /// A node that was never managed gets the finalizer without being counted
+    #[tokio::test]
+    async fn test_new_node_not_counted() {
+        let nodes = Nodes::default();
+        nodes
+            .lock()
+            .unwrap()
+            .insert("node-a".to_string(), node_json("node-a", false, false));
+        let server = cluster(nodes.clone()).await;
+        let ctx = context_with(&server, |config| config.finalizer_sweep_rate = 0.0);
+
+        let node_api: Api<Node> = Api::all(server.client());
+        let node = node_api.get("node-a").await.unwrap();
+        reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+        assert_eq!(finalizers(&nodes, "node-a"), json!([FINALIZER_NAME]));
+        assert_eq!(reinstalled_metric(&ctx), None);
+    }
+
+    /// A sweep reports nodes in scope missing the finalizer with a Warning Event and has them
+    /// reconciled, leaving out nodes being deleted or registered just now
+    #[tokio::test]
+    async fn test_sweep_reports_missing_finalizers() {
+        let nodes = Nodes::default();
+        {
+            let mut nodes = nodes.lock().unwrap();
+            for (name, held) in [
+                ("held", true),
+                ("stripped", false),
+                ("new", false),
+                ("deleting", false),
+                ("out-of-scope", false),
+            ] {
+                nodes.insert(name.to_string(), node_json(name, held, true));
+            }
+            nodes.get_mut("new").unwrap()["metadata"]["creationTimestamp"] =
+                json!(Utc::now().to_rfc3339());
+            nodes.get_mut("deleting").unwrap()["metadata"]["deletionTimestamp"] =
+                json!(Utc::now().to_rfc3339());
+        }
+        let server = cluster(nodes).await;
+        let ctx = context_with(&server, |config| {
+            config.finalizer_sweep_rate = 0.0;
+            config.node_names = Some(
+                ["held", "stripped", "new", "deleting"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            );
+        });
+        let mut requests = ctx.reconcile_requests().unwrap();
+
+        assert_eq!(sweep_finalizers(&ctx).await.unwrap(), 1);
+        let events: Vec<Value> = server
+            .requests()
+            .iter()
+            .filter(|r| r.is_event())
+            .map(|r| r.json())
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["type"], "Warning");
+        assert_eq!(events[0]["reason"], "FinalizerMissing");
+        assert_eq!(events[0]["regarding"]["name"], "stripped");
+        assert_eq!(requests.next().await.unwrap().name, "stripped");
+    }
+
+    /// Reports are spaced out by the sweep rate
+    #[tokio::test]
+    async fn test_sweep_rate_limited() {
+        let nodes = Nodes::default();
+        for name in ["node-a", "node-b", "node-c"] {
+            nodes
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), node_json(name, false, true));
+        }
+        let server = cluster(nodes).await;
+        let ctx = Arc::new(Context::with_config(
+            server.client(),
+            ControllerConfig {
+                finalizer_sweep_rate: 20.0,
+                ..Default::default()
+            },
+        ));
+        let started = Instant::now();
+        assert_eq!(sweep_finalizers(&ctx).await.unwrap(), 3);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,86 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, finalized_node, status_json, MockApiServer};
+    use axum::body::Body;
+    use http::{Method, Request, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+
+    use kube::runtime::reflector;
+    use label_preserver::{admin, reconcile, Context};
+    use std::{sync::Arc, time::Duration};
+    use tower::ServiceExt;
+
+    /// A server without backups that accepts every patch
+    async fn server() -> MockApiServer {
+        MockApiServer::start(|req| match req.method {
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    async fn probe(ctx: &Arc<Context>, uri: &str) -> StatusCode {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = admin::router(ctx.clone()).oneshot(request).await.unwrap();
+        response.status()
+    }
+
+    /// Not ready until the node watch completes its initial list, whatever is reconciled
+    /// before, then ready for good
+    #[tokio::test]
+    async fn test_readiness() {
+        let server = server().await;
+        let ctx = context_with(&server, |config| {
+            config.liveness_window = Duration::from_secs(60)
+        });
+        assert_eq!(
+            probe(&ctx, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        reconcile(Arc::new(finalized_node("node-a")), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            probe(&ctx, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        ctx.mark_ready();
+        assert_eq!(probe(&ctx, "/readyz").await, StatusCode::OK);
+    }
+
+    /// Live from startup until the window passes without a reconcile, and live again after one
+    #[tokio::test]
+    async fn test_liveness() {
+        let server = server().await;
+        let ctx = context_with(&server, |config| {
+            config.liveness_window = Duration::from_millis(200)
+        });
+        assert_eq!(probe(&ctx, "/healthz").await, StatusCode::OK);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            probe(&ctx, "/healthz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        reconcile(Arc::new(finalized_node("node-a")), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(probe(&ctx, "/healthz").await, StatusCode::OK);
+    }
+
+    /// With no nodes cached there is nothing to reconcile, so a quiet controller stays live
+    #[tokio::test]
+    async fn test_liveness_without_nodes() {
+        let server = server().await;
+        let ctx = context_with(&server, |config| config.liveness_window = Duration::ZERO);
+        let (store, _writer) = reflector::store::<Node>();
+        ctx.set_node_store(store);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(probe(&ctx, "/healthz").await, StatusCode::OK);
+    }
+}
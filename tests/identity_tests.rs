@@ -0,0 +1,181 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        reconcile, Backup, Context, ControllerConfig, IdentityMismatchPolicy, MachineIdentity,
+        RestorePolicy, FINALIZER_NAME, MANAGED_LABEL_KEY,
+    };
+    use std::sync::Arc;
+
+    const OLD_MACHINE: &str = "aws:///us-east-1a/i-0old";
+    const NEW_MACHINE: &str = "aws:///us-east-1a/i-0new";
+
+    /// A recreated node running on the machine `provider_id`
+    fn node_on(provider_id: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                provider_id: Some(provider_id.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Serve a backup of rack and team labels taken on OLD_MACHINE
+    async fn backup_server() -> MockApiServer {
+        let backup = Backup {
+            labels: [
+                ("rack.example.com/id", "r12"),
+                ("team.example.com/owner", "storage"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            identity: MachineIdentity {
+                provider_id: Some(OLD_MACHINE.to_string()),
+                machine_id: None,
+            },
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+            ),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// Restore the backup onto `node` under `policy` and return the label keys applied
+    async fn restored_keys(node: Node, policy: IdentityMismatchPolicy) -> Vec<String> {
+        let server = backup_server().await;
+        let config = ControllerConfig {
+            policy: RestorePolicy {
+                identity_mismatch: policy,
+                machine_independent_prefixes: vec!["team.example.com/".to_string()],
+                ..Default::default()
+            },
+            managed_label: false,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        reconcile(Arc::new(node), ctx).await.unwrap();
+        let requests = server.requests();
+        let apply = requests.iter().rfind(|r| r.is_restore()).unwrap();
+        assert_eq!(apply.uri.path(), "/api/v1/nodes/node-a");
+        apply.json()["metadata"]["labels"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    const ALL: [&str; 2] = ["rack.example.com/id", "team.example.com/owner"];
+
+    #[tokio::test]
+    async fn test_ignore_policy_restores_everything() {
+        let keys = restored_keys(node_on(NEW_MACHINE), IdentityMismatchPolicy::Ignore).await;
+        assert_eq!(keys, ALL);
+    }
+
+    #[tokio::test]
+    async fn test_warn_policy_restores_everything() {
+        let keys = restored_keys(node_on(NEW_MACHINE), IdentityMismatchPolicy::Warn).await;
+        assert_eq!(keys, ALL);
+    }
+
+    #[tokio::test]
+    async fn test_skip_policy_restores_nothing() {
+        let keys = restored_keys(node_on(NEW_MACHINE), IdentityMismatchPolicy::Skip).await;
+        assert!(keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_machine_independent_policy_restores_listed_prefixes() {
+        let keys = restored_keys(
+            node_on(NEW_MACHINE),
+            IdentityMismatchPolicy::MachineIndependent,
+        )
+        .await;
+        assert_eq!(keys, ["team.example.com/owner"]);
+    }
+
+    /// The same machine coming back is restored in full under any policy
+    #[tokio::test]
+    async fn test_matching_identity_restores_everything() {
+        let keys = restored_keys(node_on(OLD_MACHINE), IdentityMismatchPolicy::Skip).await;
+        assert_eq!(keys, ALL);
+    }
+
+    /// IDs missing on either side are not compared
+    #[test]
+    fn test_unknown_ids_do_not_differ() {
+        let known = MachineIdentity {
+            provider_id: Some(OLD_MACHINE.to_string()),
+            machine_id: Some("abc".to_string()),
+        };
+        assert!(!known.differs_from(&MachineIdentity::default()));
+        assert!(!MachineIdentity::default().differs_from(&known));
+        let new_machine_id = MachineIdentity {
+            provider_id: None,
+            machine_id: Some("def".to_string()),
+        };
+        assert!(known.differs_from(&new_machine_id));
+    }
+
+    /// Policies parse from their flag values
+    #[test]
+    fn test_parse_policy() {
+        for policy in [
+            IdentityMismatchPolicy::Ignore,
+            IdentityMismatchPolicy::Warn,
+            IdentityMismatchPolicy::Skip,
+            IdentityMismatchPolicy::MachineIndependent,
+        ] {
+            assert_eq!(
+                policy
+                    .to_string()
+                    .parse::<IdentityMismatchPolicy>()
+                    .unwrap(),
+                policy
+            );
+        }
+        assert!("sometimes".parse::<IdentityMismatchPolicy>().is_err());
+    }
+
+    /// The managed label is unaffected by the policy
+    #[tokio::test]
+    async fn test_skip_policy_keeps_managed_label() {
+        let server = backup_server().await;
+        let config = ControllerConfig {
+            policy: RestorePolicy {
+                identity_mismatch: IdentityMismatchPolicy::Skip,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        reconcile(Arc::new(node_on(NEW_MACHINE)), ctx)
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(
+            requests.iter().rfind(|r| r.is_restore()).unwrap().json()["metadata"]["labels"],
+            serde_json::json!({ MANAGED_LABEL_KEY: "true" })
+        );
+    }
+}
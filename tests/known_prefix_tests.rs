@@ -0,0 +1,155 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        bundle::{self, Bundle, BundleEntry, BUNDLE_VERSION},
+        configmap_name, reconcile, Backup, Context, ControllerConfig, KnownPrefixes, Redactor,
+        RestorePolicy, SkipReason, FINALIZER_NAME,
+    };
+
+    use std::sync::Arc;
+
+    const ZOMBIE: &str = "old-team.example.org/owner";
+
+    fn known() -> KnownPrefixes {
+        KnownPrefixes(vec!["example.com".to_string()])
+    }
+
+    fn backup() -> Backup {
+        Backup {
+            labels: labels(&[
+                ("zone", "a"),
+                ("team.example.com/name", "ml"),
+                ("topology.kubernetes.io/zone", "us-east-1a"),
+                (ZOMBIE, "storage"),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn policy(exclude: bool) -> RestorePolicy {
+        RestorePolicy {
+            known_prefixes: known(),
+            exclude_unknown_prefixes: exclude,
+            ..Default::default()
+        }
+    }
+
+    /// Registered prefixes and their subdomains, unprefixed keys, and Kubernetes' own prefixes
+    /// are known
+    #[test]
+    fn test_detection() {
+        let known = known();
+        assert!(!known.is_unknown("zone"));
+        assert!(!known.is_unknown("example.com/team"));
+        assert!(!known.is_unknown("team.example.com/name"));
+        assert!(!known.is_unknown("node.kubernetes.io/instance-type"));
+        assert!(!known.is_unknown("kubernetes.io/hostname"));
+        assert!(known.is_unknown(ZOMBIE));
+        assert!(known.is_unknown("notexample.com/team"));
+        assert!(!KnownPrefixes::default().is_unknown(ZOMBIE));
+        assert!(!KnownPrefixes(vec!["example.com/".to_string()]).is_unknown("example.com/team"));
+    }
+
+    /// Unknown keys are flagged and still restored, unless the policy excludes them
+    #[test]
+    fn test_exclusion() {
+        let plan = policy(false).plan(&backup(), &node(), Utc::now());
+        assert_eq!(plan.unknown_prefix, vec![ZOMBIE.to_string()]);
+        assert!(plan.labels.contains_key(ZOMBIE));
+        assert!(!plan.skipped().contains_key(ZOMBIE));
+
+        let plan = policy(true).plan(&backup(), &node(), Utc::now());
+        assert_eq!(plan.unknown_prefix, vec![ZOMBIE.to_string()]);
+        assert!(!plan.labels.contains_key(ZOMBIE));
+        assert_eq!(plan.skipped()[ZOMBIE], SkipReason::UnknownPrefix);
+        // Reserved keys are known, but never restored by default
+        assert_eq!(plan.labels.len(), 2);
+    }
+
+    /// Restore `backup()` onto `node()` and return the context and the restore patch's labels
+    async fn restore(exclude: bool) -> (Arc<Context>, serde_json::Value) {
+        let data = backup().to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+            ),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            policy: policy(exclude),
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        reconcile(Arc::new(node()), ctx.clone()).await.unwrap();
+        let restore = server
+            .requests()
+            .into_iter()
+            .find(|r| r.is_restore())
+            .unwrap();
+        (ctx, restore.json()["metadata"]["labels"].clone())
+    }
+
+    /// Unknown keys are counted whether or not they are restored, and only restored when not
+    /// excluded
+    #[tokio::test]
+    async fn test_restore_metric() {
+        let line = "label_preserver_unknown_prefix_labels_total{pool=\"none\"} 1\n";
+
+        let (ctx, restored) = restore(false).await;
+        assert_eq!(restored[ZOMBIE], "storage");
+        let metrics = ctx.render_metrics();
+        assert!(metrics.contains(line), "{metrics}");
+        assert!(!metrics.contains("reason=\"UnknownPrefix\""));
+
+        let (ctx, restored) = restore(true).await;
+        assert!(restored.get(ZOMBIE).is_none());
+        assert_eq!(restored["team.example.com/name"], "ml");
+        let metrics = ctx.render_metrics();
+        assert!(metrics.contains(line), "{metrics}");
+        assert!(metrics.contains(
+            "label_preserver_skipped_labels_total{pool=\"none\",reason=\"UnknownPrefix\"} 1\n"
+        ));
+    }
+
+    /// Bundle verification warns about unknown keys without failing
+    #[test]
+    fn test_bundle_warnings() {
+        let bundle = Bundle {
+            version: BUNDLE_VERSION,
+            backups: vec![BundleEntry {
+                configmap: configmap_name("node-a"),
+                data: backup().to_configmap_data().unwrap(),
+                ..Default::default()
+            }],
+        };
+        let verification = bundle::verify_against(&bundle, &Redactor::default(), &known());
+        assert!(verification.is_valid());
+        assert_eq!(verification.warnings.len(), 1);
+        assert!(verification.summary().contains(ZOMBIE));
+        assert!(verification.summary().contains("1 warnings"));
+
+        assert!(bundle::verify(&bundle).warnings.is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        preserve_on_cleanup, restore_on_apply, Backup, ControllerConfig, LabelFilter,
+        RestorePolicy, SkipReason,
+    };
+    use std::collections::BTreeMap;
+
+    fn filter(preserve: &[&str], ignore: &[&str]) -> LabelFilter {
+        LabelFilter {
+            preserve_prefixes: preserve.iter().map(|p| p.to_string()).collect(),
+            ignore_prefixes: ignore.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn config(filter: LabelFilter) -> ControllerConfig {
+        ControllerConfig {
+            policy: RestorePolicy {
+                label_filter: filter,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Cloud provider labels next to our own
+    fn node_labels() -> BTreeMap<String, String> {
+        labels(&[
+            ("topology.kubernetes.io/zone", "us-east-1a"),
+            ("node.kubernetes.io/instance-type", "m5.large"),
+            ("team.example.com/name", "ml"),
+            ("example.com/pool", "gpu"),
+            ("zone", "a"),
+        ])
+    }
+
+    fn node(node_labels: BTreeMap<String, String>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                labels: Some(node_labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// An empty filter preserves everything
+    #[test]
+    fn test_default_allows_all() {
+        let filter = LabelFilter::default();
+        assert!(node_labels().keys().all(|key| filter.allows(key)));
+    }
+
+    /// With preserve prefixes only matching keys are kept, and ignored prefixes are always
+    /// dropped
+    #[test]
+    fn test_preserve_and_ignore() {
+        let preserve = filter(&["example.com/", "team."], &[]);
+        assert!(preserve.allows("example.com/pool"));
+        assert!(preserve.allows("team.example.com/name"));
+        assert!(!preserve.allows("zone"));
+        assert!(!preserve.allows("topology.kubernetes.io/zone"));
+
+        let ignore = filter(&[], &["topology.kubernetes.io/", "node.kubernetes.io/"]);
+        assert!(!ignore.allows("topology.kubernetes.io/zone"));
+        assert!(!ignore.allows("node.kubernetes.io/instance-type"));
+        assert!(ignore.allows("zone"));
+        assert!(ignore.allows("example.com/pool"));
+    }
+
+    /// A key matching both lists is ignored, including when the ignored prefix is the narrower
+    /// of two overlapping prefixes
+    #[test]
+    fn test_ignore_wins() {
+        let both = filter(&["example.com/"], &["example.com/"]);
+        assert!(!both.allows("example.com/pool"));
+
+        let overlapping = filter(&["example.com/"], &["example.com/pool"]);
+        assert!(!overlapping.allows("example.com/pool"));
+        assert!(!overlapping.allows("example.com/pool-size"));
+        assert!(overlapping.allows("example.com/owner"));
+
+        // A narrower preserve prefix doesn't rescue keys under a broader ignored one
+        let narrower = filter(&["example.com/pool"], &["example.com/"]);
+        assert!(!narrower.allows("example.com/pool"));
+    }
+
+    /// Filtered labels never make it into the backup
+    #[test]
+    fn test_filtered_on_backup() {
+        let config = config(filter(
+            &[],
+            &["topology.kubernetes.io/", "node.kubernetes.io/"],
+        ));
+        let preservation =
+            preserve_on_cleanup(&node(node_labels()), &config, "corr-1", Utc::now()).unwrap();
+        assert_eq!(
+            preservation.backup.labels,
+            labels(&[
+                ("team.example.com/name", "ml"),
+                ("example.com/pool", "gpu"),
+                ("zone", "a"),
+            ])
+        );
+        assert_eq!(preservation.backup.preserved_at.len(), 3);
+    }
+
+    /// Labels in a backup written before the filter was configured are still not restored
+    #[test]
+    fn test_filtered_on_restore() {
+        let backup = Backup {
+            labels: node_labels(),
+            ..Default::default()
+        };
+        let config = config(filter(&["example.com/", "zone"], &["example.com/pool"]));
+        let restoration = restore_on_apply(&node(BTreeMap::new()), &backup, &config, Utc::now());
+        assert_eq!(restoration.added, labels(&[("zone", "a")]));
+        assert_eq!(
+            restoration.plan.filtered,
+            vec![
+                "example.com/pool".to_string(),
+                "node.kubernetes.io/instance-type".to_string(),
+                "team.example.com/name".to_string(),
+                "topology.kubernetes.io/zone".to_string(),
+            ]
+        );
+        let skipped = &restoration.counts.skipped;
+        assert_eq!(skipped.len(), 4);
+        assert!(skipped
+            .values()
+            .all(|reason| *reason == SkipReason::FilteredByPolicy));
+    }
+}
@@ -1,12 +1,167 @@
 #[cfg(test)]
 mod tests {
-    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::api::core::v1::{ConfigMap, Event, Namespace, Node};
     use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-    use kube::api::{PartialObjectMetaExt, Patch, PatchParams, PostParams};
+    use kube::api::{
+        ListParams, PartialObjectMetaExt, Patch, PatchParams, PostParams, ResourceExt,
+    };
     use kube::{api::Api, Client};
+    use label_preserver::{
+        configmap_name,
+        run::{self, Shutdown},
+        Backup, ClientFactory, ClientOptions, ControllerConfig, RestoredAnnotation,
+        CORRELATION_ID_KEY, MANAGED_LABEL_KEY, RESTORED_ANNOTATION_KEY,
+    };
     use rand::{distr::Alphanumeric, rng, Rng};
     use serde_json::json;
     use std::collections::BTreeMap;
+    use tokio::task::JoinHandle;
+
+    /// A controller running in-process against the kubeconfig's cluster, managing only the
+    /// test's node with its own namespace and finalizer. Test runs sharing a cluster therefore
+    /// never see each other's nodes, backups, or finalizers. Dropping it stops the controller and
+    /// removes everything the test created, even when the test panics.
+    struct TestCluster {
+        client: Client,
+        namespace: String,
+        finalizer: String,
+        node_name: String,
+        controller: JoinHandle<Shutdown>,
+    }
+
+    impl TestCluster {
+        async fn start(node_name: &str) -> Self {
+            Self::start_with(node_name, ControllerConfig::default()).await
+        }
+
+        /// Start with `config`, apart from the settings that isolate the test
+        async fn start_with(node_name: &str, config: ControllerConfig) -> Self {
+            let client = Client::try_default().await.unwrap();
+            let suffix = random_node_name(10);
+            let namespace = format!("label-preserver-test-{suffix}");
+            let namespaces: Api<Namespace> = Api::all(client.clone());
+            let ns = Namespace {
+                metadata: ObjectMeta {
+                    name: Some(namespace.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            namespaces
+                .create(&PostParams::default(), &ns)
+                .await
+                .unwrap();
+
+            let config = ControllerConfig {
+                instance_id: format!("test-{suffix}"),
+                namespace: namespace.clone(),
+                finalizer: format!("nodelabelpreserver.example.com/test-{suffix}"),
+                node_names: Some([node_name.to_string()].into_iter().collect()),
+                ..config
+            };
+            let finalizer = config.finalizer.clone();
+            let clients = ClientFactory::new(ClientOptions::default(), &config.instance_id);
+            let controller = tokio::spawn(async move {
+                run::run(&clients, config, None, futures::future::pending()).await
+            });
+            Self {
+                client,
+                namespace,
+                finalizer,
+                node_name: node_name.to_string(),
+                controller,
+            }
+        }
+
+        fn client(&self) -> Client {
+            self.client.clone()
+        }
+
+        /// The backup ConfigMaps of this test's controller
+        fn configmaps(&self) -> Api<ConfigMap> {
+            Api::namespaced(self.client(), &self.namespace)
+        }
+
+        /// Create the test's node and wait until the controller has put its finalizer on it, so
+        /// deleting the node afterwards is guaranteed to back it up
+        async fn create_node(&self) -> Result<(), anyhow::Error> {
+            create_node(self.client(), &self.node_name).await?;
+            let nodes: Api<Node> = Api::all(self.client());
+            let interval = std::time::Duration::from_millis(200);
+            let timeout = std::time::Duration::from_secs(10);
+            let start = std::time::Instant::now();
+            loop {
+                let node = nodes.get(&self.node_name).await?;
+                if node.finalizers().contains(&self.finalizer) {
+                    return Ok(());
+                }
+                if self.controller.is_finished() {
+                    anyhow::bail!(
+                        "The controller stopped before finalizing {}",
+                        self.node_name
+                    );
+                }
+                if start.elapsed() > timeout {
+                    anyhow::bail!("Timeout waiting for finalizer on node {}", self.node_name);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+
+    impl Drop for TestCluster {
+        fn drop(&mut self) {
+            self.controller.abort();
+            let namespace = self.namespace.clone();
+            let finalizer = self.finalizer.clone();
+            let node_name = self.node_name.clone();
+            // Drop can't await, and blocking the test's runtime would stall any client made on
+            // it, so clean up with a client and runtime of our own
+            let cleanup = std::thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(async {
+                        let client = Client::try_default().await.unwrap();
+                        release_and_delete_node(&client, &node_name, &finalizer).await;
+                        let namespaces: Api<Namespace> = Api::all(client);
+                        let _ = namespaces.delete(&namespace, &Default::default()).await;
+                    })
+            });
+            if cleanup.join().is_err() {
+                eprintln!(
+                    "Failed to clean up node {} and namespace {}",
+                    self.node_name, self.namespace
+                );
+            }
+        }
+    }
+
+    /// Remove `finalizer` from a node the controller may have left behind, then delete it
+    async fn release_and_delete_node(client: &Client, node_name: &str, finalizer: &str) {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let Ok(node) = nodes.get(node_name).await else {
+            return;
+        };
+        if node.finalizers().iter().any(|f| f == finalizer) {
+            let finalizers: Vec<&String> = node
+                .finalizers()
+                .iter()
+                .filter(|f| *f != finalizer)
+                .collect();
+            let patch = json!({
+                "metadata": {
+                    "finalizers": finalizers,
+                    "resourceVersion": node.resource_version(),
+                }
+            });
+            let _ = nodes
+                .patch(node_name, &PatchParams::default(), &Patch::Merge(patch))
+                .await;
+        }
+        let _ = nodes.delete(node_name, &Default::default()).await;
+    }
 
     /// Set label values on a node.
     pub async fn set_node_labels(
@@ -215,9 +370,8 @@ mod tests {
         loop {
             match nodes.get(node_name).await {
                 Ok(node) => {
-                    if node.metadata.labels.as_ref().is_none()
-                        || node.metadata.labels.unwrap().is_empty()
-                    {
+                    // The controller's own managed label doesn't count
+                    if node.labels().keys().all(|key| key == MANAGED_LABEL_KEY) {
                         return Ok(());
                     }
                 }
@@ -302,13 +456,14 @@ mod tests {
     /// 3. Delete the node, add the node back to the cluster and assert that the label is restored
     #[tokio::test]
     async fn test_add_and_remove_node() {
-        let client = Client::try_default().await.unwrap();
+        let test_node_name = random_node_name(253);
+        let cluster = TestCluster::start(&test_node_name).await;
+        let client = cluster.client();
 
         //
         // 1. Create a node.
         //
-        let test_node_name = random_node_name(253);
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
 
         //
         // 2. Add a label to the node
@@ -322,7 +477,7 @@ mod tests {
         // 3. Delete the node, add the node back to the cluster, and assert that the label is restored
         //
         delete_node(client.clone(), &test_node_name).await.unwrap();
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
         wait_for_label_value(
             client.clone(),
             &test_node_name,
@@ -343,13 +498,14 @@ mod tests {
     /// cluster before the controller has time to restore labels.
     #[tokio::test]
     async fn test_overwriting_labels() {
-        let client = Client::try_default().await.unwrap();
+        let test_node_name = random_node_name_random_length();
+        let cluster = TestCluster::start(&test_node_name).await;
+        let client = cluster.client();
 
         //
         // 1. Create a node.
         //
-        let test_node_name = random_node_name_random_length();
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
 
         //
         // 2. Add labels to the node
@@ -456,13 +612,14 @@ mod tests {
     #[tokio::test]
     async fn test_deleting_labels() {
         // Start from a clean slate
-        let client = Client::try_default().await.unwrap();
+        let test_node_name = random_node_name(20);
+        let cluster = TestCluster::start(&test_node_name).await;
+        let client = cluster.client();
 
         //
         // 1. Create a node.
         //
-        let test_node_name = random_node_name(1);
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
 
         //
         // 2. Add a label to the node
@@ -483,7 +640,7 @@ mod tests {
         //
         // 4. Add the node back to the cluster and assert that the label is restored
         //
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
         wait_for_label_value(
             client.clone(),
             &test_node_name,
@@ -506,7 +663,7 @@ mod tests {
         // 6. Cycle the node again and see that the label is not restored
         //
         delete_node(client.clone(), &test_node_name).await.unwrap();
-        create_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
         // The node should not have the key that was deleted
         wait_for_label_gone(client.clone(), &test_node_name, node_label_key).await;
     }
@@ -515,11 +672,12 @@ mod tests {
     /// and then recreated. It should still have no labels after recreation.
     #[tokio::test]
     async fn test_no_labels_cycle() {
-        let client = Client::try_default().await.unwrap();
-        let test_node_name = "node-no-labels-cycle-test"; // Unique name for this test
+        let test_node_name = &random_node_name(40);
+        let cluster = TestCluster::start(test_node_name).await;
+        let client = cluster.client();
 
         // 1. Create a node. It should have no labels by default.
-        create_node(client.clone(), test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
         wait_for_node(client.clone(), test_node_name, true)
             .await
             .unwrap();
@@ -538,7 +696,7 @@ mod tests {
 
         // 3. Recreate the node. Controller's apply_node should run.
         //    It should read the ConfigMap, find no labels to restore, and do nothing to node labels.
-        create_node(client.clone(), test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
         wait_for_node(client.clone(), test_node_name, true)
             .await
             .unwrap();
@@ -549,4 +707,314 @@ mod tests {
             .await
             .unwrap();
     }
+
+    /// Poll until a node has a specific annotation value
+    async fn wait_for_annotation_value(
+        client: Client,
+        node_name: &str,
+        key: &str,
+        value: Option<&String>,
+    ) -> Result<(), anyhow::Error> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let interval = std::time::Duration::from_millis(500);
+        let timeout = std::time::Duration::from_secs(10);
+        let start = std::time::Instant::now();
+        loop {
+            if let Ok(node) = nodes.get(node_name).await {
+                let current_value = node.metadata.annotations.as_ref().and_then(|a| a.get(key));
+                if current_value == value {
+                    return Ok(());
+                }
+            }
+            if start.elapsed() > timeout {
+                let node = nodes.get(node_name).await.ok();
+                anyhow::bail!(
+                    "Timeout waiting for node {} annotation {} to have value {:?}. Current: {:?}",
+                    node_name,
+                    key,
+                    value,
+                    node.and_then(|n| n.metadata.annotations)
+                );
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// 1. Create a node and add a label to it
+    /// 2. Delete the node and read the correlation ID from its backup ConfigMap
+    /// 3. Add the node back and assert the restored annotation carries the same correlation ID
+    /// 4. Cycle the node again and assert a new correlation ID was generated
+    #[tokio::test]
+    async fn test_correlation_id_round_trip() {
+        let test_node_name = random_node_name_random_length();
+        let cluster = TestCluster::start(&test_node_name).await;
+        let client = cluster.client();
+        let cms = cluster.configmaps();
+
+        //
+        // 1. Create a node and add a label to it
+        //
+        cluster.create_node().await.unwrap();
+        set_random_label(client.clone(), &test_node_name, "label_to_correlate")
+            .await
+            .unwrap();
+
+        //
+        // 2. Delete the node and read the correlation ID from its backup ConfigMap
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        let cm = cms.get(&configmap_name(&test_node_name)).await.unwrap();
+        let data = cm.data.unwrap();
+        let correlation_id = data.get(CORRELATION_ID_KEY).cloned().unwrap();
+        let backup = Backup::from_configmap_data(&data).unwrap();
+
+        //
+        // 3. Add the node back and assert the restored annotation carries the same correlation ID
+        //
+        cluster.create_node().await.unwrap();
+        wait_for_annotation_value(
+            client.clone(),
+            &test_node_name,
+            RESTORED_ANNOTATION_KEY,
+            Some(&RestoredAnnotation::of_backup(&backup).to_value()),
+        )
+        .await
+        .unwrap();
+
+        //
+        // 4. Cycle the node again and assert a new correlation ID was generated
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        let cm = cms.get(&configmap_name(&test_node_name)).await.unwrap();
+        let new_correlation_id = cm.data.unwrap().get(CORRELATION_ID_KEY).cloned().unwrap();
+        assert_ne!(correlation_id, new_correlation_id);
+    }
+
+    /// 1. Create a node with annotation preservation enabled, and add a label and an annotation
+    /// 2. Delete the node, add it back, and assert that both are restored
+    #[tokio::test]
+    async fn test_annotations_cycle() {
+        let test_node_name = random_node_name(30);
+        let config = ControllerConfig {
+            preserve_annotations: true,
+            ..Default::default()
+        };
+        let cluster = TestCluster::start_with(&test_node_name, config).await;
+        let client = cluster.client();
+
+        //
+        // 1. Create a node, and add a label and an annotation
+        //
+        cluster.create_node().await.unwrap();
+        let node_label_value =
+            set_random_label(client.clone(), &test_node_name, "label_to_persist")
+                .await
+                .unwrap();
+        let annotation_key = "example.com/owner";
+        let annotation_value = "ml-infra".to_string();
+        let nodes: Api<Node> = Api::all(client.clone());
+        let patch = json!({ "metadata": { "annotations": { annotation_key: annotation_value } } });
+        nodes
+            .patch(
+                &test_node_name,
+                &PatchParams::default(),
+                &Patch::Merge(patch),
+            )
+            .await
+            .unwrap();
+
+        //
+        // 2. Cycle the node and assert that both are restored
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
+        wait_for_label_value(
+            client.clone(),
+            &test_node_name,
+            "label_to_persist",
+            Some(&node_label_value),
+        )
+        .await
+        .unwrap();
+        wait_for_annotation_value(
+            client.clone(),
+            &test_node_name,
+            annotation_key,
+            Some(&annotation_value),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// 1. Create a node with taint preservation enabled, and add a label and a taint
+    /// 2. Delete the node, add it back, and assert that both are restored
+    #[tokio::test]
+    async fn test_taints_cycle() {
+        let test_node_name = random_node_name(30);
+        let config = ControllerConfig {
+            preserve_taints: true,
+            ..Default::default()
+        };
+        let cluster = TestCluster::start_with(&test_node_name, config).await;
+        let client = cluster.client();
+
+        //
+        // 1. Create a node, and add a label and a taint
+        //
+        cluster.create_node().await.unwrap();
+        let node_label_value =
+            set_random_label(client.clone(), &test_node_name, "label_to_persist")
+                .await
+                .unwrap();
+        let nodes: Api<Node> = Api::all(client.clone());
+        let taint = json!({ "key": "dedicated", "value": "gpu", "effect": "NoSchedule" });
+        let patch = json!({ "spec": { "taints": [taint] } });
+        nodes
+            .patch(
+                &test_node_name,
+                &PatchParams::default(),
+                &Patch::Merge(patch),
+            )
+            .await
+            .unwrap();
+
+        //
+        // 2. Cycle the node and assert that both are restored
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
+        wait_for_label_value(
+            client.clone(),
+            &test_node_name,
+            "label_to_persist",
+            Some(&node_label_value),
+        )
+        .await
+        .unwrap();
+        let node = nodes.get(&test_node_name).await.unwrap();
+        let taints = node.spec.and_then(|spec| spec.taints).unwrap_or_default();
+        assert!(
+            taints.iter().any(|t| t.key == "dedicated"
+                && t.value.as_deref() == Some("gpu")
+                && t.effect == "NoSchedule"),
+            "Taints: {:?}",
+            taints
+        );
+    }
+
+    /// 1. Create a node with cleanup after restore enabled, and add a label
+    /// 2. Delete the node and assert that its backup was written
+    /// 3. Add the node back and assert that the label is restored and the backup deleted
+    #[tokio::test]
+    async fn test_cleanup_after_restore_cycle() {
+        let test_node_name = random_node_name(30);
+        let config = ControllerConfig {
+            cleanup_after_restore: true,
+            ..Default::default()
+        };
+        let cluster = TestCluster::start_with(&test_node_name, config).await;
+        let client = cluster.client();
+        let cms = cluster.configmaps();
+        let cm_name = configmap_name(&test_node_name);
+
+        //
+        // 1. Create a node and add a label
+        //
+        cluster.create_node().await.unwrap();
+        let node_label_value =
+            set_random_label(client.clone(), &test_node_name, "label_to_persist")
+                .await
+                .unwrap();
+
+        //
+        // 2. Delete the node, which writes its backup
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        assert!(cms.get_opt(&cm_name).await.unwrap().is_some());
+
+        //
+        // 3. Add the node back, and wait for the restore and the backup's deletion
+        //
+        cluster.create_node().await.unwrap();
+        wait_for_label_value(
+            client.clone(),
+            &test_node_name,
+            "label_to_persist",
+            Some(&node_label_value),
+        )
+        .await
+        .unwrap();
+        let start = std::time::Instant::now();
+        while cms.get_opt(&cm_name).await.unwrap().is_some() {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(10),
+                "Backup ConfigMap {} was not deleted after the restore",
+                cm_name
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    }
+
+    /// 1. Create a node and add a label to it
+    /// 2. Delete the node and add it back
+    /// 3. Assert that the node's Events record both the preserve and the restore
+    #[tokio::test]
+    async fn test_events_cycle() {
+        let test_node_name = random_node_name(30);
+        let cluster = TestCluster::start(&test_node_name).await;
+        let client = cluster.client();
+
+        //
+        // 1. Create a node and add a label to it
+        //
+        cluster.create_node().await.unwrap();
+        let node_label_value = set_random_label(client.clone(), &test_node_name, "label_to_event")
+            .await
+            .unwrap();
+
+        //
+        // 2. Delete the node and add it back
+        //
+        delete_node(client.clone(), &test_node_name).await.unwrap();
+        cluster.create_node().await.unwrap();
+        wait_for_label_value(
+            client.clone(),
+            &test_node_name,
+            "label_to_event",
+            Some(&node_label_value),
+        )
+        .await
+        .unwrap();
+
+        //
+        // 3. Events on cluster-scoped objects land in the default namespace
+        //
+        let events: Api<Event> = Api::namespaced(client, "default");
+        let params =
+            ListParams::default().fields(&format!("involvedObject.name={}", test_node_name));
+        let start = std::time::Instant::now();
+        loop {
+            let reasons: Vec<String> = events
+                .list(&params)
+                .await
+                .unwrap()
+                .items
+                .into_iter()
+                .filter_map(|event| event.reason)
+                .collect();
+            if ["LabelsPreserved", "LabelsRestored"]
+                .iter()
+                .all(|reason| reasons.iter().any(|r| r == reason))
+            {
+                break;
+            }
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(10),
+                "Missing Events on node {}, found {:?}",
+                test_node_name,
+                reasons
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    }
 }
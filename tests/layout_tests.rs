@@ -0,0 +1,333 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{
+        configmaps, context, labels, node, node_json, status_json, ConfigMaps, MockApiServer,
+        RecordedRequest,
+    };
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::ConfigMap;
+
+    use kube::runtime::watcher;
+    use label_preserver::{
+        configmap_name, reconcile, shard_configmap_name, shard_key, Backup, BackupLayout,
+        BackupStore, ControllerConfig, Error, RESTORED_ANNOTATION_KEY, SHARD_CONFIGMAP_PREFIX,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::Arc;
+
+    fn sharded() -> ControllerConfig {
+        ControllerConfig {
+            backup_layout: BackupLayout::Sharded,
+            ..Default::default()
+        }
+    }
+
+    /// A ConfigMap write as the API server would merge it: a server-side apply only replaces
+    /// the data keys it sends, and a JSON patch runs its test and remove operations
+    fn write(
+        stored: Option<serde_json::Value>,
+        req: &RecordedRequest,
+    ) -> Option<serde_json::Value> {
+        let json_patch = req.headers["content-type"] == "application/json-patch+json";
+        let mut cm = match (stored, json_patch) {
+            (Some(cm), _) => cm,
+            (None, true) => return None,
+            (None, false) => serde_json::json!({ "metadata": {}, "data": {} }),
+        };
+        if !json_patch {
+            let applied = req.json();
+            cm["metadata"] = applied["metadata"].clone();
+            for (key, value) in applied["data"].as_object().into_iter().flatten() {
+                cm["data"][key] = value.clone();
+            }
+        } else {
+            for op in req.json().as_array().unwrap() {
+                let key = op["path"].as_str().unwrap().trim_start_matches("/data/");
+                let data = cm["data"].as_object_mut().unwrap();
+                match op["op"].as_str().unwrap() {
+                    "test" if data.get(key) != Some(&op["value"]) => return None,
+                    "remove" => {
+                        data.remove(key)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        cm["metadata"]["resourceVersion"] = "1".into();
+        Some(cm)
+    }
+
+    /// Keep ConfigMaps by name, and accept node patches
+    async fn cluster(initial: &[serde_json::Value]) -> (MockApiServer, ConfigMaps) {
+        let stored = configmaps(initial);
+        let configmaps = stored.clone();
+        let server = MockApiServer::start(move |req| {
+            let path = req.uri.path();
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            if req.is_event() {
+                return (StatusCode::CREATED, req.json());
+            }
+            if !path.contains("/configmaps/") {
+                return match req.method {
+                    Method::PATCH => (StatusCode::OK, node_json(&name)),
+                    _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                };
+            }
+            let mut configmaps = configmaps.lock().unwrap();
+            let found = match req.method {
+                Method::GET => configmaps.get(&name).cloned(),
+                Method::DELETE => configmaps.remove(&name),
+                Method::PATCH => match write(configmaps.get(&name).cloned(), req) {
+                    Some(cm) => {
+                        configmaps.insert(name, cm.clone());
+                        Some(cm)
+                    }
+                    None => {
+                        return (
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            status_json(422, "Invalid"),
+                        )
+                    }
+                },
+                _ => None,
+            };
+            match found {
+                Some(cm) => (StatusCode::OK, cm),
+                None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            }
+        })
+        .await;
+        (server, stored)
+    }
+
+    #[test]
+    fn test_layout_names() {
+        assert_eq!(
+            "per-node".parse::<BackupLayout>().unwrap(),
+            BackupLayout::PerNode
+        );
+        assert_eq!(
+            "sharded".parse::<BackupLayout>().unwrap(),
+            BackupLayout::Sharded
+        );
+        assert!(matches!(
+            "flat".parse::<BackupLayout>(),
+            Err(Error::InvalidBackupLayout(_))
+        ));
+        assert_eq!(BackupLayout::Sharded.to_string(), "sharded");
+
+        // The first byte of the hash picks one of at most 256 shards
+        let shards: BTreeSet<String> = (0..5000)
+            .map(|i| shard_configmap_name(&format!("node-{i}")))
+            .collect();
+        assert_eq!(shards.len(), 256);
+        let shard = shard_configmap_name("node-a");
+        assert_eq!(shard.len(), SHARD_CONFIGMAP_PREFIX.len() + 2);
+        assert!(configmap_name("node-a").ends_with(&shard_key("node-a")));
+    }
+
+    /// Two nodes of the same shard deleted at once both end up in the shard: each apply only
+    /// sends its own key, under its own field manager
+    #[tokio::test]
+    async fn test_concurrent_preservation_in_one_shard() {
+        let first = "node-0".to_string();
+        let second = (1..)
+            .map(|i| format!("node-{i}"))
+            .find(|name| shard_configmap_name(name) == shard_configmap_name(&first))
+            .unwrap();
+        let (server, stored) = cluster(&[]).await;
+        let ctx = context(&server, sharded());
+        let (a, b) = tokio::join!(
+            reconcile(
+                Arc::new(node(&first, labels(&[("zone", "a")]), true)),
+                ctx.clone()
+            ),
+            reconcile(
+                Arc::new(node(&second, labels(&[("zone", "b")]), true)),
+                ctx.clone()
+            ),
+        );
+        a.unwrap();
+        b.unwrap();
+
+        let shard_name = shard_configmap_name(&first);
+        let shard = stored.lock().unwrap()[&shard_name].clone();
+        assert_eq!(shard["data"].as_object().unwrap().len(), 2);
+        assert!(!stored
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|name| name.starts_with("node-labels-")));
+        let store = BackupStore::new(server.client(), "default", BackupLayout::Sharded);
+        for (name, zone) in [(&first, "a"), (&second, "b")] {
+            let (backup, _) = store.read(name).await.unwrap().expect("backed up");
+            assert_eq!(backup.labels, labels(&[("zone", zone)]));
+        }
+
+        let applies: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().ends_with(&shard_name))
+            .collect();
+        assert_eq!(applies.len(), 2);
+        let managers: BTreeSet<_> = applies
+            .iter()
+            .map(|r| r.field_manager().unwrap().to_string())
+            .collect();
+        assert_eq!(managers.len(), 2);
+        for apply in &applies {
+            assert_eq!(apply.json()["data"].as_object().unwrap().len(), 1);
+        }
+    }
+
+    /// A backup that would take its shard past the object limit fails before anything is
+    /// applied, leaving the shard as it was
+    #[tokio::test]
+    async fn test_full_shard_rejected() {
+        let shard_name = shard_configmap_name("node-a");
+        let shard = serde_json::json!({
+            "metadata": { "name": shard_name, "namespace": "default" },
+            "data": { "other": "x".repeat(1024 * 1024 - 300) },
+        });
+        let (server, stored) = cluster(std::slice::from_ref(&shard)).await;
+        let ctx = context(&server, sharded());
+        let error = reconcile(
+            Arc::new(node("node-a", labels(&[("zone", "a")]), true)),
+            ctx,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.kind(), "shard_full");
+        assert!(error.to_string().contains(&shard_name));
+        assert!(!server
+            .requests()
+            .iter()
+            .any(|r| r.method == Method::PATCH && r.uri.path().ends_with(&shard_name)));
+        assert_eq!(stored.lock().unwrap()[&shard_name], shard);
+    }
+
+    /// With sharding on, a backup still in its per-node ConfigMap is restored, moved into its
+    /// shard, and removed from the shard once restored if configured
+    #[tokio::test]
+    async fn test_reads_per_node_backup() {
+        let backup = Backup {
+            labels: labels(&[("zone", "a")]),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let per_node = serde_json::json!({
+            "metadata": {
+                "name": configmap_name("node-a"),
+                "namespace": "default",
+                "resourceVersion": "3",
+            },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        let other = serde_json::json!({
+            "metadata": { "name": shard_configmap_name("node-a"), "namespace": "default" },
+            "data": { "other-node-hash": "{}" },
+        });
+        let (server, stored) = cluster(&[per_node, other]).await;
+        let config = ControllerConfig {
+            cleanup_after_restore: true,
+            ..sharded()
+        };
+        let ctx = context(&server, config);
+        reconcile(Arc::new(node("node-a", BTreeMap::new(), false)), ctx)
+            .await
+            .unwrap();
+
+        let restore = server.requests().into_iter().find(|r| r.is_restore());
+        let restored = restore.expect("the node is restored").json();
+        assert_eq!(restored["metadata"]["labels"]["zone"], "a");
+        let requests = server.requests();
+        let delete = requests
+            .iter()
+            .find(|r| r.method == Method::DELETE)
+            .expect("the per-node backup is deleted");
+        assert!(delete.uri.path().ends_with(&configmap_name("node-a")));
+        // The restored entry is removed only if unchanged, leaving other nodes' entries
+        let removal = requests
+            .iter()
+            .rfind(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .unwrap()
+            .json();
+        assert_eq!(removal[0]["op"], "test");
+        assert_eq!(removal[1]["op"], "remove");
+        let stored = stored.lock().unwrap();
+        assert!(!stored.contains_key(&configmap_name("node-a")));
+        let shard = &stored[&shard_configmap_name("node-a")];
+        assert_eq!(
+            shard["data"],
+            serde_json::json!({ "other-node-hash": "{}" })
+        );
+    }
+
+    /// node-a's shard holding a backup of `pairs`, and another node's entry
+    fn shard(pairs: &[(&str, &str)]) -> serde_json::Value {
+        let backup = Backup {
+            labels: labels(pairs),
+            ..Default::default()
+        };
+        let entry = serde_json::to_string(&backup.to_configmap_data().unwrap()).unwrap();
+        serde_json::json!({
+            "metadata": { "name": shard_configmap_name("node-a"), "namespace": "default" },
+            "data": { shard_key("node-a"): entry, "other-node-hash": "{}" },
+        })
+    }
+
+    /// A restored node whose entry in its shard changes is restored again once the backup
+    /// watch sees the shard
+    #[tokio::test]
+    async fn test_changed_shard_entry_restored_again() {
+        let (server, stored) = cluster(&[shard(&[("zone", "a")])]).await;
+        let ctx = context(&server, sharded());
+        reconcile(
+            Arc::new(node("node-a", BTreeMap::new(), false)),
+            ctx.clone(),
+        )
+        .await
+        .unwrap();
+        let restores = || -> Vec<serde_json::Value> {
+            server
+                .requests()
+                .into_iter()
+                .filter(|r| r.is_restore())
+                .map(|r| r.json())
+                .collect()
+        };
+        let restored = restores()[0].clone();
+        assert_eq!(restored["metadata"]["labels"]["zone"], "a");
+        let mut restored_node = node("node-a", labels(&[("zone", "a")]), false);
+        restored_node.metadata.annotations = Some(BTreeMap::from([(
+            RESTORED_ANNOTATION_KEY.to_string(),
+            restored["metadata"]["annotations"][RESTORED_ANNOTATION_KEY]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        )]));
+
+        // An unchanged entry isn't restored again
+        let seen: ConfigMap = serde_json::from_value(shard(&[("zone", "a")])).unwrap();
+        ctx.observe_backup(&watcher::Event::Apply(seen));
+        reconcile(Arc::new(restored_node.clone()), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(restores().len(), 1);
+
+        let changed = shard(&[("zone", "a"), ("tenant", "acme")]);
+        stored
+            .lock()
+            .unwrap()
+            .insert(shard_configmap_name("node-a"), changed.clone());
+        let changed: ConfigMap = serde_json::from_value(changed).unwrap();
+        ctx.observe_backup(&watcher::Event::Apply(changed));
+        reconcile(Arc::new(restored_node), ctx).await.unwrap();
+        let restores = restores();
+        assert_eq!(restores.len(), 2);
+        assert_eq!(restores[1]["metadata"]["labels"]["tenant"], "acme");
+    }
+}
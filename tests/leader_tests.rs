@@ -0,0 +1,264 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+    use label_preserver::{
+        run::{self, ShutdownReason},
+        ClientFactory, ClientOptions, ControllerConfig, LeaderElection, LeaderElector,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const LEASE_PATH: &str = "/apis/coordination.k8s.io/v1/namespaces/default/leases";
+
+    type StoredLease = Arc<Mutex<Option<serde_json::Value>>>;
+
+    /// Store one Lease, rejecting writes based on an outdated resourceVersion the way the API
+    /// server does, and serve `node-a` to the node watch
+    async fn cluster() -> (MockApiServer, StoredLease) {
+        let lease: StoredLease = Arc::new(Mutex::new(None));
+        let stored = lease.clone();
+        let server = MockApiServer::start(move |req| {
+            if req.uri.path().starts_with(LEASE_PATH) {
+                return lease_request(&stored, req);
+            }
+            match (&req.method, req.uri.path()) {
+                (&Method::GET, "/api/v1/nodes") => (
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "apiVersion": "v1",
+                        "kind": "NodeList",
+                        "metadata": { "resourceVersion": "1" },
+                        "items": [node_json("node-a")],
+                    }),
+                ),
+                (&Method::PATCH, path) if path.starts_with("/api/v1/nodes/") => {
+                    (StatusCode::OK, node_json("node-a"))
+                }
+                (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            }
+        })
+        .await;
+        (server, lease)
+    }
+
+    fn lease_request(
+        stored: &StoredLease,
+        req: &RecordedRequest,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut stored = stored.lock().unwrap();
+        let version = |lease: &serde_json::Value| {
+            lease["metadata"]["resourceVersion"]
+                .as_str()
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+        match req.method {
+            Method::GET => match stored.as_ref() {
+                Some(lease) => (StatusCode::OK, lease.clone()),
+                None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            },
+            Method::POST if stored.is_some() => {
+                (StatusCode::CONFLICT, status_json(409, "already exists"))
+            }
+            Method::PUT if stored.as_ref().and_then(version) != version(&req.json()) => {
+                (StatusCode::CONFLICT, status_json(409, "conflict"))
+            }
+            Method::POST | Method::PUT => {
+                let mut lease = req.json();
+                let next = stored.as_ref().and_then(version).unwrap_or(0) + 1;
+                lease["metadata"]["resourceVersion"] = next.to_string().into();
+                *stored = Some(lease.clone());
+                (StatusCode::OK, lease)
+            }
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        }
+    }
+
+    fn settings(lease_duration: Duration) -> LeaderElection {
+        LeaderElection {
+            lease_duration,
+            ..Default::default()
+        }
+    }
+
+    fn holder(lease: &StoredLease) -> serde_json::Value {
+        lease.lock().unwrap().as_ref().unwrap()["spec"]["holderIdentity"].clone()
+    }
+
+    fn transitions(lease: &StoredLease) -> serde_json::Value {
+        lease.lock().unwrap().as_ref().unwrap()["spec"]["leaseTransitions"].clone()
+    }
+
+    /// Only one replica holds the lease, and the holder keeps renewing it
+    #[tokio::test]
+    async fn test_single_holder() {
+        let (server, lease) = cluster().await;
+        let settings = settings(Duration::from_secs(15));
+        let first = LeaderElector::new(server.client(), &settings, "replica-1");
+        let second = LeaderElector::new(server.client(), &settings, "replica-2");
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+        let acquired = lease.lock().unwrap().clone().unwrap();
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+        let renewed = lease.lock().unwrap().clone().unwrap();
+        assert_eq!(holder(&lease), "replica-1");
+        assert_eq!(transitions(&lease), 0);
+        assert_eq!(renewed["spec"]["leaseDurationSeconds"], 15);
+        assert_eq!(
+            renewed["spec"]["acquireTime"],
+            acquired["spec"]["acquireTime"]
+        );
+        assert_ne!(renewed["spec"]["renewTime"], acquired["spec"]["renewTime"]);
+    }
+
+    /// A released lease is taken right away
+    #[tokio::test]
+    async fn test_release() {
+        let (server, lease) = cluster().await;
+        let settings = settings(Duration::from_secs(15));
+        let first = LeaderElector::new(server.client(), &settings, "replica-1");
+        let second = LeaderElector::new(server.client(), &settings, "replica-2");
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        // Releasing a lease held by someone else leaves it alone
+        second.release().await.unwrap();
+        assert_eq!(holder(&lease), "replica-1");
+        first.release().await.unwrap();
+        assert_eq!(holder(&lease), serde_json::Value::Null);
+        assert!(second.try_acquire_or_renew().await.unwrap());
+        assert_eq!(holder(&lease), "replica-2");
+        assert_eq!(transitions(&lease), 1);
+    }
+
+    /// A lease that isn't renewed for its duration is taken over, and its former holder learns
+    /// it lost it
+    #[tokio::test]
+    async fn test_expired_lease_taken_over() {
+        let (server, lease) = cluster().await;
+        let settings = settings(Duration::from_secs(1));
+        let first = LeaderElector::new(server.client(), &settings, "replica-1");
+        let second = LeaderElector::new(server.client(), &settings, "replica-2");
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        // Renewing starts the expiry over
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(second.try_acquire_or_renew().await.unwrap());
+        assert_eq!(holder(&lease), "replica-2");
+        assert!(!first.try_acquire_or_renew().await.unwrap());
+    }
+
+    /// Holding the lease ends once another replica has it
+    #[tokio::test]
+    async fn test_hold_until_taken() {
+        let (server, lease) = cluster().await;
+        let settings = settings(Duration::from_secs(1));
+        let elector = LeaderElector::new(server.client(), &settings, "replica-1");
+        assert!(elector.try_acquire_or_renew().await.unwrap());
+        let intruder = lease.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let mut lease = intruder.lock().unwrap();
+            let lease = lease.as_mut().unwrap();
+            lease["spec"]["holderIdentity"] = "replica-2".into();
+            lease["metadata"]["resourceVersion"] = "100".into();
+        });
+        let reason = tokio::time::timeout(Duration::from_secs(5), elector.hold())
+            .await
+            .expect("leadership is lost");
+        assert!(reason.contains("taken by another replica"), "{reason}");
+    }
+
+    async fn run_replica(
+        server: &MockApiServer,
+        instance_id: &str,
+        lease_duration: Duration,
+        stop_after: Duration,
+    ) -> run::Shutdown {
+        let config = ControllerConfig {
+            instance_id: instance_id.to_string(),
+            leader_election: Some(settings(lease_duration)),
+            ..Default::default()
+        };
+        let clients =
+            ClientFactory::with_base_config(server.config(), ClientOptions::default(), instance_id);
+        run::run(&clients, config, None, tokio::time::sleep(stop_after)).await
+    }
+
+    /// The replicas whose requests patched nodes, by their User-Agent
+    fn patching_replicas(server: &MockApiServer) -> Vec<String> {
+        let mut replicas: Vec<String> = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().starts_with("/api/v1/nodes/"))
+            .map(|r| {
+                let agent = r.headers["user-agent"].to_str().unwrap();
+                agent
+                    .split("instance=")
+                    .nth(1)
+                    .unwrap()
+                    .trim_end_matches(')')
+                    .to_string()
+            })
+            .collect();
+        replicas.dedup();
+        replicas
+    }
+
+    /// Of two replicas started together, only the one holding the lease reconciles nodes, and
+    /// it releases the lease when it stops
+    #[tokio::test]
+    async fn test_two_replicas() {
+        let (server, lease) = cluster().await;
+        let stop_after = Duration::from_millis(1500);
+        let lease_duration = Duration::from_secs(15);
+        let (first, second) = tokio::join!(
+            run_replica(&server, "replica-1", lease_duration, stop_after),
+            run_replica(&server, "replica-2", lease_duration, stop_after),
+        );
+        assert_eq!(first.reason, ShutdownReason::Clean);
+        assert_eq!(second.reason, ShutdownReason::Clean);
+        let replicas = patching_replicas(&server);
+        assert_eq!(replicas.len(), 1, "{replicas:?}");
+        assert!(first.totals.reconciles + second.totals.reconciles > 0);
+        assert!(first.totals.reconciles == 0 || second.totals.reconciles == 0);
+        assert_eq!(holder(&lease), serde_json::Value::Null);
+    }
+
+    /// A follower takes over once the leader stops
+    #[tokio::test]
+    async fn test_follower_takes_over() {
+        let (server, lease) = cluster().await;
+        let lease_duration = Duration::from_secs(1);
+        let leader = run_replica(
+            &server,
+            "replica-1",
+            lease_duration,
+            Duration::from_millis(500),
+        );
+        let follower = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            run_replica(
+                &server,
+                "replica-2",
+                lease_duration,
+                Duration::from_millis(1500),
+            )
+            .await
+        };
+        let (leader, follower) = tokio::join!(leader, follower);
+        assert!(leader.totals.reconciles > 0);
+        assert!(follower.totals.reconciles > 0);
+        assert_eq!(
+            patching_replicas(&server),
+            vec!["replica-1".to_string(), "replica-2".to_string()]
+        );
+        assert_eq!(transitions(&lease), 1);
+    }
+}
@@ -0,0 +1,113 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        configmap_name, log_layer, reconcile, Backup, Context, ControllerConfig, Error, LogFormat,
+        FINALIZER_NAME,
+    };
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    /// Log lines written by the layer under test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).expect("every line is JSON"))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_log_format_names() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!(matches!(
+            "logfmt".parse::<LogFormat>(),
+            Err(Error::InvalidLogFormat(_))
+        ));
+        assert_eq!(LogFormat::default().to_string(), "text");
+    }
+
+    /// An apply reconcile logs JSON lines carrying the node, its backup ConfigMap, the kind of
+    /// reconcile, the attempt, and how many labels were restored
+    #[tokio::test]
+    async fn test_apply_fields() {
+        let backup = Backup {
+            labels: BTreeMap::from([("zone".to_string(), "a".to_string())]),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+            ),
+            Method::PATCH | Method::POST => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = ControllerConfig {
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(log_layer(LogFormat::Json, move || writer.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        reconcile(Arc::new(node), ctx).await.unwrap();
+
+        let lines = captured.lines();
+        let restored = lines
+            .iter()
+            .find(|line| {
+                line["message"]
+                    .as_str()
+                    .is_some_and(|m| m.starts_with("Restored 1 labels"))
+            })
+            .expect("the restore is logged");
+        assert_eq!(restored["level"], "INFO");
+        let spans = restored["spans"].as_array().unwrap();
+        assert_eq!(spans[0]["name"], "reconcile");
+        assert_eq!(spans[0]["node"], "node-a");
+        assert_eq!(spans[0]["reconcile_kind"], "apply");
+        assert_eq!(spans[0]["attempt"], 1);
+        assert_eq!(spans[1]["name"], "apply_node");
+        assert_eq!(spans[1]["configmap"], configmap_name("node-a"));
+        assert_eq!(spans[1]["labels_restored"], 1);
+        assert_eq!(restored["span"], spans[1]);
+    }
+}
@@ -0,0 +1,129 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, node, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{reconcile, Context, ControllerConfig, MANAGED_LABEL_KEY};
+    use std::sync::{Arc, Mutex};
+
+    /// A mock API server that stores the last ConfigMap written and serves it back
+    async fn configmap_server() -> MockApiServer {
+        let stored: Arc<Mutex<Option<serde_json::Value>>> = Arc::new(Mutex::new(None));
+        MockApiServer::start(move |req| {
+            if !req.uri.path().contains("/configmaps/") {
+                return (StatusCode::OK, node_json("node-a"));
+            }
+            let mut stored = stored.lock().unwrap();
+            if req.method == Method::PATCH {
+                *stored = Some(req.json());
+            }
+            match stored.as_ref() {
+                Some(cm) => (StatusCode::OK, cm.clone()),
+                None => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            }
+        })
+        .await
+    }
+
+    /// The label set on a node without a backup to restore
+    fn managed_label_patch(server: &MockApiServer) -> Option<serde_json::Value> {
+        server
+            .requests()
+            .iter()
+            .find(|r| r.method == Method::PATCH && r.uri.path() == "/api/v1/nodes/node-a")
+            .map(|r| r.json()["metadata"]["labels"][MANAGED_LABEL_KEY].clone())
+    }
+
+    /// The managed label is set on apply, even without a backup, left out of the backup, and
+    /// set again on the recreated node by re-application rather than by the restore
+    #[tokio::test]
+    async fn test_managed_label_survives_recreation() {
+        let server = configmap_server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+
+        reconcile(
+            Arc::new(node("node-a", labels(&[("zone", "a")]), false)),
+            ctx.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(managed_label_patch(&server), Some("true".into()));
+        assert!(!server.requests().iter().any(|r| r.is_restore()));
+
+        let mut terminating = node(
+            "node-a",
+            labels(&[("zone", "a"), (MANAGED_LABEL_KEY, "true")]),
+            false,
+        );
+        terminating.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(terminating), ctx.clone()).await.unwrap();
+        let requests = server.requests();
+        let snapshot = requests
+            .iter()
+            .find(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .unwrap();
+        let preserved = snapshot.json()["data"]["preserved_labels_json"].clone();
+        assert_eq!(preserved, serde_json::json!(r#"{"zone":"a"}"#));
+
+        reconcile(Arc::new(node("node-a", labels(&[]), false)), ctx.clone())
+            .await
+            .unwrap();
+        let requests = server.requests();
+        let apply = requests.iter().rfind(|r| r.is_restore()).unwrap();
+        assert_eq!(apply.uri.path(), "/api/v1/nodes/node-a");
+        assert_eq!(
+            apply.json()["metadata"]["labels"],
+            serde_json::json!({ "zone": "a", MANAGED_LABEL_KEY: "true" })
+        );
+    }
+
+    /// Opting out leaves the label off
+    #[tokio::test]
+    async fn test_managed_label_opt_out() {
+        let server = configmap_server().await;
+        let config = ControllerConfig {
+            managed_label: false,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+
+        reconcile(Arc::new(node("node-a", labels(&[]), false)), ctx)
+            .await
+            .unwrap();
+        assert_eq!(managed_label_patch(&server), None);
+    }
+
+    /// A node leaving scope has the label removed along with our finalizer
+    #[tokio::test]
+    async fn test_managed_label_removed_on_scope_exit() {
+        let server = configmap_server().await;
+        let config = ControllerConfig {
+            node_names: Some(["worker-1".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+
+        reconcile(
+            Arc::new(node(
+                "node-a",
+                labels(&[("zone", "a"), (MANAGED_LABEL_KEY, "true")]),
+                false,
+            )),
+            ctx,
+        )
+        .await
+        .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let release = requests[0].json();
+        assert_eq!(release["metadata"]["finalizers"], serde_json::json!([]));
+        assert_eq!(
+            release["metadata"]["labels"],
+            serde_json::json!({ MANAGED_LABEL_KEY: null })
+        );
+    }
+}
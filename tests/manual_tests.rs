@@ -0,0 +1,154 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use label_preserver::{
+        configmap_name,
+        manual::{self, EXIT_FAILED, EXIT_NO_BACKUP, EXIT_NO_NODE},
+        Backup, ControllerConfig, Error, Redactor, Surface,
+    };
+
+    /// Serve `node-a` with `zone=b`, and the backup of `node-a` with `zone=a` and `rack=r1` if
+    /// `has_backup`. Other nodes and backups don't exist.
+    async fn cluster(has_backup: bool) -> MockApiServer {
+        let backup = Backup {
+            labels: labels(&[("zone", "a"), ("rack", "r1")]),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        let node = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": { "name": "node-a", "labels": { "zone": "b" } },
+        });
+        MockApiServer::start(move |req| {
+            let backup_path = format!(
+                "/api/v1/namespaces/default/configmaps/{}",
+                configmap_name("node-a")
+            );
+            match (&req.method, req.uri.path()) {
+                (&Method::GET, "/api/v1/namespaces/default/configmaps") => (
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "apiVersion": "v1",
+                        "kind": "ConfigMapList",
+                        "metadata": { "resourceVersion": "1" },
+                        "items": [],
+                    }),
+                ),
+                (&Method::GET | &Method::DELETE, path) if path == backup_path && has_backup => {
+                    (StatusCode::OK, configmap.clone())
+                }
+                (&Method::GET, "/api/v1/nodes/node-a") => (StatusCode::OK, node.clone()),
+                (&Method::PATCH, "/api/v1/nodes/node-a") => (StatusCode::OK, req.json()),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_show() {
+        let server = cluster(true).await;
+        let config = ControllerConfig::default();
+        let state = manual::show(server.client(), &config, "node-a")
+            .await
+            .unwrap();
+        assert_eq!(state.node_name.as_deref(), Some("node-a"));
+        assert_eq!(state.labels, labels(&[("zone", "a"), ("rack", "r1")]));
+        assert_eq!(state.correlation_id.as_deref(), Some("corr-1"));
+        // Nothing is written
+        assert!(server.requests().iter().all(|r| r.method == Method::GET));
+
+        let config = ControllerConfig {
+            redactor: Redactor {
+                all_keys: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let state = manual::show(server.client(), &config, "node-a")
+            .await
+            .unwrap();
+        assert_ne!(state.labels["zone"], "a");
+        assert_eq!(
+            state.labels["zone"],
+            config.redactor.value(Surface::Reports, "zone", "a")
+        );
+    }
+
+    /// A missing node or backup fails with its own exit code, so scripts can tell them apart
+    #[tokio::test]
+    async fn test_missing() {
+        let server = cluster(false).await;
+        let config = ControllerConfig::default();
+        let error = manual::show(server.client(), &config, "node-a")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::BackupNotFound(_)), "{error}");
+        assert_eq!(manual::exit_code(&error), EXIT_NO_BACKUP);
+        let error = manual::purge(server.client(), &config, "node-a")
+            .await
+            .unwrap_err();
+        assert_eq!(manual::exit_code(&error), EXIT_NO_BACKUP);
+        let error = manual::restore(server.client(), &config, "node-a", false)
+            .await
+            .unwrap_err();
+        assert_eq!(manual::exit_code(&error), EXIT_NO_BACKUP);
+
+        let server = cluster(true).await;
+        let error = manual::restore(server.client(), &config, "node-b", false)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::NodeNotFound(_)), "{error}");
+        assert_eq!(manual::exit_code(&error), EXIT_NO_NODE);
+        assert!(server.requests().iter().all(|r| r.method == Method::GET));
+
+        let error = Error::InvalidSchemaVersion("x".to_string());
+        assert_eq!(manual::exit_code(&error), EXIT_FAILED);
+    }
+
+    /// Restoring keeps the node's own values unless forced
+    #[tokio::test]
+    async fn test_restore() {
+        let server = cluster(true).await;
+        let config = ControllerConfig::default();
+        let counts = manual::restore(server.client(), &config, "node-a", false)
+            .await
+            .unwrap();
+        assert_eq!((counts.restored, counts.conflicts), (1, 1));
+        let restore = server.requests().into_iter().find(|r| r.is_restore());
+        let restored = restore.expect("the node is restored").json();
+        assert_eq!(restored["metadata"]["labels"]["rack"], "r1");
+        assert_eq!(restored["metadata"]["labels"]["zone"], "b");
+
+        let server = cluster(true).await;
+        let counts = manual::restore(server.client(), &config, "node-a", true)
+            .await
+            .unwrap();
+        assert_eq!((counts.restored, counts.overwritten), (1, 1));
+        let restore = server.requests().into_iter().find(|r| r.is_restore());
+        let restored = restore.expect("the node is restored").json();
+        assert_eq!(restored["metadata"]["labels"]["zone"], "a");
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let server = cluster(true).await;
+        manual::purge(server.client(), &ControllerConfig::default(), "node-a")
+            .await
+            .unwrap();
+        let requests = server.requests();
+        let delete = requests
+            .iter()
+            .find(|r| r.method == Method::DELETE)
+            .expect("the backup is deleted");
+        assert!(delete.uri.path().ends_with(&configmap_name("node-a")));
+    }
+}
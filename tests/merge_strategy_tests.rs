@@ -0,0 +1,142 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        merge_labels, restore_on_apply, Backup, ControllerConfig, Error, MergeStrategy, SkipReason,
+    };
+    use std::collections::BTreeMap;
+
+    /// A fresh node stamped by its provisioner, some of whose labels conflict with the backup
+    fn current() -> BTreeMap<String, String> {
+        labels(&[
+            ("pool", "default"),
+            ("zone", "a"),
+            ("provisioner", "karpenter"),
+        ])
+    }
+
+    fn preserved() -> BTreeMap<String, String> {
+        labels(&[("pool", "gpu"), ("zone", "a"), ("team", "ml")])
+    }
+
+    /// Both strategies add vacant keys and keep equal ones
+    #[test]
+    fn test_vacant_and_equal_keys() {
+        for strategy in [MergeStrategy::PreferCurrent, MergeStrategy::PreferPreserved] {
+            let merge = merge_labels(&current(), &preserved(), strategy);
+            assert_eq!(merge.added, labels(&[("team", "ml")]));
+            assert_eq!(merge.skipped["zone"], SkipReason::ExistingValueKept);
+            assert_eq!(merge.labels["provisioner"], "karpenter");
+        }
+    }
+
+    /// The node's value wins conflicts
+    #[test]
+    fn test_prefer_current() {
+        let merge = merge_labels(&current(), &preserved(), MergeStrategy::PreferCurrent);
+        assert_eq!(
+            merge.labels,
+            labels(&[
+                ("pool", "default"),
+                ("zone", "a"),
+                ("provisioner", "karpenter"),
+                ("team", "ml"),
+            ])
+        );
+        assert!(merge.overwritten.is_empty());
+        assert_eq!(
+            merge.skipped,
+            BTreeMap::from([
+                ("pool".to_string(), SkipReason::DeferToLive),
+                ("zone".to_string(), SkipReason::ExistingValueKept),
+            ])
+        );
+    }
+
+    /// The backed up value wins conflicts, and labels only on the node are kept
+    #[test]
+    fn test_prefer_preserved() {
+        let merge = merge_labels(&current(), &preserved(), MergeStrategy::PreferPreserved);
+        assert_eq!(
+            merge.labels,
+            labels(&[
+                ("pool", "gpu"),
+                ("zone", "a"),
+                ("provisioner", "karpenter"),
+                ("team", "ml"),
+            ])
+        );
+        assert_eq!(merge.overwritten, labels(&[("pool", "gpu")]));
+        assert_eq!(
+            merge.skipped,
+            BTreeMap::from([("zone".to_string(), SkipReason::ExistingValueKept)])
+        );
+    }
+
+    /// Without conflicts the strategies agree
+    #[test]
+    fn test_no_conflicts() {
+        let current = labels(&[("provisioner", "karpenter")]);
+        let preserved = labels(&[("team", "ml")]);
+        assert_eq!(
+            merge_labels(&current, &preserved, MergeStrategy::PreferCurrent),
+            merge_labels(&current, &preserved, MergeStrategy::PreferPreserved)
+        );
+    }
+
+    /// Overwritten labels are counted and applied
+    #[test]
+    fn test_restore_on_apply() {
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                labels: Some(current()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let backup = Backup {
+            labels: preserved(),
+            ..Default::default()
+        };
+        let config = ControllerConfig {
+            merge_strategy: MergeStrategy::PreferPreserved,
+            managed_label: false,
+            ..Default::default()
+        };
+        let restoration = restore_on_apply(&node, &backup, &config, Utc::now());
+        assert_eq!(restoration.overwritten, labels(&[("pool", "gpu")]));
+        assert_eq!(restoration.counts.overwritten, 1);
+        assert_eq!(restoration.counts.conflicts, 0);
+        assert_eq!(restoration.counts.restored, 1);
+        assert_eq!(restoration.counts.total(), 3);
+        assert_eq!(restoration.node.metadata.labels.unwrap()["pool"], "gpu");
+
+        let restoration =
+            restore_on_apply(&node, &backup, &ControllerConfig::default(), Utc::now());
+        assert!(restoration.overwritten.is_empty());
+        assert_eq!(restoration.counts.conflicts, 1);
+        assert_eq!(restoration.node.metadata.labels.unwrap()["pool"], "default");
+    }
+
+    /// Strategies parse from their flag values and display as them
+    #[test]
+    fn test_parse() {
+        for strategy in [MergeStrategy::PreferCurrent, MergeStrategy::PreferPreserved] {
+            assert_eq!(
+                strategy.to_string().parse::<MergeStrategy>().unwrap(),
+                strategy
+            );
+        }
+        assert_eq!(MergeStrategy::default(), MergeStrategy::PreferCurrent);
+        assert!(matches!(
+            "overwrite".parse::<MergeStrategy>(),
+            Err(Error::InvalidMergeStrategy(_))
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use axum::body::Body;
+    use http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        admin, configmap_name, error_policy,
+        metrics::{Metrics, PoolBuckets, PoolLimit, NO_POOL, OTHER_POOL},
+        reconcile, Backup, Context, ControllerConfig, Error, RestoreOutcome, FINALIZER_NAME,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tower::ServiceExt;
+
+    const POOL_LABEL: &str = "pool.example.com/name";
+
+    fn node_in_pool(name: &str, pool: Option<&str>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: pool.map(|pool| [(POOL_LABEL.to_string(), pool.to_string())].into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn buckets(limit: PoolLimit) -> PoolBuckets {
+        PoolBuckets::new(Some(POOL_LABEL.to_string()), limit)
+    }
+
+    /// Only the first N distinct pools get their own value
+    #[test]
+    fn test_first_seen_cap() {
+        let pools = buckets(PoolLimit::FirstSeen(2));
+        let pool = |value| pools.pool(&node_in_pool("a", Some(value)));
+        assert_eq!(pool("gpu"), "gpu");
+        assert_eq!(pool("cpu"), "cpu");
+        assert_eq!(pool("arm"), OTHER_POOL);
+        assert_eq!(pool("gpu"), "gpu");
+        assert_eq!(pool("spot"), OTHER_POOL);
+    }
+
+    /// An allowlist keeps exactly its values
+    #[test]
+    fn test_allowlist() {
+        let pools = buckets(PoolLimit::Allowlist(["gpu".to_string()].into()));
+        assert_eq!(pools.pool(&node_in_pool("a", Some("gpu"))), "gpu");
+        assert_eq!(pools.pool(&node_in_pool("a", Some("cpu"))), OTHER_POOL);
+    }
+
+    /// Nodes without the label, or without a configured label, are in no pool
+    #[test]
+    fn test_no_pool() {
+        let pools = buckets(PoolLimit::FirstSeen(2));
+        assert_eq!(pools.pool(&node_in_pool("a", None)), NO_POOL);
+        let unconfigured = PoolBuckets::new(None, PoolLimit::FirstSeen(2));
+        assert_eq!(unconfigured.pool(&node_in_pool("a", Some("gpu"))), NO_POOL);
+    }
+
+    /// Counters render with their pool and outcome labels
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new(buckets(PoolLimit::FirstSeen(1)));
+        metrics.record_reconcile(&node_in_pool("a", Some("gpu")));
+        metrics.record_reconcile(&node_in_pool("b", Some("gpu")));
+        metrics.record_reconcile(&node_in_pool("c", Some("cpu")));
+        metrics.record_restore(&node_in_pool("a", Some("gpu")), RestoreOutcome::Conflict);
+        metrics.record_error(
+            &node_in_pool("d", None),
+            &Error::InvalidSchemaVersion("9".to_string()),
+        );
+        let text = metrics.render();
+        assert!(text.contains("label_preserver_reconciles_total{pool=\"gpu\"} 2\n"));
+        assert!(text.contains("label_preserver_reconciles_total{pool=\"other\"} 1\n"));
+        assert!(
+            text.contains("label_preserver_restores_total{pool=\"gpu\",outcome=\"conflict\"} 1\n")
+        );
+        assert!(text.contains(
+            "label_preserver_errors_total{pool=\"none\",kind=\"invalid_schema_version\"} 1\n"
+        ));
+    }
+
+    /// Histograms render cumulative buckets, with observations above the last bound only in +Inf
+    #[test]
+    fn test_render_histograms() {
+        let metrics = Metrics::new(buckets(PoolLimit::FirstSeen(1)));
+        let node = node_in_pool("a", Some("gpu"));
+        for restored in [0, 3, 3, 500] {
+            metrics.record_restored_labels(&node, restored);
+        }
+        metrics.record_reconcile_duration(&node, Duration::from_millis(30));
+        let text = metrics.render();
+        for line in [
+            "# TYPE label_preserver_restored_labels histogram\n",
+            "label_preserver_restored_labels_bucket{pool=\"gpu\",le=\"0\"} 1\n",
+            "label_preserver_restored_labels_bucket{pool=\"gpu\",le=\"2\"} 1\n",
+            "label_preserver_restored_labels_bucket{pool=\"gpu\",le=\"5\"} 3\n",
+            "label_preserver_restored_labels_bucket{pool=\"gpu\",le=\"100\"} 3\n",
+            "label_preserver_restored_labels_bucket{pool=\"gpu\",le=\"+Inf\"} 4\n",
+            "label_preserver_restored_labels_sum{pool=\"gpu\"} 506\n",
+            "label_preserver_restored_labels_count{pool=\"gpu\"} 4\n",
+            "label_preserver_reconcile_duration_seconds_bucket{pool=\"gpu\",le=\"0.025\"} 0\n",
+            "label_preserver_reconcile_duration_seconds_bucket{pool=\"gpu\",le=\"0.05\"} 1\n",
+            "label_preserver_reconcile_duration_seconds_count{pool=\"gpu\"} 1\n",
+        ] {
+            assert!(text.contains(line), "{} missing from:\n{}", line, text);
+        }
+    }
+
+    /// Reconciles and errors are counted under the pool of the node being reconciled
+    #[tokio::test]
+    async fn test_reconcile_counts_by_pool() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                status_json(500, "etcdserver: request timed out"),
+            )
+        })
+        .await;
+        let config = ControllerConfig {
+            pool_label: Some(POOL_LABEL.to_string()),
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let node = Arc::new(node_in_pool("node-a", Some("gpu")));
+        let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        error_policy(node, &error, ctx.clone());
+
+        let text = ctx.render_metrics();
+        assert!(text.contains("label_preserver_reconciles_total{pool=\"gpu\"} 1\n"));
+        assert!(text.contains("label_preserver_errors_total{pool=\"gpu\",kind=\"kube\"} 1\n"));
+        assert!(text.contains("label_preserver_reconcile_duration_seconds_count{pool=\"gpu\"} 1\n"));
+        assert!(
+            text.contains("label_preserver_restores_total{pool=\"gpu\",outcome=\"failed\"} 1\n")
+        );
+    }
+
+    /// A restore is observed in the restored labels histogram, and every metric is served on
+    /// the admin API's /metrics
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let backup = Backup {
+            labels: [("zone", "a"), ("team", "ml")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(node_in_pool("node-a", None)), ctx.clone())
+            .await
+            .unwrap();
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = admin::router(ctx).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for name in [
+            "label_preserver_reconciles_total",
+            "label_preserver_errors_total",
+            "label_preserver_restored_labels",
+            "label_preserver_backups_total",
+            "label_preserver_reconcile_duration_seconds",
+        ] {
+            assert!(
+                text.contains(&format!("# TYPE {} ", name)),
+                "{} missing",
+                name
+            );
+        }
+        assert!(text.contains("label_preserver_restored_labels_sum{pool=\"none\"} 2\n"));
+        assert!(
+            text.contains("label_preserver_reconcile_duration_seconds_count{pool=\"none\"} 1\n")
+        );
+    }
+}
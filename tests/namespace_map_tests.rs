@@ -0,0 +1,327 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context, labels, node, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    use kube::runtime::{reflector, watcher};
+    use label_preserver::transfer::export_all_backups_with;
+    use label_preserver::{
+        configmap_name, manual, reconcile, Backup, BackupDigests, ControllerConfig, Error,
+        NamespaceMapping,
+    };
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Backup ConfigMaps by namespace and name
+    type ConfigMaps = Arc<Mutex<BTreeMap<(String, String), Value>>>;
+
+    /// Backups of `gpu` nodes go to `team-ml` and of `general` nodes to `platform-infra`
+    fn config() -> ControllerConfig {
+        ControllerConfig {
+            namespace: "node-labels".to_string(),
+            namespace_label: Some("node-pool".to_string()),
+            namespace_map: BTreeMap::from([
+                ("gpu".to_string(), "team-ml".to_string()),
+                ("general".to_string(), "platform-infra".to_string()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_namespace_mapping() {
+        assert_eq!(
+            "gpu=team-ml".parse::<NamespaceMapping>().unwrap(),
+            NamespaceMapping {
+                value: "gpu".to_string(),
+                namespace: "team-ml".to_string(),
+            }
+        );
+        for invalid in ["gpu", "gpu="] {
+            assert!(matches!(
+                invalid.parse::<NamespaceMapping>(),
+                Err(Error::InvalidNamespaceMapping(_))
+            ));
+        }
+    }
+
+    /// Backups are written to the namespace the node's label maps to, and looked for there
+    /// first, then in the default namespace, then in every other mapped namespace
+    #[test]
+    fn test_backup_namespaces() {
+        let config = config();
+        let gpu = node("node-a", labels(&[("node-pool", "gpu")]), false);
+        assert_eq!(config.backup_namespace(&gpu), "team-ml");
+        assert_eq!(
+            config.backup_namespaces(&gpu),
+            vec!["team-ml", "node-labels", "platform-infra"]
+        );
+        for unmapped in [
+            node("node-a", BTreeMap::new(), false),
+            node("node-a", labels(&[("node-pool", "spot")]), false),
+        ] {
+            assert_eq!(config.backup_namespace(&unmapped), "node-labels");
+            assert_eq!(
+                config.backup_namespaces(&unmapped),
+                vec!["node-labels", "platform-infra", "team-ml"]
+            );
+        }
+        assert_eq!(
+            config.all_backup_namespaces(),
+            vec!["node-labels", "platform-infra", "team-ml"]
+        );
+
+        let unmapped = ControllerConfig::default();
+        assert_eq!(
+            unmapped.backup_namespaces(&gpu),
+            vec![unmapped.namespace.as_str()]
+        );
+    }
+
+    /// The namespace and name of the ConfigMap a request is for
+    fn configmap_key(path: &str) -> (String, String) {
+        let segments: Vec<&str> = path.split('/').collect();
+        let namespace = segments[segments.iter().position(|s| *s == "namespaces").unwrap() + 1];
+        (namespace.to_string(), segments.last().unwrap().to_string())
+    }
+
+    /// A cluster keeping ConfigMaps in `configmaps`, accepting node patches and Events
+    async fn cluster(configmaps: ConfigMaps) -> MockApiServer {
+        MockApiServer::start(move |req| {
+            let mut configmaps = configmaps.lock().unwrap();
+            let path = req.uri.path();
+            match req.method {
+                _ if req.is_event() => (StatusCode::CREATED, req.json()),
+                Method::GET if path.ends_with("/configmaps") => {
+                    let namespace = path.split('/').nth(4).unwrap();
+                    let items: Vec<Value> = configmaps
+                        .iter()
+                        .filter(|((ns, _), _)| ns == namespace)
+                        .map(|(_, cm)| cm.clone())
+                        .collect();
+                    let list = json!({
+                        "apiVersion": "v1",
+                        "kind": "ConfigMapList",
+                        "metadata": {},
+                        "items": items,
+                    });
+                    (StatusCode::OK, list)
+                }
+                Method::GET if path.contains("/configmaps/") => {
+                    match configmaps.get(&configmap_key(path)) {
+                        Some(cm) => (StatusCode::OK, cm.clone()),
+                        None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                    }
+                }
+                Method::PATCH if path.contains("/configmaps/") => {
+                    configmaps.insert(configmap_key(path), req.json());
+                    (StatusCode::OK, req.json())
+                }
+                Method::DELETE if path.contains("/configmaps/") => {
+                    match configmaps.remove(&configmap_key(path)) {
+                        Some(cm) => (StatusCode::OK, cm),
+                        None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                    }
+                }
+                Method::PATCH if req.is_restore() => (StatusCode::OK, req.json()),
+                Method::PATCH => (StatusCode::OK, node_json("node-a")),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+            }
+        })
+        .await
+    }
+
+    /// The namespaces holding a backup of node-a
+    fn backup_namespaces(configmaps: &ConfigMaps) -> Vec<String> {
+        configmaps
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(_, name)| *name == configmap_name("node-a"))
+            .map(|(namespace, _)| namespace.clone())
+            .collect()
+    }
+
+    /// The labels the last restore put on the node
+    fn restored_labels(server: &MockApiServer) -> Value {
+        let restore = server.requests().into_iter().rev().find(|r| r.is_restore());
+        restore.expect("the node is restored").json()["metadata"]["labels"].clone()
+    }
+
+    /// A node moved to another pool is restored from its backup in the old pool's namespace,
+    /// and its next backup moves to the new pool's namespace
+    #[tokio::test]
+    async fn test_pool_label_changes() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        let ctx = context(&server, config());
+
+        let gpu_labels = labels(&[("node-pool", "gpu"), ("tenant", "ml-1")]);
+        reconcile(Arc::new(node("node-a", gpu_labels, true)), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backup_namespaces(&configmaps), vec!["team-ml"]);
+        let key = ("team-ml".to_string(), configmap_name("node-a"));
+        let backup = configmaps.lock().unwrap()[&key].clone();
+        assert_eq!(backup["metadata"]["namespace"], "team-ml");
+
+        let general = node("node-a", labels(&[("node-pool", "general")]), false);
+        reconcile(Arc::new(general), ctx.clone()).await.unwrap();
+        assert_eq!(restored_labels(&server)["tenant"], "ml-1");
+
+        let general_labels = labels(&[("node-pool", "general"), ("tenant", "infra-1")]);
+        reconcile(Arc::new(node("node-a", general_labels, true)), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backup_namespaces(&configmaps), vec!["platform-infra"]);
+
+        let unlabeled = node("node-a", BTreeMap::new(), false);
+        reconcile(Arc::new(unlabeled), ctx).await.unwrap();
+        assert_eq!(restored_labels(&server)["tenant"], "infra-1");
+    }
+
+    /// A node recreated before its pool label is set finds its backup in its pool's namespace
+    /// after missing it in the default namespace
+    #[tokio::test]
+    async fn test_fresh_node_without_pool_label() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        let ctx = context(&server, config());
+        let gpu_labels = labels(&[("node-pool", "gpu"), ("tenant", "ml-1")]);
+        reconcile(Arc::new(node("node-a", gpu_labels, true)), ctx.clone())
+            .await
+            .unwrap();
+
+        reconcile(Arc::new(node("node-a", BTreeMap::new(), false)), ctx)
+            .await
+            .unwrap();
+        let restored = restored_labels(&server);
+        assert_eq!(restored["tenant"], "ml-1");
+        assert_eq!(restored["node-pool"], "gpu");
+        let searched: Vec<(String, String)> = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::GET && r.uri.path().contains("/configmaps/"))
+            .map(|r| configmap_key(r.uri.path()))
+            .collect();
+        let name = configmap_name("node-a");
+        assert_eq!(
+            searched,
+            vec![
+                ("node-labels".to_string(), name.clone()),
+                ("platform-infra".to_string(), name.clone()),
+                ("team-ml".to_string(), name),
+            ]
+        );
+        assert_eq!(backup_namespaces(&configmaps), vec!["team-ml"]);
+    }
+
+    /// show, purge, and export find a backup in the namespace its node's pool maps to, even
+    /// once the node is gone
+    #[tokio::test]
+    async fn test_manual_commands_see_mapped_namespaces() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        let config = config();
+        let ctx = context(&server, config.clone());
+        let gpu_labels = labels(&[("node-pool", "gpu"), ("tenant", "ml-1")]);
+        reconcile(Arc::new(node("node-a", gpu_labels, true)), ctx)
+            .await
+            .unwrap();
+        assert_eq!(backup_namespaces(&configmaps), vec!["team-ml"]);
+
+        let state = manual::show(server.client(), &config, "node-a")
+            .await
+            .unwrap();
+        assert!(state.labels.contains_key("tenant"));
+        let snapshot = export_all_backups_with(server.client(), &config)
+            .await
+            .unwrap();
+        assert_eq!(snapshot["node-a"]["tenant"], "ml-1");
+
+        manual::purge(server.client(), &config, "node-a")
+            .await
+            .unwrap();
+        assert!(backup_namespaces(&configmaps).is_empty());
+        let result = manual::show(server.client(), &config, "node-a").await;
+        assert!(matches!(result, Err(Error::BackupNotFound(_))));
+    }
+
+    /// node-a's backup ConfigMap in `namespace` at `resource_version`, holding `tenant`
+    fn backup_configmap(namespace: &str, resource_version: &str, tenant: &str) -> ConfigMap {
+        let backup = Backup {
+            labels: labels(&[("tenant", tenant)]),
+            ..Default::default()
+        };
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name("node-a")),
+                namespace: Some(namespace.to_string()),
+                resource_version: Some(resource_version.to_string()),
+                ..Default::default()
+            },
+            data: Some(backup.to_configmap_data().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Deleting a backup moved to another namespace leaves the digests of the moved one alone
+    #[test]
+    fn test_moved_backup_digests() {
+        let digests = BackupDigests::default();
+        let node = node("node-a", labels(&[("node-pool", "general")]), false);
+        digests.record("platform-infra", &node, Some("5".to_string()));
+        let moved = backup_configmap("platform-infra", "5", "infra-1");
+        digests.observe(&watcher::Event::Apply(moved));
+        let expected = Backup {
+            labels: labels(&[("tenant", "infra-1")]),
+            ..Default::default()
+        }
+        .labels_digest();
+
+        let old = backup_configmap("team-ml", "3", "ml-1");
+        digests.observe(&watcher::Event::Apply(old.clone()));
+        digests.observe(&watcher::Event::Delete(old));
+        assert!(digests.is_current("platform-infra", &node));
+        assert_eq!(
+            digests.labels_digest("platform-infra", "node-a"),
+            Some(expected)
+        );
+        assert_eq!(digests.labels_digest("team-ml", "node-a"), None);
+    }
+
+    /// Each mapped namespace is read from its own cache
+    #[tokio::test]
+    async fn test_mapped_namespace_cached() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps).await;
+        let ctx = context(&server, config());
+        let mut caches = Vec::new();
+        for namespace in ["node-labels", "platform-infra", "team-ml"] {
+            let (cache, mut writer) = reflector::store();
+            writer.apply_watcher_event(&watcher::Event::Init);
+            if namespace == "team-ml" {
+                let cached = backup_configmap(namespace, "3", "ml-1");
+                writer.apply_watcher_event(&watcher::Event::InitApply(cached));
+            }
+            writer.apply_watcher_event(&watcher::Event::InitDone);
+            ctx.set_backup_cache(namespace, cache);
+            caches.push(writer);
+        }
+
+        let gpu = node("node-a", labels(&[("node-pool", "gpu")]), false);
+        reconcile(Arc::new(gpu), ctx).await.unwrap();
+        assert_eq!(restored_labels(&server)["tenant"], "ml-1");
+        let reads = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::GET && r.uri.path().contains("/configmaps/"))
+            .count();
+        assert_eq!(reads, 0);
+    }
+}
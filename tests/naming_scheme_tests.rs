@@ -0,0 +1,200 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{cluster, configmaps, MockApiServer};
+    use http::Method;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, ControllerConfig, NamingScheme, FINALIZER_NAME,
+        NODE_NAME_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// The longest legal node name: four dot-separated DNS labels, 253 characters in all
+    fn longest_name() -> String {
+        format!(
+            "{}.{}.{}.{}",
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(61)
+        )
+    }
+
+    #[test]
+    fn test_hashed_names() {
+        for name in ["node-a", "ip-10-0-0-1.ec2.internal", &longest_name()] {
+            let hashed = NamingScheme::Hashed.configmap_name(name).unwrap();
+            assert_eq!(hashed.len(), 76, "{name}");
+            assert_eq!(hashed, configmap_name(name));
+        }
+        assert_ne!(configmap_name("node-a"), configmap_name("node-b"));
+    }
+
+    #[test]
+    fn test_plain_names() {
+        let plain = NamingScheme::plain();
+        assert_eq!(
+            plain.configmap_name("ip-10-0-0-1.ec2.internal").as_deref(),
+            Some("node-labels-ip-10-0-0-1.ec2.internal")
+        );
+        // "node-labels-" leaves 241 characters for the node name
+        let fits = "a".repeat(241);
+        assert_eq!(plain.configmap_name(&fits).unwrap().len(), 253);
+        assert_eq!(plain.configmap_name(&"a".repeat(242)), None);
+        assert_eq!(plain.configmap_name(&longest_name()), None);
+        let custom = NamingScheme::Plain {
+            prefix: "labels-".to_string(),
+        };
+        assert_eq!(
+            custom.configmap_name("node-a").as_deref(),
+            Some("labels-node-a")
+        );
+        assert_eq!(custom.to_string(), "labels-<node name>");
+        assert_eq!(NamingScheme::Hashed.to_string(), "node-labels-<sha256>");
+    }
+
+    fn created_node(name: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn legacy_configmap(name: &str) -> serde_json::Value {
+        let backup = Backup {
+            labels: BTreeMap::from([("zone".to_string(), "a".to_string())]),
+            correlation_id: Some("corr-legacy".to_string()),
+            ..Default::default()
+        };
+        serde_json::json!({
+            "metadata": { "name": name, "namespace": "default", "resourceVersion": "3" },
+            "data": backup.to_configmap_data().unwrap(),
+        })
+    }
+
+    fn restored_labels(server: &MockApiServer) -> serde_json::Value {
+        server
+            .requests()
+            .into_iter()
+            .find(|r| r.is_restore())
+            .expect("the node is restored")
+            .json()["metadata"]["labels"]
+            .clone()
+    }
+
+    /// A backup under the plain legacy name is moved to the current name, then restored
+    #[tokio::test]
+    async fn test_migrates_plain_backup() {
+        let stored = configmaps(&[legacy_configmap("node-labels-node-a")]);
+        let server = cluster(stored.clone()).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(created_node("node-a")), ctx)
+            .await
+            .unwrap();
+
+        assert_eq!(restored_labels(&server)["zone"], "a");
+        let stored = stored.lock().unwrap();
+        assert!(!stored.contains_key("node-labels-node-a"));
+        let migrated = &stored[&configmap_name("node-a")];
+        assert_eq!(migrated["metadata"]["labels"][NODE_NAME_KEY], "node-a");
+        let data: BTreeMap<String, String> =
+            serde_json::from_value(migrated["data"].clone()).unwrap();
+        let backup = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(backup.correlation_id.as_deref(), Some("corr-legacy"));
+
+        // The copy is written before the legacy ConfigMap is deleted, and only if it is unchanged
+        let requests = server.requests();
+        let write = requests
+            .iter()
+            .position(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .unwrap();
+        let delete = requests
+            .iter()
+            .position(|r| r.method == Method::DELETE)
+            .unwrap();
+        assert!(write < delete);
+        assert!(requests[delete].uri.path().ends_with("/node-labels-node-a"));
+        assert_eq!(
+            requests[delete].json()["preconditions"]["resourceVersion"],
+            "3"
+        );
+    }
+
+    /// Configured legacy prefixes are tried after the built-in one, and a backup under the
+    /// current name wins over legacy ones
+    #[tokio::test]
+    async fn test_lookup_order() {
+        let stored = configmaps(&[legacy_configmap("labels-node-a")]);
+        let server = cluster(stored.clone()).await;
+        let config = ControllerConfig {
+            legacy_naming_schemes: vec![
+                NamingScheme::plain(),
+                NamingScheme::Plain {
+                    prefix: "labels-".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        reconcile(Arc::new(created_node("node-a")), ctx)
+            .await
+            .unwrap();
+        assert_eq!(restored_labels(&server)["zone"], "a");
+        let looked_up: Vec<String> = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::GET && r.uri.path().contains("/configmaps/"))
+            .map(|r| r.uri.path().rsplit('/').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            looked_up,
+            vec![
+                configmap_name("node-a"),
+                "node-labels-node-a".to_string(),
+                "labels-node-a".to_string()
+            ]
+        );
+        assert!(stored
+            .lock()
+            .unwrap()
+            .contains_key(&configmap_name("node-a")));
+
+        let current = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": Backup::default().to_configmap_data().unwrap(),
+        });
+        let stored = configmaps(&[current, legacy_configmap("node-labels-node-a")]);
+        let server = cluster(stored.clone()).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(created_node("node-a")), ctx)
+            .await
+            .unwrap();
+        assert!(stored.lock().unwrap().contains_key("node-labels-node-a"));
+        assert!(server.requests().iter().all(|r| r.method != Method::DELETE));
+    }
+
+    /// A node name too long for the legacy scheme can't have a legacy backup, so it isn't
+    /// looked up
+    #[tokio::test]
+    async fn test_name_too_long_for_legacy_scheme() {
+        let server = cluster(configmaps(&[])).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let name = longest_name();
+        reconcile(Arc::new(created_node(&name)), ctx).await.unwrap();
+        let lookups = server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::GET && r.uri.path().contains("/configmaps/"))
+            .count();
+        assert_eq!(lookups, 1);
+        assert!(!server.requests().iter().any(|r| r.is_restore()));
+    }
+}
@@ -0,0 +1,152 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        configmap_name, node_name_error, reconcile, Context, ControllerConfig, Error,
+        FINALIZER_NAME,
+    };
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::sync::{Arc, Mutex};
+
+    /// Legal node names at the edges of what Kubernetes accepts
+    fn edge_case_names() -> Vec<String> {
+        let longest = format!(
+            "{}.{}.{}.{}",
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(61)
+        );
+        assert_eq!(longest.len(), 253);
+        vec![
+            "a".to_string(),
+            "0".to_string(),
+            longest,
+            "a.b.c.d.e.f.g.h.i.j".to_string(),
+            "ip-10-0-0-1.ec2.internal".to_string(),
+            "node--1".to_string(),
+            "1-2".to_string(),
+        ]
+    }
+
+    fn labels() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("zone".to_string(), "a".to_string()),
+            ("team.example.com/name".to_string(), "ml".to_string()),
+        ])
+    }
+
+    fn node(name: &str, deleted: bool) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: deleted.then(|| Time(Utc::now())),
+                labels: deleted.then(labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A server that keeps applied ConfigMaps and serves them back, and echoes node applies
+    async fn server() -> MockApiServer {
+        let configmaps = Arc::new(Mutex::new(HashMap::new()));
+        MockApiServer::start(move |req| {
+            let path = req.uri.path().to_string();
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            match (&req.method, path.contains("/configmaps/")) {
+                (&Method::PATCH, true) => {
+                    configmaps.lock().unwrap().insert(path, req.json());
+                    (StatusCode::OK, req.json())
+                }
+                (&Method::GET, true) => match configmaps.lock().unwrap().get(&path) {
+                    Some(configmap) => (StatusCode::OK, configmap.clone()),
+                    None => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+                },
+                (&Method::PATCH, false) if req.json().is_object() => (StatusCode::OK, req.json()),
+                (&Method::PATCH, false) => (
+                    StatusCode::OK,
+                    serde_json::json!({ "metadata": { "name": name } }),
+                ),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            }
+        })
+        .await
+    }
+
+    /// Every edge-case name gets a fixed-length backup ConfigMap name, and its labels survive a
+    /// deletion and recreation
+    #[tokio::test]
+    async fn test_preserve_restore_cycle() {
+        for name in edge_case_names() {
+            assert_eq!(node_name_error(&name), None, "{name}");
+            assert_eq!(configmap_name(&name).len(), 76, "{name}");
+
+            let server = server().await;
+            let config = ControllerConfig {
+                node_names: Some(BTreeSet::from([name.clone()])),
+                startup_rate: 0.0,
+                ..Default::default()
+            };
+            config.validate_node_names().unwrap();
+            let ctx = Arc::new(Context::with_config(server.client(), config));
+            reconcile(Arc::new(node(&name, true)), ctx.clone())
+                .await
+                .unwrap();
+            reconcile(Arc::new(node(&name, false)), ctx).await.unwrap();
+
+            let requests = server.requests();
+            let written = requests
+                .iter()
+                .find(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+                .expect("the backup is written");
+            assert!(written.uri.path().ends_with(&configmap_name(&name)));
+            let restore = requests
+                .iter()
+                .find(|r| r.is_restore() && r.json()["metadata"]["labels"].is_object())
+                .expect("the labels are restored");
+            assert!(restore
+                .uri
+                .path()
+                .ends_with(&format!("/api/v1/nodes/{name}")));
+            let restored = &restore.json()["metadata"]["labels"];
+            assert_eq!(restored["zone"], "a", "{name}");
+            assert_eq!(restored["team.example.com/name"], "ml", "{name}");
+        }
+    }
+
+    /// Names no node can have are rejected with the reason, rather than never matching
+    #[test]
+    fn test_invalid_names() {
+        let invalid = [
+            String::new(),
+            "Node-A".to_string(),
+            "node-".to_string(),
+            "-node".to_string(),
+            "node..a".to_string(),
+            "node_a".to_string(),
+            "node-a,node-b".to_string(),
+            "a".repeat(64),
+            format!("{}.{}", "a".repeat(63), "b".repeat(190)),
+        ];
+        for name in invalid {
+            assert!(node_name_error(&name).is_some(), "{name}");
+            let config = ControllerConfig {
+                node_names: Some(BTreeSet::from(["node-a".to_string(), name.clone()])),
+                ..Default::default()
+            };
+            assert!(
+                matches!(config.validate_node_names(), Err(Error::InvalidNodeName(_))),
+                "{name}"
+            );
+        }
+        assert!(ControllerConfig::default().validate_node_names().is_ok());
+    }
+}
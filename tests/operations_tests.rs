@@ -0,0 +1,256 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::{TimeZone, Utc};
+    use label_preserver::{
+        apply_params, configmap_name, preserve_on_cleanup, restore_on_apply, Backup,
+        ControllerConfig, Degradation, RestoredAnnotation, SkipReason, CONFIGMAP_NAMESPACE,
+        MANAGED_LABEL_KEY, RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+
+    fn node(node_labels: BTreeMap<String, String>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                labels: Some(node_labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_params() {
+        let params = apply_params();
+        assert_eq!(
+            params.field_manager.as_deref(),
+            Some("node-label-preserver")
+        );
+        assert!(params.force);
+    }
+
+    /// A deleted node's labels, without our managed label, go into its backup ConfigMap
+    #[test]
+    fn test_preserve_on_cleanup() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let node = node(labels(&[
+            ("zone", "a"),
+            ("team", "ml"),
+            (MANAGED_LABEL_KEY, "true"),
+        ]));
+        let preservation =
+            preserve_on_cleanup(&node, &ControllerConfig::default(), "corr-1", now).unwrap();
+
+        assert!(preservation.degradations.is_empty());
+        let backup = &preservation.backup;
+        assert_eq!(backup.labels, labels(&[("zone", "a"), ("team", "ml")]));
+        assert_eq!(backup.correlation_id.as_deref(), Some("corr-1"));
+        assert!(backup.preserved_at.values().all(|at| *at == now));
+
+        let configmap = &preservation.configmap;
+        assert_eq!(configmap.metadata.name, Some(configmap_name("node-a")));
+        assert_eq!(
+            configmap.metadata.namespace.as_deref(),
+            Some(CONFIGMAP_NAMESPACE)
+        );
+        let stored = Backup::from_configmap_data(configmap.data.as_ref().unwrap()).unwrap();
+        assert_eq!(&stored, backup);
+
+        // A node without labels still replaces its old backup
+        let empty = preserve_on_cleanup(
+            &self::node(BTreeMap::new()),
+            &ControllerConfig::default(),
+            "corr-2",
+            now,
+        )
+        .unwrap();
+        assert!(empty.backup.labels.is_empty());
+        assert!(empty.configmap.data.is_some());
+
+        // The backup goes to the configured namespace
+        let config = ControllerConfig {
+            namespace: "node-labels".to_string(),
+            ..Default::default()
+        };
+        let elsewhere = preserve_on_cleanup(&node, &config, "corr-3", now).unwrap();
+        assert_eq!(
+            elsewhere.configmap.metadata.namespace.as_deref(),
+            Some("node-labels")
+        );
+    }
+
+    /// A backup over the size limit is degraded, and reports the labels actually stored
+    #[test]
+    fn test_preserve_degraded() {
+        let long = "v".repeat(60);
+        let pairs: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("label-{i:02}"), long.clone()))
+            .collect();
+        let node = node(pairs.into_iter().collect());
+        let config = ControllerConfig {
+            max_backup_bytes: 1000,
+            max_labels: 3,
+            ..Default::default()
+        };
+        let preservation = preserve_on_cleanup(&node, &config, "corr-1", Utc::now()).unwrap();
+        assert_eq!(
+            preservation.degradations,
+            vec![Degradation::CappedLabels { dropped: 17 }]
+        );
+        assert_eq!(preservation.backup.labels.len(), 3);
+    }
+
+    /// Backed up labels are merged into the node's without overwriting any, and skipped ones
+    /// say why
+    #[test]
+    fn test_restore_on_apply() {
+        let backup = Backup {
+            labels: labels(&[
+                ("zone", "a"),
+                ("team", "ml"),
+                ("tier", "gold"),
+                ("bad key!", "x"),
+            ]),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let node = node(labels(&[
+            ("zone", "a"),
+            ("tier", "silver"),
+            ("os", "linux"),
+        ]));
+        let restoration =
+            restore_on_apply(&node, &backup, &ControllerConfig::default(), Utc::now());
+
+        assert_eq!(restoration.added, labels(&[("team", "ml")]));
+        let counts = &restoration.counts;
+        assert_eq!(
+            (counts.restored, counts.unchanged, counts.conflicts),
+            (1, 1, 1)
+        );
+        assert_eq!(counts.skipped["zone"], SkipReason::ExistingValueKept);
+        assert_eq!(counts.skipped["tier"], SkipReason::DeferToLive);
+        assert_eq!(counts.skipped["bad key!"], SkipReason::InvalidSyntax);
+        assert!(counts.rewritten.is_empty());
+
+        let applied = &restoration.node.metadata;
+        assert_eq!(applied.name.as_deref(), Some("node-a"));
+        assert_eq!(
+            applied.labels,
+            Some(labels(&[
+                ("zone", "a"),
+                ("team", "ml"),
+                ("tier", "silver"),
+                ("os", "linux"),
+                (MANAGED_LABEL_KEY, "true"),
+            ]))
+        );
+        let restored = RestoredAnnotation::of_backup(&backup).to_value();
+        assert_eq!(
+            applied.annotations,
+            Some(labels(&[(RESTORED_ANNOTATION_KEY, restored.as_str())]))
+        );
+    }
+
+    /// Annotations are only backed up when enabled, leaving out our own, kubectl's, and deletion
+    /// markers
+    #[test]
+    fn test_preserve_annotations() {
+        let mut node = node(labels(&[("zone", "a")]));
+        node.metadata.annotations = Some(labels(&[
+            ("example.com/owner", "ml-infra"),
+            (RESTORED_ANNOTATION_KEY, r#"{"v":2}"#),
+            ("kubectl.kubernetes.io/last-applied-configuration", "{}"),
+            ("example.com/draining", "true"),
+        ]));
+        let now = Utc::now();
+        let disabled =
+            preserve_on_cleanup(&node, &ControllerConfig::default(), "corr-1", now).unwrap();
+        assert!(disabled.backup.annotations.is_empty());
+
+        let mut config = ControllerConfig {
+            preserve_annotations: true,
+            ..Default::default()
+        };
+        config.deletion_markers.annotations = vec!["example.com/draining".to_string()];
+        let preservation = preserve_on_cleanup(&node, &config, "corr-1", now).unwrap();
+        assert_eq!(
+            preservation.backup.annotations,
+            labels(&[("example.com/owner", "ml-infra")])
+        );
+    }
+
+    /// Backed up annotations are merged like labels, and the applied node leaves out annotations
+    /// that didn't come from the backup so their owners keep them
+    #[test]
+    fn test_restore_annotations() {
+        let backup = Backup {
+            labels: labels(&[("zone", "a")]),
+            annotations: labels(&[
+                ("example.com/owner", "ml-infra"),
+                ("example.com/hint", "gpu"),
+                ("example.com/rack", "r1"),
+            ]),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let mut node = node(BTreeMap::new());
+        node.metadata.annotations = Some(labels(&[
+            ("example.com/hint", "cpu"),
+            ("example.com/rack", "r1"),
+            ("node.alpha.kubernetes.io/ttl", "0"),
+        ]));
+
+        let disabled = restore_on_apply(&node, &backup, &ControllerConfig::default(), Utc::now());
+        assert!(disabled.added_annotations.is_empty());
+
+        let config = ControllerConfig {
+            preserve_annotations: true,
+            ..Default::default()
+        };
+        let restoration = restore_on_apply(&node, &backup, &config, Utc::now());
+        let restored = RestoredAnnotation::of_backup(&backup).to_value();
+        assert_eq!(
+            restoration.added_annotations,
+            labels(&[("example.com/owner", "ml-infra")])
+        );
+        assert_eq!(
+            restoration.node.metadata.annotations,
+            Some(labels(&[
+                ("example.com/owner", "ml-infra"),
+                ("example.com/rack", "r1"),
+                (RESTORED_ANNOTATION_KEY, restored.as_str()),
+            ]))
+        );
+        assert_eq!(restoration.counts.restored, 1);
+    }
+
+    /// A backup without a correlation ID still marks the node restored, and the managed label
+    /// can be left off
+    #[test]
+    fn test_restore_without_correlation_id() {
+        let backup = Backup {
+            labels: labels(&[("zone", "a")]),
+            ..Default::default()
+        };
+        let config = ControllerConfig {
+            managed_label: false,
+            ..Default::default()
+        };
+        let restoration = restore_on_apply(&node(BTreeMap::new()), &backup, &config, Utc::now());
+        let applied = &restoration.node.metadata;
+        assert_eq!(applied.labels, Some(labels(&[("zone", "a")])));
+        assert_eq!(
+            applied.annotations,
+            Some(labels(&[(
+                RESTORED_ANNOTATION_KEY,
+                &RestoredAnnotation::of_backup(&backup).to_value()
+            )]))
+        );
+    }
+}
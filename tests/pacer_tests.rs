@@ -0,0 +1,130 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{finalized_node, node_json, MockApiServer};
+    use http::{Method, StatusCode};
+
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{reconcile, Backup, Context, ControllerConfig, StartupPacer};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    /// Turns are handed out at the configured rate
+    #[test]
+    fn test_reserve_paces_turns() {
+        let start = Instant::now();
+        let pacer = StartupPacer::new(10.0, WINDOW, start);
+        let waits: Vec<Duration> = (0..4).map(|_| pacer.reserve(start)).collect();
+        assert_eq!(waits[0], Duration::ZERO);
+        for (i, wait) in waits.iter().enumerate().skip(1) {
+            let expected = Duration::from_millis(100 * i as u64);
+            assert!(
+                wait.abs_diff(expected) < Duration::from_millis(1),
+                "{wait:?}"
+            );
+        }
+        let progress = pacer.progress(start);
+        assert!(progress.in_warmup);
+        assert_eq!(progress.paced, 3);
+
+        // Idle time refills at most one turn
+        let later = start + Duration::from_secs(10);
+        assert_eq!(pacer.reserve(later), Duration::ZERO);
+        assert!(pacer.reserve(later) > Duration::ZERO);
+    }
+
+    /// Pacing stops once the warmup window is over, and never waits past it
+    #[test]
+    fn test_warmup_ends() {
+        let start = Instant::now();
+        let pacer = StartupPacer::new(1.0, Duration::from_secs(2), start);
+        pacer.reserve(start);
+        pacer.reserve(start);
+        assert_eq!(pacer.reserve(start), Duration::from_secs(2));
+
+        let after = start + Duration::from_secs(2);
+        assert_eq!(pacer.reserve(after), Duration::ZERO);
+        assert!(!pacer.progress(after).in_warmup);
+
+        let disabled = StartupPacer::new(0.0, WINDOW, start);
+        assert_eq!(disabled.reserve(start), Duration::ZERO);
+        assert!(!disabled.progress(start).in_warmup);
+    }
+
+    /// A flood of restores is paced at the startup rate while a terminating node is cleaned up
+    /// right away
+    #[tokio::test]
+    async fn test_startup_flood_is_paced_and_cleanups_go_first() {
+        // Restores and cleanups, in the order they reached the API server
+        let patches: Arc<Mutex<Vec<(String, Instant)>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorded = patches.clone();
+        let backup = Backup {
+            labels: [("zone".to_string(), "a".to_string())].into(),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| {
+            let path = req.uri.path().to_string();
+            if req.is_restore() || (req.method == Method::PATCH && path.contains("/configmaps/")) {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((path.clone(), Instant::now()));
+            }
+            match (&req.method, path.contains("/configmaps/")) {
+                (&Method::GET, true) => (StatusCode::OK, serde_json::json!({ "data": data })),
+                (_, true) => (StatusCode::OK, req.json()),
+                _ => (StatusCode::OK, node_json(path.rsplit('/').next().unwrap())),
+            }
+        })
+        .await;
+        let config = ControllerConfig {
+            startup_rate: 10.0,
+            startup_warmup: WINDOW,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+
+        let restores: Vec<_> = (0..5)
+            .map(|i| {
+                let node = Arc::new(finalized_node(&format!("node-{i}")));
+                tokio::spawn(reconcile(node, ctx.clone()))
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(ctx.warmup_progress().waiting, 4);
+        let mut terminating = finalized_node("terminating");
+        terminating.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(terminating), ctx.clone()).await.unwrap();
+        for restore in restores {
+            restore.await.unwrap().unwrap();
+        }
+
+        let patches = patches.lock().unwrap().clone();
+        let node_patches: Vec<_> = patches
+            .iter()
+            .filter(|(path, _)| path.starts_with("/api/v1/nodes/node-"))
+            .collect();
+        assert_eq!(node_patches.len(), 5);
+        let spread = node_patches[4].1 - node_patches[0].1;
+        assert!(spread >= Duration::from_millis(350), "{spread:?}");
+
+        let cleanup = patches
+            .iter()
+            .position(|(path, _)| path.contains("/configmaps/"))
+            .unwrap();
+        let last_restore = patches
+            .iter()
+            .rposition(|(path, _)| path.starts_with("/api/v1/nodes/node-"))
+            .unwrap();
+        assert!(cleanup < last_restore);
+        assert_eq!(ctx.warmup_progress().paced, 4);
+        assert_eq!(ctx.warmup_progress().waiting, 0);
+    }
+}
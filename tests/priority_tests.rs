@@ -0,0 +1,136 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeSpec, Taint};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::{controller::Action, reflector, watcher};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+    };
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// How soon a restored node's deferred background work is retried
+    const BACKGROUND_DEFERRAL_INTERVAL: Duration = Duration::from_secs(2);
+
+    const BACKGROUND_NODES: usize = 50;
+
+    /// A node, restored and marked for removal by the autoscaler when `restored` is set, which
+    /// gives it background work: an early backup. Otherwise it just registered.
+    fn node(name: &str, restored: bool) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                creation_timestamp: (!restored).then(|| Time(Utc::now())),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                annotations: restored
+                    .then(|| [(RESTORED_ANNOTATION_KEY.to_string(), "1".to_string())].into()),
+                ..Default::default()
+            },
+            spec: restored.then(|| NodeSpec {
+                taints: Some(vec![Taint {
+                    key: "ToBeDeletedByClusterAutoscaler".to_string(),
+                    effect: "NoSchedule".to_string(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Serves a backup for the node named `new` only
+    async fn server() -> MockApiServer {
+        let backup = Backup {
+            labels: [("zone".to_string(), "a".to_string())].into(),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data().unwrap();
+        MockApiServer::start(move |req| match req.method {
+            Method::PATCH => (StatusCode::OK, req.json()),
+            Method::GET if req.uri.path().ends_with(&configmap_name("new")) => {
+                (StatusCode::OK, serde_json::json!({ "data": data }))
+            }
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    fn backup_writes(server: &MockApiServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .count()
+    }
+
+    /// A flood of background work waits while a new node is pending, the new node restores
+    /// promptly, and the background work runs once it has
+    #[tokio::test]
+    async fn test_new_nodes_restore_first() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let (store, mut writer) = reflector::store();
+        ctx.set_node_store(store);
+        let background: Vec<Node> = (0..BACKGROUND_NODES)
+            .map(|i| node(&format!("old-{i}"), true))
+            .collect();
+        for node in background.iter().chain([&node("new", false)]) {
+            writer.apply_watcher_event(&watcher::Event::Apply(node.clone()));
+        }
+        assert_eq!(ctx.pending_restores(), 1);
+
+        for node in &background {
+            let action = reconcile(Arc::new(node.clone()), ctx.clone())
+                .await
+                .unwrap();
+            assert_eq!(action, Action::requeue(BACKGROUND_DEFERRAL_INTERVAL));
+        }
+        assert_eq!(backup_writes(&server), 0);
+        let metrics = ctx.render_metrics();
+        assert!(metrics.contains("label_preserver_queue_depth{tier=\"restore\"} 1\n"));
+        assert!(metrics.contains(&format!(
+            "label_preserver_queue_depth{{tier=\"background\"}} {}\n",
+            BACKGROUND_NODES
+        )));
+
+        let started = Instant::now();
+        reconcile(Arc::new(node("new", false)), ctx.clone())
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(server.requests().iter().any(|r| r.is_restore()));
+
+        // Once the new node shows up as restored, the background work goes ahead
+        writer.apply_watcher_event(&watcher::Event::Apply(node("new", true)));
+        assert_eq!(ctx.pending_restores(), 0);
+        for node in &background {
+            reconcile(Arc::new(node.clone()), ctx.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(backup_writes(&server), BACKGROUND_NODES);
+        let metrics = ctx.render_metrics();
+        assert!(metrics.contains("label_preserver_queue_depth{tier=\"restore\"} 0\n"));
+        assert!(metrics.contains("label_preserver_queue_depth{tier=\"background\"} 0\n"));
+    }
+
+    /// Restored nodes without background work are not requeued
+    #[tokio::test]
+    async fn test_idle_nodes_not_deferred() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let (store, mut writer) = reflector::store();
+        ctx.set_node_store(store);
+        writer.apply_watcher_event(&watcher::Event::Apply(node("new", false)));
+
+        let mut idle = node("idle", true);
+        idle.spec = None;
+        let action = reconcile(Arc::new(idle), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+    }
+}
@@ -0,0 +1,125 @@
+//! Fails to compile if a path library consumers rely on moves or changes signature
+
+#[cfg(test)]
+mod tests {
+    use kube::runtime::controller::Action;
+    use label_preserver::{
+        admin, bundle, manual, metrics, restore_all, transfer, BackoffState, Backup, Context,
+        ControllerConfig, Error, Operation, RestoreCounts, RestorePolicy, Result,
+        CONFIGMAP_NAMESPACE, CORRUPT_BACKUP_ANNOTATION_KEY, FINALIZER_NAME, MANAGED_BY_LABEL_KEY,
+        MANAGED_LABEL_KEY, NODE_NAME_KEY, PRESERVED_AT_ANNOTATION_KEY, RESTORED_ANNOTATION_KEY,
+        SCHEMA_VERSION, STATUS_CONFIGMAP_NAME,
+    };
+    use std::{future::Future, sync::Arc};
+
+    /// Type-check `f` as an async fn from `A` to `T`
+    fn is_async_fn<A, T, F: Future<Output = T>>(
+        _: fn(Arc<k8s_openapi::api::core::v1::Node>, A) -> F,
+    ) {
+    }
+
+    fn exists<T>() {}
+
+    /// The entry points main.rs wires into the Controller
+    #[test]
+    fn test_controller_entry_points() {
+        is_async_fn::<Arc<Context>, Result<Action>, _>(label_preserver::reconcile);
+        let _: fn(Arc<k8s_openapi::api::core::v1::Node>, &Error, Arc<Context>) -> Action =
+            label_preserver::error_policy;
+        let _: fn(kube::Client) -> Context = Context::new;
+        let _: fn(kube::Client, ControllerConfig) -> Context = Context::with_config;
+        let _: fn(&str) -> String = label_preserver::configmap_name;
+        let _ = admin::router;
+    }
+
+    /// Everything else main.rs and the integration tests import from the crate root
+    #[test]
+    fn test_root_exports() {
+        let _ = (
+            label_preserver::client_with_user_agent,
+            label_preserver::release_out_of_scope_finalizers,
+            label_preserver::sweep_finalizers,
+            label_preserver::conflicting_field_managers,
+            label_preserver::write_status,
+            label_preserver::status_configmap,
+            label_preserver::user_agent,
+            label_preserver::should_force_release,
+            label_preserver::is_briefly_not_ready,
+            label_preserver::is_permanent,
+            label_preserver::foreign_restore_claim,
+            label_preserver::payload_size,
+            label_preserver::label_error,
+            label_preserver::validate_label,
+            label_preserver::apply_params,
+            label_preserver::preserve_on_cleanup,
+            label_preserver::restore_on_apply,
+            label_preserver::missing_taints,
+            label_preserver::merge_labels,
+            label_preserver::filter_restorable_labels,
+            label_preserver::is_reserved_label,
+            label_preserver::node_name_label_value,
+            label_preserver::find_configmap_for_node,
+            label_preserver::shard_configmap_name,
+            label_preserver::shard_key,
+        );
+        exists::<Backup>();
+        exists::<BackoffState>();
+        exists::<Operation>();
+        exists::<RestoreCounts>();
+        exists::<RestorePolicy>();
+        exists::<label_preserver::MergeStrategy>();
+        exists::<label_preserver::LabelMerge>();
+        exists::<label_preserver::BurstTracker>();
+        exists::<label_preserver::StartupPacer>();
+        exists::<label_preserver::MachineIdentity>();
+        exists::<label_preserver::IdentityMismatchPolicy>();
+        exists::<label_preserver::UnknownBackupAgePolicy>();
+        exists::<label_preserver::LabelExpiry>();
+        exists::<label_preserver::LabelFilter>();
+        exists::<label_preserver::DeletionMarkers>();
+        exists::<label_preserver::SkipReason>();
+        exists::<label_preserver::types::PreservedState>();
+        exists::<label_preserver::types::PreservedTaint>();
+        exists::<label_preserver::Preservation>();
+        exists::<label_preserver::Restoration>();
+        exists::<label_preserver::RestoredAnnotation>();
+        exists::<label_preserver::AnnotationFormat>();
+        exists::<label_preserver::LeaderElection>();
+        exists::<label_preserver::LeaderElector>();
+        exists::<label_preserver::NamingScheme>();
+        exists::<label_preserver::BackupLayout>();
+        exists::<label_preserver::BackupStore>();
+        exists::<label_preserver::Deletion>();
+        exists::<label_preserver::LogFormat>();
+        exists::<label_preserver::LabelValidationError>();
+        exists::<label_preserver::EncryptionKey>();
+        exists::<label_preserver::EncryptionKeys>();
+        exists::<label_preserver::NamespaceMapping>();
+        let _ = (
+            CONFIGMAP_NAMESPACE,
+            CORRUPT_BACKUP_ANNOTATION_KEY,
+            FINALIZER_NAME,
+            MANAGED_BY_LABEL_KEY,
+            MANAGED_LABEL_KEY,
+            NODE_NAME_KEY,
+            PRESERVED_AT_ANNOTATION_KEY,
+            RESTORED_ANNOTATION_KEY,
+            SCHEMA_VERSION,
+            STATUS_CONFIGMAP_NAME,
+            label_preserver::CIPHER,
+        );
+        let _ = (
+            restore_all::restore_all,
+            bundle::verify_json,
+            manual::show,
+            manual::restore,
+            manual::purge,
+            manual::exit_code,
+            metrics::Metrics::new,
+            transfer::import_backups_with,
+            transfer::export_all_backups_with,
+            label_preserver::export_all_backups,
+            label_preserver::import_backups,
+        );
+    }
+}
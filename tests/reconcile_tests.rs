@@ -0,0 +1,608 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{finalized_node, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use kube::{
+        error::ErrorResponse,
+        runtime::{controller::Action, finalizer, reflector, watcher},
+    };
+    use label_preserver::{
+        error_policy, is_briefly_not_ready, is_permanent, reconcile, should_force_release, Backup,
+        Context, ControllerConfig, Error, FINALIZER_NAME,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    /// How soon a node briefly not accepting patches is reconciled again
+    const DEFERRAL_INTERVAL: Duration = Duration::from_secs(5);
+    /// Failed cleanups before the finalizer is released anyway
+    const MAX_CLEANUP_ATTEMPTS: u32 = 12;
+    /// How soon a throttled reconcile is retried
+    const THROTTLED_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// A node that has never been reconciled
+    fn new_node(name: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn scoped_config(names: &[&str]) -> ControllerConfig {
+        ControllerConfig {
+            node_names: Some(names.iter().map(|n| n.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn api_error(code: u16, message: &str) -> Error {
+        Error::Kube(kube::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: message.to_string(),
+            reason: String::new(),
+            code,
+        }))
+    }
+
+    /// Only errors that indicate a node briefly not accepting patches are deferred
+    #[test]
+    fn test_briefly_not_ready_classification() {
+        assert!(is_briefly_not_ready(&api_error(
+            404,
+            "nodes \"a\" not found"
+        )));
+        assert!(is_briefly_not_ready(&api_error(503, "unavailable")));
+        assert!(is_briefly_not_ready(&api_error(
+            500,
+            "Internal error occurred: failed calling webhook \"node.example.com\""
+        )));
+        assert!(!is_briefly_not_ready(&api_error(
+            500,
+            "etcdserver: timeout"
+        )));
+        assert!(!is_briefly_not_ready(&api_error(403, "forbidden")));
+        assert!(!is_briefly_not_ready(&api_error(409, "conflict")));
+        assert!(!is_briefly_not_ready(&Error::Serialization(
+            serde_json::from_str::<u32>("not json").unwrap_err()
+        )));
+    }
+
+    /// A node whose first patch 404s is retried on the fast schedule and converges on the next try
+    #[tokio::test]
+    async fn test_not_found_then_ok_converges_in_deferral_window() {
+        let node_patches = Arc::new(AtomicUsize::new(0));
+        let server_node_patches = node_patches.clone();
+        let data = Backup::default().to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| {
+            if req.uri.path().contains("/configmaps/") {
+                return (StatusCode::OK, serde_json::json!({ "data": data }));
+            }
+            if req.method == Method::PATCH && req.uri.path() == "/api/v1/nodes/node-a" {
+                if server_node_patches.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return (StatusCode::NOT_FOUND, status_json(404, "NotFound"));
+                }
+                return (StatusCode::OK, node_json("node-a"));
+            }
+            (StatusCode::NOT_FOUND, status_json(404, "NotFound"))
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Arc::new(finalized_node("node-a"));
+
+        let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        assert!(is_briefly_not_ready(&error));
+        assert_eq!(
+            error_policy(node.clone(), &error, ctx.clone()),
+            Action::requeue(DEFERRAL_INTERVAL)
+        );
+        assert!(ctx.is_deferred("node-a"));
+
+        // The failed claim, then the claim, restore, and release
+        reconcile(node.clone(), ctx.clone()).await.unwrap();
+        assert_eq!(node_patches.load(Ordering::SeqCst), 4);
+        assert!(!ctx.is_deferred("node-a"));
+    }
+
+    /// Errors that aren't deferrable go straight to exponential backoff
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_deferred() {
+        let server = MockApiServer::start(|_| (StatusCode::NOT_FOUND, status_json(404, ""))).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Arc::new(finalized_node("node-b"));
+
+        let action = error_policy(node, &api_error(403, "forbidden"), ctx.clone());
+        assert_ne!(action, Action::requeue(DEFERRAL_INTERVAL));
+        assert!(!ctx.is_deferred("node-b"));
+    }
+
+    fn serialization_error() -> Error {
+        Error::Serialization(serde_json::from_str::<u32>("not json").unwrap_err())
+    }
+
+    /// The first retry of each kind of error: permanent ones wait the longest, throttling waits
+    /// a fixed interval, and transient ones start the exponential schedule
+    #[tokio::test]
+    async fn test_retry_delay_by_error() {
+        let server = MockApiServer::start(|_| (StatusCode::NOT_FOUND, status_json(404, ""))).await;
+        let hour = Duration::from_secs(3600);
+        let first_backoff = Duration::from_secs(4);
+        let cases = [
+            (serialization_error(), hour),
+            (Error::MissingNodeName(Box::new(new_node(""))), hour),
+            (Error::InvalidSchemaVersion("v9".to_string()), hour),
+            (
+                Error::BackupTooLarge {
+                    size: 2_000_000,
+                    limit: 1_000_000,
+                },
+                hour,
+            ),
+            (api_error(422, "metadata.labels: Invalid value"), hour),
+            (
+                Error::Finalizer(Box::new(finalizer::Error::ApplyFailed(
+                    serialization_error(),
+                ))),
+                hour,
+            ),
+            (
+                api_error(429, "Too many requests"),
+                THROTTLED_RETRY_INTERVAL,
+            ),
+            (api_error(500, "etcdserver: timeout"), first_backoff),
+            (api_error(502, "bad gateway"), first_backoff),
+            (api_error(409, "conflict"), first_backoff),
+            (api_error(403, "forbidden"), first_backoff),
+            (
+                Error::Finalizer(Box::new(finalizer::Error::CleanupFailed(api_error(
+                    500,
+                    "etcdserver: timeout",
+                )))),
+                first_backoff,
+            ),
+        ];
+        for (error, expected) in cases {
+            let ctx = Arc::new(Context::new(server.client()));
+            let node = Arc::new(finalized_node("node-a"));
+            assert_eq!(
+                error_policy(node, &error, ctx.clone()),
+                Action::requeue(expected),
+                "{error}"
+            );
+            // Every failure counts towards releasing a failing cleanup
+            assert_eq!(ctx.backoff_states()["node-a"].attempt, 1, "{error}");
+        }
+        assert!(!is_permanent(&api_error(404, "not found")));
+        assert!(!is_permanent(&api_error(503, "unavailable")));
+    }
+
+    /// Throttled retries still count as attempts, so transient failures after them keep
+    /// backing off
+    #[tokio::test]
+    async fn test_throttled_then_transient() {
+        let server = MockApiServer::start(|_| (StatusCode::NOT_FOUND, status_json(404, ""))).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Arc::new(finalized_node("node-a"));
+        for _ in 0..2 {
+            assert_eq!(
+                error_policy(node.clone(), &api_error(429, ""), ctx.clone()),
+                Action::requeue(THROTTLED_RETRY_INTERVAL)
+            );
+        }
+        assert_eq!(
+            error_policy(node.clone(), &api_error(500, ""), ctx.clone()),
+            Action::requeue(Duration::from_secs(16))
+        );
+    }
+
+    /// Each node backs off on its own, and a successful reconcile starts its backoff over
+    #[tokio::test]
+    async fn test_backoff_is_per_node() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node_a = Arc::new(finalized_node("node-a"));
+        let node_b = Arc::new(finalized_node("node-b"));
+        let error = || api_error(403, "forbidden");
+
+        for expected_delay in [4, 8, 16] {
+            assert_eq!(
+                error_policy(node_a.clone(), &error(), ctx.clone()),
+                Action::requeue(Duration::from_secs(expected_delay))
+            );
+        }
+        assert_eq!(
+            error_policy(node_b.clone(), &error(), ctx.clone()),
+            Action::requeue(Duration::from_secs(4))
+        );
+
+        reconcile(node_a.clone(), ctx.clone()).await.unwrap();
+        assert!(!ctx.backoff_states().contains_key("node-a"));
+        assert_eq!(ctx.backoff_states()["node-b"].attempt, 1);
+        assert_eq!(
+            error_policy(node_a, &error(), ctx.clone()),
+            Action::requeue(Duration::from_secs(4))
+        );
+    }
+
+    /// The retry and deferral state of nodes that left the cache is dropped
+    #[tokio::test]
+    async fn test_backoff_forgets_deleted_nodes() {
+        let server = MockApiServer::start(|_| (StatusCode::NOT_FOUND, status_json(404, ""))).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let (store, mut writer) = reflector::store::<Node>();
+        writer.apply_watcher_event(&watcher::Event::Apply(finalized_node("node-b")));
+        ctx.set_node_store(store);
+
+        error_policy(
+            Arc::new(finalized_node("node-c")),
+            &api_error(404, "not found"),
+            ctx.clone(),
+        );
+        assert!(ctx.is_deferred("node-c"));
+        error_policy(
+            Arc::new(finalized_node("node-a")),
+            &api_error(403, "forbidden"),
+            ctx.clone(),
+        );
+        error_policy(
+            Arc::new(finalized_node("node-b")),
+            &api_error(403, "forbidden"),
+            ctx.clone(),
+        );
+        assert_eq!(
+            ctx.backoff_states().into_keys().collect::<Vec<_>>(),
+            vec!["node-b".to_string()]
+        );
+        assert!(!ctx.is_deferred("node-c"));
+    }
+
+    /// A single listed node is selected server-side, multiple listed nodes client-side
+    #[test]
+    fn test_node_names_watcher_config() {
+        assert_eq!(
+            ControllerConfig::default().watcher_config().field_selector,
+            None
+        );
+        assert_eq!(
+            scoped_config(&["worker-1"]).watcher_config().field_selector,
+            Some("metadata.name=worker-1".to_string())
+        );
+        assert_eq!(
+            scoped_config(&["worker-1", "worker-2"])
+                .watcher_config()
+                .field_selector,
+            None
+        );
+    }
+
+    /// Unlisted nodes never receive the finalizer, while listed nodes do
+    #[tokio::test]
+    async fn test_unlisted_nodes_are_not_finalized() {
+        let server = MockApiServer::start(|req| {
+            let name = req.uri.path().rsplit('/').next().unwrap().to_string();
+            (StatusCode::OK, node_json(&name))
+        })
+        .await;
+        let ctx = Arc::new(Context::with_config(
+            server.client(),
+            scoped_config(&["worker-1", "worker-2"]),
+        ));
+
+        reconcile(Arc::new(new_node("worker-3")), ctx.clone())
+            .await
+            .unwrap();
+        assert!(server.requests().is_empty());
+
+        reconcile(Arc::new(new_node("worker-1")), ctx.clone())
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::PATCH);
+        assert_eq!(requests[0].uri.path(), "/api/v1/nodes/worker-1");
+        assert!(requests[0].json().to_string().contains(FINALIZER_NAME));
+    }
+
+    /// A node removed from the list has our finalizer released and keeps any others
+    #[tokio::test]
+    async fn test_node_leaving_scope_releases_finalizer() {
+        let server = MockApiServer::start(|_| (StatusCode::OK, node_json("worker-3"))).await;
+        let ctx = Arc::new(Context::with_config(
+            server.client(),
+            scoped_config(&["worker-1"]),
+        ));
+        let mut node = finalized_node("worker-3");
+        node.metadata.finalizers = Some(vec![
+            "other.example.com/finalizer".to_string(),
+            FINALIZER_NAME.to_string(),
+        ]);
+
+        reconcile(Arc::new(node), ctx).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::PATCH);
+        assert_eq!(
+            requests[0].json()["metadata"]["finalizers"],
+            serde_json::json!(["other.example.com/finalizer"])
+        );
+    }
+
+    /// With a configured finalizer, another controller's finalizer is neither added nor released
+    #[tokio::test]
+    async fn test_configured_finalizer() {
+        let server = MockApiServer::start(|_| (StatusCode::OK, node_json("worker-3"))).await;
+        let ctx = Arc::new(Context::with_config(
+            server.client(),
+            ControllerConfig {
+                finalizer: "test.example.com/finalizer".to_string(),
+                ..scoped_config(&["worker-1"])
+            },
+        ));
+
+        // Out of scope, but only the default finalizer is on it
+        reconcile(Arc::new(finalized_node("worker-3")), ctx.clone())
+            .await
+            .unwrap();
+        assert!(server.requests().is_empty());
+
+        reconcile(Arc::new(new_node("worker-1")), ctx)
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let patch = requests[0].json().to_string();
+        assert!(patch.contains("test.example.com/finalizer"));
+        assert!(!patch.contains(FINALIZER_NAME));
+    }
+
+    /// Field and label selectors are combined with the node name selector
+    #[test]
+    fn test_selectors_watcher_config() {
+        let config = ControllerConfig {
+            field_selector: Some("spec.unschedulable=false".to_string()),
+            label_selector: Some("pool=gpu".to_string()),
+            ..scoped_config(&["worker-1"])
+        };
+        let watcher_config = config.watcher_config();
+        assert_eq!(
+            watcher_config.field_selector,
+            Some("metadata.name=worker-1,spec.unschedulable=false".to_string())
+        );
+        assert_eq!(watcher_config.label_selector, Some("pool=gpu".to_string()));
+
+        let config = ControllerConfig {
+            field_selector: Some("metadata.name!=worker-9".to_string()),
+            ..scoped_config(&["worker-1", "worker-2"])
+        };
+        assert_eq!(
+            config.watcher_config().field_selector,
+            Some("metadata.name!=worker-9".to_string())
+        );
+    }
+
+    /// Selectors are sent to the API server and a rejected selector surfaces its error message
+    #[tokio::test]
+    async fn test_invalid_field_selector_fails_validation() {
+        let server = MockApiServer::start(|req| {
+            let query = req.uri.query().unwrap_or_default().to_string();
+            if query.contains("bogus") {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    status_json(400, "field label not supported: spec.bogus"),
+                );
+            }
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "apiVersion": "meta.k8s.io/v1",
+                    "kind": "PartialObjectMetadataList",
+                    "metadata": {},
+                    "items": [],
+                }),
+            )
+        })
+        .await;
+
+        let config = ControllerConfig {
+            field_selector: Some("spec.unschedulable=false".to_string()),
+            ..Default::default()
+        };
+        config.validate_selectors(server.client()).await.unwrap();
+        let query = server.requests()[0].uri.query().unwrap().to_string();
+        assert!(query.contains("fieldSelector=spec.unschedulable%3Dfalse"));
+
+        let config = ControllerConfig {
+            field_selector: Some("spec.bogus=true".to_string()),
+            ..Default::default()
+        };
+        match config.validate_selectors(server.client()).await {
+            Err(Error::InvalidSelector(message)) => {
+                assert_eq!(message, "field label not supported: spec.bogus")
+            }
+            other => panic!("Expected InvalidSelector, got {:?}", other),
+        }
+    }
+
+    const OTHER_FINALIZER: &str = "other.example.com/finalizer";
+
+    /// A node that was deleted `hours_ago` and is held by our finalizer and optionally another
+    fn terminating_node(name: &str, hours_ago: i64, other_finalizer: bool) -> Node {
+        let mut node = finalized_node(name);
+        node.metadata.deletion_timestamp =
+            Some(Time(Utc::now() - ChronoDuration::hours(hours_ago)));
+        if other_finalizer {
+            node.metadata
+                .finalizers
+                .as_mut()
+                .unwrap()
+                .push(OTHER_FINALIZER.to_string());
+        }
+        node
+    }
+
+    /// We only give up on a snapshot once our own cleanup has been failing past the deadline,
+    /// and hold on longer while another finalizer keeps the node around anyway
+    #[test]
+    fn test_should_force_release() {
+        let now = Utc::now();
+        // Within the deadline
+        assert!(!should_force_release(
+            &terminating_node("a", 0, false),
+            FINALIZER_NAME,
+            5,
+            now
+        ));
+        // Past the deadline but we never failed, e.g. the controller was down
+        assert!(!should_force_release(
+            &terminating_node("a", 2, false),
+            FINALIZER_NAME,
+            0,
+            now
+        ));
+        // Past the deadline and failing
+        assert!(should_force_release(
+            &terminating_node("a", 2, false),
+            FINALIZER_NAME,
+            1,
+            now
+        ));
+        // Another finalizer is keeping the node, so keep trying until attempts run out
+        assert!(!should_force_release(
+            &terminating_node("a", 2, true),
+            FINALIZER_NAME,
+            MAX_CLEANUP_ATTEMPTS - 1,
+            now
+        ));
+        assert!(should_force_release(
+            &terminating_node("a", 2, true),
+            FINALIZER_NAME,
+            MAX_CLEANUP_ATTEMPTS,
+            now
+        ));
+        // Not terminating
+        assert!(!should_force_release(
+            &finalized_node("a"),
+            FINALIZER_NAME,
+            100,
+            now
+        ));
+    }
+
+    /// A node held by a never-removed second finalizer keeps our finalizer and keeps trying to
+    /// snapshot until our attempts are exhausted
+    #[tokio::test]
+    async fn test_cleanup_with_other_finalizer() {
+        let server = MockApiServer::start(|req| {
+            if req.uri.path().contains("/configmaps/") {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    status_json(500, "etcdserver: request timed out"),
+                );
+            }
+            (StatusCode::OK, node_json("node-a"))
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Arc::new(terminating_node("node-a", 2, true));
+
+        for _ in 0..MAX_CLEANUP_ATTEMPTS {
+            let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+            error_policy(node.clone(), &error, ctx.clone());
+        }
+        let requests: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| !r.is_event())
+            .collect();
+        assert_eq!(requests.len(), MAX_CLEANUP_ATTEMPTS as usize);
+        assert!(requests
+            .iter()
+            .all(|r| r.uri.path().contains("/configmaps/")));
+
+        // Attempts exhausted: our finalizer is released without another snapshot attempt
+        reconcile(node.clone(), ctx.clone()).await.unwrap();
+        let requests: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| !r.is_event())
+            .collect();
+        let last = requests.last().unwrap();
+        assert_eq!(requests.len(), MAX_CLEANUP_ATTEMPTS as usize + 1);
+        assert_eq!(last.uri.path(), "/api/v1/nodes/node-a");
+        assert!(last.json().to_string().contains("remove"));
+    }
+
+    /// A node deleted over the deadline ago whose backups keep failing loses our finalizer
+    /// without another backup attempt, and the missing backup is recorded in an Event
+    #[tokio::test]
+    async fn test_forced_release_removes_finalizer() {
+        let node = terminating_node("node-a", 2, false);
+        let stored = Arc::new(Mutex::new(serde_json::to_value(&node).unwrap()));
+        let current = stored.clone();
+        let server = MockApiServer::start(move |req| {
+            if req.is_event() {
+                return (StatusCode::CREATED, req.json());
+            }
+            if req.uri.path().contains("/configmaps/") {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    status_json(500, "etcdserver: request timed out"),
+                );
+            }
+            // Apply the finalizer helper's JSON patch removals
+            let mut node = current.lock().unwrap();
+            for op in req.json().as_array().into_iter().flatten() {
+                if op["op"] != "remove" {
+                    continue;
+                }
+                let path = op["path"].as_str().unwrap();
+                let index = path.rsplit('/').next().unwrap().parse::<usize>().unwrap();
+                node["metadata"]["finalizers"]
+                    .as_array_mut()
+                    .unwrap()
+                    .remove(index);
+            }
+            (StatusCode::OK, node.clone())
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let node = Arc::new(node);
+
+        let error = reconcile(node.clone(), ctx.clone()).await.unwrap_err();
+        error_policy(node.clone(), &error, ctx.clone());
+        reconcile(node.clone(), ctx.clone()).await.unwrap();
+
+        assert_eq!(
+            stored.lock().unwrap()["metadata"]["finalizers"],
+            serde_json::json!([])
+        );
+        let requests = server.requests();
+        let backups = requests
+            .iter()
+            .filter(|r| r.uri.path().contains("/configmaps/"))
+            .count();
+        assert_eq!(backups, 1);
+        let event = requests
+            .iter()
+            .map(|r| r.json())
+            .find(|event| event["reason"] == "LabelsNotPreserved")
+            .expect("the missing backup is recorded");
+        assert_eq!(event["type"], "Warning");
+    }
+}
@@ -0,0 +1,127 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::{reflector, watcher};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, RestoredAnnotation, FINALIZER_NAME,
+        RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// The old node, deleted with its final labels
+    fn deleted_node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-0".to_string()),
+                uid: Some("uid-1".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: Some(Time(Utc::now())),
+                labels: Some(labels(&[("zone", "b"), ("pool", "gpu"), ("team", "y")])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The same node recreated and restored from the backup of an earlier deletion. `team` was
+    /// set on the new node directly.
+    fn recreated_node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-0".to_string()),
+                uid: Some("uid-2".to_string()),
+                labels: Some(labels(&[("zone", "a"), ("rack", "r1"), ("team", "x")])),
+                annotations: Some(labels(&[(RESTORED_ANNOTATION_KEY, "corr-old")])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Serve the stale backup the recreated node was restored from
+    async fn server() -> MockApiServer {
+        let stale = Backup {
+            labels: labels(&[("zone", "a"), ("rack", "r1")]),
+            correlation_id: Some("corr-old".to_string()),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-0"), "namespace": "default" },
+            "data": stale.to_configmap_data().unwrap(),
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => (StatusCode::OK, req.json()),
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-0")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// A node recreated and restored before the old node's cleanup finished is brought up to
+    /// date with the old node's final labels
+    #[tokio::test]
+    async fn test_recreated_node_gets_final_labels() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let (store, mut writer) = reflector::store();
+        ctx.set_node_store(store);
+        writer.apply_watcher_event(&watcher::Event::Apply(recreated_node()));
+
+        reconcile(Arc::new(deleted_node()), ctx).await.unwrap();
+
+        let requests = server.requests();
+        let written = requests
+            .iter()
+            .find(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .unwrap()
+            .json();
+        let written: BTreeMap<String, String> =
+            serde_json::from_value(written["data"].clone()).unwrap();
+        let written = Backup::from_configmap_data(&written).unwrap();
+        let update = requests
+            .iter()
+            .filter(|r| r.uri.path().starts_with("/api/v1/nodes/"))
+            .map(|r| r.json())
+            .find(|body| body["metadata"]["labels"].is_object())
+            .expect("the recreated node is updated");
+        // Labels from the stale backup follow the final backup, missing ones are added, and
+        // labels set on the new node are kept
+        assert_eq!(
+            update["metadata"]["labels"],
+            serde_json::json!({ "zone": "b", "rack": null, "pool": "gpu" })
+        );
+        assert_eq!(
+            update["metadata"]["annotations"][RESTORED_ANNOTATION_KEY],
+            serde_json::json!(RestoredAnnotation::of_backup(&written).to_value())
+        );
+    }
+
+    /// Without a recreated node the cleanup only writes the backup
+    #[tokio::test]
+    async fn test_no_recreated_node() {
+        let server = server().await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let (store, mut writer) = reflector::store();
+        ctx.set_node_store(store);
+        writer.apply_watcher_event(&watcher::Event::Apply(deleted_node()));
+
+        reconcile(Arc::new(deleted_node()), ctx).await.unwrap();
+
+        let requests = server.requests();
+        assert!(!requests.iter().any(|r| r.method == Method::GET));
+        assert!(!requests
+            .iter()
+            .filter(|r| r.uri.path().starts_with("/api/v1/nodes/"))
+            .any(|r| r.json()["metadata"]["labels"].is_object()));
+    }
+}
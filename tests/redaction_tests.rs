@@ -0,0 +1,201 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer};
+    use axum::body::Body;
+    use http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        admin,
+        bundle::{self, Bundle, BundleEntry, BUNDLE_VERSION},
+        configmap_name, hash_value, reconcile, Backup, Context, ControllerConfig, Redactor,
+        Surface, FINALIZER_NAME,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    const SECRET: &str = "acme-corp";
+
+    fn labels() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("example.com/customer-id".to_string(), SECRET.to_string()),
+            ("zone".to_string(), "us-east-1a".to_string()),
+        ])
+    }
+
+    fn showing(surface: Surface) -> Redactor {
+        Redactor {
+            shown: BTreeSet::from([surface]),
+            ..Default::default()
+        }
+    }
+
+    /// Values are hashed deterministically, for matching keys only, unless shown
+    #[test]
+    fn test_redactor() {
+        let redactor = Redactor::default();
+        assert_eq!(hash_value(SECRET), hash_value(SECRET));
+        assert_ne!(hash_value(SECRET), hash_value("other-corp"));
+        assert!(hash_value(SECRET).starts_with("sha256:"));
+        assert_eq!(hash_value(SECRET).len(), "sha256:".len() + 12);
+
+        assert!(redactor.redacts(Surface::Logs, "customer"));
+        assert!(redactor.redacts(Surface::Logs, "example.com/tenant-name"));
+        assert!(!redactor.redacts(Surface::Logs, "zone"));
+        assert_eq!(
+            redactor.labels(Surface::Admin, &labels()),
+            BTreeMap::from([
+                ("example.com/customer-id".to_string(), hash_value(SECRET)),
+                ("zone".to_string(), "us-east-1a".to_string()),
+            ])
+        );
+
+        let all = Redactor {
+            all_keys: true,
+            ..Default::default()
+        };
+        assert_eq!(all.value(Surface::Logs, "zone", "a"), hash_value("a"));
+        assert!(!showing(Surface::Logs).redacts(Surface::Logs, "customer"));
+        assert!(showing(Surface::Logs).redacts(Surface::Admin, "customer"));
+        assert!(!Redactor::disabled().redacts(Surface::Logs, "customer"));
+    }
+
+    /// A writer collecting everything logged
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Back up a node carrying a secret value and return what was logged
+    async fn cleanup_logs(redactor: Redactor) -> String {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server = MockApiServer::start(|req| match (&req.method, req.uri.path()) {
+            (&Method::PATCH, path) if path.contains("/configmaps/") => (StatusCode::OK, req.json()),
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            redactor,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                deletion_timestamp: Some(Time(Utc::now())),
+                labels: Some(labels()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        reconcile(Arc::new(node), ctx).await.unwrap();
+        let logs = logs.0.lock().unwrap().clone();
+        String::from_utf8(logs).unwrap()
+    }
+
+    /// Logs show the hash of sensitive values, and the values themselves when shown
+    #[tokio::test]
+    async fn test_logs() {
+        let logs = cleanup_logs(Redactor::default()).await;
+        assert!(logs.contains("Labels to preserve"));
+        assert!(logs.contains(&hash_value(SECRET)));
+        assert!(logs.contains("us-east-1a"));
+        assert!(!logs.contains(SECRET));
+
+        let logs = cleanup_logs(showing(Surface::Logs)).await;
+        assert!(logs.contains(SECRET));
+    }
+
+    /// Bundle problems quoting a sensitive value hide it
+    #[test]
+    fn test_reports() {
+        let backup = Backup {
+            labels: BTreeMap::from([(
+                "example.com/customer-id".to_string(),
+                "acme corp!".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let bundle = Bundle {
+            version: BUNDLE_VERSION,
+            backups: vec![BundleEntry {
+                configmap: configmap_name("node-a"),
+                data: backup.to_configmap_data().unwrap(),
+                ..Default::default()
+            }],
+        };
+        let summary = bundle::verify(&bundle).summary();
+        assert!(summary.contains(&hash_value("acme corp!")));
+        assert!(!summary.contains("acme corp!"));
+
+        let summary = bundle::verify_with(&bundle, &showing(Surface::Reports)).summary();
+        assert!(summary.contains("acme corp!"));
+    }
+
+    async fn admin_backup(redactor: Redactor) -> serde_json::Value {
+        let data = Backup {
+            labels: labels(),
+            ..Default::default()
+        }
+        .to_configmap_data()
+        .unwrap();
+        let server = MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": configmap_name("node-a") }, "data": data }),
+            ),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            redactor,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let request = Request::builder()
+            .uri("/backups/node-a")
+            .body(Body::empty())
+            .unwrap();
+        let response = admin::router(ctx).oneshot(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// The admin API hides sensitive values unless shown
+    #[tokio::test]
+    async fn test_admin() {
+        let state = admin_backup(Redactor::default()).await;
+        assert_eq!(
+            state["labels"]["example.com/customer-id"],
+            hash_value(SECRET)
+        );
+        assert_eq!(state["labels"]["zone"], "us-east-1a");
+
+        let state = admin_backup(showing(Surface::Admin)).await;
+        assert_eq!(state["labels"]["example.com/customer-id"], SECRET);
+    }
+}
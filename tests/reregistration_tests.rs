@@ -0,0 +1,107 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, labels, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeStatus, NodeSystemInfo};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use label_preserver::{
+        configmap_name, reconcile, Backup, FINALIZER_NAME, RESTORED_ANNOTATION_KEY,
+    };
+
+    use std::sync::Arc;
+
+    /// A restored node booted as `boot_id`
+    fn restored_node(boot_id: &str, node_labels: &[(&str, &str)]) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some(labels(node_labels)),
+                annotations: Some(labels(&[(RESTORED_ANNOTATION_KEY, "corr-1")])),
+                ..Default::default()
+            },
+            status: Some(NodeStatus {
+                node_info: Some(NodeSystemInfo {
+                    boot_id: boot_id.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Serve a backup of `zone` and `team`
+    async fn server() -> MockApiServer {
+        let backup = Backup {
+            labels: labels(&[("zone", "a"), ("team", "ml")]),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// A new boot ID on a restored node that lost a label restores it on that reconcile
+    #[tokio::test]
+    async fn test_repair_after_reboot() {
+        let server = server().await;
+        let ctx = context_with(&server, |config| config.repair_on_reregistration = true);
+
+        // The first boot ID seen is not a reboot, and an unchanged one isn't either
+        for _ in 0..2 {
+            let node = restored_node("boot-1", &[("zone", "a"), ("team", "ml")]);
+            reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+        }
+        assert!(server.requests().is_empty());
+
+        // Tooling reacting to the kubelet restart dropped `team`
+        let node = restored_node("boot-2", &[("zone", "a")]);
+        reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+        let restores: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.is_restore())
+            .collect();
+        assert_eq!(restores.len(), 1);
+        assert_eq!(restores[0].json()["metadata"]["labels"]["team"], "ml");
+        assert_eq!(restores[0].json()["metadata"]["labels"]["zone"], "a");
+    }
+
+    /// A reboot that kept every label, or with repair disabled, patches nothing
+    #[tokio::test]
+    async fn test_no_repair() {
+        let server = server().await;
+        let ctx = context_with(&server, |config| config.repair_on_reregistration = true);
+        let full = [("zone", "a"), ("team", "ml")];
+        reconcile(Arc::new(restored_node("boot-1", &full)), ctx.clone())
+            .await
+            .unwrap();
+        reconcile(Arc::new(restored_node("boot-2", &full)), ctx.clone())
+            .await
+            .unwrap();
+        assert!(!server.requests().iter().any(|r| r.method == Method::PATCH));
+
+        let server = self::server().await;
+        let ctx = context_with(&server, |config| config.repair_on_reregistration = false);
+        reconcile(Arc::new(restored_node("boot-1", &full)), ctx.clone())
+            .await
+            .unwrap();
+        reconcile(Arc::new(restored_node("boot-2", &[])), ctx.clone())
+            .await
+            .unwrap();
+        assert!(server.requests().is_empty());
+    }
+}
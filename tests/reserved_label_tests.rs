@@ -0,0 +1,120 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::labels;
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        filter_restorable_labels, is_reserved_label, preserve_on_cleanup, restore_on_apply, Backup,
+        ControllerConfig, RestorePolicy, SkipReason,
+    };
+    use std::collections::BTreeMap;
+
+    /// Labels Kubernetes and cloud providers set from the machine a node runs on
+    const WELL_KNOWN: [&str; 10] = [
+        "kubernetes.io/hostname",
+        "kubernetes.io/os",
+        "kubernetes.io/arch",
+        "beta.kubernetes.io/instance-type",
+        "topology.kubernetes.io/zone",
+        "topology.kubernetes.io/region",
+        "node.kubernetes.io/instance-type",
+        "node.kubernetes.io/exclude-from-external-load-balancers",
+        "failure-domain.beta.kubernetes.io/zone",
+        "topology.k8s.io/zone",
+    ];
+
+    /// The labels of the node's old incarnation: reserved ones next to our own
+    fn old_labels() -> BTreeMap<String, String> {
+        let mut old = labels(&[("team.example.com/name", "ml"), ("zone", "a")]);
+        for key in WELL_KNOWN {
+            old.insert(key.to_string(), "old".to_string());
+        }
+        old
+    }
+
+    fn node(node_labels: BTreeMap<String, String>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                labels: Some(node_labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn config(allow_reserved: bool) -> ControllerConfig {
+        ControllerConfig {
+            policy: RestorePolicy {
+                allow_reserved_label_restore: allow_reserved,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Kubernetes' domains and their subdomains are reserved, lookalike domains and unprefixed
+    /// keys aren't
+    #[test]
+    fn test_reserved_domains() {
+        assert!(WELL_KNOWN.iter().all(|key| is_reserved_label(key)));
+        assert!(is_reserved_label("k8s.io/team"));
+        assert!(!is_reserved_label("notkubernetes.io/team"));
+        assert!(!is_reserved_label("kubernetes.io.example.com/team"));
+        assert!(!is_reserved_label("example.com/kubernetes.io"));
+        assert!(!is_reserved_label("zone"));
+
+        let mut restorable = old_labels();
+        let mut reserved = filter_restorable_labels(&mut restorable, false);
+        reserved.sort();
+        let mut expected: Vec<String> = WELL_KNOWN.iter().map(|key| key.to_string()).collect();
+        expected.sort();
+        assert_eq!(reserved, expected);
+        assert_eq!(
+            restorable,
+            labels(&[("team.example.com/name", "ml"), ("zone", "a")])
+        );
+
+        let mut restorable = old_labels();
+        assert!(filter_restorable_labels(&mut restorable, true).is_empty());
+        assert_eq!(restorable, old_labels());
+    }
+
+    /// A node recreated in another zone, with none of its labels yet, only gets the backed up
+    /// labels that aren't reserved. The reserved ones are still backed up.
+    #[test]
+    fn test_recreated_node_keeps_fresh_reserved_labels() {
+        let config = config(false);
+        let backup = preserve_on_cleanup(&node(old_labels()), &config, "corr-1", Utc::now())
+            .unwrap()
+            .backup;
+        assert_eq!(backup.labels, old_labels());
+
+        let restoration = restore_on_apply(&node(BTreeMap::new()), &backup, &config, Utc::now());
+        assert_eq!(
+            restoration.added,
+            labels(&[("team.example.com/name", "ml"), ("zone", "a")])
+        );
+        for key in WELL_KNOWN {
+            assert_eq!(restoration.counts.skipped[key], SkipReason::ReservedDomain);
+        }
+        let restored = restoration.node.metadata.labels.unwrap();
+        assert!(!restored.keys().any(|key| is_reserved_label(key)));
+    }
+
+    /// The escape hatch restores reserved labels like any other
+    #[test]
+    fn test_allow_reserved_label_restore() {
+        let backup = Backup {
+            labels: old_labels(),
+            ..Default::default()
+        };
+        let restoration =
+            restore_on_apply(&node(BTreeMap::new()), &backup, &config(true), Utc::now());
+        assert_eq!(restoration.added, old_labels());
+        assert!(restoration.plan.reserved.is_empty());
+    }
+}
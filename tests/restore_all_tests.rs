@@ -0,0 +1,399 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use label_preserver::{
+        configmap_name,
+        restore_all::{
+            restore_all, NodeResult, RestoreAllOptions, RestoreReport, EXIT_FAILED, EXIT_OK,
+            EXIT_SKIPPED,
+        },
+        run::{EXIT_CLEAN, EXIT_CONFIG, EXIT_LOST_LEADERSHIP, EXIT_PREFLIGHT, EXIT_WATCH_FAILED},
+        shard_configmap_name, shard_key, Backup, BackupLayout, ControllerConfig, NamingScheme,
+        RestoreCounts, STATUS_CONFIGMAP_NAME,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn backup_data(labels: &[(&str, &str)]) -> serde_json::Value {
+        let backup = Backup {
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        serde_json::json!(backup.to_configmap_data().unwrap())
+    }
+
+    fn configmap_json(name: &str, data: serde_json::Value) -> serde_json::Value {
+        configmap_in("default", name, data)
+    }
+
+    fn configmap_in(namespace: &str, name: &str, data: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "metadata": { "name": name, "namespace": namespace },
+            "data": data,
+        })
+    }
+
+    /// Serve `nodes` and the backups in `configmaps`, failing patches to `failing_node`
+    async fn cluster(
+        nodes: &[&str],
+        configmaps: Vec<serde_json::Value>,
+        failing_node: &'static str,
+    ) -> MockApiServer {
+        let nodes = nodes.iter().map(|name| node_json(name)).collect();
+        cluster_of(nodes, configmaps, failing_node).await
+    }
+
+    /// Serve the Node objects `nodes` and the backups in `configmaps`, by namespace
+    async fn cluster_of(
+        nodes: Vec<serde_json::Value>,
+        configmaps: Vec<serde_json::Value>,
+        failing_node: &'static str,
+    ) -> MockApiServer {
+        MockApiServer::start(move |req| {
+            let path = req.uri.path();
+            let segments: Vec<&str> = path.split('/').collect();
+            let in_namespace = |cm: &&serde_json::Value| cm["metadata"]["namespace"] == segments[4];
+            match (&req.method, &segments[..]) {
+                (&Method::GET, ["", "api", "v1", "nodes"]) => (
+                    StatusCode::OK,
+                    serde_json::json!({ "metadata": {}, "items": nodes }),
+                ),
+                (&Method::GET, ["", "api", "v1", "namespaces", _, "configmaps"]) => {
+                    let items: Vec<_> = configmaps.iter().filter(in_namespace).collect();
+                    (
+                        StatusCode::OK,
+                        serde_json::json!({ "metadata": {}, "items": items }),
+                    )
+                }
+                (&Method::GET, ["", "api", "v1", "namespaces", _, "configmaps", name]) => {
+                    match configmaps
+                        .iter()
+                        .filter(in_namespace)
+                        .find(|cm| cm["metadata"]["name"] == *name)
+                    {
+                        Some(cm) => (StatusCode::OK, cm.clone()),
+                        None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                    }
+                }
+                (&Method::PATCH, _) if path.ends_with(failing_node) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    status_json(500, "etcdserver: request timed out"),
+                ),
+                (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            }
+        })
+        .await
+    }
+
+    /// The nodes restored, by name
+    fn restored(report: &RestoreReport) -> Vec<&str> {
+        report
+            .nodes
+            .iter()
+            .filter(|n| matches!(n.result, NodeResult::Restored { .. }))
+            .filter_map(|n| n.node.as_deref())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The ConfigMaps of the backups no node matches
+    fn orphans(report: &RestoreReport) -> Vec<&str> {
+        report
+            .nodes
+            .iter()
+            .filter(|n| n.node.is_none())
+            .map(|n| n.configmap.as_str())
+            .collect()
+    }
+
+    fn result_for<'a>(report: &'a RestoreReport, node: &str) -> &'a NodeResult {
+        &report
+            .nodes
+            .iter()
+            .find(|n| n.configmap == configmap_name(node))
+            .unwrap()
+            .result
+    }
+
+    const OPTIONS: RestoreAllOptions = RestoreAllOptions {
+        concurrency: 4,
+        stop_on_error: false,
+    };
+
+    /// Good, corrupt, empty, orphaned, and failing backups are each classified independently
+    #[tokio::test]
+    async fn test_report_classification() {
+        let server = cluster(
+            &["good", "corrupt", "empty", "failing"],
+            vec![
+                configmap_json(&configmap_name("good"), backup_data(&[("zone", "a")])),
+                configmap_json(
+                    &configmap_name("corrupt"),
+                    serde_json::json!({ "preserved_labels_json": "{not json" }),
+                ),
+                configmap_json(&configmap_name("empty"), backup_data(&[])),
+                configmap_json(&configmap_name("gone"), backup_data(&[("zone", "b")])),
+                configmap_json(&configmap_name("failing"), backup_data(&[("zone", "c")])),
+                configmap_json(STATUS_CONFIGMAP_NAME, serde_json::json!({})),
+            ],
+            "failing",
+        )
+        .await;
+        let report = restore_all(server.client(), &ControllerConfig::default(), OPTIONS)
+            .await
+            .unwrap();
+
+        assert_eq!(report.nodes.len(), 5);
+        assert_eq!(
+            result_for(&report, "good"),
+            &NodeResult::Restored {
+                counts: RestoreCounts {
+                    restored: 1,
+                    ..Default::default()
+                }
+            }
+        );
+        assert!(matches!(
+            result_for(&report, "corrupt"),
+            NodeResult::Failed { .. }
+        ));
+        assert!(matches!(
+            result_for(&report, "empty"),
+            NodeResult::Skipped { .. }
+        ));
+        let orphan = report
+            .nodes
+            .iter()
+            .find(|n| n.configmap == configmap_name("gone"))
+            .unwrap();
+        assert_eq!(orphan.node, None);
+        assert!(matches!(orphan.result, NodeResult::Skipped { .. }));
+        match result_for(&report, "failing") {
+            NodeResult::Failed { error } => assert!(error.contains("etcdserver")),
+            other => panic!("Expected Failed, got {:?}", other),
+        }
+        assert_eq!(report.exit_code(), EXIT_FAILED);
+
+        let json = serde_json::to_value(&report).unwrap();
+        let good = json["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|n| n["node"] == "good")
+            .unwrap();
+        assert_eq!(good["result"], "restored");
+        assert_eq!(good["restored"], 1);
+    }
+
+    /// Exit codes distinguish all ok from some skipped
+    #[tokio::test]
+    async fn test_exit_codes() {
+        let server = cluster(
+            &["good"],
+            vec![configmap_json(
+                &configmap_name("good"),
+                backup_data(&[("zone", "a")]),
+            )],
+            "none",
+        )
+        .await;
+        let report = restore_all(server.client(), &ControllerConfig::default(), OPTIONS)
+            .await
+            .unwrap();
+        assert_eq!(report.exit_code(), EXIT_OK);
+
+        let server = cluster(
+            &["good"],
+            vec![
+                configmap_json(&configmap_name("good"), backup_data(&[("zone", "a")])),
+                configmap_json(&configmap_name("gone"), backup_data(&[("zone", "b")])),
+            ],
+            "none",
+        )
+        .await;
+        let report = restore_all(server.client(), &ControllerConfig::default(), OPTIONS)
+            .await
+            .unwrap();
+        assert_eq!(report.exit_code(), EXIT_SKIPPED);
+    }
+
+    /// The report's exit codes don't collide with invalid flags or the controller's exit codes
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let controller = [
+            EXIT_CLEAN,
+            EXIT_CONFIG,
+            EXIT_PREFLIGHT,
+            EXIT_WATCH_FAILED,
+            EXIT_LOST_LEADERSHIP,
+        ];
+        for code in [EXIT_SKIPPED, EXIT_FAILED] {
+            assert!(!controller.contains(&code), "{code} collides");
+        }
+    }
+
+    /// Backups in a mapped namespace are restored and their orphans reported, like those in
+    /// the default namespace
+    #[tokio::test]
+    async fn test_mapped_namespace() {
+        let mut gpu = node_json("gpu-1");
+        gpu["metadata"]["labels"] = serde_json::json!({ "pool": "gpu" });
+        let server = cluster_of(
+            vec![gpu, node_json("good")],
+            vec![
+                configmap_json(&configmap_name("good"), backup_data(&[("zone", "a")])),
+                configmap_in(
+                    "team-ml",
+                    &configmap_name("gpu-1"),
+                    backup_data(&[("zone", "b")]),
+                ),
+                configmap_in(
+                    "team-ml",
+                    &configmap_name("gone"),
+                    backup_data(&[("zone", "c")]),
+                ),
+            ],
+            "none",
+        )
+        .await;
+        let config = ControllerConfig {
+            namespace_label: Some("pool".to_string()),
+            namespace_map: [("gpu".to_string(), "team-ml".to_string())].into(),
+            ..Default::default()
+        };
+        let report = restore_all(server.client(), &config, OPTIONS)
+            .await
+            .unwrap();
+
+        assert_eq!(restored(&report), vec!["good", "gpu-1"]);
+        assert_eq!(orphans(&report), vec![configmap_name("gone")]);
+        assert!(server.requests().iter().any(|r| r.method == Method::PATCH
+            && r.uri.path() == "/api/v1/nodes/gpu-1"
+            && r.json()["metadata"]["labels"]["zone"] == "b"));
+        assert_eq!(report.exit_code(), EXIT_SKIPPED);
+    }
+
+    /// Sharded backups are restored from their node's entry, and entries of nodes that are gone
+    /// are reported under their shard
+    #[tokio::test]
+    async fn test_sharded() {
+        let entry = |labels| serde_json::to_string(&backup_data(labels)).unwrap();
+        let mut configmaps: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+        for (node, labels) in [("good", &[("zone", "a")]), ("gone", &[("zone", "b")])] {
+            let shard = shard_configmap_name(node);
+            let cm = configmaps
+                .entry(shard.clone())
+                .or_insert_with(|| configmap_json(&shard, serde_json::json!({})));
+            cm["data"][shard_key(node)] = entry(labels).into();
+        }
+        let server = cluster(&["good"], configmaps.into_values().collect(), "none").await;
+        let config = ControllerConfig {
+            backup_layout: BackupLayout::Sharded,
+            ..Default::default()
+        };
+        let report = restore_all(server.client(), &config, OPTIONS)
+            .await
+            .unwrap();
+
+        assert_eq!(restored(&report), vec!["good"]);
+        assert_eq!(orphans(&report), vec![shard_configmap_name("gone")]);
+    }
+
+    /// Backups under a legacy naming scheme are restored, and not reported as orphans
+    #[tokio::test]
+    async fn test_legacy_naming_scheme() {
+        let server = cluster(
+            &["old"],
+            vec![configmap_json(
+                "node-labels-old",
+                backup_data(&[("zone", "a")]),
+            )],
+            "none",
+        )
+        .await;
+        let config = ControllerConfig {
+            legacy_naming_schemes: vec![NamingScheme::plain()],
+            ..Default::default()
+        };
+        let report = restore_all(server.client(), &config, OPTIONS)
+            .await
+            .unwrap();
+
+        assert_eq!(restored(&report), vec!["old"]);
+        assert!(orphans(&report).is_empty());
+        assert_eq!(report.exit_code(), EXIT_OK);
+    }
+
+    /// With stop_on_error, nodes after the first failure are not restored
+    #[tokio::test]
+    async fn test_stop_on_error() {
+        let server = cluster(
+            &["corrupt", "good"],
+            vec![
+                configmap_json(
+                    &configmap_name("corrupt"),
+                    serde_json::json!({ "preserved_labels_json": "{not json" }),
+                ),
+                configmap_json(&configmap_name("good"), backup_data(&[("zone", "a")])),
+            ],
+            "none",
+        )
+        .await;
+        let options = RestoreAllOptions {
+            concurrency: 1,
+            stop_on_error: true,
+        };
+        let report = restore_all(server.client(), &ControllerConfig::default(), options)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            result_for(&report, "corrupt"),
+            NodeResult::Failed { .. }
+        ));
+        assert!(matches!(
+            result_for(&report, "good"),
+            NodeResult::Skipped { .. }
+        ));
+        assert!(!server.requests().iter().any(|r| r.method == Method::PATCH));
+        assert_eq!(report.exit_code(), EXIT_FAILED);
+    }
+
+    /// A dry run reports what would be restored without patching any node
+    #[tokio::test]
+    async fn test_dry_run() {
+        let server = cluster(
+            &["good"],
+            vec![configmap_json(
+                &configmap_name("good"),
+                backup_data(&[("zone", "a")]),
+            )],
+            "none",
+        )
+        .await;
+        let config = ControllerConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = restore_all(server.client(), &config, OPTIONS)
+            .await
+            .unwrap();
+        assert_eq!(
+            result_for(&report, "good"),
+            &NodeResult::Restored {
+                counts: RestoreCounts {
+                    restored: 1,
+                    ..Default::default()
+                }
+            }
+        );
+        assert!(!server.requests().iter().any(|r| r.method == Method::PATCH));
+    }
+}
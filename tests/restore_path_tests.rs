@@ -0,0 +1,360 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use kube::runtime::controller::Action;
+    use label_preserver::{
+        awaits_backup, configmap_name, reconcile, Backup, Context, ControllerConfig,
+        RestoredAnnotation, CORRUPT_BACKUP_ANNOTATION_KEY, FINALIZER_NAME, MANAGED_LABEL_KEY,
+        RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// How soon a new node without a backup is checked again
+    const MISSING_BACKUP_INTERVAL: Duration = Duration::from_secs(5);
+    /// How long after registration a missing backup is still awaited
+    const MISSING_BACKUP_WINDOW: Duration = Duration::from_secs(120);
+
+    /// A node carrying our finalizer
+    fn node() -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn configmap(data: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": data,
+        })
+    }
+
+    /// Answer reads of the backup with `backup` and the restoring apply with `restore`, and
+    /// accept every other patch
+    async fn cluster(
+        backup: (StatusCode, serde_json::Value),
+        restore: StatusCode,
+    ) -> MockApiServer {
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => backup.clone(),
+            _ if req.is_restore() && restore != StatusCode::OK => {
+                (restore, status_json(restore.as_u16(), "restore failed"))
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// The labels and restored annotation applied by the restore
+    fn restored(server: &MockApiServer) -> (serde_json::Value, RestoredAnnotation) {
+        let restore = server
+            .requests()
+            .into_iter()
+            .find(|r| r.is_restore())
+            .expect("the node is restored")
+            .json();
+        let annotation = restore["metadata"]["annotations"][RESTORED_ANNOTATION_KEY]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (
+            restore["metadata"]["labels"].clone(),
+            RestoredAnnotation::parse(&annotation),
+        )
+    }
+
+    /// The node patches that aren't restores, e.g. setting the managed label
+    fn other_node_patches(server: &MockApiServer) -> Vec<serde_json::Value> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path() == "/api/v1/nodes/node-a")
+            .filter(|r| !r.is_restore())
+            .map(|r| r.json())
+            .collect()
+    }
+
+    /// A node without a backup only gets the managed label, and isn't marked restored so a
+    /// backup that shows up later is still restored. A new node looks for its backup again.
+    #[tokio::test]
+    async fn test_missing_backup() {
+        let missing = (StatusCode::NOT_FOUND, status_json(404, "not found"));
+        let server = cluster(missing.clone(), StatusCode::OK).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let action = reconcile(Arc::new(node()), ctx).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(!server.requests().iter().any(|r| r.is_restore()));
+        assert_eq!(
+            other_node_patches(&server),
+            vec![serde_json::json!({ "metadata": { "labels": { MANAGED_LABEL_KEY: "true" } } })]
+        );
+
+        let server = cluster(missing, StatusCode::OK).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut registered = node();
+        registered.metadata.creation_timestamp = Some(Time(Utc::now()));
+        let action = reconcile(Arc::new(registered), ctx).await.unwrap();
+        assert_eq!(action, Action::requeue(MISSING_BACKUP_INTERVAL));
+        assert!(!server.requests().iter().any(|r| r.is_restore()));
+    }
+
+    #[test]
+    fn test_awaits_backup() {
+        let now = Utc::now();
+        let mut node = node();
+        assert!(!awaits_backup(&node, now));
+        node.metadata.creation_timestamp = Some(Time(now - ChronoDuration::seconds(30)));
+        assert!(awaits_backup(&node, now));
+        node.metadata.creation_timestamp = Some(Time(now + ChronoDuration::seconds(5)));
+        assert!(awaits_backup(&node, now));
+        let window = ChronoDuration::from_std(MISSING_BACKUP_WINDOW).unwrap();
+        node.metadata.creation_timestamp = Some(Time(now - window));
+        assert!(!awaits_backup(&node, now));
+    }
+
+    /// A node recreated before the old node's cleanup has written its backup is restored from
+    /// that backup once it lands
+    #[tokio::test]
+    async fn test_recreated_before_backup_written() {
+        let stored: Arc<Mutex<Option<serde_json::Value>>> = Arc::default();
+        let backups = stored.clone();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                match backups.lock().unwrap().clone() {
+                    Some(cm) => (StatusCode::OK, cm),
+                    None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                }
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                *backups.lock().unwrap() = Some(req.json());
+                (StatusCode::OK, req.json())
+            }
+            _ if req.is_restore() => (StatusCode::OK, req.json()),
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+        })
+        .await;
+        let config = ControllerConfig {
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let mut recreated = node();
+        recreated.metadata.creation_timestamp = Some(Time(Utc::now()));
+        let recreated = Arc::new(recreated);
+        let action = reconcile(recreated.clone(), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::requeue(MISSING_BACKUP_INTERVAL));
+        assert!(!server.requests().iter().any(|r| r.is_restore()));
+
+        // The old node's cleanup writes its backup only now
+        let mut deleted = node();
+        deleted.metadata.labels = Some(BTreeMap::from([("zone".to_string(), "a".to_string())]));
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(deleted), ctx.clone()).await.unwrap();
+
+        reconcile(recreated, ctx).await.unwrap();
+        let (labels, annotation) = restored(&server);
+        assert_eq!(labels["zone"], "a");
+        let configmap = stored.lock().unwrap().clone().unwrap();
+        let data = serde_json::from_value(configmap["data"].clone()).unwrap();
+        let backup = Backup::from_configmap_data(&data).unwrap();
+        assert_eq!(annotation, RestoredAnnotation::of_backup(&backup));
+    }
+
+    /// An empty backup, or a ConfigMap without data, restores nothing but still marks the node
+    #[tokio::test]
+    async fn test_empty_backup() {
+        let empty = Backup::default().to_configmap_data().unwrap();
+        for data in [
+            serde_json::to_value(empty).unwrap(),
+            serde_json::Value::Null,
+        ] {
+            let server = cluster((StatusCode::OK, configmap(data)), StatusCode::OK).await;
+            let ctx = Arc::new(Context::new(server.client()));
+            reconcile(Arc::new(node()), ctx).await.unwrap();
+            let (labels, annotation) = restored(&server);
+            assert_eq!(labels, serde_json::json!({ MANAGED_LABEL_KEY: "true" }));
+            assert_eq!(
+                annotation.labels_digest,
+                Some(Backup::default().labels_digest())
+            );
+        }
+    }
+
+    /// A backup that doesn't parse, or names no schema version, is marked corrupt and the node
+    /// is treated as if it had none
+    #[tokio::test]
+    async fn test_corrupt_backup() {
+        for data in [
+            serde_json::json!({ "preserved_labels_json": "{not json" }),
+            serde_json::json!({ "preserved_labels_json": "[\"zone\"]" }),
+            serde_json::json!({ "schema_version": "five" }),
+        ] {
+            let server = cluster((StatusCode::OK, configmap(data.clone())), StatusCode::OK).await;
+            let ctx = Arc::new(Context::new(server.client()));
+            reconcile(Arc::new(node()), ctx).await.unwrap();
+            let mark = server
+                .requests()
+                .into_iter()
+                .find(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+                .expect("the backup is marked corrupt");
+            assert_eq!(
+                mark.json(),
+                serde_json::json!({
+                    "metadata": { "annotations": { CORRUPT_BACKUP_ANNOTATION_KEY: "true" } }
+                }),
+                "{data}"
+            );
+            assert!(!server.requests().iter().any(|r| r.is_restore()));
+            assert_eq!(other_node_patches(&server).len(), 1);
+        }
+    }
+
+    /// Backing up a node again clears the corrupt mark of its ConfigMap
+    #[tokio::test]
+    async fn test_rewrite_clears_corrupt_mark() {
+        let server = MockApiServer::start(|req| match (&req.method, req.uri.path()) {
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                let mut cm = req.json();
+                if cm.get("data").is_some() {
+                    cm["metadata"]["annotations"][CORRUPT_BACKUP_ANNOTATION_KEY] = "true".into();
+                }
+                (StatusCode::OK, cm)
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut deleted = node();
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(deleted), ctx).await.unwrap();
+        let writes: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .map(|r| r.json())
+            .collect();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(
+            writes[1],
+            serde_json::json!({
+                "metadata": { "annotations": { CORRUPT_BACKUP_ANNOTATION_KEY: null } }
+            })
+        );
+    }
+
+    /// Labels over the Kubernetes length limits are left out instead of failing the restore
+    #[tokio::test]
+    async fn test_oversized_labels_skipped() {
+        let backup = Backup {
+            labels: BTreeMap::from([
+                ("team".to_string(), "ml".to_string()),
+                ("long-value".to_string(), "v".repeat(64)),
+                ("k".repeat(64), "x".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let data = serde_json::to_value(backup.to_configmap_data().unwrap()).unwrap();
+        let server = cluster((StatusCode::OK, configmap(data)), StatusCode::OK).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(node()), ctx).await.unwrap();
+        let (labels, _) = restored(&server);
+        assert_eq!(
+            labels,
+            serde_json::json!({ "team": "ml", MANAGED_LABEL_KEY: "true" })
+        );
+    }
+
+    /// A backup split into chunks is restored whole
+    #[tokio::test]
+    async fn test_chunked_backup() {
+        let backup = Backup {
+            labels: (0..50)
+                .map(|i| (format!("example.com/label-{i:02}"), "x".to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let data = backup.to_configmap_data_chunked(256).unwrap();
+        assert!(data.contains_key("preserved_labels_json_chunks"));
+        let data = serde_json::to_value(data).unwrap();
+        let server = cluster((StatusCode::OK, configmap(data)), StatusCode::OK).await;
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(node()), ctx).await.unwrap();
+        let (labels, annotation) = restored(&server);
+        assert_eq!(labels.as_object().unwrap().len(), 51);
+        assert_eq!(annotation, RestoredAnnotation::of_backup(&backup));
+    }
+
+    /// A failed read of the backup fails the reconcile before the node is touched, and a
+    /// rejected restore fails it without marking the node restored
+    #[tokio::test]
+    async fn test_api_errors() {
+        let server = cluster(
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                status_json(500, "etcd unavailable"),
+            ),
+            StatusCode::OK,
+        )
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let error = reconcile(Arc::new(node()), ctx).await.unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert!(!server.requests().iter().any(|r| r.method == Method::PATCH));
+
+        let backup = Backup {
+            labels: BTreeMap::from([("team".to_string(), "ml".to_string())]),
+            ..Default::default()
+        };
+        let data = serde_json::to_value(backup.to_configmap_data().unwrap()).unwrap();
+        let server = cluster(
+            (StatusCode::OK, configmap(data)),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let error = reconcile(Arc::new(node()), ctx).await.unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert_eq!(
+            server.requests().iter().filter(|r| r.is_restore()).count(),
+            1
+        );
+    }
+
+    /// A deleted node whose backup can't be written keeps our finalizer
+    #[tokio::test]
+    async fn test_backup_write_error() {
+        let server = MockApiServer::start(|req| match (&req.method, req.uri.path()) {
+            (&Method::PATCH, path) if path.contains("/configmaps/") => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                status_json(500, "etcd unavailable"),
+            ),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        let mut deleted = node();
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        let error = reconcile(Arc::new(deleted), ctx).await.unwrap_err();
+        assert_eq!(error.kind(), "kube");
+        assert!(!server
+            .requests()
+            .iter()
+            .any(|r| r.uri.path().starts_with("/api/v1/nodes/")));
+    }
+}
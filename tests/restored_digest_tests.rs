@@ -0,0 +1,125 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::runtime::watcher;
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, RestoredAnnotation, FINALIZER_NAME,
+        RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn backup(pairs: &[(&str, &str)]) -> Backup {
+        Backup {
+            labels: pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn configmap(backup: &Backup) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name("node-a")),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            data: Some(backup.to_configmap_data().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// A node marked restored with `annotation`
+    fn node(annotation: &RestoredAnnotation) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                annotations: Some(BTreeMap::from([(
+                    RESTORED_ANNOTATION_KEY.to_string(),
+                    annotation.to_value(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Serve `backup` and reconcile a node restored with `annotation`, after the backup watch
+    /// saw `backup`, returning the annotation of the restore, if any
+    async fn reconcile_restored(
+        annotation: &RestoredAnnotation,
+        backup: &Backup,
+    ) -> Option<RestoredAnnotation> {
+        let served = serde_json::to_value(configmap(backup)).unwrap();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, served.clone())
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+        ctx.backup_digests()
+            .observe(&watcher::Event::Apply(configmap(backup)));
+        reconcile(Arc::new(node(annotation)), ctx).await.unwrap();
+        server.requests().iter().find(|r| r.is_restore()).map(|r| {
+            let body = r.json();
+            let value = body["metadata"]["annotations"][RESTORED_ANNOTATION_KEY]
+                .as_str()
+                .unwrap()
+                .to_string();
+            RestoredAnnotation::parse(&value)
+        })
+    }
+
+    /// A backup holding other labels than the restored ones is restored again, and the node
+    /// records the new digest
+    #[tokio::test]
+    async fn test_changed_backup_restored_again() {
+        let restored = RestoredAnnotation::of_backup(&backup(&[("zone", "a")]));
+        let changed = backup(&[("zone", "b")]);
+        let annotation = reconcile_restored(&restored, &changed)
+            .await
+            .expect("the node is restored again");
+        assert_eq!(annotation, RestoredAnnotation::of_backup(&changed));
+        assert!(!annotation.is_outdated(&changed.labels_digest()));
+    }
+
+    /// An unchanged backup, including one without labels, is not restored again
+    #[tokio::test]
+    async fn test_unchanged_backup() {
+        for backup in [backup(&[("zone", "a")]), backup(&[])] {
+            let restored = RestoredAnnotation::of_backup(&backup);
+            assert_eq!(reconcile_restored(&restored, &backup).await, None);
+        }
+    }
+
+    /// Labels added to a backup that had none are restored
+    #[tokio::test]
+    async fn test_labels_added_to_empty_backup() {
+        let restored = RestoredAnnotation::of_backup(&backup(&[]));
+        let annotation = reconcile_restored(&restored, &backup(&[("zone", "a")])).await;
+        assert!(annotation.is_some());
+    }
+
+    /// A node restored by a release that writes no digest is never restored again
+    #[tokio::test]
+    async fn test_annotation_without_digest() {
+        let restored = RestoredAnnotation::new(Some("corr-1"));
+        assert!(!restored.is_outdated(&backup(&[]).labels_digest()));
+        assert_eq!(
+            reconcile_restored(&restored, &backup(&[("zone", "b")])).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,188 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{self, labels, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::runtime::{controller::Action, watcher};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, RestoredAnnotation, FINALIZER_NAME,
+        RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+    type StoredBackup = Arc<Mutex<Option<serde_json::Value>>>;
+
+    fn backup_configmap(pairs: &[(&str, &str)]) -> ConfigMap {
+        let backup = Backup {
+            labels: labels(pairs),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name("node-a")),
+                namespace: Some("default".to_string()),
+                resource_version: Some("1".to_string()),
+                ..Default::default()
+            },
+            data: Some(backup.to_configmap_data().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Store the backup of `node-a`, starting from `initial`, and accept node patches
+    async fn cluster(initial: &ConfigMap) -> (MockApiServer, StoredBackup) {
+        let stored: StoredBackup =
+            Arc::new(Mutex::new(Some(serde_json::to_value(initial).unwrap())));
+        let backup = stored.clone();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                match backup.lock().unwrap().clone() {
+                    Some(cm) => (StatusCode::OK, cm),
+                    None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                }
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                let mut cm = req.json();
+                cm["metadata"]["resourceVersion"] = "2".into();
+                *backup.lock().unwrap() = Some(cm.clone());
+                (StatusCode::OK, cm)
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        (server, stored)
+    }
+
+    /// `node-a` restored from `backup`, now with `current` labels
+    fn restored_node(backup: &ConfigMap, current: &[(&str, &str)]) -> Node {
+        let backup = Backup::from_configmap_data(backup.data.as_ref().unwrap()).unwrap();
+        let annotation = RestoredAnnotation::of_backup(&backup);
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                resource_version: Some("10".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some(labels(current)),
+                annotations: Some(BTreeMap::from([(
+                    RESTORED_ANNOTATION_KEY.to_string(),
+                    annotation.to_value(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A context whose backup watch saw `backup`
+    fn context(server: &MockApiServer, backup: &ConfigMap, resync: bool) -> Arc<Context> {
+        let ctx = common::context_with(server, |config| {
+            config.resync_interval = resync.then_some(RESYNC_INTERVAL)
+        });
+        ctx.backup_digests()
+            .observe(&watcher::Event::Apply(backup.clone()));
+        ctx
+    }
+
+    fn backup_writes(server: &MockApiServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .count()
+    }
+
+    fn stored_labels(stored: &StoredBackup) -> BTreeMap<String, String> {
+        let cm: ConfigMap =
+            serde_json::from_value(stored.lock().unwrap().clone().unwrap()).unwrap();
+        Backup::from_configmap_data(&cm.data.unwrap())
+            .unwrap()
+            .labels
+    }
+
+    /// A label changed on a live node is backed up, so it survives the node going away
+    /// without a final backup, e.g. because the cleanup failed
+    #[tokio::test]
+    async fn test_changed_label_survives_lost_cleanup() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, stored) = cluster(&initial).await;
+        let ctx = context(&server, &initial, true);
+        let node = restored_node(&initial, &[("zone", "b")]);
+        let action = reconcile(Arc::new(node), ctx).await.unwrap();
+        assert_eq!(action, Action::requeue(RESYNC_INTERVAL));
+        assert_eq!(stored_labels(&stored), labels(&[("zone", "b")]));
+        let marked = server
+            .requests()
+            .into_iter()
+            .find(|r| r.method == Method::PATCH && r.uri.path() == "/api/v1/nodes/node-a")
+            .expect("the node is marked restored from the new backup");
+        let value = marked.json()["metadata"]["annotations"][RESTORED_ANNOTATION_KEY]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let backup = Backup {
+            labels: labels(&[("zone", "b")]),
+            ..Default::default()
+        };
+        assert!(!RestoredAnnotation::parse(&value).is_outdated(&backup.labels_digest()));
+
+        // The node is replaced, and its cleanup never ran
+        let replacement = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // A restarted controller restores the replacement
+        let ctx = Arc::new(Context::new(server.client()));
+        reconcile(Arc::new(replacement), ctx).await.unwrap();
+        let restore = server
+            .requests()
+            .into_iter()
+            .find(|r| r.is_restore())
+            .expect("the replacement is restored");
+        assert_eq!(restore.json()["metadata"]["labels"]["zone"], "b");
+    }
+
+    /// Unchanged labels aren't written again, whether the backup was written before a restart
+    /// or just now
+    #[tokio::test]
+    async fn test_unchanged_labels_not_rewritten() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(&initial).await;
+        let ctx = context(&server, &initial, true);
+        let node = restored_node(&initial, &[("zone", "a")]);
+        let action = reconcile(Arc::new(node), ctx).await.unwrap();
+        assert_eq!(action, Action::requeue(RESYNC_INTERVAL));
+        assert_eq!(backup_writes(&server), 0);
+
+        let ctx = context(&server, &initial, true);
+        let node = Arc::new(restored_node(&initial, &[("zone", "b")]));
+        reconcile(node.clone(), ctx.clone()).await.unwrap();
+        reconcile(node, ctx).await.unwrap();
+        assert_eq!(backup_writes(&server), 1);
+    }
+
+    /// Without a resync interval, live nodes are only backed up when deleted
+    #[tokio::test]
+    async fn test_resync_disabled() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(&initial).await;
+        let ctx = context(&server, &initial, false);
+        let node = restored_node(&initial, &[("zone", "b")]);
+        let action = reconcile(Arc::new(node), ctx).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{context_with, status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::Node;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::runtime::controller::Action;
+    use label_preserver::{reconcile, Backup, FINALIZER_NAME, RESTORED_ANNOTATION_KEY};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// How often rewritten labels are checked before giving up
+    const MAX_REWRITE_VERIFICATIONS: u32 = 5;
+    /// How soon rewritten labels are checked again
+    const REWRITE_VERIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn node(zone: Option<&str>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: zone.map(|zone| [("zone".to_string(), zone.to_string())].into()),
+                annotations: zone
+                    .map(|_| [(RESTORED_ANNOTATION_KEY.to_string(), "1".to_string())].into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Serve a backup of `zone=US-East` behind a webhook that lowercases node label values
+    async fn lowercasing_server() -> MockApiServer {
+        let data = Backup {
+            labels: [("zone".to_string(), "US-East".to_string())].into(),
+            ..Default::default()
+        }
+        .to_configmap_data()
+        .unwrap();
+        MockApiServer::start(move |req| {
+            let is_configmap = req.uri.path().contains("/configmaps/");
+            match req.method {
+                Method::GET if is_configmap => (
+                    StatusCode::OK,
+                    serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+                ),
+                Method::PATCH if is_configmap => (StatusCode::OK, req.json()),
+                Method::PATCH => {
+                    let mut body = req.json();
+                    if let Some(labels) = body["metadata"]["labels"].as_object_mut() {
+                        for value in labels.values_mut() {
+                            *value = value.as_str().unwrap().to_lowercase().into();
+                        }
+                    }
+                    (StatusCode::OK, body)
+                }
+                _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+            }
+        })
+        .await
+    }
+
+    /// The labels of each backup written
+    fn backups(requests: &[RecordedRequest]) -> Vec<BTreeMap<String, String>> {
+        requests
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .map(|r| {
+                let data = serde_json::from_value(r.json()["data"].clone()).unwrap();
+                Backup::from_configmap_data(&data).unwrap().labels
+            })
+            .collect()
+    }
+
+    /// A value rewritten the same way twice is accepted and recorded in the backup
+    #[tokio::test]
+    async fn test_stable_rewrite_converges() {
+        let server = lowercasing_server().await;
+        let ctx = context_with(&server, |config| {
+            config.record_rewrites = true;
+            config.managed_label = false;
+        });
+        let action = reconcile(Arc::new(node(None)), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::requeue(REWRITE_VERIFY_INTERVAL));
+
+        let action = reconcile(Arc::new(node(Some("us-east"))), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(action, Action::await_change());
+        let restores = server.requests().iter().filter(|r| r.is_restore()).count();
+        assert_eq!(restores, 1, "the rewritten value is never re-applied");
+        assert_eq!(
+            backups(&server.requests()),
+            [BTreeMap::from([(
+                "zone".to_string(),
+                "us-east".to_string()
+            )])]
+        );
+
+        // Converged nodes aren't verified again
+        reconcile(Arc::new(node(Some("us-east"))), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(backups(&server.requests()).len(), 1);
+    }
+
+    /// The backup is left alone unless recording is enabled
+    #[tokio::test]
+    async fn test_rewrite_not_recorded_by_default() {
+        let server = lowercasing_server().await;
+        let ctx = context_with(&server, |config| {
+            config.record_rewrites = false;
+            config.managed_label = false;
+        });
+        reconcile(Arc::new(node(None)), ctx.clone()).await.unwrap();
+        let action = reconcile(Arc::new(node(Some("us-east"))), ctx.clone())
+            .await
+            .unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(backups(&server.requests()).is_empty());
+    }
+
+    /// Values that keep changing are checked again until they settle, or given up on
+    #[tokio::test]
+    async fn test_changing_values_are_verified_again() {
+        let server = lowercasing_server().await;
+        let ctx = context_with(&server, |config| {
+            config.record_rewrites = false;
+            config.managed_label = false;
+        });
+        reconcile(Arc::new(node(None)), ctx.clone()).await.unwrap();
+        let verify = |zone: String| reconcile(Arc::new(node(Some(&zone))), ctx.clone());
+
+        let action = verify("us-east-2".to_string()).await.unwrap();
+        assert_eq!(action, Action::requeue(REWRITE_VERIFY_INTERVAL));
+        let action = verify("us-east-2".to_string()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        reconcile(Arc::new(node(None)), ctx.clone()).await.unwrap();
+        let mut actions = Vec::new();
+        for i in 0..MAX_REWRITE_VERIFICATIONS {
+            actions.push(verify(format!("zone-{i}")).await.unwrap());
+        }
+        assert_eq!(actions.pop(), Some(Action::await_change()));
+        assert!(actions
+            .iter()
+            .all(|action| *action == Action::requeue(REWRITE_VERIFY_INTERVAL)));
+    }
+}
@@ -0,0 +1,223 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use label_preserver::{
+        metrics::Totals,
+        run::{self, Shutdown, ShutdownReason},
+        ClientFactory, ClientOptions, ControllerConfig,
+    };
+    use std::time::Duration;
+
+    fn node_list() -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "NodeList",
+            "metadata": { "resourceVersion": "1" },
+            "items": [],
+        })
+    }
+
+    /// Whether `req` is the single-item list that validates the selectors, as opposed to the
+    /// controller's watch
+    fn is_preflight_list(req: &super::common::RecordedRequest) -> bool {
+        req.uri
+            .query()
+            .is_some_and(|q| q.contains("limit=1&") || q.ends_with("limit=1"))
+    }
+
+    /// Run until stopped against `server`, asking to stop after `stop_after`
+    async fn run(
+        server: &MockApiServer,
+        config: ControllerConfig,
+        stop_after: Duration,
+    ) -> Shutdown {
+        let clients =
+            ClientFactory::with_base_config(server.config(), ClientOptions::default(), "test");
+        run::run(&clients, config, None, tokio::time::sleep(stop_after)).await
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(ShutdownReason::Clean.exit_code(), 0);
+        assert_eq!(ShutdownReason::Config(String::new()).exit_code(), 2);
+        assert_eq!(ShutdownReason::Preflight(String::new()).exit_code(), 3);
+        assert_eq!(ShutdownReason::WatchFailed(String::new()).exit_code(), 4);
+        assert_eq!(ShutdownReason::LostLeadership(String::new()).exit_code(), 5);
+    }
+
+    /// A selector the API server rejects is a configuration error
+    #[tokio::test]
+    async fn test_config_error() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                status_json(400, "unable to parse requirement"),
+            )
+        })
+        .await;
+        let config = ControllerConfig {
+            label_selector: Some("pool==".to_string()),
+            ..Default::default()
+        };
+        let shutdown = run(&server, config, Duration::from_secs(60)).await;
+        assert!(matches!(shutdown.reason, ShutdownReason::Config(_)));
+        assert_eq!(shutdown.exit_code, run::EXIT_CONFIG);
+        assert_eq!(shutdown.totals, Totals::default());
+    }
+
+    /// Missing permissions for the startup checks fail preflight
+    #[tokio::test]
+    async fn test_preflight_failure() {
+        let server =
+            MockApiServer::start(|_| (StatusCode::FORBIDDEN, status_json(403, "forbidden"))).await;
+        let shutdown = run(
+            &server,
+            ControllerConfig::default(),
+            Duration::from_secs(60),
+        )
+        .await;
+        assert!(matches!(shutdown.reason, ShutdownReason::Preflight(_)));
+        assert_eq!(shutdown.exit_code, run::EXIT_PREFLIGHT);
+    }
+
+    /// A backup namespace that doesn't exist is a configuration error, caught before watching
+    #[tokio::test]
+    async fn test_missing_namespace() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::GET => (StatusCode::OK, node_list()),
+            _ => (
+                StatusCode::NOT_FOUND,
+                status_json(404, "namespaces \"node-labels\" not found"),
+            ),
+        })
+        .await;
+        let config = ControllerConfig {
+            namespace: "node-labels".to_string(),
+            ..Default::default()
+        };
+        let shutdown = run(&server, config, Duration::from_secs(60)).await;
+        assert!(
+            matches!(&shutdown.reason, ShutdownReason::Config(message) if message.contains("node-labels"))
+        );
+        assert_eq!(shutdown.exit_code, run::EXIT_CONFIG);
+        assert!(server
+            .requests()
+            .iter()
+            .all(|req| req.method != Method::GET || is_preflight_list(req)));
+    }
+
+    /// Every ConfigMap is written to the configured namespace, starting with a dry run
+    #[tokio::test]
+    async fn test_configured_namespace() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::GET => (StatusCode::OK, node_list()),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            namespace: "node-labels".to_string(),
+            ..Default::default()
+        };
+        let shutdown = run(&server, config, Duration::from_millis(200)).await;
+        assert_eq!(shutdown.reason, ShutdownReason::Clean);
+
+        let requests = server.requests();
+        let patches: Vec<_> = requests
+            .iter()
+            .filter(|req| req.method == Method::PATCH)
+            .collect();
+        assert!(patches.len() >= 2);
+        assert!(patches[0].uri.query().unwrap().contains("dryRun=All"));
+        for patch in &patches {
+            assert!(patch
+                .uri
+                .path()
+                .starts_with("/api/v1/namespaces/node-labels/configmaps/"));
+        }
+        assert!(requests
+            .iter()
+            .filter(|req| req.uri.path().contains("/configmaps"))
+            .all(|req| req.uri.path().contains("/namespaces/node-labels/")));
+    }
+
+    /// Losing permission to watch nodes after starting is an unrecoverable watch failure
+    #[tokio::test]
+    async fn test_watch_failure() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::GET if is_preflight_list(req) => (StatusCode::OK, node_list()),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::FORBIDDEN, status_json(403, "forbidden")),
+        })
+        .await;
+        let shutdown = run(
+            &server,
+            ControllerConfig::default(),
+            Duration::from_secs(60),
+        )
+        .await;
+        assert!(matches!(shutdown.reason, ShutdownReason::WatchFailed(_)));
+        assert_eq!(shutdown.exit_code, run::EXIT_WATCH_FAILED);
+    }
+
+    /// Asking to stop shuts down cleanly, with a summary of the run
+    #[tokio::test]
+    async fn test_clean_shutdown() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::GET => (StatusCode::OK, node_list()),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let shutdown = run(
+            &server,
+            ControllerConfig::default(),
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(shutdown.reason, ShutdownReason::Clean);
+        assert_eq!(shutdown.exit_code, run::EXIT_CLEAN);
+
+        let summary = serde_json::to_value(&shutdown).unwrap();
+        assert_eq!(summary["reason"], "clean");
+        assert_eq!(summary["exit_code"], 0);
+        for field in [
+            "uptime_seconds",
+            "reconciles",
+            "restores",
+            "backups",
+            "errors",
+        ] {
+            assert!(summary[field].is_u64(), "{field} missing from {summary}");
+        }
+    }
+
+    /// A dry run writes no status ConfigMap and releases no finalizers at startup
+    #[tokio::test]
+    async fn test_dry_run_startup() {
+        let server = MockApiServer::start(|req| match req.method {
+            Method::GET => (StatusCode::OK, node_list()),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            dry_run: true,
+            node_names: Some(["node-a".to_string()].into()),
+            ..Default::default()
+        };
+        let shutdown = run(&server, config, Duration::from_millis(200)).await;
+        assert_eq!(shutdown.reason, ShutdownReason::Clean);
+        // Only the namespace check, which is a dry-run write itself
+        let writes: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method != Method::GET)
+            .collect();
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].uri.query().unwrap().contains("dryRun=All"));
+    }
+}
@@ -0,0 +1,158 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{finalized_node, status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use label_preserver::{
+        reconcile, Backup, Context, ControllerConfig, PlanDiff, RestorePolicy, ShadowStats,
+        RESTORE_CLAIMED_AT_KEY,
+    };
+    use std::sync::Arc;
+
+    /// A backup of a maintenance label and a zone label preserved ten days ago
+    fn backup() -> Backup {
+        let preserved_at = Utc::now() - ChronoDuration::days(10);
+        let labels: std::collections::BTreeMap<String, String> = [
+            ("maintenance.example.com/drain", "true"),
+            ("topology.kubernetes.io/zone", "a"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        Backup {
+            preserved_at: labels.keys().map(|k| (k.clone(), preserved_at)).collect(),
+            labels,
+            ..Default::default()
+        }
+    }
+
+    /// A policy that expires maintenance labels after a week
+    fn expiring_policy() -> RestorePolicy {
+        RestorePolicy {
+            label_expiry: vec!["maintenance.example.com/=7".parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    /// Restore `backup()` onto a node with `config` and return every request made
+    async fn restore_with(config: ControllerConfig) -> (Vec<RecordedRequest>, ShadowStats) {
+        let data = backup().to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+            ),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        reconcile(Arc::new(finalized_node("node-a")), ctx.clone())
+            .await
+            .unwrap();
+        (server.requests(), ctx.shadow_stats())
+    }
+
+    /// The method, path, and body of each request but Events, without the time the restore
+    /// was claimed
+    fn summary(requests: &[RecordedRequest]) -> Vec<(Method, String, serde_json::Value)> {
+        requests
+            .iter()
+            .filter(|r| !r.is_event())
+            .map(|r| {
+                let mut body = r.json();
+                if let Some(annotations) = body["metadata"]["annotations"].as_object_mut() {
+                    annotations.remove(RESTORE_CLAIMED_AT_KEY);
+                }
+                (r.method.clone(), r.uri.path().to_string(), body)
+            })
+            .collect()
+    }
+
+    /// Evaluating a candidate policy records what it would change and makes exactly the same
+    /// API calls as running without one
+    #[tokio::test]
+    async fn test_shadow_evaluation_never_mutates() {
+        let (baseline, stats) = restore_with(ControllerConfig::default()).await;
+        assert_eq!(stats, ShadowStats::default());
+
+        let (shadowed, stats) = restore_with(ControllerConfig {
+            candidate_policy: Some(expiring_policy()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(summary(&shadowed), summary(&baseline));
+        assert_eq!(
+            shadowed.iter().rfind(|r| r.is_restore()).unwrap().json()["metadata"]["labels"]
+                ["maintenance.example.com/drain"],
+            "true"
+        );
+        assert_eq!(
+            stats,
+            ShadowStats {
+                nodes_evaluated: 1,
+                nodes_affected: 1,
+                keys_newly_restored: 0,
+                keys_no_longer_restored: 1,
+            }
+        );
+    }
+
+    /// A candidate that would restore more than the active policy is counted the other way
+    #[tokio::test]
+    async fn test_shadow_counts_newly_restored() {
+        let (requests, stats) = restore_with(ControllerConfig {
+            policy: expiring_policy(),
+            candidate_policy: Some(RestorePolicy::default()),
+            ..Default::default()
+        })
+        .await;
+        assert!(
+            requests.iter().rfind(|r| r.is_restore()).unwrap().json()["metadata"]["labels"]
+                .get("maintenance.example.com/drain")
+                .is_none()
+        );
+        assert_eq!(stats.nodes_affected, 1);
+        assert_eq!(stats.keys_newly_restored, 1);
+        assert_eq!(stats.keys_no_longer_restored, 0);
+    }
+
+    /// A candidate identical to the active policy is evaluated but changes nothing
+    #[tokio::test]
+    async fn test_identical_candidate() {
+        let (_, stats) = restore_with(ControllerConfig {
+            policy: expiring_policy(),
+            candidate_policy: Some(expiring_policy()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(
+            stats,
+            ShadowStats {
+                nodes_evaluated: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    /// Plans list the keys each policy leaves out
+    #[test]
+    fn test_plan_diff() {
+        let node = finalized_node("node-a");
+        let now = Utc::now();
+        let active = RestorePolicy::default().plan(&backup(), &node, now);
+        let candidate = expiring_policy().plan(&backup(), &node, now);
+        assert_eq!(candidate.expired, vec!["maintenance.example.com/drain"]);
+        assert_eq!(
+            active.diff(&candidate),
+            PlanDiff {
+                newly_restored: vec![],
+                no_longer_restored: vec!["maintenance.example.com/drain".to_string()],
+            }
+        );
+        assert!(active.diff(&active).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{labels, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+    use label_preserver::{
+        reconcile, Backup, Context, ControllerConfig, IdentityMismatchPolicy, MachineIdentity,
+        RestoreCounts, RestorePolicy, SkipReason, FINALIZER_NAME,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// A backup taken ten days ago on machine `old`
+    fn backup(pairs: &[(&str, &str)]) -> Backup {
+        let preserved_at = Utc::now() - ChronoDuration::days(10);
+        Backup {
+            labels: labels(pairs),
+            preserved_at: pairs
+                .iter()
+                .map(|(k, _)| (k.to_string(), preserved_at))
+                .collect(),
+            identity: MachineIdentity {
+                provider_id: Some("old".to_string()),
+                machine_id: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn node(provider_id: &str, pairs: &[(&str, &str)]) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                labels: Some(labels(pairs)),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                provider_id: Some(provider_id.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Each plan-level skip path gets its own reason
+    #[test]
+    fn test_plan_reasons() {
+        let policy = RestorePolicy {
+            label_expiry: vec!["maintenance.example.com/=7".parse().unwrap()],
+            identity_mismatch: IdentityMismatchPolicy::MachineIndependent,
+            machine_independent_prefixes: vec!["team.example.com/".to_string()],
+            ..Default::default()
+        };
+        let backup = backup(&[
+            ("maintenance.example.com/drain", "true"),
+            ("rack.example.com/id", "r12"),
+            ("team.example.com/owner", "storage"),
+            ("bad key", "x"),
+        ]);
+        let plan = policy.plan(&backup, &node("new", &[]), Utc::now());
+        assert_eq!(
+            plan.skipped(),
+            BTreeMap::from([
+                ("bad key".to_string(), SkipReason::InvalidSyntax),
+                (
+                    "maintenance.example.com/drain".to_string(),
+                    SkipReason::Expired
+                ),
+                (
+                    "rack.example.com/id".to_string(),
+                    SkipReason::FilteredByPolicy
+                ),
+            ])
+        );
+        assert_eq!(
+            plan.labels,
+            labels(&[("team.example.com/owner", "storage")])
+        );
+    }
+
+    #[test]
+    fn test_skip_breakdown() {
+        let counts = RestoreCounts {
+            skipped: BTreeMap::from([
+                ("a".to_string(), SkipReason::DeferToLive),
+                ("b".to_string(), SkipReason::DeferToLive),
+                ("c".to_string(), SkipReason::Expired),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            counts.skip_breakdown(),
+            BTreeMap::from([(SkipReason::Expired, 1), (SkipReason::DeferToLive, 2)])
+        );
+        assert_eq!(
+            serde_json::to_value(&counts).unwrap()["skipped"],
+            serde_json::json!({ "a": "DeferToLive", "b": "DeferToLive", "c": "Expired" })
+        );
+    }
+
+    /// Keys already on the node are kept, whether or not the value matches, and every reason is
+    /// counted in the metrics
+    #[tokio::test]
+    async fn test_merge_reasons_are_counted() {
+        let data = backup(&[
+            ("zone", "a"),
+            ("pool", "gpu"),
+            ("team", "storage"),
+            ("maintenance.example.com/drain", "true"),
+        ])
+        .to_configmap_data()
+        .unwrap();
+        let server = MockApiServer::start(move |req| match req.method {
+            Method::GET => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": "backup" }, "data": data }),
+            ),
+            Method::PATCH => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let config = ControllerConfig {
+            policy: RestorePolicy {
+                label_expiry: vec!["maintenance.example.com/=7".parse().unwrap()],
+                ..Default::default()
+            },
+            startup_rate: 0.0,
+            ..Default::default()
+        };
+        let ctx = Arc::new(Context::with_config(server.client(), config));
+        let node = node("old", &[("zone", "a"), ("pool", "cpu")]);
+        reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+
+        let metrics = ctx.render_metrics();
+        for reason in ["ExistingValueKept", "DeferToLive", "Expired"] {
+            let line = format!(
+                "label_preserver_skipped_labels_total{{pool=\"none\",reason=\"{reason}\"}} 1\n"
+            );
+            assert!(metrics.contains(&line), "{metrics}");
+        }
+        assert!(!metrics.contains("reason=\"InvalidSyntax\""));
+    }
+}
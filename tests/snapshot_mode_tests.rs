@@ -0,0 +1,204 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{self, labels, node_json, status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{ConfigMap, Node};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::{controller::Action, watcher};
+    use label_preserver::{
+        configmap_name, reconcile, Backup, Context, RestoredAnnotation, FINALIZER_NAME,
+        MANAGED_LABEL_KEY, RESTORED_ANNOTATION_KEY,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    type StoredBackup = Arc<Mutex<Option<serde_json::Value>>>;
+
+    fn backup_configmap(pairs: &[(&str, &str)]) -> ConfigMap {
+        let backup = Backup {
+            labels: labels(pairs),
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(configmap_name("node-a")),
+                namespace: Some("default".to_string()),
+                resource_version: Some("1".to_string()),
+                ..Default::default()
+            },
+            data: Some(backup.to_configmap_data().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Store the backup of `node-a`, starting from `initial`, and accept node patches
+    async fn cluster(initial: Option<&ConfigMap>) -> (MockApiServer, StoredBackup) {
+        let stored: StoredBackup = Arc::new(Mutex::new(
+            initial.map(|cm| serde_json::to_value(cm).unwrap()),
+        ));
+        let backup = stored.clone();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            _ if req.is_event() => (StatusCode::CREATED, req.json()),
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                match backup.lock().unwrap().clone() {
+                    Some(cm) => (StatusCode::OK, cm),
+                    None => (StatusCode::NOT_FOUND, status_json(404, "NotFound")),
+                }
+            }
+            (&Method::PATCH, path) if path.contains("/configmaps/") => {
+                let mut cm = req.json();
+                cm["metadata"]["resourceVersion"] = "2".into();
+                *backup.lock().unwrap() = Some(cm.clone());
+                (StatusCode::OK, cm)
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, node_json("node-a")),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        (server, stored)
+    }
+
+    /// `node-a` restored from `backup`, now with `current` labels, still holding the finalizer
+    /// and managed label from finalizer mode if `finalized`
+    fn restored_node(backup: &ConfigMap, current: &[(&str, &str)], finalized: bool) -> Node {
+        let backup = Backup::from_configmap_data(backup.data.as_ref().unwrap()).unwrap();
+        let annotation = RestoredAnnotation::of_backup(&backup);
+        let mut node_labels = labels(current);
+        if finalized {
+            node_labels.insert(MANAGED_LABEL_KEY.to_string(), "true".to_string());
+        }
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                resource_version: Some("10".to_string()),
+                finalizers: finalized
+                    .then(|| vec![FINALIZER_NAME.to_string(), "other/finalizer".to_string()]),
+                labels: Some(node_labels),
+                annotations: Some(BTreeMap::from([(
+                    RESTORED_ANNOTATION_KEY.to_string(),
+                    annotation.to_value(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A snapshot mode context whose backup watch saw `backup`
+    fn context(server: &MockApiServer, backup: Option<&ConfigMap>) -> Arc<Context> {
+        let ctx = common::context_with(server, |config| config.snapshot_mode = true);
+        if let Some(backup) = backup {
+            ctx.backup_digests()
+                .observe(&watcher::Event::Apply(backup.clone()));
+        }
+        ctx
+    }
+
+    fn node_patches(server: &MockApiServer) -> Vec<serde_json::Value> {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method == Method::PATCH && r.uri.path() == "/api/v1/nodes/node-a")
+            .map(|r| r.json())
+            .collect()
+    }
+
+    fn stored_labels(stored: &StoredBackup) -> BTreeMap<String, String> {
+        let cm: ConfigMap =
+            serde_json::from_value(stored.lock().unwrap().clone().unwrap()).unwrap();
+        Backup::from_configmap_data(&cm.data.unwrap())
+            .unwrap()
+            .labels
+    }
+
+    /// Switching a cluster to snapshot mode releases the finalizer of live nodes, keeping other
+    /// finalizers, and their changed labels are backed up on the event the release causes
+    #[tokio::test]
+    async fn test_releases_finalizer_of_live_node() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, stored) = cluster(Some(&initial)).await;
+        let ctx = context(&server, Some(&initial));
+        let node = restored_node(&initial, &[("zone", "b")], true);
+        let action = reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        let patches = node_patches(&server);
+        assert_eq!(patches.len(), 1);
+        assert_eq!(
+            patches[0]["metadata"]["finalizers"],
+            serde_json::json!(["other/finalizer"])
+        );
+        // The node is still managed, only no longer held
+        assert!(patches[0]["metadata"].get("labels").is_none());
+        assert_eq!(stored_labels(&stored), labels(&[("zone", "a")]));
+
+        let node = restored_node(&initial, &[("zone", "b")], false);
+        let action = reconcile(Arc::new(node), ctx).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert_eq!(stored_labels(&stored), labels(&[("zone", "b")]));
+    }
+
+    /// A node already being deleted under the finalizer of finalizer mode is backed up before
+    /// the finalizer is released
+    #[tokio::test]
+    async fn test_backs_up_finalized_deleting_node() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, stored) = cluster(Some(&initial)).await;
+        let ctx = context(&server, Some(&initial));
+        let mut node = restored_node(&initial, &[("zone", "b")], true);
+        node.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        reconcile(Arc::new(node), ctx).await.unwrap();
+
+        assert_eq!(stored_labels(&stored), labels(&[("zone", "b")]));
+        let requests = server.requests();
+        let backup = requests
+            .iter()
+            .position(|r| r.method == Method::PATCH && r.uri.path().contains("/configmaps/"))
+            .expect("the node is backed up");
+        let release = requests
+            .iter()
+            .position(|r| r.method == Method::PATCH && r.uri.path() == "/api/v1/nodes/node-a")
+            .expect("the finalizer is released");
+        assert!(backup < release);
+    }
+
+    /// A new node is restored without a finalizer, and switching back to finalizer mode adds
+    /// one again
+    #[tokio::test]
+    async fn test_restores_without_finalizer() {
+        let initial = backup_configmap(&[("zone", "a")]);
+        let (server, _) = cluster(Some(&initial)).await;
+        let ctx = context(&server, None);
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        reconcile(Arc::new(node.clone()), ctx).await.unwrap();
+        let restore = server
+            .requests()
+            .into_iter()
+            .find(|r| r.is_restore())
+            .expect("the node is restored");
+        assert_eq!(restore.json()["metadata"]["labels"]["zone"], "a");
+        assert!(server
+            .requests()
+            .iter()
+            .all(|r| !r.uri.path().ends_with("/nodes/node-a")
+                || r.method != Method::PATCH
+                || !r.json().to_string().contains(FINALIZER_NAME)));
+
+        let ctx = Arc::new(Context::new(server.client()));
+        let seen = server.requests().len();
+        reconcile(Arc::new(node), ctx).await.unwrap();
+        let added = server.requests()[seen..]
+            .iter()
+            .any(|r| r.method == Method::PATCH && r.json().to_string().contains(FINALIZER_NAME));
+        assert!(added, "finalizer mode adds the finalizer back");
+    }
+}
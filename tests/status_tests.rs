@@ -0,0 +1,94 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use http::{Method, StatusCode};
+    use k8s_openapi::chrono::{TimeZone, Utc};
+    use label_preserver::{
+        status_configmap, write_status, ControllerConfig, LeaderElection, RestorePolicy,
+        STATUS_CONFIGMAP_NAME,
+    };
+
+    fn test_config() -> ControllerConfig {
+        ControllerConfig {
+            instance_id: "replica-0".to_string(),
+            node_names: Some(["worker-1".to_string()].into_iter().collect()),
+            policy: RestorePolicy {
+                label_expiry: vec!["maintenance.example.com/=7".parse().unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The status ConfigMap describes the resolved configuration and identity
+    #[test]
+    fn test_status_configmap_content() {
+        let started_at = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        let cm = status_configmap(&test_config(), started_at);
+        assert_eq!(cm.metadata.name.as_deref(), Some(STATUS_CONFIGMAP_NAME));
+        let data = cm.data.unwrap();
+        assert_eq!(data["instance_id"], "replica-0");
+        assert_eq!(data["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(data["started_at"], "2025-01-02T03:04:05+00:00");
+        assert_eq!(data["leader_election"], "disabled");
+        assert_eq!(data["leader"], "false");
+        let config: serde_json::Value = serde_json::from_str(&data["config"]).unwrap();
+        assert_eq!(config["node_names"], serde_json::json!(["worker-1"]));
+        assert_eq!(
+            config["label_expiry"],
+            serde_json::json!(["maintenance.example.com/=7"])
+        );
+        assert_eq!(config["burst_threshold"], 20);
+        assert_eq!(config["candidate_policy"], serde_json::Value::Null);
+    }
+
+    /// Under leader election, the status names the lease and that this replica holds it
+    #[test]
+    fn test_status_configmap_leader_election() {
+        let config = ControllerConfig {
+            leader_election: Some(LeaderElection {
+                lease_name: "preserver".to_string(),
+                lease_namespace: "kube-system".to_string(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let data = status_configmap(&config, Utc::now()).data.unwrap();
+        assert_eq!(data["leader_election"], "kube-system/preserver");
+        assert_eq!(data["leader"], "true");
+    }
+
+    /// The status is written with server-side apply under our field manager
+    #[tokio::test]
+    async fn test_write_status() {
+        let server = MockApiServer::start(|req| (StatusCode::OK, req.json())).await;
+        write_status(server.client(), &test_config(), Utc::now()).await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::PATCH);
+        assert_eq!(
+            requests[0].uri.path(),
+            format!("/api/v1/namespaces/default/configmaps/{STATUS_CONFIGMAP_NAME}")
+        );
+        let query = requests[0].uri.query().unwrap();
+        assert!(query.contains("fieldManager=node-label-preserver"));
+        assert_eq!(requests[0].json()["data"]["instance_id"], "replica-0");
+    }
+
+    /// Failing to write the status is not fatal
+    #[tokio::test]
+    async fn test_write_status_failure_is_ignored() {
+        let server = MockApiServer::start(|_| {
+            (
+                StatusCode::FORBIDDEN,
+                status_json(403, "configmaps is forbidden"),
+            )
+        })
+        .await;
+        write_status(server.client(), &test_config(), Utc::now()).await;
+        assert_eq!(server.requests().len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer, RecordedRequest};
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Node, NodeSpec, Taint};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::chrono::Utc;
+    use label_preserver::{
+        configmap_name, missing_taints, preserve_on_cleanup, reconcile, restore_on_apply,
+        types::PreservedTaint, Backup, Context, ControllerConfig, FINALIZER_NAME,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn taint(key: &str, value: Option<&str>, effect: &str) -> Taint {
+        Taint {
+            key: key.to_string(),
+            value: value.map(str::to_string),
+            effect: effect.to_string(),
+            time_added: None,
+        }
+    }
+
+    fn preserved(key: &str, value: Option<&str>, effect: &str) -> PreservedTaint {
+        PreservedTaint::of(&taint(key, value, effect))
+    }
+
+    fn node(taints: Vec<Taint>) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-a".to_string()),
+                finalizers: Some(vec![FINALIZER_NAME.to_string()]),
+                resource_version: Some("7".to_string()),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                taints: Some(taints),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn preserving() -> ControllerConfig {
+        ControllerConfig {
+            preserve_taints: true,
+            ..Default::default()
+        }
+    }
+
+    /// Taints the node lifecycle controller and autoscalers manage are left out of the backup,
+    /// and nothing is backed up unless taints are preserved
+    #[test]
+    fn test_preserved_taints() {
+        let node = node(vec![
+            taint("dedicated", Some("gpu"), "NoSchedule"),
+            taint("node.kubernetes.io/not-ready", None, "NoExecute"),
+            taint(
+                "node.cloudprovider.kubernetes.io/uninitialized",
+                Some("true"),
+                "NoSchedule",
+            ),
+            taint(
+                "ToBeDeletedByClusterAutoscaler",
+                Some("1700000000"),
+                "NoSchedule",
+            ),
+        ]);
+        assert_eq!(
+            preserving().preserved_taints(&node),
+            vec![preserved("dedicated", Some("gpu"), "NoSchedule")]
+        );
+        assert!(ControllerConfig::default()
+            .preserved_taints(&node)
+            .is_empty());
+
+        let preservation = preserve_on_cleanup(&node, &preserving(), "corr-1", Utc::now()).unwrap();
+        assert_eq!(
+            preservation.backup.taints,
+            vec![preserved("dedicated", Some("gpu"), "NoSchedule")]
+        );
+    }
+
+    /// A taint of the same key and effect on the node is kept whatever its value
+    #[test]
+    fn test_missing_taints() {
+        let node = node(vec![taint("dedicated", Some("cpu"), "NoSchedule")]);
+        let backed_up = [
+            preserved("dedicated", Some("gpu"), "NoSchedule"),
+            preserved("dedicated", Some("gpu"), "NoExecute"),
+            preserved("spot", None, "PreferNoSchedule"),
+        ];
+        assert_eq!(
+            missing_taints(&node, &backed_up),
+            vec![
+                preserved("dedicated", Some("gpu"), "NoExecute"),
+                preserved("spot", None, "PreferNoSchedule"),
+            ]
+        );
+        assert_eq!(missing_taints(&Node::default(), &backed_up), backed_up);
+    }
+
+    /// Restored taints are reported but never part of the applied node
+    #[test]
+    fn test_restore_on_apply() {
+        let backup = Backup {
+            taints: vec![preserved("dedicated", Some("gpu"), "NoSchedule")],
+            ..Default::default()
+        };
+        let restoration = restore_on_apply(&node(Vec::new()), &backup, &preserving(), Utc::now());
+        assert_eq!(restoration.added_taints, backup.taints);
+        assert!(restoration.node.spec.is_none());
+
+        let disabled = ControllerConfig::default();
+        let restoration = restore_on_apply(&node(Vec::new()), &backup, &disabled, Utc::now());
+        assert!(restoration.added_taints.is_empty());
+    }
+
+    fn is_taint_patch(req: &RecordedRequest) -> bool {
+        req.method == Method::PATCH
+            && req
+                .headers
+                .get("content-type")
+                .is_some_and(|value| value == "application/merge-patch+json")
+    }
+
+    /// Serve a backup with a taint and a node with a not-ready taint, answering the first
+    /// `conflicts` taint patches with 409
+    async fn cluster(conflicts: usize) -> MockApiServer {
+        let backup = Backup {
+            taints: vec![preserved("dedicated", Some("gpu"), "NoSchedule")],
+            correlation_id: Some("corr-1".to_string()),
+            ..Default::default()
+        };
+        let configmap = serde_json::json!({
+            "metadata": { "name": configmap_name("node-a"), "namespace": "default" },
+            "data": backup.to_configmap_data().unwrap(),
+        });
+        let live = serde_json::to_value(node(vec![taint(
+            "node.kubernetes.io/not-ready",
+            None,
+            "NoExecute",
+        )]))
+        .unwrap();
+        let patches = AtomicUsize::new(0);
+        MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.contains("/configmaps/") => {
+                (StatusCode::OK, configmap.clone())
+            }
+            (&Method::GET, "/api/v1/nodes/node-a") => (StatusCode::OK, live.clone()),
+            _ if is_taint_patch(req) => {
+                if patches.fetch_add(1, Ordering::SeqCst) < conflicts {
+                    (StatusCode::CONFLICT, status_json(409, "Conflict"))
+                } else {
+                    (StatusCode::OK, live.clone())
+                }
+            }
+            (&Method::PATCH, _) => (StatusCode::OK, req.json()),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await
+    }
+
+    /// Backed up taints are added to the node's current ones, guarded by its resourceVersion,
+    /// before the labels are applied
+    #[tokio::test]
+    async fn test_restore_taints() {
+        let server = cluster(0).await;
+        let ctx = Arc::new(Context::with_config(server.client(), preserving()));
+        reconcile(Arc::new(node(Vec::new())), ctx).await.unwrap();
+
+        let requests = server.requests();
+        let patch = requests.iter().position(is_taint_patch).unwrap();
+        let apply = requests.iter().position(|r| r.is_restore()).unwrap();
+        assert!(patch < apply);
+        assert_eq!(
+            requests[patch].json(),
+            serde_json::json!({
+                "metadata": { "resourceVersion": "7" },
+                "spec": { "taints": [
+                    { "key": "node.kubernetes.io/not-ready", "effect": "NoExecute" },
+                    { "key": "dedicated", "value": "gpu", "effect": "NoSchedule" },
+                ] },
+            })
+        );
+        assert!(requests[apply].json().get("spec").is_none());
+    }
+
+    /// A conflicting update rereads the node and tries again, up to a limit
+    #[tokio::test]
+    async fn test_restore_taints_conflict() {
+        let server = cluster(1).await;
+        let ctx = Arc::new(Context::with_config(server.client(), preserving()));
+        reconcile(Arc::new(node(Vec::new())), ctx).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|r| is_taint_patch(r)).count(), 2);
+        assert!(requests.iter().any(|r| r.is_restore()));
+
+        let server = cluster(usize::MAX).await;
+        let ctx = Arc::new(Context::with_config(server.client(), preserving()));
+        reconcile(Arc::new(node(Vec::new())), ctx)
+            .await
+            .unwrap_err();
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|r| is_taint_patch(r)).count(), 3);
+        assert!(!requests.iter().any(|r| r.is_restore()));
+    }
+}
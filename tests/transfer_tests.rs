@@ -0,0 +1,201 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{cluster, context, labels, node, ConfigMaps, MockApiServer};
+
+    use label_preserver::transfer::export_all_backups_with;
+    use label_preserver::transfer::{import_backups_with, ImportOptions, ImportReport};
+    use label_preserver::{
+        configmap_name, export_all_backups, import_backups, reconcile, shard_configmap_name,
+        shard_key, Backup, BackupLayout, ControllerConfig, Error, LabelFilter, RestorePolicy,
+    };
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// Back up `node-a` and `node-b` by deleting them
+    async fn preserve_two_nodes(server: &MockApiServer) {
+        let ctx = context(server, ControllerConfig::default());
+        for (name, node_labels) in [
+            ("node-a", labels(&[("zone", "a"), ("workload", "database")])),
+            ("node-b", labels(&[("zone", "b")])),
+        ] {
+            reconcile(Arc::new(node(name, node_labels, true)), ctx.clone())
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Labels exported from one namespace are restored onto the same nodes after being imported
+    /// into a wiped one
+    #[tokio::test]
+    async fn test_round_trip() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        preserve_two_nodes(&server).await;
+        configmaps.lock().unwrap().insert(
+            "unrelated".to_string(),
+            json!({"metadata": {"name": "unrelated", "namespace": "default"}}),
+        );
+
+        let snapshot = export_all_backups(server.client(), "default")
+            .await
+            .unwrap();
+        let expected = BTreeMap::from([
+            (
+                "node-a".to_string(),
+                labels(&[("zone", "a"), ("workload", "database")]),
+            ),
+            ("node-b".to_string(), labels(&[("zone", "b")])),
+        ]);
+        assert_eq!(snapshot, expected);
+        let json = serde_json::to_string(&snapshot).unwrap();
+
+        configmaps.lock().unwrap().clear();
+        let snapshot = serde_json::from_str(&json).unwrap();
+        let report = import_backups(server.client(), "default", &snapshot, false)
+            .await
+            .unwrap();
+        assert_eq!(report.imported, vec!["node-a", "node-b"]);
+        assert!(configmaps
+            .lock()
+            .unwrap()
+            .contains_key(&configmap_name("node-a")));
+
+        let ctx = context(&server, ControllerConfig::default());
+        for (name, node_labels) in &expected {
+            let node = node(name, BTreeMap::new(), false);
+            reconcile(Arc::new(node), ctx.clone()).await.unwrap();
+            let restore = server
+                .requests()
+                .into_iter()
+                .rev()
+                .find(|r| r.is_restore() && r.uri.path().ends_with(name.as_str()))
+                .expect("the node is restored");
+            let restored = restore.json()["metadata"]["labels"].clone();
+            for (key, value) in node_labels {
+                assert_eq!(restored[key], value.as_str(), "{} on {}", key, name);
+            }
+        }
+    }
+
+    /// Existing backups are kept unless overwriting, and a dry run writes nothing
+    #[tokio::test]
+    async fn test_import_existing() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        preserve_two_nodes(&server).await;
+        let snapshot = BTreeMap::from([
+            ("node-a".to_string(), labels(&[("zone", "imported")])),
+            ("node-c".to_string(), labels(&[("zone", "c")])),
+        ]);
+
+        let dry_run = ImportOptions {
+            overwrite: true,
+            dry_run: true,
+        };
+        let config = ControllerConfig::default();
+        let report = import_backups_with(server.client(), &config, &snapshot, dry_run)
+            .await
+            .unwrap();
+        assert_eq!(report.imported, vec!["node-a", "node-c"]);
+        assert_eq!(configmaps.lock().unwrap().len(), 2);
+
+        let report = import_backups(server.client(), "default", &snapshot, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: vec!["node-c".to_string()],
+                skipped: vec!["node-a".to_string()],
+            }
+        );
+        let exported = export_all_backups(server.client(), "default")
+            .await
+            .unwrap();
+        assert_eq!(exported["node-a"]["zone"], "a");
+        assert_eq!(exported["node-c"]["zone"], "c");
+
+        import_backups(server.client(), "default", &snapshot, true)
+            .await
+            .unwrap();
+        let exported = export_all_backups(server.client(), "default")
+            .await
+            .unwrap();
+        assert_eq!(exported["node-a"], labels(&[("zone", "imported")]));
+        assert_eq!(exported.len(), 3);
+    }
+
+    /// A snapshot naming an invalid node is refused before anything is written
+    #[tokio::test]
+    async fn test_import_invalid_node() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        let snapshot = BTreeMap::from([
+            ("node-a".to_string(), labels(&[("zone", "a")])),
+            ("Not_A_Node".to_string(), labels(&[("zone", "b")])),
+        ]);
+        let result = import_backups(server.client(), "default", &snapshot, true).await;
+        assert!(matches!(result, Err(Error::InvalidSnapshotNode(name, _)) if name == "Not_A_Node"));
+        assert!(configmaps.lock().unwrap().is_empty());
+        assert!(server.requests().is_empty());
+    }
+
+    /// An import is written the way the controller's config backs nodes up: filtered, and into
+    /// shards under the sharded layout, where a backup already in a shard is found. Export
+    /// refuses the sharded layout.
+    #[tokio::test]
+    async fn test_import_with_config() {
+        let configmaps = ConfigMaps::default();
+        let server = cluster(configmaps.clone()).await;
+        let config = ControllerConfig {
+            backup_layout: BackupLayout::Sharded,
+            policy: RestorePolicy {
+                label_filter: LabelFilter {
+                    ignore_prefixes: vec!["node.kubernetes.io/".to_string()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let snapshot = BTreeMap::from([(
+            "node-a".to_string(),
+            labels(&[("zone", "a"), ("node.kubernetes.io/instance-type", "m5")]),
+        )]);
+
+        let report = import_backups_with(
+            server.client(),
+            &config,
+            &snapshot,
+            ImportOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.imported, vec!["node-a"]);
+        let shard = configmaps.lock().unwrap()[&shard_configmap_name("node-a")].clone();
+        assert!(!configmaps
+            .lock()
+            .unwrap()
+            .contains_key(&configmap_name("node-a")));
+        let entry: BTreeMap<String, String> =
+            serde_json::from_str(shard["data"][shard_key("node-a")].as_str().unwrap()).unwrap();
+        let backup = Backup::from_configmap_data(&entry).unwrap();
+        assert_eq!(backup.labels, labels(&[("zone", "a")]));
+
+        let report = import_backups_with(
+            server.client(),
+            &config,
+            &snapshot,
+            ImportOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.skipped, vec!["node-a"]);
+
+        let result = export_all_backups_with(server.client(), &config).await;
+        assert!(matches!(result, Err(Error::ShardedExport)));
+    }
+}
@@ -0,0 +1,138 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::common::{status_json, MockApiServer};
+    use axum::body::Body;
+    use http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use k8s_openapi::chrono::{DateTime, Utc};
+    use label_preserver::{
+        admin, configmap_name,
+        types::{PreservedState, PreservedTaint},
+        Backup, Context, MachineIdentity, SCHEMA_VERSION,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    /// The wire format at the current schema version. A change to this file is a change to the
+    /// format every export, report, and admin response uses.
+    const SNAPSHOT: &str = include_str!("snapshots/preserved_state.json");
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn state() -> PreservedState {
+        let preserved_at: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+        let labels = map(&[
+            ("topology.kubernetes.io/zone", "us-east-1a"),
+            ("team", "ml"),
+        ]);
+        PreservedState {
+            schema_version: SCHEMA_VERSION,
+            node_name: Some("node-a".to_string()),
+            preserved_at: labels
+                .keys()
+                .map(|key| (key.clone(), preserved_at))
+                .collect(),
+            backed_up_at: Some(preserved_at),
+            labels,
+            annotations: map(&[("example.com/owner", "ml-infra")]),
+            taints: vec![
+                PreservedTaint {
+                    key: "dedicated".to_string(),
+                    value: Some("gpu".to_string()),
+                    effect: "NoSchedule".to_string(),
+                },
+                PreservedTaint {
+                    key: "maintenance".to_string(),
+                    value: None,
+                    effect: "NoExecute".to_string(),
+                },
+            ],
+            correlation_id: Some("6f1c2e7a-0000-4000-8000-000000000000".to_string()),
+            identity: MachineIdentity {
+                provider_id: Some("aws:///us-east-1a/i-0123456789abcdef0".to_string()),
+                machine_id: Some("ec2a1b2c3d4e5f".to_string()),
+            },
+        }
+    }
+
+    /// The serialized state matches the snapshot field for field, both ways
+    #[test]
+    fn test_snapshot() {
+        let snapshot: serde_json::Value = serde_json::from_str(SNAPSHOT).unwrap();
+        assert_eq!(serde_json::to_value(state()).unwrap(), snapshot);
+        assert_eq!(
+            serde_json::from_str::<PreservedState>(SNAPSHOT).unwrap(),
+            state()
+        );
+    }
+
+    /// Payloads written before fields were added still deserialize, with defaults for the rest
+    #[test]
+    fn test_older_payloads() {
+        let state: PreservedState =
+            serde_json::from_str(r#"{ "labels": { "zone": "a" } }"#).unwrap();
+        assert_eq!(state.schema_version, 1);
+        assert_eq!(state.labels, map(&[("zone", "a")]));
+        assert!(state.annotations.is_empty() && state.taints.is_empty());
+        assert_eq!(state.identity, MachineIdentity::default());
+
+        // Empty optional fields are left out
+        let state: PreservedState = serde_json::from_str(r#"{ "schema_version": 2 }"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(&state).unwrap(),
+            serde_json::json!({ "schema_version": 2, "labels": {}, "identity": {} })
+        );
+    }
+
+    /// The ConfigMap payload round-trips through the state
+    #[test]
+    fn test_configmap_round_trip() {
+        let data = state().to_configmap_data().unwrap();
+        let read = PreservedState::from_configmap_data(Some("node-a".to_string()), &data).unwrap();
+        assert_eq!(read, state());
+        assert_eq!(
+            Backup::from_configmap_data(&data).unwrap(),
+            read.to_backup()
+        );
+    }
+
+    async fn get(ctx: Arc<Context>, uri: &str) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = admin::router(ctx).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body.to_vec())
+    }
+
+    /// The admin API serves a node's backup as a preserved state
+    #[tokio::test]
+    async fn test_admin_backup() {
+        let data = state().to_configmap_data().unwrap();
+        let server = MockApiServer::start(move |req| match (&req.method, req.uri.path()) {
+            (&Method::GET, path) if path.ends_with(&configmap_name("node-a")) => (
+                StatusCode::OK,
+                serde_json::json!({ "metadata": { "name": configmap_name("node-a") }, "data": data }),
+            ),
+            _ => (StatusCode::NOT_FOUND, status_json(404, "not found")),
+        })
+        .await;
+        let ctx = Arc::new(Context::new(server.client()));
+
+        let (status, body) = get(ctx.clone(), "/backups/node-a").await;
+        assert_eq!(status, StatusCode::OK);
+        let served: PreservedState = serde_json::from_slice(&body).unwrap();
+        assert_eq!(served.node_name.as_deref(), Some("node-a"));
+        assert_eq!(served.labels, state().labels);
+
+        let (status, _) = get(ctx, "/backups/node-b").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}